@@ -1,10 +1,29 @@
-use std::path::PathBuf;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 
-use clap::{Args, Parser, Subcommand};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum, ValueHint};
+use clap_complete::Shell;
+
+use aporture::fs::config::IpFamily;
+use aporture::passphrase::Wordlist;
+use aporture::transfer::{ArchiveFormat, Compression, OnConflict, SymlinkMode};
+use aporture::transfer::{DEFAULT_CHUNK_SIZE, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
 
 #[derive(Debug, Parser)]
 #[command(name = "Aporture", author, version, about)]
 pub struct Cli {
+    /// Print results as JSON for scripting
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    /// Do not track or show transfer progress
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+
+    /// Answer yes to every confirmation, such as deleting a contact, instead of asking
+    #[arg(short, long, global = true)]
+    pub yes: bool,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -12,27 +31,82 @@ pub struct Cli {
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     /// Send a file
+    ///
+    /// On unix the transfer pauses when the process receives SIGUSR1 and resumes on the next.
     Send {
-        path: PathBuf,
+        /// Files or folders to send, use - to send standard input. Several are sent one after
+        /// another over the same pairing, the receiver gets them all with --count
+        #[arg(value_hint = ValueHint::AnyPath, required = true)]
+        paths: Vec<PathBuf>,
 
         #[command(flatten)]
         method: SendMethod,
 
+        /// Do not print the passphrase given
+        #[arg(long)]
+        hide_passphrase: bool,
+
         #[arg(short, long, value_names(["NAME"]))]
         save: Option<String>,
+
+        #[command(flatten)]
+        options: SendOptions,
     },
     /// Receive a file
+    ///
+    /// On unix the transfer pauses when the process receives SIGUSR1 and resumes on the next.
     Receive {
-        #[arg(short, long, value_names(["PATH"]))]
+        /// Folder to save the transfer into, or the exact path to save it as if it is not a folder.
+        /// Use - to write a piped transfer to standard output
+        #[arg(
+            short,
+            long,
+            visible_alias = "output",
+            visible_short_alias = 'o',
+            value_names(["PATH"]),
+            value_hint = ValueHint::AnyPath
+        )]
         destination: Option<PathBuf>,
 
         #[command(flatten)]
         method: ReceiveMethod,
 
+        /// Do not print the passphrase given
+        #[arg(long)]
+        hide_passphrase: bool,
+
         #[arg(short, long, value_names(["NAME"]))]
         save: Option<String>,
+
+        #[command(flatten)]
+        options: ReceiveOptions,
+    },
+
+    /// Check files received before against the originals without transferring them again
+    Verify {
+        #[command(subcommand)]
+        command: VerifyCommand,
+    },
+
+    /// Wait for transfers from saved contacts and receive them automatically
+    Listen {
+        #[arg(short, long, value_names(["PATH"]), value_hint = ValueHint::DirPath)]
+        destination: Option<PathBuf>,
+
+        /// Only accept transfers from these contacts, defaults to the configured ones or else
+        /// all of them
+        #[arg(short, long, value_names(["NAME"]))]
+        contact: Vec<String>,
+
+        /// Port contacts connect to directly before using the server, any free one by default.
+        /// A fixed port keeps the address they saved valid after restarting
+        #[arg(short, long)]
+        port: Option<u16>,
     },
 
+    /// List recent transfers
+    History,
+
     /// Query or modify saved contacts
     Contacts {
         #[command(subcommand)]
@@ -50,6 +124,15 @@ pub enum Commands {
         #[command(subcommand)]
         command: ConfigCommand,
     },
+
+    /// Check the connection to the server and the ways of reaching a peer directly
+    Doctor {
+        #[command(flatten)]
+        family: FamilyOverride,
+    },
+
+    /// Print a completion script for a shell
+    Completions { shell: Shell },
 }
 
 #[derive(Debug, Args)]
@@ -58,19 +141,203 @@ pub struct SendMethod {
     #[arg(short, long)]
     pub passphrase: Option<String>,
 
+    /// Read the passphrase from a file instead of the command line
+    #[arg(long, value_names(["PATH"]), value_hint = ValueHint::FilePath)]
+    pub passphrase_file: Option<PathBuf>,
+
+    /// Read the passphrase from the first line of standard input
+    #[arg(long)]
+    pub passphrase_stdin: bool,
+
     #[arg(short, long, value_names(["NAME"]))]
     pub contact: Option<String>,
 }
 
+#[derive(Debug, Args)]
+pub struct SendOptions {
+    /// Exclude entries matching a .gitignore style pattern when sending a folder
+    #[arg(short, long, value_names(["PATTERN"]))]
+    pub exclude: Vec<String>,
+
+    /// Do not send directories without any entry when sending a folder
+    #[arg(long)]
+    pub skip_empty_dirs: bool,
+
+    /// Verify the whole transfer with a single hash instead of one per file
+    #[arg(long)]
+    pub stream_hash: bool,
+
+    /// When to send a folder as a single compressed archive: auto, always or never.
+    /// Auto compresses folders with many small files, see the compression config
+    #[arg(long, value_names(["MODE"]), default_value_t)]
+    pub compress: Compression,
+
+    /// How to send symbolic links inside a folder: follow, preserve or skip
+    #[arg(long, value_names(["MODE"]), default_value_t)]
+    pub symlinks: SymlinkMode,
+
+    /// Replace the encryption key after this many messages, useful for very large transfers
+    #[arg(long, value_names(["MESSAGES"]), value_parser = clap::value_parser!(u64).range(1..))]
+    pub rekey_every: Option<u64>,
+
+    /// Size of the chunks files are sent in, from 1024 to 65535 bytes as every chunk is a
+    /// message with a 16 bit length. Larger chunks can be faster on fast connections
+    #[arg(
+        long,
+        value_names(["BYTES"]),
+        default_value_t = DEFAULT_CHUNK_SIZE,
+        value_parser = chunk_size,
+    )]
+    pub chunk_size: usize,
+
+    /// Send the entries of the tar or zip archive at the path as a folder, without extracting it
+    #[arg(long, conflicts_with_all = ["exclude", "skip_empty_dirs"])]
+    pub from_archive: bool,
+
+    #[command(flatten)]
+    pub connection: ConnectionOptions,
+}
+
+#[derive(Debug, Args)]
+pub struct ReceiveOptions {
+    /// Transfers to receive over the same pairing, for a sender given several paths
+    #[arg(long, default_value_t = 1, value_names(["N"]), value_parser = clap::value_parser!(u64).range(1..))]
+    pub count: u64,
+
+    /// Times a corrupted file is requested again before giving up
+    #[arg(long, default_value_t = 1, value_names(["N"]))]
+    pub retries: usize,
+
+    /// Skip files that stay corrupted after retrying instead of aborting
+    #[arg(long)]
+    pub skip_failed: bool,
+
+    /// What to do when the destination already exists: rename, overwrite, skip or merge.
+    /// Defaults to the configured policy
+    #[arg(long, value_names(["POLICY"]))]
+    pub on_conflict: Option<OnConflict>,
+
+    /// Add a received folder into the existing copy, only receiving the files that changed.
+    /// Same as --on-conflict merge
+    #[arg(long, conflicts_with = "on_conflict")]
+    pub merge: bool,
+
+    /// Save the transfer as a single tar or zip archive instead of extracting it.
+    /// Named after it with the extension if the destination is a folder
+    #[arg(long, value_names(["FORMAT"]), conflicts_with = "merge")]
+    pub archive: Option<ArchiveFormat>,
+
+    /// Keep waiting up to this many seconds for a sender that has not started yet
+    #[arg(long, value_names(["SECS"]))]
+    pub wait: Option<u64>,
+
+    /// Longest pause in seconds between attempts while waiting for the sender
+    #[arg(long, value_names(["SECS"]), requires = "wait")]
+    pub poll_interval: Option<u64>,
+
+    #[command(flatten)]
+    pub connection: ConnectionOptions,
+}
+
+/// How the peers connect, shared by both sides
+#[derive(Debug, Args)]
+#[allow(clippy::struct_excessive_bools)] // Independent command line flags
+pub struct ConnectionOptions {
+    /// Skip the application encryption on direct connections if the peer asks for it too.
+    /// QUIC still encrypts the data, only use it on trusted networks
+    #[arg(long)]
+    pub insecure_lan: bool,
+
+    /// Never connect directly to the peer, always transfer through the server relay
+    #[arg(long)]
+    pub force_relay: bool,
+
+    /// Do not map a port on the router with upnp, saving its timeout on networks without it
+    #[arg(long)]
+    pub no_upnp: bool,
+
+    /// Do not find the external address for hole punching
+    #[arg(long)]
+    pub no_holepunch: bool,
+
+    #[command(flatten)]
+    pub bind: BindOptions,
+}
+
+/// Local address the transfer goes out from, defaults to the configured one
+#[derive(Debug, Args)]
+#[group(multiple = false)]
+pub struct BindOptions {
+    /// Bind the transfer sockets to this local address, for example to stay off a VPN
+    #[arg(long, value_names(["IP"]))]
+    pub bind_ip: Option<IpAddr>,
+
+    /// Bind the transfer sockets to the address of this network interface
+    #[arg(long, value_names(["NAME"]))]
+    pub interface: Option<String>,
+}
+
 #[derive(Debug, Args)]
 #[group(required = true, multiple = false)]
 pub struct ReceiveMethod {
     pub passphrase: Option<String>,
 
+    /// Read the passphrase from a file instead of the command line
+    #[arg(long, value_names(["PATH"]), value_hint = ValueHint::FilePath)]
+    pub passphrase_file: Option<PathBuf>,
+
+    /// Read the passphrase from the first line of standard input
+    #[arg(long)]
+    pub passphrase_stdin: bool,
+
     #[arg(short, long, value_names(["NAME"]))]
     pub contact: Option<String>,
 }
 
+#[derive(Debug, Subcommand)]
+pub enum VerifyCommand {
+    /// Share the hashes of the original files, not their contents
+    Send {
+        /// File or folder that was sent
+        #[arg(value_hint = ValueHint::AnyPath)]
+        path: PathBuf,
+
+        #[command(flatten)]
+        method: SendMethod,
+
+        /// Do not print the passphrase given
+        #[arg(long)]
+        hide_passphrase: bool,
+
+        /// Exclude entries matching a .gitignore style pattern, like when it was sent
+        #[arg(short, long, value_names(["PATTERN"]))]
+        exclude: Vec<String>,
+
+        /// How symbolic links were sent: follow, preserve or skip
+        #[arg(long, value_names(["MODE"]), default_value_t)]
+        symlinks: SymlinkMode,
+
+        #[command(flatten)]
+        connection: ConnectionOptions,
+    },
+    /// Hash the copy received before and report the files that differ from the originals
+    Receive {
+        /// The received copy, or the folder it was saved in. Defaults to the download folder
+        #[arg(short, long, value_names(["PATH"]), value_hint = ValueHint::AnyPath)]
+        destination: Option<PathBuf>,
+
+        #[command(flatten)]
+        method: ReceiveMethod,
+
+        /// Do not print the passphrase given
+        #[arg(long)]
+        hide_passphrase: bool,
+
+        #[command(flatten)]
+        connection: ConnectionOptions,
+    },
+}
+
 #[derive(Debug, Subcommand)]
 pub enum PairCommand {
     Start {
@@ -81,16 +348,335 @@ pub enum PairCommand {
         name: String,
         passphrase: String,
     },
+    /// List paired contacts with the fingerprint of their key
+    List,
 }
 
 #[derive(Debug, Subcommand)]
 pub enum ContactCommand {
-    List,
-    Delete { name: String },
+    List {
+        /// How the contacts are printed, --json always prints them as JSON
+        #[arg(long, value_enum, default_value_t)]
+        output_format: ListFormat,
+
+        /// Only list the contacts with this tag
+        #[arg(long)]
+        tag: Option<String>,
+    },
+    Delete {
+        name: String,
+    },
+    /// Replace the tags a contact is grouped under, none removes them all
+    Tag {
+        name: String,
+        tags: Vec<String>,
+    },
+    /// Rename a contact keeping its key
+    Rename {
+        old: String,
+        new: String,
+    },
+    /// Change the password of the contact database
+    Passwd,
+    /// Export every contact to a file encrypted with a new password
+    Export {
+        #[arg(value_hint = ValueHint::FilePath)]
+        file: PathBuf,
+    },
+    /// Import contacts from an exported file
+    Import {
+        #[arg(value_hint = ValueHint::FilePath)]
+        file: PathBuf,
+
+        /// Overwrite contacts with the same name instead of keeping the existing ones
+        #[arg(long)]
+        replace: bool,
+    },
+}
+
+/// Format of the contact list
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ListFormat {
+    /// Markdown table
+    #[default]
+    Table,
+    Csv,
+    Json,
 }
 
 #[derive(Debug, Subcommand)]
 pub enum ConfigCommand {
-    Get,
-    Set { server_address: String },
+    /// Show the configured server, or every value of the config with --all or --json as
+    /// `config show` does
+    Get {
+        /// Print every value of the config
+        #[arg(long)]
+        all: bool,
+
+        /// Print every value of the config as JSON, for example to share it when asking for help
+        #[arg(long)]
+        json: bool,
+    },
+    /// Replace the primary server or the download folder
+    #[command(arg_required_else_help = true)]
+    Set {
+        server_address: Option<String>,
+
+        /// Folder received files are saved in when no destination is given
+        #[arg(long, value_names(["PATH"]), value_hint = ValueHint::DirPath)]
+        download_dir: Option<PathBuf>,
+
+        /// Save received files in the system download folder again
+        #[arg(long, conflicts_with = "download_dir")]
+        default_download_dir: bool,
+    },
+    /// Set the amount of words of generated passphrases
+    PassphraseWords {
+        #[arg(value_parser = clap::value_parser!(u16).range(2..))]
+        count: u16,
+    },
+    /// Set the list generated passphrases take their words from
+    Wordlist {
+        /// One of long or short
+        wordlist: Wordlist,
+    },
+    /// Set what to do when a received file already exists
+    OnConflict {
+        /// One of rename, overwrite, skip or merge
+        policy: OnConflict,
+    },
+    /// Set which folders are compressed before sending when compression is automatic
+    Compression {
+        /// Least amount of files in the folder
+        files: u64,
+        /// Largest total size of the folder in bytes
+        size: u64,
+    },
+    /// Set how long the address a contact was reached at is tried before the server,
+    /// zero always uses the server
+    HintAge {
+        /// Age in seconds
+        seconds: u64,
+    },
+    /// Set the local address transfer sockets bind to, none lets the system pick it again
+    BindIp { ip: Option<IpAddr> },
+    /// Set the name shown to peers when pairing, none stops sending one
+    DisplayName { name: Option<String> },
+    /// Set whether pairing maps a port on the router with upnp
+    Upnp {
+        #[arg(action = ArgAction::Set)]
+        enabled: bool,
+    },
+    /// Set whether pairing finds the external address for hole punching
+    HolePunching {
+        #[arg(action = ArgAction::Set)]
+        enabled: bool,
+    },
+    /// Set the STUN servers asked for the external address when the server does not answer it,
+    /// none restores the default ones
+    Stun {
+        #[arg(value_names(["HOST:PORT"]))]
+        servers: Vec<String>,
+    },
+    /// Set the contacts accepted by listen, none accepts every saved contact
+    ListenContacts {
+        #[arg(value_names(["NAME"]))]
+        names: Vec<String>,
+    },
+    /// Add a fallback server tried after the configured ones
+    Add { server_address: String },
+    /// Remove a configured server
+    Remove { server_address: String },
+    /// Move a server to a position in the order they are tried
+    Move {
+        server_address: String,
+        /// Position starting from 0 for the primary server
+        position: usize,
+    },
+    /// Replace the whole config with the default one, also when it can not be read
+    Reset,
+    /// Show the effective configuration and where each value came from
+    Show {
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Resolve the configured server address
+    Resolve {
+        #[command(flatten)]
+        family: FamilyOverride,
+    },
+}
+
+#[derive(Debug, Args)]
+#[group(multiple = false)]
+pub struct FamilyOverride {
+    /// Only use IPv4 addresses
+    #[arg(long)]
+    pub force_v4: bool,
+
+    /// Only use IPv6 addresses
+    #[arg(long)]
+    pub force_v6: bool,
+}
+
+/// Whether the path refers to the standard input or output instead of a file
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// Chunk size in bytes, rejecting sizes a message can not carry
+fn chunk_size(value: &str) -> Result<usize, String> {
+    let size = value.parse::<usize>().map_err(|e| e.to_string())?;
+
+    if (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&size) {
+        Ok(size)
+    } else {
+        Err(format!(
+            "chunk size must be between {MIN_CHUNK_SIZE} and {MAX_CHUNK_SIZE} bytes, \
+            the most a message can carry"
+        ))
+    }
+}
+
+impl Commands {
+    /// Whether the command writes transferred data to the standard output
+    pub fn writes_stdout(&self) -> bool {
+        matches!(self, Self::Receive { destination: Some(d), .. } if is_stdio(d))
+    }
+}
+
+impl BindOptions {
+    /// Address given directly or of the interface, `None` if neither was given.
+    pub fn ip(&self) -> anyhow::Result<Option<IpAddr>> {
+        if let Some(name) = &self.interface {
+            let ip = aporture::pairing::interface_ip(name)
+                .map_err(|_| anyhow::anyhow!("Could not find an address of interface {name}"))?;
+
+            return Ok(Some(ip));
+        }
+
+        Ok(self.bind_ip)
+    }
+}
+
+impl FamilyOverride {
+    pub const fn family(&self) -> Option<IpFamily> {
+        if self.force_v4 {
+            Some(IpFamily::V4)
+        } else if self.force_v6 {
+            Some(IpFamily::V6)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn test_cli_definition_is_valid() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_receive_to_stdout_is_detected() {
+        let cli = Cli::parse_from(["aporture", "receive", "pass", "-d", "-"]);
+        assert!(cli.command.writes_stdout());
+
+        let cli = Cli::parse_from(["aporture", "receive", "pass", "-d", "dir"]);
+        assert!(!cli.command.writes_stdout());
+
+        let cli = Cli::parse_from(["aporture", "send", "-"]);
+        assert!(!cli.command.writes_stdout());
+    }
+
+    #[test]
+    fn test_passphrase_sources_are_exclusive() {
+        let cli = Cli::try_parse_from(["aporture", "receive", "--passphrase-stdin"]);
+        assert!(cli.is_ok());
+
+        let cli = Cli::try_parse_from(["aporture", "receive", "pass", "--passphrase-stdin"]);
+        assert!(cli.is_err());
+
+        let cli = Cli::try_parse_from(["aporture", "receive", "--hide-passphrase"]);
+        assert!(cli.is_err());
+
+        let cli =
+            Cli::try_parse_from(["aporture", "send", "f", "--passphrase-file", "p", "-c", "a"]);
+        assert!(cli.is_err());
+    }
+
+    #[test]
+    fn test_yes_is_accepted_after_the_subcommand() {
+        let cli = Cli::parse_from(["aporture", "contacts", "delete", "alice", "--yes"]);
+        assert!(cli.yes);
+
+        let cli = Cli::parse_from(["aporture", "-y", "--json", "contacts", "delete", "alice"]);
+        assert!(cli.yes && cli.json);
+    }
+
+    #[test]
+    fn test_chunk_size_is_bounded() {
+        let cli = Cli::try_parse_from(["aporture", "send", "f", "--chunk-size", "65535"]);
+        assert!(cli.is_ok());
+
+        for size in ["0", "65536", "big"] {
+            let cli = Cli::try_parse_from(["aporture", "send", "f", "--chunk-size", size]);
+            assert!(cli.is_err(), "{size} must be rejected");
+        }
+    }
+
+    #[test]
+    fn test_several_paths_share_a_pairing() {
+        let cli = Cli::parse_from(["aporture", "send", "a", "b"]);
+        assert!(matches!(cli.command, Commands::Send { paths, .. } if paths.len() == 2));
+
+        let cli = Cli::parse_from(["aporture", "receive", "pass", "--count", "2"]);
+        assert!(matches!(cli.command, Commands::Receive { options, .. } if options.count == 2));
+
+        let cli = Cli::try_parse_from(["aporture", "receive", "pass", "--count", "0"]);
+        assert!(cli.is_err());
+    }
+
+    #[test]
+    fn test_verify_takes_the_connection_options() {
+        let cli = Cli::parse_from(["aporture", "verify", "send", "f", "--force-relay"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Verify { command: VerifyCommand::Send { connection, .. } }
+                if connection.force_relay
+        ));
+
+        let cli = Cli::parse_from(["aporture", "verify", "receive", "pass", "--no-upnp"]);
+        assert!(matches!(
+            cli.command,
+            Commands::Verify { command: VerifyCommand::Receive { connection, .. } }
+                if connection.no_upnp
+        ));
+    }
+
+    #[test]
+    fn test_completions_cover_subcommands() {
+        let mut script = Vec::new();
+
+        clap_complete::generate(Shell::Bash, &mut Cli::command(), "aporture", &mut script);
+
+        let script = String::from_utf8(script).expect("Completion script is utf8");
+
+        for subcommand in [
+            "send",
+            "receive",
+            "contacts",
+            "pair",
+            "config",
+            "--passphrase",
+        ] {
+            assert!(script.contains(subcommand), "Missing {subcommand}");
+        }
+    }
 }
@@ -1,15 +1,29 @@
 #![allow(clippy::similar_names)]
 
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use colored::Colorize;
+use serde_json::json;
 use tokio::io::AsyncReadExt;
 
+use crate::args::{self, ListFormat, SendOptions};
 use crate::contacts::Holder;
+use crate::output::{self, Output};
 use crate::progress;
-use aporture::pairing::AporturePairingProtocol;
-use aporture::transfer::AportureTransferProtocol;
+use aporture::fs::config::{self, Config, ConfigSource, IpFamily, Server};
+use aporture::fs::contacts::{Conflict, Contacts};
+use aporture::fs::history::{self, Direction, Entry, History};
+use aporture::net::quic::{DEFAULT_KEEP_ALIVE_INTERVAL, DEFAULT_RECONNECT_WINDOW};
+use aporture::pairing::error::{Error as PairingError, Hello};
+use aporture::pairing::{diagnostics, direct, DEFAULT_POLL_INTERVAL};
+use aporture::pairing::{AporturePairingProtocol, Kind, PairInfo, Start};
+use aporture::transfer::{
+    AportureTransferProtocol, ManifestRoot, OnConflict, RetryPolicy, TransferOptions,
+    TransferReport, VerifyReport, DEFAULT_CONNECTION_TIMEOUT,
+};
 use aporture::{Receiver, Sender};
 
 pub async fn send(
@@ -17,111 +31,885 @@ pub async fn send(
     save: Option<String>,
     old_contact: Option<String>,
     contacts: &mut Holder,
-    path: PathBuf,
+    paths: Vec<PathBuf>,
+    options: &SendOptions,
+    output: Output,
 ) -> Result<()> {
-    let app = AporturePairingProtocol::<Sender>::new(passphrase, save.is_some());
+    let peer = history_peer(&passphrase, old_contact.as_deref());
 
-    let mut pair_info = app.pair().await?;
+    let piped = paths.iter().any(|path| args::is_stdio(path));
+    if piped && paths.len() > 1 {
+        bail!("Standard input can only be sent on its own");
+    }
 
-    println!("{}", "Pairing Successful!!".green());
+    let mut app = AporturePairingProtocol::<Sender>::new(passphrase, save.is_some());
+    connection_options(&mut app, &options.connection)?;
 
-    println!(
-        "Transferring file to {}...",
-        "peer".bright_cyan().bold().underline()
-    );
+    if let Some(name) = &old_contact {
+        let age = Config::get().await.hint_age();
+
+        if let Some(address) = contacts.get_or_init().await?.hint(name, age) {
+            app.set_address_hint(address);
+        }
+    }
+
+    let mut pair_info = contact_hint(app.pair().await, old_contact.as_deref())?;
+    let summary = pair_info.summary();
+
+    // NOTE: Every path is sent over the same pairing, the receiver uses --count to get them all
+    pair_info.set_session(paths.len() > 1);
+
+    if output.is_human() {
+        println!("{}", "Pairing Successful!!".green());
+    }
+
+    let mut sent = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        if output.is_human() {
+            println!("Transferring file to {}...", peer_name(&pair_info));
+        }
 
-    let mut atp = AportureTransferProtocol::<Sender>::new(&mut pair_info, &path);
+        let transfer_options = send_options(options, piped).await?;
+        let mut atp = AportureTransferProtocol::<Sender>::with_options(
+            &mut pair_info,
+            &path,
+            transfer_options,
+        );
 
-    let (snd, rcv) = tokio::sync::mpsc::channel(64);
+        let progress = progress::attach(&mut atp, output);
 
-    atp.add_progress_notifier(snd);
-    progress::init_progress_bar(rcv);
+        let report = if piped {
+            atp.transfer_from(tokio::io::stdin()).await
+        } else {
+            atp.transfer().await
+        };
+
+        let entry = Entry::new(Direction::Sent, peer.clone(), file_name(&path), 0);
+
+        let report = match report {
+            Ok(report) => report,
+            Err(e) => {
+                record(entry.failed()).await;
+                return Err(e.into());
+            }
+        };
+
+        record(Entry {
+            size: report.total_size,
+            ..entry
+        })
+        .await;
 
-    atp.transfer().await?;
+        let bytes = progress::total(progress).await;
+
+        if output.is_human() {
+            println!("{}", "File transferred successfully!".green());
+            print_verification(report.total_files, report.root, report.verified);
+            println!("Connected through {}", report.route);
+        }
+
+        sent.push((path, bytes, report));
+    }
 
-    let save_confirmation = pair_info.save_contact;
+    let save_confirmation = pair_info.peer_save_contact();
+    let peer_address = pair_info.peer_direct_address();
 
     let key = pair_info.finalize().await;
 
-    println!("{}", "File transferred successfully!".green());
+    let (new_name, old_name) = (save.clone(), old_contact.clone());
 
-    if let Some(name) = save {
-        if save_confirmation {
-            println!("Saving key for contact {}...", name.bright_blue().bold());
+    let contact_saved =
+        save_contact(contacts, save, old_contact, key, save_confirmation, output).await?;
 
-            let contacts = contacts.get_mut_or_init().await?;
+    // NOTE: A contact saved under a new name replaces the one used for the transfer
+    let contact = if contact_saved == Some(true) {
+        new_name
+    } else {
+        old_name
+    };
 
-            contacts.replace(name, old_contact, key);
-        } else {
-            let message = "Warning: Not saving contact because peer refused".yellow();
-            println!("{message}",);
+    if let (Some(name), Some(address)) = (contact, peer_address) {
+        let _ = contacts.get_mut_or_init().await?.set_hint(&name, address);
+    }
+
+    if output.is_json() {
+        for (path, bytes, report) in sent {
+            output::print(&json!({
+                "status": "success",
+                "path": path,
+                "bytes": bytes,
+                "files": report.total_files,
+                "root": report.root.to_string(),
+                "verified": report.verified,
+                "route": report.route,
+                "contact_saved": contact_saved,
+                "pairing": summary,
+            }));
         }
     }
 
     Ok(())
 }
 
+async fn send_options(options: &SendOptions, piped: bool) -> Result<TransferOptions<Sender>> {
+    let transfer_options = TransferOptions::default().rekey_interval(options.rekey_every);
+    if piped {
+        return Ok(transfer_options);
+    }
+
+    let threshold = Config::get().await.compression_threshold();
+
+    Ok(transfer_options
+        .exclude(&options.exclude)?
+        .empty_dirs(!options.skip_empty_dirs)
+        .stream_hash(options.stream_hash)
+        .symlinks(options.symlinks)
+        .compression(options.compress)
+        .compression_threshold(threshold)
+        .chunk_size(options.chunk_size)?
+        .from_archive(options.from_archive))
+}
+
+/// Peers with different keys for a contact get different pairing ids and never meet at the
+/// server, so a contact key that changed on the other end looks like the peer not arriving.
+/// That can not be told apart from the contact simply not showing up, so it is only a hint.
+fn contact_hint<T>(result: Result<T, PairingError>, contact: Option<&str>) -> Result<T> {
+    match (result, contact) {
+        (Err(e @ PairingError::Hello(Hello::NoPeer | Hello::Expired)), Some(name)) => {
+            Err(anyhow!(e).context(format!(
+                "{name} did not arrive. If they paired again or reinstalled, their key may have \
+                 changed, in which case pair with them again"
+            )))
+        }
+        (result, _) => Ok(result?),
+    }
+}
+
+/// Name the peer shows itself as, or just peer if it did not set one.
+fn peer_name(pair_info: &PairInfo) -> colored::ColoredString {
+    pair_info
+        .peer_display_name()
+        .unwrap_or("peer")
+        .bright_cyan()
+        .bold()
+        .underline()
+}
+
+/// Shows whether both peers computed the same combined hash of the transferred files.
+fn print_verification(files: u64, root: ManifestRoot, verified: bool) {
+    let root = root.to_string();
+    let short = &root[..12];
+
+    if verified {
+        println!("Verified {files} files, root {short}…");
+    } else {
+        let message = format!("Warning: Peer did not verify the same files, root {short}…");
+        println!("{}", message.yellow());
+    }
+}
+
+/// Saves the contact if requested, returning whether it was saved. When the peer did not
+/// accept, the user is asked whether to keep it anyway.
+async fn save_contact(
+    contacts: &mut Holder,
+    save: Option<String>,
+    old_contact: Option<String>,
+    key: aporture::crypto::Key,
+    accepted: bool,
+    output: Output,
+) -> Result<Option<bool>> {
+    let Some(name) = save else {
+        return Ok(None);
+    };
+
+    if !accepted {
+        // NOTE: Without a user to ask only an explicit yes saves it
+        if !output.is_human() && !output.confirms() {
+            return Ok(Some(false));
+        }
+
+        if output.is_human() {
+            let message =
+                "Warning: Peer did not save the contact, it only works if they keep the key";
+            println!("{}", message.yellow());
+        }
+
+        let question = format!("Save contact {} anyway?", name.bright_blue().bold());
+
+        if !confirm(&question, output).await? {
+            println!("Not saving contact");
+            return Ok(Some(false));
+        }
+    }
+
+    if output.is_human() {
+        println!("Saving key for contact {}...", name.bright_blue().bold());
+    }
+
+    let contacts = contacts.get_mut_or_init().await?;
+
+    contacts.replace(name, old_contact, key);
+
+    Ok(Some(true))
+}
+
+/// Asks a yes or no question, answered yes without asking if the user chose so beforehand.
+async fn confirm(question: &str, output: Output) -> Result<bool> {
+    if output.confirms() {
+        return Ok(true);
+    }
+
+    println!("{question} y/N");
+
+    let confirmation = tokio::io::stdin().read_u8().await? as char;
+
+    Ok(confirmation.eq_ignore_ascii_case(&'y'))
+}
+
+/// Applies the options of how the peers connect, shared by both sides.
+fn connection_options<K: Kind>(
+    app: &mut AporturePairingProtocol<Start<K>>,
+    options: &args::ConnectionOptions,
+) -> Result<()> {
+    app.set_insecure_lan(options.insecure_lan);
+    app.set_relay_only(options.force_relay);
+
+    // NOTE: Only disabled from the command line, otherwise the configured value is used
+    if options.no_upnp {
+        app.set_upnp(false);
+    }
+
+    if options.no_holepunch {
+        app.set_hole_punching(false);
+    }
+
+    if let Some(ip) = options.bind.ip()? {
+        app.set_bind_ip(ip);
+    }
+
+    Ok(())
+}
+
+/// The destination given or else the configured download folder.
+async fn destination_or_default(destination: Option<PathBuf>) -> Result<PathBuf> {
+    if let Some(destination) = destination {
+        return Ok(destination);
+    }
+
+    Config::get()
+        .await
+        .download_dir()
+        .ok_or_else(|| anyhow!("Could not find destination directory"))
+}
+
+/// Keeps asking for a sender that has not started yet, telling the user the first time.
+fn wait_for_sender(
+    app: &mut AporturePairingProtocol<Start<Receiver>>,
+    wait: u64,
+    poll_interval: Option<u64>,
+    output: Output,
+) {
+    app.set_wait(Duration::from_secs(wait));
+
+    if let Some(interval) = poll_interval {
+        app.set_poll_interval(Duration::from_secs(interval));
+    }
+
+    if output.is_human() {
+        let (snd, mut rcv) = tokio::sync::mpsc::channel(1);
+        app.add_wait_notifier(snd);
+
+        tokio::spawn(async move {
+            if let Some(remaining) = rcv.recv().await {
+                println!(
+                    "Waiting for sender... (up to {} seconds)",
+                    remaining.as_secs().to_string().bright_blue().bold()
+                );
+            }
+        });
+    }
+}
+
+/// Transfer options of the receiver, using the configured conflict policy if none is given.
+async fn receive_options(options: &args::ReceiveOptions) -> TransferOptions<Receiver> {
+    let on_conflict = match options.on_conflict {
+        Some(on_conflict) => on_conflict,
+        None if options.merge => OnConflict::Merge,
+        None => Config::get().await.on_conflict(),
+    };
+
+    TransferOptions::default()
+        .retry_policy(RetryPolicy {
+            retries: options.retries,
+            skip_failed: options.skip_failed,
+        })
+        .on_conflict(on_conflict)
+        .archive(options.archive)
+}
+
 pub async fn receive(
     passphrase: Vec<u8>,
     save: Option<String>,
     old_contact: Option<String>,
     contacts: &mut Holder,
     destination: Option<PathBuf>,
+    options: args::ReceiveOptions,
+    output: Output,
 ) -> Result<()> {
-    let app = AporturePairingProtocol::<Receiver>::new(passphrase, save.is_some());
+    let peer = history_peer(&passphrase, old_contact.as_deref());
 
-    let mut pair_info = app.pair().await?;
+    let piped = destination.as_deref().is_some_and(args::is_stdio);
+    if piped && options.count > 1 {
+        bail!("Only one transfer can be written to standard output");
+    }
 
-    println!("{}", "Pairing Successful!!".green());
+    let mut app = AporturePairingProtocol::<Receiver>::new(passphrase, save.is_some());
+    connection_options(&mut app, &options.connection)?;
 
-    println!(
-        "Receiving file from {}...",
-        "peer".bright_cyan().bold().underline()
-    );
+    if let Some(wait) = options.wait {
+        wait_for_sender(&mut app, wait, options.poll_interval, output);
+    }
 
-    let Some(destination) = destination.or_else(aporture::fs::downloads_directory) else {
-        bail!("Could not find destination directory");
-    };
+    let mut pair_info = contact_hint(app.pair().await, old_contact.as_deref())?;
+    let summary = pair_info.summary();
 
-    let mut atp = AportureTransferProtocol::<Receiver>::new(&mut pair_info, &destination);
+    pair_info.set_session(options.count > 1);
 
-    let (snd, rcv) = tokio::sync::mpsc::channel(64);
+    if output.is_human() {
+        println!("{}", "Pairing Successful!!".green());
+    }
+
+    let destination = destination_or_default(destination).await?;
+
+    let mut received = Vec::new();
+
+    for _ in 0..options.count {
+        if output.is_human() {
+            println!("Receiving file from {}...", peer_name(&pair_info));
+        }
+
+        let transfer_options = receive_options(&options).await;
+
+        let mut atp = AportureTransferProtocol::<Receiver>::with_options(
+            &mut pair_info,
+            &destination,
+            transfer_options,
+        );
 
-    atp.add_progress_notifier(snd);
-    progress::init_progress_bar(rcv);
+        let progress = progress::attach(&mut atp, output);
 
-    let path = atp.transfer().await?;
+        let report = if piped {
+            atp.transfer_into(tokio::io::stdout()).await
+        } else {
+            atp.transfer().await
+        };
+
+        let report = match report {
+            Ok(report) => report,
+            Err(e) => {
+                let entry = Entry::new(Direction::Received, peer, String::new(), 0);
+                record(entry.failed()).await;
+                return Err(e.into());
+            }
+        };
+
+        let name = file_name(&report.destination);
+        record(Entry::new(
+            Direction::Received,
+            peer.clone(),
+            name,
+            report.total_size,
+        ))
+        .await;
+
+        let bytes = progress::total(progress).await;
 
-    let accepted_save_contact = pair_info.save_contact;
+        if output.is_human() {
+            print_received(&report);
+        }
+
+        received.push((bytes, report));
+    }
+
+    let accepted_save_contact = pair_info.peer_save_contact();
 
     let key = pair_info.finalize().await;
 
+    let contact_saved = save_contact(
+        contacts,
+        save,
+        old_contact,
+        key,
+        accepted_save_contact,
+        output,
+    )
+    .await?;
+
+    if output.is_json() {
+        for (bytes, report) in received {
+            output::print(&json!({
+                "status": "success",
+                "path": report.destination,
+                "bytes": bytes,
+                "files": report.total_files,
+                "skipped": report.skipped,
+                "root": report.root.to_string(),
+                "verified": report.verified,
+                "kept_existing": report.kept_existing,
+                "unchanged": report.unchanged,
+                "route": report.route,
+                "contact_saved": contact_saved,
+                "pairing": summary,
+            }));
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints where the received transfer was saved and how it went.
+fn print_received(report: &TransferReport) {
     println!("{}", "File received successfully!".green());
-    println!("Saved in {}", path.display());
 
-    if let Some(name) = save {
-        if accepted_save_contact {
-            println!("Saving key for contact {}...", name.bright_blue().bold());
+    if report.kept_existing {
+        let message = format!(
+            "Warning: {} already exists, discarded the received one",
+            report.destination.display()
+        );
+        println!("{}", message.yellow());
+    } else {
+        println!("Saved in {}", report.destination.display());
+    }
+    print_verification(report.total_files, report.root, report.verified);
+    println!("Connected through {}", report.route);
 
-            let contacts = contacts.get_mut_or_init().await?;
+    if report.unchanged > 0 {
+        println!("{} files were already up to date", report.unchanged);
+    }
 
-            contacts.replace(name, old_contact, key);
-        } else {
-            let message = "Warning: Not saving contact because peer refused".yellow();
-            println!("{message}");
+    if !report.skipped.is_empty() {
+        let message = format!(
+            "Warning: {} of {} files failed and were skipped:",
+            report.skipped.len(),
+            report.total_files
+        );
+        println!("{}", message.yellow());
+
+        for name in &report.skipped {
+            println!("  {name}");
         }
     }
+}
+
+pub async fn verify_send(
+    passphrase: Vec<u8>,
+    path: &Path,
+    options: TransferOptions<Sender>,
+    connection: &args::ConnectionOptions,
+    output: Output,
+) -> Result<()> {
+    let mut app = AporturePairingProtocol::<Sender>::new(passphrase, false);
+    connection_options(&mut app, connection)?;
+
+    let mut pair_info = app.pair().await?;
+
+    if output.is_human() {
+        println!("{}", "Pairing Successful!!".green());
+
+        println!("Sending file hashes to {}...", peer_name(&pair_info));
+    }
+
+    let mut atp = AportureTransferProtocol::<Sender>::with_options(&mut pair_info, path, options);
+
+    let progress = progress::attach(&mut atp, output);
+
+    let report = atp.verify().await?;
+
+    progress::total(progress).await;
+    pair_info.finalize().await;
+
+    print_verify_report(&report, output)
+}
+
+pub async fn verify_receive(
+    passphrase: Vec<u8>,
+    destination: Option<PathBuf>,
+    connection: &args::ConnectionOptions,
+    output: Output,
+) -> Result<()> {
+    let mut app = AporturePairingProtocol::<Receiver>::new(passphrase, false);
+    connection_options(&mut app, connection)?;
+
+    let mut pair_info = app.pair().await?;
+
+    if output.is_human() {
+        println!("{}", "Pairing Successful!!".green());
+
+        println!("Checking files against {}...", peer_name(&pair_info));
+    }
+
+    let destination = destination_or_default(destination).await?;
+
+    let mut atp = AportureTransferProtocol::<Receiver>::new(&mut pair_info, &destination);
+
+    let progress = progress::attach(&mut atp, output);
+
+    let report = atp.verify().await?;
+
+    progress::total(progress).await;
+    pair_info.finalize().await;
+
+    print_verify_report(&report, output)
+}
+
+/// Lists the files that differ, failing if any does unless printing JSON.
+fn print_verify_report(report: &VerifyReport, output: Output) -> Result<()> {
+    if output.is_json() {
+        output::print(&json!({
+            "status": if report.is_intact() { "success" } else { "mismatch" },
+            "path": report.path,
+            "files": report.total_files,
+            "mismatched": report.mismatched,
+            "missing": report.missing,
+            "route": report.route,
+        }));
+
+        return Ok(());
+    }
+
+    if output.is_human() {
+        println!("Connected through {}", report.route);
+
+        for name in &report.mismatched {
+            println!("  {} {name}", "changed".yellow());
+        }
+
+        for name in &report.missing {
+            println!("  {} {name}", "missing".red());
+        }
+    }
+
+    if !report.is_intact() {
+        bail!(
+            "{} of {} files in {} do not match",
+            report.mismatched.len() + report.missing.len(),
+            report.total_files,
+            report.path.display()
+        );
+    }
+
+    if output.is_human() {
+        println!("All {} files match", report.total_files);
+    }
 
     Ok(())
 }
 
-pub async fn list_contacts(contacts: &Holder) -> Result<()> {
+const LISTEN_INTERVAL: Duration = Duration::from_secs(5);
+
+pub async fn listen(
+    contacts: &Holder,
+    names: Vec<String>,
+    destination: Option<PathBuf>,
+    port: Option<u16>,
+    output: Output,
+) -> Result<()> {
     let contacts = contacts.get_or_init().await?;
 
+    let names = if names.is_empty() {
+        Config::get().await.listen_contacts().to_vec()
+    } else {
+        names
+    };
+
+    let keys = listened_keys(contacts, names)?;
+
+    let destination = destination_or_default(destination).await?;
+
+    let on_conflict = Config::get().await.on_conflict();
+
+    if output.is_human() {
+        println!(
+            "Listening for transfers from {} contacts...",
+            keys.len().to_string().bright_blue().bold()
+        );
+    }
+
+    let listener = match direct::Listener::bind(port.unwrap_or(0)).await {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            log::warn!("Could not accept direct pairing, only using the server - {e}");
+            None
+        }
+    };
+
+    let direct_address = listener.as_ref().map(direct::Listener::address);
+
+    let accept_next = || async {
+        match &listener {
+            Some(listener) => accept_contact(listener, &keys).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    // NOTE: Kept across passes, so waiting for the server never drops a pairing in progress
+    let mut accepting = std::pin::pin!(accept_next());
+
+    loop {
+        for (name, paired) in poll_server(&keys, direct_address).await {
+            match paired {
+                Ok(pair_info) => {
+                    receive_from(&name, pair_info, &destination, on_conflict, output).await;
+                }
+                Err(PairingError::Hello(Hello::NoPeer)) => {}
+                Err(e) => {
+                    let message = format!("Could not pair with {name}: {e}");
+                    listen_failure(&name, &message, output);
+                }
+            }
+        }
+
+        let accepted = tokio::select! {
+            () = tokio::time::sleep(LISTEN_INTERVAL) => continue,
+            accepted = &mut accepting => accepted,
+        };
+
+        accepting.set(accept_next());
+
+        let Some(((name, key), incoming)) = accepted else {
+            continue;
+        };
+
+        let mut app = AporturePairingProtocol::<Receiver>::new(key.clone(), false);
+
+        if let Some(address) = direct_address {
+            app.set_direct_address(address);
+        }
+
+        match app.pair_direct(incoming).await {
+            Ok(pair_info) => {
+                receive_from(name, pair_info, &destination, on_conflict, output).await;
+            }
+            Err(e) => {
+                let message = format!("Could not pair with {name}: {e}");
+                listen_failure(name, &message, output);
+            }
+        }
+    }
+}
+
+/// Reports a failure of [`listen`] that does not stop it.
+fn listen_failure(name: &str, message: &str, output: Output) {
+    if output.is_json() {
+        output::print(&json!({ "status": "error", "contact": name, "message": message }));
+    } else if output.is_human() {
+        println!("{}", format!("Warning: {message}").yellow());
+    }
+}
+
+/// Keys of the contacts listened for, every saved contact when no names are given.
+fn listened_keys(contacts: &Contacts, names: Vec<String>) -> Result<Vec<(String, Vec<u8>)>> {
+    let names = if names.is_empty() {
+        contacts.list().map(|(n, _)| n.clone()).collect()
+    } else {
+        names
+    };
+
+    let keys = names
+        .into_iter()
+        .map(|name| match contacts.get(&name) {
+            Some(key) => Ok((name, key.to_vec())),
+            None => Err(anyhow!("Contact {name} not found")),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if keys.is_empty() {
+        bail!("No contacts to listen for");
+    }
+
+    Ok(keys)
+}
+
+/// Asks the server for every contact at once, so each contact listened for does not add
+/// another round trip to the pass.
+async fn poll_server(
+    keys: &[(String, Vec<u8>)],
+    direct_address: Option<SocketAddr>,
+) -> Vec<(String, Result<PairInfo, PairingError>)> {
+    let mut polls = tokio::task::JoinSet::new();
+
+    for (name, key) in keys {
+        // NOTE: Only peers that know a saved contact key can pair with us
+        let mut app = AporturePairingProtocol::<Receiver>::new(key.clone(), false);
+
+        if let Some(address) = direct_address {
+            app.set_direct_address(address);
+        }
+
+        let name = name.clone();
+        polls.spawn(async move { (name, app.pair().await) });
+    }
+
+    polls.join_all().await
+}
+
+/// Waits for a peer pairing directly, refusing it unless it knows the key of a contact.
+async fn accept_contact<'a>(
+    listener: &direct::Listener,
+    keys: &'a [(String, Vec<u8>)],
+) -> Option<(&'a (String, Vec<u8>), direct::Incoming)> {
+    let incoming = match listener.accept().await {
+        Ok(incoming) => incoming,
+        Err(e) => {
+            log::warn!("Could not accept direct pairing - {e}");
+            return None;
+        }
+    };
+
+    let Some(contact) = keys.iter().find(|(_, key)| incoming.is_for(key)) else {
+        incoming.refuse().await;
+        return None;
+    };
+
+    Some((contact, incoming))
+}
+
+/// Receives the transfer of a contact found by [`listen`], failures are only reported.
+async fn receive_from(
+    name: &str,
+    mut pair_info: PairInfo,
+    destination: &Path,
+    on_conflict: OnConflict,
+    output: Output,
+) {
+    if output.is_human() {
+        println!(
+            "Receiving file from {}...",
+            name.bright_blue().bold().underline()
+        );
+    }
+
+    let transfer_options = TransferOptions::default().on_conflict(on_conflict);
+
+    let mut atp = AportureTransferProtocol::<Receiver>::with_options(
+        &mut pair_info,
+        destination,
+        transfer_options,
+    );
+
+    let progress = progress::attach(&mut atp, output);
+
+    let peer = history::Peer::Contact(name.to_owned());
+
+    match atp.transfer().await {
+        Ok(report) => {
+            let bytes = progress::total(progress).await;
+
+            if output.is_json() {
+                output::print(&json!({
+                    "status": "success",
+                    "contact": name,
+                    "path": report.destination,
+                    "bytes": bytes,
+                    "files": report.total_files,
+                    "skipped": report.skipped,
+                    "root": report.root.to_string(),
+                    "verified": report.verified,
+                    "route": report.route,
+                }));
+            } else if output.is_human() {
+                println!("Saved in {}", report.destination.display());
+            }
+
+            let file = file_name(&report.destination);
+            record(Entry::new(
+                Direction::Received,
+                peer,
+                file,
+                report.total_size,
+            ))
+            .await;
+        }
+        Err(e) => {
+            let message = format!("Transfer from {name} failed: {e}");
+            listen_failure(name, &message, output);
+
+            record(Entry::new(Direction::Received, peer, String::new(), 0).failed()).await;
+        }
+    }
+
+    let _ = pair_info.finalize().await;
+}
+
+/// Who a transfer was with, without storing the passphrase itself.
+fn history_peer(passphrase: &[u8], contact: Option<&str>) -> history::Peer {
+    contact.map_or_else(
+        || history::Peer::passphrase(passphrase),
+        |name| history::Peer::Contact(name.to_owned()),
+    )
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(
+        || path.display().to_string(),
+        |n| n.to_string_lossy().into_owned(),
+    )
+}
+
+/// Appends a transfer to the history, a failure to do so does not fail the transfer.
+async fn record(entry: Entry) {
+    if let Err(e) = History::record(entry).await {
+        log::warn!("Could not save transfer history: {e}");
+    }
+}
+
+pub async fn history(output: Output) -> Result<()> {
+    let history = History::load().await?;
+
+    if output.is_json() {
+        let list = history
+            .entries()
+            .map(|e| {
+                json!({
+                    "direction": e.direction.to_string(),
+                    "peer": e.peer.to_string(),
+                    "file": e.file_name,
+                    "bytes": e.size,
+                    "date": e.timestamp.to_rfc3339(),
+                    "success": e.success,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        output::print(&json!({ "status": "success", "history": list }));
+
+        return Ok(());
+    }
+
+    if history.entries().next().is_none() {
+        println!("No transfers yet");
+        return Ok(());
+    }
+
     let mut builder = tabled::builder::Builder::new();
-    builder.push_record(["Name", "Added"]);
-    contacts.list().for_each(|(n, t)| {
-        builder.push_record([n, &t.format("%d/%m/%Y %H:%M").to_string()]);
+    builder.push_record(["Date", "Direction", "Peer", "File", "Size", "Result"]);
+    history.entries().for_each(|e| {
+        let file = if e.file_name.is_empty() {
+            "-"
+        } else {
+            &e.file_name
+        };
+        let result = if e.success { "ok" } else { "failed" };
+
+        builder.push_record([
+            &e.timestamp.format("%d/%m/%Y %H:%M").to_string(),
+            &e.direction.to_string(),
+            &e.peer.to_string(),
+            file,
+            &indicatif::HumanBytes(e.size).to_string(),
+            result,
+        ]);
     });
     let mut table = builder.build();
     table.with(tabled::settings::Style::markdown());
@@ -130,14 +918,73 @@ pub async fn list_contacts(contacts: &Holder) -> Result<()> {
     Ok(())
 }
 
-pub async fn delete_contact(contacts: &mut Holder, name: String) -> Result<()> {
-    let contacts = contacts.get_mut_or_init().await?;
+/// Lists the contacts, only those with the tag if one is given.
+pub async fn list_contacts(
+    contacts: &Holder,
+    output: Output,
+    format: ListFormat,
+    tag: Option<&str>,
+) -> Result<()> {
+    let contacts = contacts.get_or_init().await?;
+
+    let format = if output.is_json() {
+        ListFormat::Json
+    } else {
+        format
+    };
+
+    let listed = tag.map_or_else(
+        || contacts.list_detailed().collect::<Vec<_>>(),
+        |tag| contacts.list_by_tag(tag).collect(),
+    );
 
-    println!("Delete contact {}? y/N", name.red());
+    match format {
+        ListFormat::Json => {
+            let list = listed
+                .iter()
+                .map(|c| {
+                    json!({
+                        "name": c.name,
+                        "added": c.added.to_rfc3339(),
+                        "fingerprint": c.fingerprint,
+                        "tags": c.tags,
+                    })
+                })
+                .collect::<Vec<_>>();
 
-    let confirmation = tokio::io::stdin().read_u8().await? as char;
+            output::print(&json!({ "status": "success", "contacts": list }));
+        }
+        ListFormat::Csv => {
+            let header = ["name", "added", "fingerprint", "tags"];
+            println!("{}", output::csv_record(&header));
+            for c in &listed {
+                let (added, tags) = (c.added.to_rfc3339(), c.tags.join(", "));
+                let record = [c.name, &added, &c.fingerprint, &tags];
+                println!("{}", output::csv_record(&record));
+            }
+        }
+        ListFormat::Table => {
+            let mut builder = tabled::builder::Builder::new();
+            builder.push_record(["Name", "Added", "Fingerprint", "Tags"]);
+            for c in &listed {
+                let added = c.added.format("%d/%m/%Y %H:%M").to_string();
+                builder.push_record([c.name, &added, &c.fingerprint, &c.tags.join(", ")]);
+            }
+            let mut table = builder.build();
+            table.with(tabled::settings::Style::markdown());
+            println!("\n{table}\n");
+        }
+    }
+
+    Ok(())
+}
 
-    if confirmation.eq_ignore_ascii_case(&'y') {
+pub async fn delete_contact(contacts: &mut Holder, name: String, output: Output) -> Result<()> {
+    let contacts = contacts.get_mut_or_init().await?;
+
+    let question = format!("Delete contact {}?", name.red());
+
+    if confirm(&question, output).await? {
         let deleted = contacts.delete(&name);
         if deleted {
             println!("Contact deleted");
@@ -152,6 +999,125 @@ pub async fn delete_contact(contacts: &mut Holder, name: String) -> Result<()> {
     Ok(())
 }
 
+pub async fn tag_contact(contacts: &mut Holder, name: &str, tags: &[String]) -> Result<()> {
+    let contacts = contacts.get_mut_or_init().await?;
+
+    if !contacts.set_tags(name, tags) {
+        bail!("Contact {name} not found");
+    }
+
+    contacts.save().await?;
+
+    if tags.is_empty() {
+        println!("Removed the tags of contact {}", name.bright_blue().bold());
+    } else {
+        println!(
+            "Contact {} tagged {}",
+            name.bright_blue().bold(),
+            tags.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn rename_contact(contacts: &mut Holder, old: &str, new: &str) -> Result<()> {
+    let contacts = contacts.get_mut_or_init().await?;
+
+    if contacts.get(old).is_none() {
+        bail!("Contact {old} not found");
+    }
+
+    if !contacts.rename(old, new) {
+        bail!("Contact {new} already exists");
+    }
+
+    contacts.save().await?;
+
+    println!(
+        "Contact {} renamed to {}",
+        old.red(),
+        new.bright_blue().bold()
+    );
+
+    Ok(())
+}
+
+pub async fn change_contacts_password() -> Result<()> {
+    let old = rpassword::prompt_password("Insert current contact database password: ")?;
+
+    let mut contacts = match Contacts::load(old.as_bytes()).await {
+        Err(aporture::io::Error::Cipher(_)) => bail!("Incorrect password"),
+        result => result?,
+    };
+
+    let new = rpassword::prompt_password("Enter new password: ")?;
+    let confirmation = rpassword::prompt_password("Reenter new password: ")?;
+
+    if new != confirmation {
+        bail!("Passwords do not match");
+    }
+
+    contacts
+        .change_password(old.as_bytes(), new.as_bytes())
+        .await?;
+
+    println!("Contact database password changed");
+
+    Ok(())
+}
+
+pub async fn export_contacts(contacts: &Holder, file: &Path) -> Result<()> {
+    let contacts = contacts.get_or_init().await?;
+
+    let password = rpassword::prompt_password("Enter password to encrypt the export: ")?;
+    let confirmation = rpassword::prompt_password("Reenter password to encrypt the export: ")?;
+
+    if password != confirmation {
+        bail!("Passwords do not match");
+    }
+
+    contacts.export(file, password.as_bytes()).await?;
+
+    println!("Contacts exported to {}", file.display());
+
+    Ok(())
+}
+
+pub async fn import_contacts(contacts: &mut Holder, file: &Path, replace: bool) -> Result<()> {
+    let contacts = contacts.get_mut_or_init().await?;
+
+    let password = rpassword::prompt_password("Insert password of the export: ")?;
+
+    let conflict = if replace {
+        Conflict::Overwrite
+    } else {
+        Conflict::Keep
+    };
+
+    let conflicts = match contacts.import(file, password.as_bytes(), conflict).await {
+        Err(aporture::io::Error::Cipher(_)) => bail!("Incorrect password or corrupted export"),
+        result => result?,
+    };
+
+    for name in conflicts {
+        if replace {
+            println!("Replaced existing contact {}", name.yellow());
+        } else {
+            println!(
+                "Kept existing contact {}, use --replace to overwrite it",
+                name.yellow()
+            );
+        }
+    }
+
+    contacts.save().await?;
+
+    println!("Contacts imported from {}", file.display());
+
+    Ok(())
+}
+
 pub async fn pair_start(passphrase: Vec<u8>, name: String, contacts: &mut Holder) -> Result<()> {
     let app = AporturePairingProtocol::<Sender>::new(passphrase, true);
 
@@ -197,3 +1163,505 @@ pub async fn pair_complete(passphrase: Vec<u8>, name: String, contacts: &mut Hol
 
     Ok(())
 }
+
+pub async fn config_get() {
+    let config = Config::get().await;
+
+    let addresses = config.resolved_addresses().await.unwrap_or_else(|e| {
+        log::warn!("Could not resolve server: {e}");
+        vec![config.server_address()]
+    });
+
+    let fallbacks = config.servers()[1..]
+        .iter()
+        .map(|s| s.domain().to_owned())
+        .collect::<Vec<_>>();
+
+    let info = config_info(
+        config.server_domain(),
+        config.source(),
+        &addresses,
+        &fallbacks,
+    );
+
+    drop(config);
+
+    print!("{info}");
+}
+
+fn config_info(
+    domain: &str,
+    source: ConfigSource,
+    addresses: &[SocketAddr],
+    fallbacks: &[String],
+) -> String {
+    use std::fmt::Write;
+
+    let mut info = format!("Current configured server address: {domain} (from {source})\n");
+
+    for address in addresses {
+        let _ = writeln!(info, "  {address}");
+    }
+
+    if !fallbacks.is_empty() {
+        info.push_str("Fallback servers:\n");
+
+        for (i, fallback) in fallbacks.iter().enumerate() {
+            let _ = writeln!(info, "  {}. {fallback}", i + 1);
+        }
+    }
+
+    info
+}
+
+struct Setting {
+    name: &'static str,
+    value: serde_json::Value,
+    source: &'static str,
+}
+
+pub async fn config_show(json: bool) {
+    let config = Config::get().await;
+
+    let file = Config::path().map_or_else(|_| "none".to_owned(), |p| p.display().to_string());
+    let resolved = config.resolved_addresses().await.unwrap_or_else(|e| {
+        log::warn!("Could not resolve server: {e}");
+        Vec::new()
+    });
+
+    let settings = effective_config(&config, &file, &resolved);
+
+    drop(config);
+
+    if json {
+        println!("{:#}", settings_json(&settings));
+    } else {
+        for setting in settings {
+            let value = match setting.value {
+                serde_json::Value::String(s) => s,
+                serde_json::Value::Null => "none".to_owned(),
+                v => v.to_string(),
+            };
+
+            println!("{}: {value} ({})", setting.name.bold(), setting.source);
+        }
+    }
+}
+
+/// Every value the config resolves to, named as it is saved, with where each one came from.
+fn effective_config(config: &Config, file: &str, resolved: &[SocketAddr]) -> Vec<Setting> {
+    let source = |name| match config.value_source(name) {
+        ConfigSource::File => "file",
+        ConfigSource::Default => "default",
+    };
+
+    let setting = |name, value: serde_json::Value| Setting {
+        name,
+        value,
+        source: source(name),
+    };
+
+    let servers = config
+        .servers()
+        .iter()
+        .map(|s| s.domain().to_owned())
+        .collect::<Vec<_>>();
+    let resolved = resolved.iter().map(ToString::to_string).collect::<Vec<_>>();
+    let threshold = config.compression_threshold();
+
+    // NOTE: Without a folder set the system one is used, which is not a default of the config
+    let download_dir = Setting {
+        name: "download_dir",
+        value: config
+            .download_dir()
+            .map(|dir| dir.display().to_string())
+            .into(),
+        source: match config.custom_download_dir() {
+            Some(_) => source("download_dir"),
+            None => "system",
+        },
+    };
+
+    vec![
+        Setting {
+            name: "config_file",
+            value: file.into(),
+            source: match config.source() {
+                ConfigSource::File => "file",
+                ConfigSource::Default => "default",
+            },
+        },
+        Setting {
+            name: "server_domain",
+            value: config.server_domain().into(),
+            source: source("servers"),
+        },
+        Setting {
+            name: "server_address",
+            value: config.server_address().to_string().into(),
+            source: source("servers"),
+        },
+        setting("servers", servers.into()),
+        setting("stun_servers", config.stun_servers().into()),
+        Setting {
+            name: "resolved_addresses",
+            value: resolved.into(),
+            source: "dns",
+        },
+        setting("passphrase_words", config.passphrase_words().into()),
+        setting("wordlist", config.wordlist().to_string().into()),
+        setting("notifications", config.notifications().into()),
+        setting("on_conflict", config.on_conflict().to_string().into()),
+        setting(
+            "compression",
+            json!({ "files": threshold.files, "size": threshold.size }),
+        ),
+        download_dir,
+        setting("hint_age", config.hint_age().as_secs().into()),
+        setting("bind_ip", config.bind_ip().map(|ip| ip.to_string()).into()),
+        setting("display_name", config.display_name().into()),
+        setting("listen_contacts", config.listen_contacts().into()),
+        setting("use_upnp", config.use_upnp().into()),
+        setting("use_hole_punching", config.use_hole_punching().into()),
+        Setting {
+            name: "connection_timeout_secs",
+            value: DEFAULT_CONNECTION_TIMEOUT.as_secs().into(),
+            source: "default",
+        },
+        Setting {
+            name: "reconnect_window_secs",
+            value: DEFAULT_RECONNECT_WINDOW.as_secs().into(),
+            source: "default",
+        },
+        Setting {
+            name: "keep_alive_interval_secs",
+            value: DEFAULT_KEEP_ALIVE_INTERVAL.as_secs().into(),
+            source: "default",
+        },
+        Setting {
+            name: "poll_interval_secs",
+            value: DEFAULT_POLL_INTERVAL.as_secs().into(),
+            source: "default",
+        },
+    ]
+}
+
+/// Result of one check of [`doctor`]
+struct Check {
+    name: String,
+    passed: bool,
+    detail: String,
+}
+
+impl Check {
+    fn new<E: std::fmt::Display>(name: impl Into<String>, result: Result<String, E>) -> Self {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(e) => (false, e.to_string()),
+        };
+
+        Self {
+            name: name.into(),
+            passed,
+            detail,
+        }
+    }
+}
+
+pub async fn doctor(family: Option<IpFamily>, output: Output) {
+    let servers = Config::get().await.servers().to_vec();
+
+    let mut checks = Vec::new();
+    let mut addresses = Vec::new();
+
+    for server in &servers {
+        let resolved = match probed_addresses(server, family).await {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                checks.push(Check::new(format!("Server {}", server.domain()), Err(e)));
+                continue;
+            }
+        };
+
+        for address in resolved {
+            let result = diagnostics::server(address).await;
+            checks.push(Check::new(
+                format!("Server {address}"),
+                result.map(|()| "reachable".to_owned()),
+            ));
+
+            addresses.push(address);
+        }
+    }
+
+    let result = diagnostics::upnp().await;
+    checks.push(Check::new(
+        "UPnP",
+        result.map(|()| "gateway found".to_owned()),
+    ));
+
+    let external = match addresses.first() {
+        Some(&address) => diagnostics::external_address(address).await,
+        None => Err(aporture::io::Error::Custom("No server address to ask")),
+    };
+
+    match external {
+        Ok(external) => {
+            let stun = external.stun.as_ref().ok().copied();
+
+            checks.push(Check::new(
+                "STUN",
+                external.stun.map(|a| format!("public address {a}")),
+            ));
+            let mut check = public_address_check(external.server, stun);
+            if check.passed && external.server_attempts > 1 {
+                use std::fmt::Write;
+                let _ = write!(check.detail, ", asked {} times", external.server_attempts);
+            }
+            checks.push(check);
+        }
+        Err(e) => checks.push(Check::new("STUN", Err::<String, _>(e))),
+    }
+
+    if output.is_json() {
+        let list = checks
+            .iter()
+            .map(|c| json!({ "name": c.name, "passed": c.passed, "detail": c.detail }))
+            .collect::<Vec<_>>();
+
+        output::print(&json!({ "status": "success", "checks": list }));
+
+        return;
+    }
+
+    for check in &checks {
+        let mark = if check.passed {
+            "PASS".green()
+        } else {
+            "FAIL".red()
+        };
+
+        println!("{mark} {}: {}", check.name, check.detail);
+    }
+}
+
+/// Addresses of a server the checks of [`doctor`] probe, only those of the family if one
+/// is forced.
+async fn probed_addresses(
+    server: &Server,
+    family: Option<IpFamily>,
+) -> Result<Vec<SocketAddr>, aporture::io::Error> {
+    let configured = server.address();
+
+    let Some(family) = family else {
+        return Ok(vec![configured]);
+    };
+
+    // NOTE: The port is the saved one, the domain may not include it
+    let addresses = config::resolve(server.domain(), Some(family))
+        .await?
+        .into_iter()
+        .map(|a| SocketAddr::new(a.ip(), configured.port()))
+        .collect();
+
+    Ok(addresses)
+}
+
+/// Whether the address the server sees is the one STUN found, which peers connect to.
+fn public_address_check(server: Option<SocketAddr>, stun: Option<SocketAddr>) -> Check {
+    let result = match (server, stun) {
+        (None, _) => Err("the server did not report the address it sees"),
+        (Some(_), None) => Err("no STUN address to compare with"),
+        (Some(s), Some(p)) if s.ip() != p.ip() => {
+            Err("the server and STUN see different public IPs, the server may be in your network")
+        }
+        (Some(s), Some(p)) if s.port() != p.port() => Err(
+            "the NAT uses a different port for each destination, direct connections will likely fall back to the relay",
+        ),
+        (Some(s), Some(_)) => Ok(format!("peers see {s}")),
+    };
+
+    Check::new("Public address", result)
+}
+
+fn settings_json(settings: &[Setting]) -> serde_json::Value {
+    settings
+        .iter()
+        .map(|s| {
+            let entry = serde_json::json!({ "value": s.value, "source": s.source });
+
+            (s.name.to_owned(), entry)
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config_show_json_has_every_field() {
+        let address = SocketAddr::from(([127, 0, 0, 1], 8765));
+
+        let config = serde_json::from_value::<Config>(json!({
+            "servers": [
+                { "domain": "aporture.example", "address": "127.0.0.1", "port": 8765 },
+                { "domain": "backup.example", "address": "127.0.0.2", "port": 8765 },
+            ],
+            "passphrase_words": 4,
+            "use_upnp": "false",
+        }))
+        .expect("Valid config");
+
+        let settings = effective_config(&config, "config.app", &[address]);
+
+        let json = settings_json(&settings);
+
+        // NOTE: Every saved value, so one added to the config can not be left out
+        let saved = serde_json::to_value(&config).expect("Serializable config");
+        let saved = saved.as_object().expect("Config is an object").keys();
+
+        let derived = [
+            "config_file",
+            "server_domain",
+            "server_address",
+            "resolved_addresses",
+            "connection_timeout_secs",
+            "reconnect_window_secs",
+            "keep_alive_interval_secs",
+            "poll_interval_secs",
+        ];
+
+        for field in saved.map(String::as_str).chain(derived) {
+            assert!(json[field].get("value").is_some(), "Missing {field}");
+            assert!(
+                json[field]["source"].is_string(),
+                "Missing source of {field}"
+            );
+        }
+
+        assert_eq!("127.0.0.1:8765", json["resolved_addresses"]["value"][0]);
+        assert_eq!("backup.example", json["servers"]["value"][1]);
+        assert_eq!(4, json["passphrase_words"]["value"]);
+        assert_eq!(false, json["use_upnp"]["value"]);
+        assert_eq!("default", json["hint_age"]["source"]);
+    }
+
+    #[test]
+    fn test_config_get_shows_domain_and_addresses() {
+        let addresses = [SocketAddr::from(([127, 0, 0, 1], 8765))];
+
+        let fallbacks = ["backup.example".to_owned()];
+
+        let info = config_info(
+            "aporture.example",
+            ConfigSource::File,
+            &addresses,
+            &fallbacks,
+        );
+
+        assert!(info.contains("aporture.example"));
+        assert!(info.contains("127.0.0.1:8765"));
+        assert!(info.contains("config file"));
+        assert!(info.contains("1. backup.example"));
+    }
+
+    #[test]
+    fn test_contact_hint_only_for_missing_contacts() {
+        let missing = || Err::<(), _>(PairingError::Hello(Hello::NoPeer));
+
+        let e = contact_hint(missing(), Some("alice")).expect_err("Missing peer");
+        assert!(e.to_string().contains("pair with them again"));
+
+        let e = contact_hint(missing(), None).expect_err("Missing peer");
+        assert!(!e.to_string().contains("pair with them again"));
+
+        let e = contact_hint(Err::<(), _>(PairingError::Timeout), Some("alice"));
+        assert!(!e.expect_err("Timeout").to_string().contains("alice"));
+    }
+
+    #[tokio::test]
+    async fn test_listen_accepts_contacts_and_refuses_others() {
+        use aporture::crypto::hasher::Hasher;
+        use aporture::net::NetworkPeer;
+        use aporture::parser::SerdeIO;
+        use aporture::protocol::{PairKind, PairingResponseCode};
+
+        let listener = direct::Listener::bind(0).await.expect("Bind");
+        let address = SocketAddr::from(([127, 0, 0, 1], listener.address().port()));
+        let keys = vec![("alice".to_owned(), vec![1; 32])];
+
+        let greet = |key: [u8; 32]| async move {
+            let stream = tokio::net::TcpStream::connect(address)
+                .await
+                .expect("Connect");
+            let mut peer = NetworkPeer::new(stream);
+
+            let hello = aporture::protocol::Hello::new(PairKind::Sender, Hasher::hash(&key));
+            peer.write_ser(&hello).await.expect("Greet");
+
+            peer
+        };
+
+        let unknown = async {
+            let mut peer = greet([2; 32]).await;
+            peer.read_ser::<PairingResponseCode>()
+                .await
+                .expect("Answer")
+        };
+        let (accepted, answer) = tokio::join!(accept_contact(&listener, &keys), unknown);
+        assert!(accepted.is_none());
+        assert_eq!(PairingResponseCode::NoPeer, answer);
+
+        let (accepted, _peer) = tokio::join!(accept_contact(&listener, &keys), greet([1; 32]));
+        let ((name, _), _) = accepted.expect("Contact accepted");
+        assert_eq!("alice", name);
+    }
+
+    #[tokio::test]
+    async fn test_doctor_only_probes_the_forced_family() {
+        let config = serde_json::from_value::<Config>(json!({
+            "servers": [
+                { "domain": "127.0.0.1:8765", "address": "127.0.0.1", "port": 8765 },
+                { "domain": "[::1]:8765", "address": "::1", "port": 8765 },
+            ],
+        }))
+        .expect("Valid config");
+
+        let probed = |family| {
+            let servers = config.servers();
+
+            async move {
+                let mut addresses = Vec::new();
+                for server in servers {
+                    addresses.extend(probed_addresses(server, family).await.unwrap_or_default());
+                }
+                addresses
+            }
+        };
+
+        let v4 = probed(Some(IpFamily::V4)).await;
+        assert_eq!(vec![SocketAddr::from(([127, 0, 0, 1], 8765))], v4);
+
+        let v6 = probed(Some(IpFamily::V6)).await;
+        assert_eq!(vec![SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 8765))], v6);
+
+        assert_eq!(2, probed(None).await.len());
+
+        let server = &config.servers()[0];
+        assert!(probed_addresses(server, Some(IpFamily::V6)).await.is_err());
+    }
+
+    #[test]
+    fn test_public_address_check() {
+        let public = SocketAddr::from(([203, 0, 113, 1], 4000));
+        let other_port = SocketAddr::from(([203, 0, 113, 1], 4001));
+        let other_ip = SocketAddr::from(([192, 168, 1, 2], 4000));
+
+        assert!(public_address_check(Some(public), Some(public)).passed);
+        assert!(!public_address_check(Some(public), Some(other_port)).passed);
+        assert!(!public_address_check(Some(other_ip), Some(public)).passed);
+        assert!(!public_address_check(None, Some(public)).passed);
+    }
+}
@@ -24,7 +24,6 @@ impl Holder {
                             }
                             Err(aporture::io::Error::Cipher(_)) => {
                                 println!("Sorry, try again");
-                                continue;
                             }
                             Err(_) => bail!("Could not find or create contacts file"),
                         };
@@ -1,14 +1,21 @@
-use anyhow::Result;
-use clap::Parser;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use clap::{CommandFactory, Parser};
 use colored::Colorize;
 
-use aporture::fs::{config::Config, contacts::Contacts};
-use args::{Cli, Commands, ConfigCommand, ContactCommand, PairCommand};
+use aporture::fs::config::{self, Config};
+use aporture::fs::contacts::Contacts;
+use aporture::transfer::{CompressionThreshold, TransferOptions};
+use args::{Cli, Commands, ConfigCommand, ContactCommand, ListFormat, PairCommand};
+use args::{ReceiveMethod, SendMethod, VerifyCommand};
+use output::Output;
 use passphrase::Method;
 
 mod args;
 mod commands;
 mod contacts;
+mod output;
 mod passphrase;
 mod progress;
 
@@ -38,90 +45,345 @@ async fn main() -> Result<()> {
 
     let args = Cli::parse();
 
+    if let Commands::Completions { shell } = args.command {
+        clap_complete::generate(
+            shell,
+            &mut Cli::command(),
+            "aporture",
+            &mut std::io::stdout(),
+        );
+
+        return Ok(());
+    }
+
+    let piped = args.command.writes_stdout();
+
+    if piped && args.json {
+        bail!("JSON output is not available when writing the transfer to standard output");
+    }
+
+    let output = Output::new(args.json, args.quiet, piped).assume_yes(args.yes);
+
+    match run(args.command, output).await {
+        Err(e) if output.is_json() => {
+            output::print(&output::error(&e));
+            std::process::exit(1);
+        }
+        result => result,
+    }
+}
+
+/// Passphrase of the side starting the transfer: given, of the contact or else generated.
+async fn sender_passphrase(
+    method: &SendMethod,
+    hide: bool,
+    contacts_holder: &contacts::Holder,
+    output: Output,
+) -> Result<Vec<u8>> {
+    let given = passphrase::given(
+        method.passphrase.clone(),
+        method.passphrase_file.as_deref(),
+        method.passphrase_stdin,
+    )?;
+
+    let passphrase_method = if let Some(passphrase) = given {
+        if output.is_human() && !hide {
+            println!("Your passphrase is '{}'", passphrase.green().bold());
+
+            println!(
+                "Share it with your {}",
+                "peer".bright_cyan().bold().underline()
+            );
+        }
+        Method::Direct(passphrase)
+    } else if let Some(ref name) = method.contact {
+        let contacts = contacts_holder.get_or_init().await?;
+        Method::Contact(name, contacts)
+    } else {
+        let config = Config::get().await;
+        Method::Generate(config.passphrase_words(), config.wordlist())
+    };
+
+    passphrase::get(passphrase_method, output)
+}
+
+/// Passphrase of the side joining the transfer: given or of the contact.
+async fn receiver_passphrase(
+    method: &ReceiveMethod,
+    hide: bool,
+    contacts_holder: &contacts::Holder,
+    output: Output,
+) -> Result<Vec<u8>> {
+    let given = passphrase::given(
+        method.passphrase.clone(),
+        method.passphrase_file.as_deref(),
+        method.passphrase_stdin,
+    )?;
+
+    let passphrase_method = if let Some(passphrase) = given {
+        if output.is_human() && !hide {
+            println!("Your passphrase is '{}'", passphrase.green().bold());
+        }
+
+        Method::Direct(passphrase)
+    } else if let Some(ref name) = method.contact {
+        let contacts = contacts_holder.get_or_init().await?;
+        Method::Contact(name, contacts)
+    } else {
+        unreachable!("Guaranteed by clap");
+    };
+
+    passphrase::get(passphrase_method, output)
+}
+
+#[allow(clippy::too_many_lines)]
+async fn run(command: Commands, output: Output) -> Result<()> {
     let mut contacts_holder = contacts::Holder::default();
 
-    match args.command {
-        Commands::Send { path, method, save } => {
-            let passphrase_method = if let Some(passphrase) = method.passphrase {
-                println!("Your passphrase is '{}'", passphrase.green().bold());
-
-                println!(
-                    "Share it with your {}",
-                    "peer".bright_cyan().bold().underline()
-                );
-                Method::Direct(passphrase)
-            } else if let Some(ref name) = method.contact {
-                let contacts = contacts_holder.get_or_init().await?;
-                Method::Contact(name, contacts)
-            } else {
-                Method::Generate
-            };
-            let passphrase = passphrase::get(passphrase_method)?;
+    match command {
+        Commands::Send {
+            paths,
+            method,
+            hide_passphrase,
+            save,
+            options,
+        } => {
+            if method.passphrase_stdin && paths.iter().any(|path| args::is_stdio(path)) {
+                bail!("The passphrase can not be read from standard input while sending it");
+            }
 
-            commands::send(passphrase, save, method.contact, &mut contacts_holder, path).await?;
+            let passphrase =
+                sender_passphrase(&method, hide_passphrase, &contacts_holder, output).await?;
+
+            commands::send(
+                passphrase,
+                save,
+                method.contact,
+                &mut contacts_holder,
+                paths,
+                &options,
+                output,
+            )
+            .await?;
         }
         Commands::Receive {
             destination: path,
             method,
+            hide_passphrase,
             save,
+            options,
         } => {
-            let passphrase_method = if let Some(passphrase) = method.passphrase {
-                println!("Your passphrase is '{}'", passphrase.green().bold());
+            let passphrase =
+                receiver_passphrase(&method, hide_passphrase, &contacts_holder, output).await?;
 
-                Method::Direct(passphrase)
-            } else if let Some(ref name) = method.contact {
-                let contacts = contacts_holder.get_or_init().await?;
-                Method::Contact(name, contacts)
-            } else {
-                unreachable!("Guaranteed by clap");
-            };
-            let passphrase = passphrase::get(passphrase_method)?;
+            commands::receive(
+                passphrase,
+                save,
+                method.contact,
+                &mut contacts_holder,
+                path,
+                options,
+                output,
+            )
+            .await?;
+        }
+        Commands::Verify {
+            command:
+                VerifyCommand::Send {
+                    path,
+                    method,
+                    hide_passphrase,
+                    exclude,
+                    symlinks,
+                    connection,
+                },
+        } => {
+            let passphrase =
+                sender_passphrase(&method, hide_passphrase, &contacts_holder, output).await?;
+
+            let options = TransferOptions::default()
+                .exclude(&exclude)?
+                .symlinks(symlinks);
 
-            commands::receive(passphrase, save, method.contact, &mut contacts_holder, path).await?;
+            commands::verify_send(passphrase, &path, options, &connection, output).await?;
+        }
+        Commands::Verify {
+            command:
+                VerifyCommand::Receive {
+                    destination,
+                    method,
+                    hide_passphrase,
+                    connection,
+                },
+        } => {
+            let passphrase =
+                receiver_passphrase(&method, hide_passphrase, &contacts_holder, output).await?;
+
+            commands::verify_receive(passphrase, destination, &connection, output).await?;
+        }
+        Commands::Listen {
+            destination,
+            contact,
+            port,
+        } => {
+            if Contacts::exists() {
+                commands::listen(&contacts_holder, contact, destination, port, output).await?;
+            } else if output.is_json() {
+                output::print(&serde_json::json!({ "status": "success", "contacts": [] }));
+            } else {
+                println!("No contacts found");
+            }
+        }
+        Commands::History => commands::history(output).await?,
+        Commands::Doctor { family } => commands::doctor(family.family(), output).await,
+        Commands::Contacts {
+            command: ContactCommand::Import { file, replace },
+        } => {
+            commands::import_contacts(&mut contacts_holder, &file, replace).await?;
         }
         Commands::Contacts { command } => {
             if Contacts::exists() {
                 match command {
-                    ContactCommand::List => commands::list_contacts(&contacts_holder).await?,
+                    ContactCommand::List { output_format, tag } => {
+                        let tag = tag.as_deref();
+                        commands::list_contacts(&contacts_holder, output, output_format, tag)
+                            .await?;
+                    }
                     ContactCommand::Delete { name } => {
-                        commands::delete_contact(&mut contacts_holder, name).await?;
+                        commands::delete_contact(&mut contacts_holder, name, output).await?;
+                    }
+                    ContactCommand::Tag { name, tags } => {
+                        commands::tag_contact(&mut contacts_holder, &name, &tags).await?;
+                    }
+                    ContactCommand::Rename { old, new } => {
+                        commands::rename_contact(&mut contacts_holder, &old, &new).await?;
                     }
+                    ContactCommand::Passwd => commands::change_contacts_password().await?,
+                    ContactCommand::Export { file } => {
+                        commands::export_contacts(&contacts_holder, &file).await?;
+                    }
+                    ContactCommand::Import { .. } => unreachable!("Handled above"),
                 }
+            } else if output.is_json() {
+                output::print(&serde_json::json!({ "status": "success", "contacts": [] }));
             } else {
                 println!("No contacts found");
             }
         }
         Commands::Pair { command } => match command {
             PairCommand::Start { passphrase, name } => {
-                let method = passphrase.map_or(Method::Generate, Method::Direct);
-                let passphrase = passphrase::get(method)?;
+                let config = Config::get().await;
+                let generate = Method::Generate(config.passphrase_words(), config.wordlist());
+                drop(config);
+
+                let method = passphrase.map_or(generate, Method::Direct);
+                let passphrase = passphrase::get(method, output)?;
 
                 commands::pair_start(passphrase, name, &mut contacts_holder).await?;
             }
             PairCommand::Complete { passphrase, name } => {
-                let passphrase = passphrase::get(Method::Direct(passphrase))?;
+                let passphrase = passphrase::get(Method::Direct(passphrase), output)?;
 
                 commands::pair_complete(passphrase, name, &mut contacts_holder).await?;
             }
+            PairCommand::List if Contacts::exists() => {
+                let format = ListFormat::default();
+                commands::list_contacts(&contacts_holder, output, format, None).await?;
+            }
+            PairCommand::List if output.is_json() => {
+                output::print(&serde_json::json!({ "status": "success", "contacts": [] }));
+            }
+            PairCommand::List => println!("No contacts found"),
         },
         Commands::Config { command } => match command {
-            ConfigCommand::Get => {
-                let config = Config::get().await;
+            ConfigCommand::Get {
+                all: false,
+                json: false,
+            } => commands::config_get().await,
+            ConfigCommand::Get { json, .. } | ConfigCommand::Show { json } => {
+                commands::config_show(json).await;
+            }
+            ConfigCommand::Reset => {
+                let _ = Config::reset().await?;
+            }
+            ConfigCommand::Set {
+                server_address,
+                download_dir,
+                default_download_dir,
+            } => {
+                if let Some(address) = server_address {
+                    let _ = Config::update_address(address).await?;
+                }
 
-                println!(
-                    "Current configured server address: {}",
-                    config.server_domain()
-                );
+                if download_dir.is_some() || default_download_dir {
+                    let _ = Config::update_download_dir(download_dir).await?;
+                }
+            }
+            ConfigCommand::PassphraseWords { count } => {
+                let _ = Config::update_passphrase_words(count.into()).await?;
+            }
+            ConfigCommand::Wordlist { wordlist } => {
+                let _ = Config::update_wordlist(wordlist).await?;
+            }
+            ConfigCommand::OnConflict { policy } => {
+                let _ = Config::update_on_conflict(policy).await?;
+            }
+            ConfigCommand::Compression { files, size } => {
+                let threshold = CompressionThreshold { files, size };
+                let _ = Config::update_compression_threshold(threshold).await?;
+            }
+            ConfigCommand::HintAge { seconds } => {
+                let _ = Config::update_hint_age(Duration::from_secs(seconds)).await?;
+            }
+            ConfigCommand::BindIp { ip } => {
+                let _ = Config::update_bind_ip(ip).await?;
+            }
+            ConfigCommand::DisplayName { name } => {
+                let _ = Config::update_display_name(name).await?;
+            }
+            ConfigCommand::Upnp { enabled } => {
+                let _ = Config::update_upnp(enabled).await?;
+            }
+            ConfigCommand::HolePunching { enabled } => {
+                let _ = Config::update_hole_punching(enabled).await?;
             }
-            ConfigCommand::Set { server_address } => {
-                let _ = Config::update_address(server_address).await?;
+            ConfigCommand::Stun { servers } => {
+                let _ = Config::set_stun_servers(servers).await?;
+            }
+            ConfigCommand::ListenContacts { names } => {
+                let _ = Config::set_listen_contacts(names).await?;
+            }
+            ConfigCommand::Add { server_address } => {
+                let _ = Config::add_server(server_address).await?;
+            }
+            ConfigCommand::Remove { server_address } => {
+                let _ = Config::remove_server(&server_address).await?;
+            }
+            ConfigCommand::Move {
+                server_address,
+                position,
+            } => {
+                let _ = Config::move_server(&server_address, position).await?;
+            }
+            ConfigCommand::Resolve { family } => {
+                let domain = Config::get().await.server_domain().to_owned();
+
+                let addresses = config::resolve(&domain, family.family()).await?;
+
+                println!("Server {domain} resolves to:");
+                for address in addresses {
+                    println!("  {address}");
+                }
             }
         },
-    };
+        Commands::Completions { .. } => unreachable!("Handled above"),
+    }
 
     contacts_holder.save().await?;
 
-    println!("{}", "Success!!".green());
+    if output.is_human() {
+        println!("{}", "Success!!".green());
+    }
 
     Ok(())
 }
@@ -0,0 +1,91 @@
+use serde_json::{json, Value};
+
+/// How results are reported to the user, and whether they are asked for confirmation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::struct_excessive_bools)] // Independent command line flags
+pub struct Output {
+    json: bool,
+    quiet: bool,
+    piped: bool,
+    yes: bool,
+}
+
+impl Output {
+    /// If piped the standard output is kept for transferred data, so no messages are printed.
+    pub const fn new(json: bool, quiet: bool, piped: bool) -> Self {
+        Self {
+            json,
+            quiet,
+            piped,
+            yes: false,
+        }
+    }
+
+    /// Answers yes to every confirmation instead of asking.
+    pub const fn assume_yes(mut self, yes: bool) -> Self {
+        self.yes = yes;
+        self
+    }
+
+    pub const fn confirms(self) -> bool {
+        self.yes
+    }
+
+    pub const fn is_human(self) -> bool {
+        !self.json && !self.piped
+    }
+
+    pub const fn is_json(self) -> bool {
+        self.json
+    }
+
+    /// Whether transfer progress is tracked at all
+    pub const fn progress(self) -> bool {
+        !self.quiet
+    }
+}
+
+pub fn print(value: &Value) {
+    println!("{value}");
+}
+
+pub fn error(error: &anyhow::Error) -> Value {
+    json!({ "status": "error", "message": format!("{error:#}") })
+}
+
+/// Joins the fields as a CSV line, quoting the ones with separators, quotes or line breaks.
+pub fn csv_record(fields: &[&str]) -> String {
+    fields
+        .iter()
+        .map(|f| {
+            if f.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", f.replace('"', "\"\""))
+            } else {
+                (*f).to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_has_status_and_full_message() {
+        let e = anyhow::anyhow!("Connection refused").context("Could not pair");
+
+        let value = error(&e);
+
+        assert_eq!("error", value["status"]);
+        assert_eq!("Could not pair: Connection refused", value["message"]);
+    }
+
+    #[test]
+    fn test_csv_record_quotes_special_fields() {
+        let record = csv_record(&["alice", "bob, jr", "say \"hi\""]);
+
+        assert_eq!(r#"alice,"bob, jr","say ""hi""""#, record);
+    }
+}
@@ -1,24 +1,37 @@
-use anyhow::{bail, Result};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
 use colored::Colorize;
 
 use aporture::fs::contacts::Contacts;
+use aporture::passphrase::Wordlist;
+use serde_json::json;
+
+use crate::output::{self, Output};
 
 #[derive(Debug)]
 pub enum Method<'a> {
     Direct(String),
-    Generate,
+    Generate(usize, Wordlist),
     Contact(&'a str, &'a Contacts),
 }
 
-pub fn get(method: Method) -> Result<Vec<u8>> {
+pub fn get(method: Method, output: Output) -> Result<Vec<u8>> {
     match method {
         Method::Direct(passphrase) => Ok(passphrase.into_bytes()),
-        Method::Generate => {
-            let passphrase = aporture::passphrase::generate(3);
+        Method::Generate(word_count, wordlist) => {
+            let passphrase = aporture::passphrase::generate(word_count, wordlist);
+
+            if output.is_json() {
+                output::print(&json!({ "status": "passphrase", "passphrase": passphrase }));
+
+                return Ok(passphrase.into_bytes());
+            }
 
             println!(
-                "The generated passphrase is '{}'",
-                passphrase.green().bold()
+                "The generated passphrase is '{}' ({:.0} bits of entropy)",
+                passphrase.green().bold(),
+                aporture::passphrase::entropy(word_count, wordlist)
             );
             println!(
                 "Share it with your {}",
@@ -29,13 +42,55 @@ pub fn get(method: Method) -> Result<Vec<u8>> {
         }
         Method::Contact(name, contacts) => match contacts.get(name) {
             Some(key) => {
-                println!(
-                    "Using key associated with contact {}",
-                    name.bright_blue().bold()
-                );
+                if output.is_human() {
+                    println!(
+                        "Using key associated with contact {}",
+                        name.bright_blue().bold()
+                    );
+                }
                 Ok(key.to_vec())
             }
             None => bail!("Contact {name} not found"),
         },
     }
 }
+
+/// Passphrase given by the user as an argument, in a file or on the standard input,
+/// `None` if none of them was used.
+pub fn given(argument: Option<String>, file: Option<&Path>, stdin: bool) -> Result<Option<String>> {
+    let read = if let Some(path) = file {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read passphrase from {}", path.display()))?
+    } else if stdin {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        line
+    } else {
+        return Ok(argument);
+    };
+
+    let passphrase = without_line_break(&read);
+
+    if passphrase.is_empty() {
+        bail!("The passphrase read is empty");
+    }
+
+    Ok(Some(passphrase.to_owned()))
+}
+
+/// Strips the line breaks editors and `echo` leave at the end, which are never part of it.
+fn without_line_break(passphrase: &str) -> &str {
+    passphrase.trim_end_matches(['\n', '\r'])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trailing_line_breaks_are_trimmed() {
+        assert_eq!("a b c", without_line_break("a b c\n"));
+        assert_eq!("a b c", without_line_break("a b c\r\n\n"));
+        assert_eq!(" a b ", without_line_break(" a b "));
+    }
+}
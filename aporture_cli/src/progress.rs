@@ -1,41 +1,195 @@
 use std::fmt::Write;
+use std::io::IsTerminal;
 
+use colored::Colorize;
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
-use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task::JoinHandle;
 
-use aporture::transfer::ChannelMessage;
+use aporture::transfer::{AportureTransferProtocol, ChannelMessage};
+use aporture::State;
+
+use crate::output::Output;
+
+/// Percentage between the lines printed instead of the bar
+const LINE_STEP: usize = 25;
+
+/// How the progress is shown, a bar on terminals and a line every few percent elsewhere,
+/// such as over SSH without a terminal or when redirected to a file.
+enum Tracker {
+    Bar(ProgressBar),
+    Lines(Lines),
+}
+
+struct Lines {
+    total: usize,
+    done: usize,
+    reported: usize,
+}
+
+impl Lines {
+    const fn new(total: usize) -> Self {
+        Self {
+            total,
+            done: 0,
+            reported: 0,
+        }
+    }
+
+    /// Adds the bytes transferred, returning the percentage to print if it reached another step.
+    fn add(&mut self, bytes: usize) -> Option<usize> {
+        self.done += bytes;
+
+        if self.total == 0 {
+            return None;
+        }
+
+        let percent = self.done.min(self.total) * 100 / self.total;
+        let step = percent / LINE_STEP * LINE_STEP;
+
+        (step > self.reported).then(|| {
+            self.reported = step;
+            step
+        })
+    }
+}
+
+/// Tracks the progress of a transfer unless it is disabled, so no messages are produced when quiet.
+/// The transfer can be paused with a signal either way.
+pub fn attach<S: State>(
+    atp: &mut AportureTransferProtocol<'_, S>,
+    output: Output,
+) -> Option<JoinHandle<usize>> {
+    pause_on_signal(atp, output);
+
+    let (snd, rcv) = notifier(output)?;
+
+    atp.add_progress_notifier(snd);
+
+    Some(init_progress_bar(rcv, output))
+}
+
+/// Channel the transfer reports its progress on, `None` when quiet so none is registered.
+fn notifier(output: Output) -> Option<(Sender<ChannelMessage>, Receiver<ChannelMessage>)> {
+    output.progress().then(|| tokio::sync::mpsc::channel(64))
+}
+
+/// Pauses the transfer every time the process receives SIGUSR1 and resumes it on the next one,
+/// until the transfer is dropped.
+#[cfg(unix)]
+fn pause_on_signal<S: State>(atp: &mut AportureTransferProtocol<'_, S>, output: Output) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => return log::warn!("Could not listen for the pause signal: {e}"),
+    };
+
+    let (snd, rcv) = tokio::sync::watch::channel(false);
+
+    atp.add_pause_control(rcv);
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = signals.recv() => (),
+                () = snd.closed() => break,
+            }
+
+            let paused = !*snd.borrow();
+            snd.send_replace(paused);
+
+            if output.is_human() {
+                let message = if paused {
+                    "Transfer paused, send SIGUSR1 again to resume"
+                } else {
+                    "Transfer resumed"
+                };
+                eprintln!("{}", message.yellow());
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+const fn pause_on_signal<S: State>(_atp: &mut AportureTransferProtocol<'_, S>, _output: Output) {}
+
+/// Waits for the tracked transfer to finish, returning the bytes transferred if known.
+pub async fn total(progress: Option<JoinHandle<usize>>) -> Option<usize> {
+    match progress {
+        Some(handle) => handle.await.ok(),
+        None => None,
+    }
+}
+
+/// Shows the transfer progress, returning the total bytes once the channel closes.
+///
+/// The bar is only drawn on a terminal, otherwise the progress is printed as lines.
+pub fn init_progress_bar(
+    mut channel: Receiver<ChannelMessage>,
+    output: Output,
+) -> JoinHandle<usize> {
+    // NOTE: The bar is drawn on the standard error
+    let bar = std::io::stderr().is_terminal() && std::io::stdout().is_terminal();
 
-pub fn init_progress_bar(mut channel: Receiver<ChannelMessage>) -> JoinHandle<()> {
     tokio::spawn(async move {
         let mut progress = None;
+        let mut bytes = 0;
 
         while let Some(message) = channel.recv().await {
+            if let ChannelMessage::ProgressSize(total) = message {
+                bytes = total;
+            }
+
+            if !output.is_human() {
+                continue;
+            }
+
             match message {
                 ChannelMessage::Compression => {
-                    println!("The folder to send had too many files!");
-                    println!("Please be patient, it will be compressed before the transfer...");
+                    println!("Compressing the folder while it is sent...");
                 }
                 ChannelMessage::ProgressSize(total) => {
-                    let p = ProgressBar::new(total as u64);
-                    p.set_style(style());
-                    progress = Some(p);
+                    progress = Some(if bar {
+                        let p = ProgressBar::new(total as u64);
+                        p.set_style(style());
+                        Tracker::Bar(p)
+                    } else {
+                        Tracker::Lines(Lines::new(total))
+                    });
                 }
-                ChannelMessage::Progress(n) => {
-                    if let Some(ref p) = progress {
-                        p.inc(n as u64);
+                // NOTE: The bar only shows the whole transfer
+                ChannelMessage::FileStarted { .. } => {}
+                ChannelMessage::Progress(n) => match progress {
+                    Some(Tracker::Bar(ref p)) => p.inc(n as u64),
+                    Some(Tracker::Lines(ref mut lines)) => {
+                        if let Some(percent) = lines.add(n) {
+                            println!("{percent}%…");
+                        }
                     }
+                    None => {}
+                },
+                ChannelMessage::Finished => match progress.take() {
+                    Some(Tracker::Bar(p)) => p.finish(),
+                    Some(Tracker::Lines(lines)) if lines.reported < 100 => println!("100%"),
+                    Some(Tracker::Lines(_)) | None => {}
+                },
+                ChannelMessage::UsingRelay => {
+                    let message = "Warning: Direct connection failed, relaying through the server";
+                    println!("{}", message.yellow());
                 }
-                ChannelMessage::Uncompressing => {
-                    println!("Waiting for transferred file to be uncompressed...");
-                }
-                ChannelMessage::Finished => {
-                    if let Some(p) = progress.take() {
-                        p.finish();
+                ChannelMessage::Reconnecting => {
+                    if let Some(Tracker::Bar(p)) = progress.take() {
+                        p.abandon();
                     }
+
+                    let message = "Warning: Connection lost, reconnecting to resume the transfer";
+                    println!("{}", message.yellow());
                 }
             }
         }
+
+        bytes
     })
 }
 
@@ -45,3 +199,33 @@ pub fn style() -> ProgressStyle {
         .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).expect("ETA does not fail to write"))
         .progress_chars("#>-")
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lines_are_printed_every_step() {
+        let mut lines = Lines::new(1000);
+
+        let printed = [100, 200, 0, 500, 2000]
+            .into_iter()
+            .map(|bytes| lines.add(bytes))
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec![None, Some(25), None, Some(75), Some(100)], printed);
+        assert_eq!(None, Lines::new(0).add(10));
+    }
+
+    #[test]
+    fn test_quiet_registers_no_notifier() {
+        let quiet = Output::new(false, true, false);
+        assert!(notifier(quiet).is_none());
+
+        let json = Output::new(true, true, false);
+        assert!(notifier(json).is_none());
+
+        let tracked = Output::new(false, false, false);
+        assert!(notifier(tracked).is_some());
+    }
+}
@@ -13,7 +13,8 @@ use crate::components::modal::contacts::{
 };
 use crate::components::modal::preferences::{self, Preferences};
 use crate::components::toaster::{Severity, Toaster};
-use crate::pages::{contacts, receive, send, ContactPage, ReceiverPage, SenderPage};
+use crate::pages::{contacts, history, receive, send};
+use crate::pages::{ContactPage, HistoryPage, ReceiverPage, SenderPage};
 
 #[derive(Debug)]
 pub struct App {
@@ -22,6 +23,7 @@ pub struct App {
     receive_page: Controller<ReceiverPage>,
     sender_page: Controller<SenderPage>,
     contacts_page: Controller<ContactPage>,
+    history_page: Controller<HistoryPage>,
     contacts_holder: Controller<ContactHolder>,
     preferences: Controller<Preferences>,
     current_page: GString,
@@ -31,6 +33,7 @@ pub struct App {
 const CONTACTS_PAGE_NAME: &str = "Contacts";
 const SENDER_PAGE_NAME: &str = "Send";
 const RECEIVER_PAGE_NAME: &str = "Receive";
+const HISTORY_PAGE_NAME: &str = "History";
 
 #[derive(Debug)]
 pub enum Msg {
@@ -116,6 +119,8 @@ impl Component for App {
                         add_titled_with_icon[Some(RECEIVER_PAGE_NAME), RECEIVER_PAGE_NAME, icon_names::INBOX] = model.receive_page.widget(),
 
                         add_titled_with_icon[Some(CONTACTS_PAGE_NAME), CONTACTS_PAGE_NAME, icon_names::ADDRESS_BOOK] = model.contacts_page.widget(),
+
+                        add_titled_with_icon[Some(HISTORY_PAGE_NAME), HISTORY_PAGE_NAME, icon_names::CLOCK] = model.history_page.widget(),
                     },
                 }
             }
@@ -139,6 +144,10 @@ impl Component for App {
             .launch(())
             .forward(sender.input_sender(), Msg::from);
 
+        let history_page = HistoryPage::builder()
+            .launch(())
+            .forward(sender.input_sender(), Msg::from);
+
         let contacts_holder = ContactHolder::builder()
             .transient_for(&root)
             .launch(())
@@ -157,6 +166,7 @@ impl Component for App {
             receive_page,
             sender_page,
             contacts_page,
+            history_page,
             contacts_holder,
             preferences,
             current_page: SENDER_PAGE_NAME.into(),
@@ -192,6 +202,9 @@ impl Component for App {
 
                     self.contacts_page
                         .emit(contacts::Msg::ContactsReady(self.contacts.clone()));
+
+                    self.preferences
+                        .emit(preferences::Msg::ContactsReady(self.contacts.clone()));
                 }
                 ContactOutput::Error(message) => {
                     sender.input(Msg::ToastS(message, Severity::Error));
@@ -202,6 +215,10 @@ impl Component for App {
 
             Msg::PageSwitch => {
                 if let Some(page) = self.stack.visible_child_name() {
+                    if page == HISTORY_PAGE_NAME {
+                        self.history_page.emit(history::Msg::Refresh);
+                    }
+
                     if page == self.current_page || self.contacts.is_some() {
                         self.current_page = page;
                         return;
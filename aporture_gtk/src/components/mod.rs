@@ -1,4 +1,5 @@
 pub mod confirmation;
 pub mod file_chooser;
 pub mod modal;
+pub mod password;
 pub mod toaster;
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use relm4::ComponentSender;
 use tokio::sync::mpsc::Receiver;
 use tokio::task::JoinHandle;
@@ -15,8 +17,10 @@ pub fn handle_progress(
             let input = match message {
                 ChannelMessage::Compression => Msg::UpdateState(State::Compress),
                 ChannelMessage::ProgressSize(total) => Msg::UpdateState(State::Sending(total)),
-                ChannelMessage::Uncompressing => Msg::UpdateState(State::Uncompress),
                 ChannelMessage::Finished => Msg::UpdateState(State::Final),
+                ChannelMessage::UsingRelay => Msg::UpdateState(State::Relayed),
+                ChannelMessage::Reconnecting => Msg::UpdateState(State::Reconnecting),
+                ChannelMessage::FileStarted { name, size } => Msg::FileStarted(name, size),
                 ChannelMessage::Progress(n) => Msg::Progress(n),
             };
 
@@ -25,11 +29,22 @@ pub fn handle_progress(
     })
 }
 
+pub fn handle_waiting(
+    mut channel: Receiver<Duration>,
+    sender: ComponentSender<Peer>,
+) -> JoinHandle<()> {
+    relm4::spawn(async move {
+        if channel.recv().await.is_some() {
+            sender.input(Msg::UpdateState(State::Waiting));
+        }
+    })
+}
+
 pub fn handle_pulse(sender: ComponentSender<Peer>) -> JoinHandle<()> {
     relm4::spawn(async move {
         loop {
             sender.input(Msg::Pulse);
-            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
         }
     })
 }
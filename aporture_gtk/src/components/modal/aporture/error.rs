@@ -1,4 +1,5 @@
-use aporture::pairing::error::Error as PairingError;
+use aporture::pairing::error::{Error as PairingError, Negotiation};
+use aporture::protocol::PROTOCOL_VERSION;
 use aporture::transfer::{ReceiveError, SendError};
 
 use thiserror::Error;
@@ -7,28 +8,58 @@ use thiserror::Error;
 pub enum Error {
     #[error("The peer sending the file has not arrived yet")]
     NoPeer,
+    #[error("The peer disconnected before the transfer started")]
+    PeerDisconnected,
+    #[error("Waited too long for the peer")]
+    Expired,
     #[error("Could not connect to server")]
     NoServer,
     #[error("The server is malfunctioning, please try again later")]
     InvalidServer,
+    #[error("The server uses protocol version {0} and this app {PROTOCOL_VERSION}, update whichever is older")]
+    IncompatibleServer(u8),
+    #[error("The peer uses protocol version {0} and this app {PROTOCOL_VERSION}, update whichever is older")]
+    IncompatiblePeer(u8),
     #[error("The server is malfunctioning, please try again later")]
     ServerFailure,
+    #[error("The server is too busy, please try again later")]
+    ServerBusy,
     #[error("Could not perform pairing with peer")]
     PairingFailure,
+    #[error("The peer took too long to pair")]
+    PairingTimeout,
     #[error("The file selected is invalid")]
     FileNotFound,
+    #[error("The destination is no longer available")]
+    DestinationUnavailable,
+    #[error("The destination is read only")]
+    DestinationReadOnly,
+    #[error("The destination folder does not exist")]
+    MissingFolder,
+    #[error("A folder cannot be saved as an existing file")]
+    FolderIntoFile,
     #[error("You do not have access to the file you are trying to send")]
     FilePermission,
+    #[error("A file you are trying to send has a name that is not valid UTF-8, rename it")]
+    InvalidName,
     #[error("There was a problem in the transfered file")]
     HashMismatch,
+    #[error("One of the exclude patterns is invalid")]
+    InvalidPattern,
     #[error("Could not transfer file")]
     TransferFailure,
+    #[error("The connection with the peer was lost, check your network and try again")]
+    ConnectionLost,
+    #[error("The server stopped relaying the transfer, try on the same network as the peer")]
+    RelayLimit,
     #[error("Contact not found")]
     NoContact,
     #[error("Could not save the contact")]
     ContactSaving,
     #[error("Transfer cancelled")]
     Cancel,
+    #[error("The other side cancelled the transfer")]
+    PeerCancelled,
 }
 
 impl From<PairingError> for Error {
@@ -44,9 +75,19 @@ impl From<PairingError> for Error {
                 }
                 aporture::pairing::error::Hello::ServerUnsupportedVersion
                 | aporture::pairing::error::Hello::ClientError => Self::InvalidServer,
+                aporture::pairing::error::Hello::VersionMismatch { server } => {
+                    Self::IncompatibleServer(server)
+                }
                 aporture::pairing::error::Hello::ServerError(_) => Self::ServerFailure,
+                aporture::pairing::error::Hello::PeerDisconnected => Self::PeerDisconnected,
+                aporture::pairing::error::Hello::Expired => Self::Expired,
+                aporture::pairing::error::Hello::ServerBusy => Self::ServerBusy,
             },
+            PairingError::AddressExchange(Negotiation::VersionMismatch { peer }) => {
+                Self::IncompatiblePeer(peer)
+            }
             PairingError::KeyExchange(_) | PairingError::AddressExchange(_) => Self::PairingFailure,
+            PairingError::Timeout => Self::PairingTimeout,
         }
     }
 }
@@ -57,8 +98,24 @@ impl From<ReceiveError> for Error {
 
         match e {
             ReceiveError::File(_) | ReceiveError::Destination => Self::FileNotFound,
+            ReceiveError::DestinationUnavailable => Self::DestinationUnavailable,
+            ReceiveError::DestinationReadOnly => Self::DestinationReadOnly,
+            ReceiveError::MissingParent(_) => Self::MissingFolder,
+            ReceiveError::FolderIntoFile(_) => Self::FolderIntoFile,
             ReceiveError::Network(_) | ReceiveError::Cipher(_) => Self::TransferFailure,
-            ReceiveError::HashMismatch => Self::HashMismatch,
+            ReceiveError::HashMismatch
+            | ReceiveError::FileCount { .. }
+            | ReceiveError::ManifestMismatch => Self::HashMismatch,
+            ReceiveError::Timeout
+            | ReceiveError::Stalled
+            | ReceiveError::NotPiped
+            | ReceiveError::Verifying
+            | ReceiveError::NotVerifying
+            | ReceiveError::NoRelay => Self::TransferFailure,
+            ReceiveError::Cancelled => Self::Cancel,
+            ReceiveError::PeerCancelled => Self::PeerCancelled,
+            ReceiveError::ConnectionLost => Self::ConnectionLost,
+            ReceiveError::RelayLimit => Self::RelayLimit,
         }
     }
 }
@@ -70,8 +127,17 @@ impl From<SendError> for Error {
         match e {
             SendError::File(_) | SendError::Path => Self::FileNotFound,
             SendError::Subpath(_) => Self::FilePermission,
-            SendError::Network(_) => Self::TransferFailure,
+            SendError::NonUtf8Name(_) => Self::InvalidName,
+            SendError::Network(_)
+            | SendError::NoRelay
+            | SendError::PeerFailed
+            | SendError::ChunkSize(_) => Self::TransferFailure,
             SendError::HashMismatch => Self::HashMismatch,
+            SendError::Pattern(_) => Self::InvalidPattern,
+            SendError::Cancelled => Self::Cancel,
+            SendError::PeerCancelled => Self::PeerCancelled,
+            SendError::ConnectionLost => Self::ConnectionLost,
+            SendError::RelayLimit => Self::RelayLimit,
         }
     }
 }
@@ -1,14 +1,20 @@
+use std::collections::{HashMap, VecDeque};
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use adw::prelude::*;
 use channel::handle_pulse;
+use gtk::gio::Cancellable;
 use relm4::prelude::*;
 use relm4::JoinHandle;
 use relm4_icons::icon_names;
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
 
+use aporture::crypto::Key;
+use aporture::fs::config::Config;
 use aporture::fs::contacts::Contacts;
+use aporture::fs::history::{self, Direction, Entry, History};
 
 use crate::emit;
 
@@ -18,22 +24,107 @@ mod channel;
 mod error;
 mod protocol;
 
+/// Time a cancelled transfer has to tell the peer before it is aborted
+const CANCEL_GRACE: Duration = Duration::from_secs(2);
+
+/// Rows of the file list, the oldest finished files are removed past it
+const MAX_FILE_ROWS: usize = 200;
+
 #[derive(Debug)]
 pub struct Peer {
     pulser: Option<JoinHandle<()>>,
+    /// Running pairing and transfer, aborted on cancel if it does not stop by itself
+    transfer: Option<JoinHandle<()>>,
+    title: String,
     progress_bar: gtk::ProgressBar,
     progress_text: String,
     total: usize,
     current: usize,
+    /// Files of the transfer as they start, only shown for more than one
+    file_list: gtk::ListBox,
+    files: HashMap<String, FileRow>,
+    /// Names of the rows in the order they were added
+    shown: VecDeque<String>,
+    /// Names of the files not complete yet in the order they started, the order they are filled
+    filling: VecDeque<String>,
+    /// Halts the running transfer while true
+    pause: watch::Sender<bool>,
+    /// Stops the running transfer, telling the peer
+    cancel: watch::Sender<bool>,
+    /// Whether files are being transferred, the only time it can be paused
+    transferring: bool,
+    sending: bool,
+    /// Whether the transfer goes through the server as the direct connection failed
+    relayed: bool,
+    /// Who the transfer is with, as recorded in the history
+    counterpart: history::Peer,
+    /// Contact the peer did not save, waiting for the user to keep or discard it
+    unconfirmed: Option<UnconfirmedContact>,
+}
+
+/// Progress of a single file, filled in the order the files started.
+#[derive(Debug)]
+struct FileRow {
+    size: u64,
+    done: u64,
+    row: adw::ActionRow,
+    bar: gtk::ProgressBar,
+}
+
+impl FileRow {
+    fn new(name: &str, size: u64) -> Self {
+        let row = adw::ActionRow::builder()
+            .title(gtk::glib::markup_escape_text(name))
+            .subtitle(gtk::glib::format_size(size))
+            .build();
+
+        let bar = gtk::ProgressBar::builder()
+            .valign(gtk::Align::Center)
+            .width_request(60)
+            .build();
+
+        row.add_suffix(&bar);
+
+        Self {
+            size,
+            done: 0,
+            row,
+            bar,
+        }
+    }
+
+    /// Adds the bytes that fit in the file, returning the rest.
+    fn advance(&mut self, bytes: u64) -> u64 {
+        let added = bytes.min(self.size - self.done);
+        self.done += added;
+
+        #[allow(clippy::cast_precision_loss)]
+        let fraction = if self.size == 0 {
+            1.0
+        } else {
+            self.done as f64 / self.size as f64
+        };
+
+        self.bar.set_fraction(fraction);
+
+        bytes - added
+    }
+
+    const fn is_complete(&self) -> bool {
+        self.done == self.size
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum State {
     Initial,
+    Waiting,
+    Relayed,
+    Reconnecting,
     Paired,
     Compress,
     Sending(usize),
-    Uncompress,
+    Paused,
     Final,
 }
 
@@ -90,6 +181,7 @@ pub struct Params {
     passphrase: PassphraseMethod,
     path: PathBuf,
     save: Option<(String, Arc<Mutex<Contacts>>)>,
+    exclude: Vec<String>,
 }
 
 impl Params {
@@ -102,16 +194,27 @@ impl Params {
             passphrase,
             path,
             save,
+            exclude: Vec::new(),
         }
     }
+
+    pub fn with_exclude(mut self, exclude: Vec<String>) -> Self {
+        self.exclude = exclude;
+        self
+    }
 }
 
 #[derive(Debug)]
 pub enum Msg {
     Pulse,
     Cancel,
+    TogglePause,
     UpdateState(State),
+    FileStarted(String, u64),
     Progress(usize),
+    /// Name the peer shows itself as, known once paired
+    PeerName(String),
+    KeepContact(bool),
 }
 
 #[derive(Debug)]
@@ -121,12 +224,103 @@ pub enum ContactAction {
     PeerRefused,
 }
 
+/// Contact requested while the peer did not save it, only useful if they kept the key anyway.
+#[derive(Debug)]
+pub struct UnconfirmedContact {
+    name: String,
+    key: Key,
+    contacts: Arc<Mutex<Contacts>>,
+}
+
+impl UnconfirmedContact {
+    async fn save(self) -> Result<(), Error> {
+        let mut contacts = self.contacts.lock().await;
+        contacts.add(self.name, self.key);
+        contacts.save().await.map_err(|_| Error::ContactSaving)
+    }
+}
+
+#[derive(Debug)]
+pub struct Transferred {
+    action: ContactAction,
+    unconfirmed: Option<UnconfirmedContact>,
+    /// Path sent or where the file was saved
+    path: PathBuf,
+    size: u64,
+}
+
+#[derive(Debug)]
+pub struct Finished {
+    result: Result<Transferred, Error>,
+    /// File chosen to be sent, as the receiver only knows it once saved
+    file: Option<PathBuf>,
+    notify: bool,
+}
+
+impl Finished {
+    async fn new(result: Result<Transferred, Error>, file: Option<PathBuf>) -> Self {
+        let notify = Config::get().await.notifications();
+
+        Self {
+            result,
+            file,
+            notify,
+        }
+    }
+
+    fn notification(&self, sending: bool) -> gtk::gio::Notification {
+        let title = match (&self.result, sending) {
+            (Ok(_), true) => "File sent",
+            (Ok(_), false) => "File received",
+            (Err(_), _) => "Transfer failed",
+        };
+
+        let notification = gtk::gio::Notification::new(title);
+
+        let body = match &self.result {
+            Ok(t) if sending => file_name(&t.path),
+            Ok(t) => format!("{} saved to {}", file_name(&t.path), t.path.display()),
+            Err(e) => self
+                .file
+                .as_ref()
+                .map_or_else(|| e.to_string(), |file| format!("{}: {e}", file_name(file))),
+        };
+
+        notification.set_body(Some(&body));
+
+        notification
+    }
+
+    fn entry(&self, sending: bool, peer: history::Peer) -> Entry {
+        let direction = if sending {
+            Direction::Sent
+        } else {
+            Direction::Received
+        };
+
+        let Ok(t) = &self.result else {
+            let file = self.file.as_deref().map(file_name).unwrap_or_default();
+
+            return Entry::new(direction, peer, file, 0).failed();
+        };
+
+        Entry::new(direction, peer, file_name(&t.path), t.size)
+    }
+}
+
+fn file_name(path: &std::path::Path) -> String {
+    path.file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
 #[relm4::component(pub)]
 impl Component for Peer {
     type Init = TransferType;
     type Input = Msg;
     type Output = Result<ContactAction, Error>;
-    type CommandOutput = Result<ContactAction, Error>;
+    type CommandOutput = Finished;
 
     view! {
         dialog = adw::Window {
@@ -159,15 +353,31 @@ impl Component for Peer {
 
                     gtk::Label {
                         set_justify: gtk::Justification::Center,
+                        set_wrap: true,
 
-                        set_text: &title,
+                        #[watch]
+                        set_text: &model.title,
                     },
 
-                    gtk::Button {
-                        add_css_class: "suggested-action",
+                    gtk::Box {
+                        set_halign: gtk::Align::Center,
+                        set_spacing: 10,
+
+                        gtk::Button {
+                            #[watch]
+                            set_visible: model.transferring,
+
+                            #[watch]
+                            set_label: if *model.pause.borrow() { "Resume" } else { "Pause" },
+                            connect_clicked => Msg::TogglePause,
+                        },
+
+                        gtk::Button {
+                            add_css_class: "suggested-action",
 
-                        set_label: "Cancel",
-                        connect_clicked => Msg::Cancel,
+                            set_label: "Cancel",
+                            connect_clicked => Msg::Cancel,
+                        },
                     },
 
                     #[local_ref]
@@ -178,6 +388,32 @@ impl Component for Peer {
 
                         set_pulse_step: 0.1,
                     },
+
+                    gtk::ScrolledWindow {
+                        #[watch]
+                        set_visible: model.files.len() > 1,
+
+                        set_hscrollbar_policy: gtk::PolicyType::Never,
+                        set_max_content_height: 200,
+                        set_propagate_natural_height: true,
+
+                        #[local_ref]
+                        file_list -> gtk::ListBox {
+                            set_selection_mode: gtk::SelectionMode::None,
+                            add_css_class: "boxed-list",
+                        },
+                    },
+
+                    gtk::Label {
+                        #[watch]
+                        set_visible: model.relayed,
+
+                        set_justify: gtk::Justification::Center,
+                        set_wrap: true,
+                        add_css_class: "warning",
+
+                        set_text: "Direct connection failed, relaying via server",
+                    },
                 },
             },
         }
@@ -192,27 +428,66 @@ impl Component for Peer {
 
         let title = init.title();
 
+        let (TransferType::Send(params) | TransferType::Receive(params)) = &init;
+
+        let counterpart = match &params.passphrase {
+            PassphraseMethod::Direct(passphrase) => history::Peer::passphrase(passphrase),
+            PassphraseMethod::Contact(name, _) => history::Peer::Contact(name.clone()),
+        };
+
+        let sending = matches!(init, TransferType::Send(_));
+
+        let progress = sender.clone();
+        let output = sender.command_sender().clone();
+
+        let (pause, paused) = watch::channel(false);
+        let (cancel, cancelled) = watch::channel(false);
+
+        // NOTE: Spawned instead of a command so cancelling can drop it, which stops the
+        // connection and removes any partially received file
+        let transfer = relm4::spawn(async move {
+            let controls = (paused, cancelled);
+            let finished = match init {
+                TransferType::Send(params) => {
+                    let file = params.path.clone();
+                    let result = protocol::send(progress, params, controls).await;
+                    Finished::new(result, Some(file)).await
+                }
+                TransferType::Receive(params) => {
+                    let result = protocol::receive(progress, params, controls).await;
+                    Finished::new(result, None).await
+                }
+            };
+
+            output.emit(finished);
+        });
+
         let model = Self {
             pulser: None,
+            transfer: Some(transfer),
+            title,
             progress_bar: gtk::ProgressBar::default(),
             progress_text: String::new(),
             total: 0,
             current: 0,
+            file_list: gtk::ListBox::default(),
+            files: HashMap::new(),
+            shown: VecDeque::new(),
+            filling: VecDeque::new(),
+            pause,
+            cancel,
+            transferring: false,
+            sending,
+            relayed: false,
+            counterpart,
+            unconfirmed: None,
         };
 
         let pb = &model.progress_bar;
+        let file_list = &model.file_list;
 
         let widgets = view_output!();
 
-        match init {
-            TransferType::Send(params) => {
-                sender.oneshot_command(protocol::send(sender.clone(), params));
-            }
-            TransferType::Receive(params) => {
-                sender.oneshot_command(protocol::receive(sender.clone(), params));
-            }
-        }
-
         ComponentParts { model, widgets }
     }
 
@@ -225,23 +500,31 @@ impl Component for Peer {
                         self.pulser = Some(handle_pulse(sender));
                         String::from("Waiting for peer...")
                     }
+                    State::Waiting => String::from("Waiting for the sender to start..."),
+                    State::Relayed => {
+                        self.relayed = true;
+                        String::from("Connecting through the server...")
+                    }
+                    State::Reconnecting => {
+                        self.transferring = false;
+                        String::from("Connection lost, reconnecting...")
+                    }
                     State::Paired => String::from("Pairing complete!"),
-                    State::Compress => String::from("Compressing files before transfer..."),
+                    State::Compress => String::from("Compressing files while sending..."),
                     State::Sending(total) => {
                         self.total = total;
                         self.current = 0;
+                        self.clear_files();
+                        self.transferring = true;
                         self.pulser.take().as_ref().map(JoinHandle::abort);
 
                         sender.input(Msg::Progress(0));
 
                         String::from("0%")
                     }
-                    State::Uncompress => {
-                        self.progress_bar.set_fraction(0.0);
-                        self.pulser = Some(handle_pulse(sender));
-                        String::from("Uncompressing files...")
-                    }
+                    State::Paused => String::from("Paused"),
                     State::Final => {
+                        self.transferring = false;
                         self.pulser.take().as_ref().map(JoinHandle::abort);
                         String::from("Finished Transfer")
                     }
@@ -250,18 +533,75 @@ impl Component for Peer {
 
             Msg::Pulse => self.progress_bar.pulse(),
 
+            Msg::PeerName(name) => {
+                self.title = if self.sending {
+                    format!("Sending file to\n{name}")
+                } else {
+                    format!("Receiving file from\n{name}")
+                };
+            }
+
+            Msg::FileStarted(name, size) => self.file_started(name, size),
+
             Msg::Progress(n) => {
                 self.current += n;
+                self.file_progress(n as u64);
 
                 #[allow(clippy::cast_precision_loss)]
                 let fraction = self.current as f64 / self.total as f64;
 
                 self.progress_bar.set_fraction(fraction);
-                self.progress_text = format!("{:.2}%", fraction * 100.0);
+
+                // NOTE: Chunks already on their way still arrive after pausing
+                if !*self.pause.borrow() {
+                    self.progress_text = format!("{:.2}%", fraction * 100.0);
+                }
+            }
+
+            Msg::TogglePause => {
+                let paused = !*self.pause.borrow();
+                self.pause.send_replace(paused);
+
+                if paused {
+                    sender.input(Msg::UpdateState(State::Paused));
+                } else {
+                    sender.input(Msg::Progress(0));
+                }
+            }
+
+            Msg::KeepContact(keep) => {
+                let Some(contact) = self.unconfirmed.take() else {
+                    return;
+                };
+
+                root.close();
+
+                if keep {
+                    let output = sender.output_sender().clone();
+                    relm4::spawn(async move {
+                        let result = contact.save().await.map(|()| ContactAction::Added);
+                        output.emit(result);
+                    });
+                } else {
+                    emit!(Ok(ContactAction::PeerRefused) => sender);
+                }
             }
 
             Msg::Cancel => {
                 self.pulser.take().as_ref().map(JoinHandle::abort);
+                self.cancel.send_replace(true);
+
+                // NOTE: A running transfer stops by itself so the peer learns it was cancelled,
+                // anything else, like the pairing, does not follow the cancel and is aborted
+                if let Some(mut transfer) = self.transfer.take() {
+                    relm4::spawn(async move {
+                        let stopped = tokio::time::timeout(CANCEL_GRACE, &mut transfer).await;
+                        if stopped.is_err() {
+                            transfer.abort();
+                        }
+                    });
+                }
+
                 root.close();
 
                 emit!(Err(Error::Cancel) => sender);
@@ -275,9 +615,123 @@ impl Component for Peer {
         sender: ComponentSender<Self>,
         root: &Self::Root,
     ) {
-        emit!(message => sender);
+        // NOTE: The transfer may have finished just before being cancelled
+        if self.transfer.take().is_none() {
+            return;
+        }
+
+        if message.notify {
+            let notification = message.notification(self.sending);
+            relm4::main_adw_application().send_notification(Some("transfer"), &notification);
+        }
+
+        let entry = message.entry(self.sending, self.counterpart.clone());
+        relm4::spawn(async move {
+            if let Err(e) = History::record(entry).await {
+                log::warn!("Could not save transfer history: {e}");
+            }
+        });
 
         self.pulser.take().as_ref().map(JoinHandle::abort);
-        root.close();
+
+        match message.result {
+            Ok(Transferred {
+                unconfirmed: Some(contact),
+                ..
+            }) => {
+                self.unconfirmed = Some(contact);
+                ask_keep_contact(root, &sender);
+            }
+            result => {
+                emit!(result.map(|t| t.action) => sender);
+                root.close();
+            }
+        }
+    }
+}
+
+impl Peer {
+    fn file_started(&mut self, name: String, size: u64) {
+        // NOTE: A file requested again starts over in its own row
+        if let Some(file) = self.files.get_mut(&name) {
+            if file.is_complete() {
+                self.filling.push_back(name);
+            }
+
+            file.done = 0;
+            file.advance(0);
+
+            return;
+        }
+
+        let file = FileRow::new(&name, size);
+        self.file_list.append(&file.row);
+
+        if !file.is_complete() {
+            self.filling.push_back(name.clone());
+        }
+
+        self.files.insert(name.clone(), file);
+        self.shown.push_back(name);
+
+        while self.shown.len() > MAX_FILE_ROWS {
+            let oldest = self.shown.front().and_then(|name| self.files.get(name));
+
+            if !oldest.is_some_and(FileRow::is_complete) {
+                break;
+            }
+
+            let name = self.shown.pop_front().expect("Checked above");
+            let file = self.files.remove(&name).expect("Shown files are kept");
+            self.file_list.remove(&file.row);
+        }
+    }
+
+    /// Fills the files in the order they started, which is exact unless several are sent at once.
+    fn file_progress(&mut self, mut bytes: u64) {
+        while bytes > 0 {
+            let Some(file) = self
+                .filling
+                .front()
+                .and_then(|name| self.files.get_mut(name))
+            else {
+                break;
+            };
+
+            bytes = file.advance(bytes);
+
+            if file.is_complete() {
+                self.filling.pop_front();
+            }
+        }
     }
+
+    fn clear_files(&mut self) {
+        for (_, file) in self.files.drain() {
+            self.file_list.remove(&file.row);
+        }
+
+        self.shown.clear();
+        self.filling.clear();
+    }
+}
+
+/// Asks whether to save the contact the peer did not save, answered with [`Msg::KeepContact`].
+fn ask_keep_contact(root: &adw::Window, sender: &ComponentSender<Peer>) {
+    relm4::view! {
+        dialog = adw::AlertDialog {
+            set_heading: Some("Your peer did not save the contact"),
+            set_body: "Saving it anyway only works if they also kept the key",
+
+            add_response: ("discard", "Discard"),
+            add_response: ("save", "Save anyway"),
+
+            set_close_response: "discard",
+        }
+    }
+
+    let sender = sender.clone();
+    dialog.choose(root, Some(&Cancellable::default()), move |r| {
+        sender.input(Msg::KeepContact(r == "save"));
+    });
 }
@@ -1,14 +1,29 @@
 #![allow(clippy::similar_names)]
 
+use std::sync::Arc;
+use std::time::Duration;
+
+use aporture::crypto::Key;
+use aporture::fs::config::Config;
+use aporture::fs::contacts::Contacts;
 use aporture::pairing::AporturePairingProtocol;
-use aporture::transfer::AportureTransferProtocol;
+use aporture::transfer::{AportureTransferProtocol, TransferOptions};
 use aporture::{Receiver, Sender};
 use relm4::ComponentSender;
+use tokio::sync::{watch, Mutex};
 
 use super::channel;
-use super::{ContactAction, Error, Msg, Params, PassphraseMethod, Peer, State};
+use super::UnconfirmedContact;
+use super::{ContactAction, Error, Msg, Params, PassphraseMethod, Peer, State, Transferred};
+
+/// Time the receiver keeps asking for a sender that has not started yet
+const SENDER_WAIT: Duration = Duration::from_mins(1);
 
-pub async fn send(sender: ComponentSender<Peer>, params: Params) -> Result<ContactAction, Error> {
+pub async fn send(
+    sender: ComponentSender<Peer>,
+    params: Params,
+    (pause, cancel): (watch::Receiver<bool>, watch::Receiver<bool>),
+) -> Result<Transferred, Error> {
     let passphrase = match params.passphrase {
         PassphraseMethod::Direct(p) => p,
         PassphraseMethod::Contact(name, contacts) => contacts
@@ -27,42 +42,52 @@ pub async fn send(sender: ComponentSender<Peer>, params: Params) -> Result<Conta
 
     sender.input(Msg::UpdateState(State::Paired));
 
-    let mut atp = AportureTransferProtocol::<Sender>::new(&mut pair_info, &params.path);
+    if let Some(name) = pair_info.peer_display_name() {
+        sender.input(Msg::PeerName(name.to_owned()));
+    }
+
+    let threshold = Config::get().await.compression_threshold();
+
+    let options = TransferOptions::default()
+        .exclude(&params.exclude)?
+        .compression_threshold(threshold);
+
+    let mut atp =
+        AportureTransferProtocol::<Sender>::with_options(&mut pair_info, &params.path, options);
 
     let (snd, rcv) = tokio::sync::mpsc::channel(64);
 
     atp.add_progress_notifier(snd);
+    atp.add_pause_control(pause);
+    atp.add_cancel_control(cancel);
 
     let handle = channel::handle_progress(rcv, sender.clone());
 
-    atp.transfer().await?;
+    let report = atp.transfer().await?;
 
     let _ = handle.await;
 
-    let save_confirmation = pair_info.save_contact;
+    let (path, size) = (params.path, report.total_size);
+
+    let accepted = pair_info.peer_save_contact();
 
     let key = pair_info.finalize().await;
 
-    if let Some((name, contacts)) = params.save {
-        if save_confirmation {
-            let mut contacts = contacts.lock().await;
-            contacts.add(name, key);
-            contacts.save().await.map_err(|_| Error::ContactSaving)?;
-            drop(contacts);
-
-            Ok(ContactAction::Added)
-        } else {
-            Ok(ContactAction::PeerRefused)
-        }
-    } else {
-        Ok(ContactAction::NoOp)
-    }
+    let (action, unconfirmed) = contact_action(params.save, key, accepted).await?;
+
+    Ok(Transferred {
+        action,
+        unconfirmed,
+        path,
+        size,
+    })
 }
 
 pub async fn receive(
     sender: ComponentSender<Peer>,
     params: Params,
-) -> Result<ContactAction, Error> {
+    (pause, cancel): (watch::Receiver<bool>, watch::Receiver<bool>),
+) -> Result<Transferred, Error> {
     let passphrase = match params.passphrase {
         PassphraseMethod::Direct(p) => p,
         PassphraseMethod::Contact(name, contacts) => contacts
@@ -75,40 +100,83 @@ pub async fn receive(
 
     sender.input(Msg::UpdateState(State::Initial));
 
-    let app = AporturePairingProtocol::<Receiver>::new(passphrase, params.save.is_some());
+    let mut app = AporturePairingProtocol::<Receiver>::new(passphrase, params.save.is_some());
 
-    let mut pair_info = app.pair().await?;
+    let (snd, rcv) = tokio::sync::mpsc::channel(1);
+
+    app.set_wait(SENDER_WAIT);
+    app.add_wait_notifier(snd);
+
+    let waiting = channel::handle_waiting(rcv, sender.clone());
+
+    let paired = app.pair().await;
+
+    // NOTE: The pairing drops the notifier when it finishes, which ends the task
+    if let Err(e) = waiting.await {
+        log::warn!("Could not show that the sender is awaited: {e}");
+    }
+
+    let mut pair_info = paired?;
 
     sender.input(Msg::UpdateState(State::Paired));
 
-    let mut atp = AportureTransferProtocol::<Receiver>::new(&mut pair_info, &params.path);
+    if let Some(name) = pair_info.peer_display_name() {
+        sender.input(Msg::PeerName(name.to_owned()));
+    }
+
+    let options = TransferOptions::default().on_conflict(Config::get().await.on_conflict());
+
+    let mut atp =
+        AportureTransferProtocol::<Receiver>::with_options(&mut pair_info, &params.path, options);
 
     let (snd, rcv) = tokio::sync::mpsc::channel(64);
 
     atp.add_progress_notifier(snd);
+    atp.add_pause_control(pause);
+    atp.add_cancel_control(cancel);
 
     let handle = channel::handle_progress(rcv, sender.clone());
 
-    atp.transfer().await?;
+    let report = atp.transfer().await?;
+    let (path, size) = (report.destination, report.total_size);
 
     let _ = handle.await;
 
-    let save_confirmation = pair_info.save_contact;
+    let accepted = pair_info.peer_save_contact();
 
     let key = pair_info.finalize().await;
 
-    if let Some((name, contacts)) = params.save {
-        if save_confirmation {
-            let mut contacts = contacts.lock().await;
-            contacts.add(name, key);
-            contacts.save().await.map_err(|_| Error::ContactSaving)?;
-            drop(contacts);
-
-            Ok(ContactAction::Added)
-        } else {
-            Ok(ContactAction::PeerRefused)
-        }
+    let (action, unconfirmed) = contact_action(params.save, key, accepted).await?;
+
+    Ok(Transferred {
+        action,
+        unconfirmed,
+        path,
+        size,
+    })
+}
+
+/// Saves the requested contact if the peer accepted, otherwise leaves it for the user to decide.
+async fn contact_action(
+    save: Option<(String, Arc<Mutex<Contacts>>)>,
+    key: Key,
+    accepted: bool,
+) -> Result<(ContactAction, Option<UnconfirmedContact>), Error> {
+    let Some((name, contacts)) = save else {
+        return Ok((ContactAction::NoOp, None));
+    };
+
+    let contact = UnconfirmedContact {
+        name,
+        key,
+        contacts,
+    };
+
+    if accepted {
+        contact.save().await?;
+
+        Ok((ContactAction::Added, None))
     } else {
-        Ok(ContactAction::NoOp)
+        Ok((ContactAction::PeerRefused, Some(contact)))
     }
 }
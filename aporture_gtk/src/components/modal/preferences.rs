@@ -1,23 +1,67 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
 use adw::prelude::*;
-use aporture::fs::config::Config;
+use aporture::fs::config::{Config, Server};
+use aporture::fs::contacts::Contacts;
+use aporture::passphrase::{self, Wordlist};
+use aporture::transfer::OnConflict;
 use relm4::prelude::*;
 use relm4_icons::icon_names;
+use tokio::sync::Mutex;
 
 use crate::components::modal::utils::escape_action;
 use crate::components::toaster::{Severity, Toaster};
 use crate::emit;
 
+const MAX_WORDS: f64 = 12.0;
+
 #[derive(Debug)]
 pub struct Preferences {
     visible: bool,
     form_disabled: bool,
     server_address: adw::EntryRow,
+    fallback_servers: adw::EntryRow,
+    passphrase_words: adw::SpinRow,
+    wordlist: adw::ComboRow,
+    notifications: adw::SwitchRow,
+    on_conflict: adw::ComboRow,
+    download_dir: adw::EntryRow,
+    display_name: adw::EntryRow,
+    entropy: f64,
+    contacts: Option<Arc<Mutex<Contacts>>>,
+    old_password: adw::PasswordEntryRow,
+    new_password: adw::PasswordEntryRow,
+    repeat_password: adw::PasswordEntryRow,
     toaster: Toaster,
 }
 
+#[derive(Debug)]
+pub struct Settings {
+    address: String,
+    fallbacks: String,
+    words: usize,
+    wordlist: Wordlist,
+    notifications: bool,
+    on_conflict: OnConflict,
+    /// Empty when the system download folder is used
+    download_dir: String,
+    /// Empty when no name is shown to peers
+    display_name: String,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Settings(Option<Settings>),
+    PasswordChanged(Result<(), &'static str>),
+}
+
 #[derive(Debug)]
 pub enum Msg {
     EditServerAddress,
+    WordsChanged,
+    ContactsReady(Option<Arc<Mutex<Contacts>>>),
+    ChangePassword,
     Return,
     Open,
     Hide,
@@ -29,7 +73,7 @@ impl Component for Preferences {
     type Init = ();
     type Input = Msg;
     type Output = ();
-    type CommandOutput = Option<String>;
+    type CommandOutput = Command;
 
     view! {
         dialog = adw::Window {
@@ -44,7 +88,7 @@ impl Component for Preferences {
             grab_focus: (),
 
             set_default_width: 400,
-            set_default_height: 450,
+            set_default_height: 650,
 
             adw::ToolbarView {
                 set_top_bar_style: adw::ToolbarStyle::Raised,
@@ -53,41 +97,144 @@ impl Component for Preferences {
 
                 #[local_ref]
                 toaster -> adw::ToastOverlay {
-                    adw::PreferencesGroup {
-                        set_margin_horizontal: 20,
-                        set_margin_vertical: 50,
+                    gtk::Box {
+                        set_orientation: gtk::Orientation::Vertical,
 
-                        set_title: "Preferences",
+                        adw::PreferencesGroup {
+                            set_margin_horizontal: 20,
+                            set_margin_vertical: 50,
 
-                        #[local_ref]
-                        address -> adw::EntryRow {
-                            set_title: "server_address",
+                            set_title: "Preferences",
 
-                            #[watch]
-                            set_sensitive: !model.form_disabled,
+                            #[local_ref]
+                            address -> adw::EntryRow {
+                                set_title: "server_address",
+
+                                #[watch]
+                                set_sensitive: !model.form_disabled,
+
+                                add_css_class: "no-edit-button",
+
+                                set_can_focus: false,
+
+                                #[name = "edit"]
+                                add_suffix = &gtk::Button {
+                                    set_icon_name: icon_names::EDIT,
+
+                                    add_css_class: "flat",
+                                    add_css_class: "circular",
+
+                                    connect_clicked => Msg::EditServerAddress,
+                                },
+                            },
+
+                            #[local_ref]
+                            fallbacks -> adw::EntryRow {
+                                set_title: "Fallback servers (comma separated, in order)",
+
+                                #[watch]
+                                set_sensitive: !model.form_disabled,
+                            },
+
+                            #[local_ref]
+                            words -> adw::SpinRow {
+                                set_title: "Passphrase words",
+                                #[watch]
+                                set_subtitle: &format!("{:.0} bits of entropy", model.entropy),
 
-                            add_css_class: "no-edit-button",
+                                #[watch]
+                                set_sensitive: !model.form_disabled,
 
-                            set_can_focus: false,
+                                connect_value_notify => Msg::WordsChanged,
+                            },
+
+                            #[local_ref]
+                            wordlist -> adw::ComboRow {
+                                set_title: "Passphrase wordlist",
+
+                                #[watch]
+                                set_sensitive: !model.form_disabled,
+
+                                connect_selected_notify => Msg::WordsChanged,
+                            },
+
+                            #[local_ref]
+                            notifications -> adw::SwitchRow {
+                                set_title: "Notifications",
+                                set_subtitle: "Notify when a transfer finishes",
+
+                                #[watch]
+                                set_sensitive: !model.form_disabled,
+                            },
+
+                            #[local_ref]
+                            on_conflict -> adw::ComboRow {
+                                set_title: "When the file exists",
+                                set_subtitle: "What to do with received files that already exist",
+
+                                #[watch]
+                                set_sensitive: !model.form_disabled,
+                            },
+
+                            #[local_ref]
+                            download_dir -> adw::EntryRow {
+                                set_title: "Download folder (empty for the system one)",
 
-                            #[name = "edit"]
-                            add_suffix = &gtk::Button {
-                                set_icon_name: icon_names::EDIT,
+                                #[watch]
+                                set_sensitive: !model.form_disabled,
+                            },
 
-                                add_css_class: "flat",
-                                add_css_class: "circular",
+                            #[local_ref]
+                            display_name -> adw::EntryRow {
+                                set_title: "Name shown to peers (optional)",
 
-                                connect_clicked => Msg::EditServerAddress,
+                                #[watch]
+                                set_sensitive: !model.form_disabled,
                             },
+
+                            gtk::Button {
+                                set_margin_all: 40,
+
+                                add_css_class: "suggested-action",
+
+                                set_label: "Save",
+                                connect_clicked => Msg::Return,
+                            }
                         },
 
-                        gtk::Button {
-                            set_margin_all: 40,
+                        adw::PreferencesGroup {
+                            set_margin_horizontal: 20,
+                            set_margin_bottom: 50,
+
+                            set_title: "Contacts",
+                            set_description: Some("Change the password of the contact database"),
+
+                            #[watch]
+                            set_visible: model.contacts.is_some(),
+
+                            #[local_ref]
+                            old_password -> adw::PasswordEntryRow {
+                                set_title: "Current password",
+                            },
+
+                            #[local_ref]
+                            new_password -> adw::PasswordEntryRow {
+                                set_title: "New password",
+                            },
+
+                            #[local_ref]
+                            repeat_password -> adw::PasswordEntryRow {
+                                set_title: "Repeat new password",
+
+                                connect_entry_activated => Msg::ChangePassword,
+                            },
 
-                            add_css_class: "suggested-action",
+                            gtk::Button {
+                                set_margin_all: 40,
 
-                            set_label: "Save",
-                            connect_clicked => Msg::Return,
+                                set_label: "Change password",
+                                connect_clicked => Msg::ChangePassword,
+                            }
                         }
                     }
                 }
@@ -105,19 +252,56 @@ impl Component for Preferences {
         root: Self::Root,
         sender: ComponentSender<Self>,
     ) -> ComponentParts<Self> {
+        #[allow(clippy::cast_precision_loss)]
+        let min_words = passphrase::MIN_WORD_COUNT as f64;
+
         let model = Self {
             toaster: Toaster::default(),
             visible: false,
             form_disabled: false,
             server_address: adw::EntryRow::new(),
+            fallback_servers: adw::EntryRow::new(),
+            passphrase_words: adw::SpinRow::with_range(min_words, MAX_WORDS, 1.0),
+            wordlist: adw::ComboRow::new(),
+            notifications: adw::SwitchRow::new(),
+            on_conflict: adw::ComboRow::new(),
+            download_dir: adw::EntryRow::new(),
+            display_name: adw::EntryRow::new(),
+            entropy: passphrase::entropy(passphrase::DEFAULT_WORD_COUNT, Wordlist::default()),
+            contacts: None,
+            old_password: adw::PasswordEntryRow::new(),
+            new_password: adw::PasswordEntryRow::new(),
+            repeat_password: adw::PasswordEntryRow::new(),
         };
 
+        let names = Wordlist::ALL.map(|w| w.to_string());
+        let names = names.iter().map(String::as_str).collect::<Vec<_>>();
+        model
+            .wordlist
+            .set_model(Some(&gtk::StringList::new(&names)));
+
+        let policies = OnConflict::ALL.map(|p| p.to_string());
+        let policies = policies.iter().map(String::as_str).collect::<Vec<_>>();
+        model
+            .on_conflict
+            .set_model(Some(&gtk::StringList::new(&policies)));
+
         sender.oneshot_command(async {
             let config = Config::get().await;
-            Some(config.server_domain().to_owned())
+            Command::Settings(Some(settings(&config)))
         });
 
         let address = &model.server_address;
+        let fallbacks = &model.fallback_servers;
+        let words = &model.passphrase_words;
+        let wordlist = &model.wordlist;
+        let notifications = &model.notifications;
+        let on_conflict = &model.on_conflict;
+        let download_dir = &model.download_dir;
+        let display_name = &model.display_name;
+        let old_password = &model.old_password;
+        let new_password = &model.new_password;
+        let repeat_password = &model.repeat_password;
         let toaster = model.toaster.as_ref();
 
         let widgets = view_output!();
@@ -128,11 +312,52 @@ impl Component for Preferences {
     fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>, _: &Self::Root) {
         match msg {
             Msg::Return => {
-                let address = self.server_address.text().to_string();
-                sender.oneshot_command(async move {
-                    let config = Config::update_address(address).await.ok()?;
+                let primary = self.server_address.text().to_string();
+                let fallbacks = self.fallback_servers.text();
+
+                let addresses = std::iter::once(primary)
+                    .chain(
+                        fallbacks
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|s| !s.is_empty())
+                            .map(ToOwned::to_owned),
+                    )
+                    .collect();
+
+                let words = self.words();
+                let wordlist = self.selected_wordlist();
+                let notifications = self.notifications.is_active();
+                let on_conflict = self.selected_on_conflict();
+
+                let download_dir = self.download_dir.text();
+                let download_dir = download_dir.trim();
+                let download_dir = (!download_dir.is_empty()).then(|| PathBuf::from(download_dir));
+
+                if download_dir.as_ref().is_some_and(|d| !d.is_dir()) {
+                    self.download_dir.add_css_class("error");
+                    sender.input(Msg::Error("The download folder does not exist"));
+                    return;
+                }
+
+                self.download_dir.remove_css_class("error");
+
+                let display_name = Some(self.display_name.text().to_string());
 
-                    Some(config.server_domain().to_owned())
+                sender.oneshot_command(async move {
+                    let save = async {
+                        drop(Config::set_servers(addresses).await.ok()?);
+                        drop(Config::update_passphrase_words(words).await.ok()?);
+                        drop(Config::update_wordlist(wordlist).await.ok()?);
+                        drop(Config::update_notifications(notifications).await.ok()?);
+                        drop(Config::update_download_dir(download_dir).await.ok()?);
+                        drop(Config::update_display_name(display_name).await.ok()?);
+                        let config = Config::update_on_conflict(on_conflict).await.ok()?;
+
+                        Some(settings(&config))
+                    };
+
+                    Command::Settings(save.await)
                 });
             }
 
@@ -140,6 +365,47 @@ impl Component for Preferences {
                 self.server_address.grab_focus_without_selecting();
             }
 
+            Msg::WordsChanged => {
+                self.entropy = passphrase::entropy(self.words(), self.selected_wordlist());
+            }
+
+            Msg::ContactsReady(contacts) => self.contacts = contacts,
+
+            Msg::ChangePassword => {
+                let Some(contacts) = self.contacts.clone() else {
+                    return;
+                };
+
+                let old = self.old_password.text();
+                let new = self.new_password.text();
+
+                if new.is_empty() || new != self.repeat_password.text() {
+                    self.new_password.add_css_class("error");
+                    self.repeat_password.add_css_class("error");
+                    sender.input(Msg::Error("The new passwords do not match"));
+                    return;
+                }
+
+                self.new_password.remove_css_class("error");
+                self.repeat_password.remove_css_class("error");
+
+                sender.oneshot_command(async move {
+                    let changed = contacts
+                        .lock()
+                        .await
+                        .change_password(old.as_bytes(), new.as_bytes())
+                        .await;
+
+                    let result = match changed {
+                        Ok(()) => Ok(()),
+                        Err(aporture::io::Error::Cipher(_)) => Err("Wrong current password"),
+                        Err(_) => Err("Could not change password"),
+                    };
+
+                    Command::PasswordChanged(result)
+                });
+            }
+
             Msg::Open => {
                 self.visible = true;
             }
@@ -158,9 +424,47 @@ impl Component for Preferences {
         sender: ComponentSender<Self>,
         _: &Self::Root,
     ) {
-        if let Some(address) = message {
+        let message = match message {
+            Command::Settings(settings) => settings,
+            Command::PasswordChanged(Ok(())) => {
+                self.old_password.set_text("");
+                self.new_password.set_text("");
+                self.repeat_password.set_text("");
+                self.old_password.remove_css_class("error");
+                self.toaster
+                    .add_toast("Contacts password changed", Severity::Success);
+                return;
+            }
+            Command::PasswordChanged(Err(e)) => {
+                self.old_password.add_css_class("error");
+                sender.input(Msg::Error(e));
+                return;
+            }
+        };
+
+        if let Some(settings) = message {
             if self.server_address.text_length() == 0 {
-                self.server_address.set_text(&address);
+                self.server_address.set_text(&settings.address);
+                self.fallback_servers.set_text(&settings.fallbacks);
+                #[allow(clippy::cast_precision_loss)]
+                self.passphrase_words.set_value(settings.words as f64);
+                self.wordlist.set_selected(
+                    Wordlist::ALL
+                        .iter()
+                        .position(|w| *w == settings.wordlist)
+                        .and_then(|i| u32::try_from(i).ok())
+                        .unwrap_or_default(),
+                );
+                self.notifications.set_active(settings.notifications);
+                self.on_conflict.set_selected(
+                    OnConflict::ALL
+                        .iter()
+                        .position(|p| *p == settings.on_conflict)
+                        .and_then(|i| u32::try_from(i).ok())
+                        .unwrap_or_default(),
+                );
+                self.download_dir.set_text(&settings.download_dir);
+                self.display_name.set_text(&settings.display_name);
             } else {
                 emit!(() => sender);
                 self.visible = false;
@@ -171,3 +475,46 @@ impl Component for Preferences {
         }
     }
 }
+
+impl Preferences {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn words(&self) -> usize {
+        self.passphrase_words.value() as usize
+    }
+
+    fn selected_wordlist(&self) -> Wordlist {
+        usize::try_from(self.wordlist.selected())
+            .ok()
+            .and_then(|i| Wordlist::ALL.get(i).copied())
+            .unwrap_or_default()
+    }
+
+    fn selected_on_conflict(&self) -> OnConflict {
+        usize::try_from(self.on_conflict.selected())
+            .ok()
+            .and_then(|i| OnConflict::ALL.get(i).copied())
+            .unwrap_or_default()
+    }
+}
+
+fn settings(config: &Config) -> Settings {
+    let fallbacks = config.servers()[1..]
+        .iter()
+        .map(Server::domain)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Settings {
+        address: config.server_domain().to_owned(),
+        fallbacks,
+        words: config.passphrase_words(),
+        wordlist: config.wordlist(),
+        notifications: config.notifications(),
+        on_conflict: config.on_conflict(),
+        download_dir: config
+            .custom_download_dir()
+            .map(|d| d.display().to_string())
+            .unwrap_or_default(),
+        display_name: config.display_name().unwrap_or_default().to_owned(),
+    }
+}
@@ -0,0 +1,41 @@
+use adw::prelude::*;
+use gtk::gio::Cancellable;
+use relm4::prelude::*;
+
+/// Asks for a password, calling `on_response` with the chosen response and the password
+pub fn prompt(
+    parent: &impl IsA<gtk::Widget>,
+    heading: &str,
+    responses: &[(&'static str, &'static str)],
+    on_response: impl FnOnce(&str, String) + 'static,
+) {
+    relm4::view! {
+        dialog = adw::AlertDialog {
+            set_heading: Some(heading),
+
+            #[wrap(Some)]
+            set_extra_child = &gtk::ListBox {
+                add_css_class: "boxed-list",
+
+                #[name = "entry"]
+                adw::PasswordEntryRow {
+                    set_title: "Password",
+                },
+            },
+
+            add_response: ("cancel", "Cancel"),
+            set_close_response: "cancel",
+        }
+    }
+
+    for (id, label) in responses {
+        dialog.add_response(id, label);
+        dialog.set_response_appearance(id, adw::ResponseAppearance::Suggested);
+    }
+
+    dialog.choose(parent, Some(&Cancellable::default()), move |r| {
+        if r != "cancel" {
+            on_response(r.as_str(), entry.text().to_string());
+        }
+    });
+}
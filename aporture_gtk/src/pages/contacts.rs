@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::hash::RandomState;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -7,25 +8,36 @@ use open_dialog::{OpenDialog, OpenDialogMsg, OpenDialogResponse, OpenDialogSetti
 use relm4::factory::FactoryHashMap;
 use relm4::prelude::*;
 use relm4_components::open_dialog;
+use relm4_components::save_dialog::{
+    SaveDialog, SaveDialogMsg, SaveDialogResponse, SaveDialogSettings,
+};
 use tokio::sync::Mutex;
 
 use crate::components::confirmation::Confirmation;
-use crate::components::file_chooser;
 use crate::components::modal::aporture::{ContactAction, Params, PassphraseMethod, Peer};
 use crate::components::modal::aporture::{Error as AportureError, TransferType};
 use crate::components::toaster::Severity;
+use crate::components::{file_chooser, password};
 use crate::{app, emit};
 
-use aporture::fs::contacts::Contacts;
+use aporture::fs::contacts::{Conflict, Contacts};
+
+type ContactRows = FactoryHashMap<String, contact_row::Contact, RandomState>;
 
 #[derive(Debug)]
 pub struct ContactPage {
-    contacts_ui: FactoryHashMap<String, contact_row::Contact, RandomState>,
+    groups: gtk::Box,
+    /// Contacts without tags
+    contacts_ui: ContactRows,
+    /// A group per tag, contacts are listed under each of their tags
+    tagged_ui: BTreeMap<String, ContactRows>,
     contacts: Option<Arc<Mutex<Contacts>>>,
     current_contact: String,
     sender_picker_dialog: Controller<OpenDialog>,
     sender_dir_picker_dialog: Controller<OpenDialog>,
     receiver_picker_dialog: Controller<OpenDialog>,
+    export_dialog: Controller<SaveDialog>,
+    import_dialog: Controller<OpenDialog>,
     peer: Option<Controller<Peer>>,
 }
 
@@ -35,6 +47,16 @@ impl ContactPage {
             .clone()
             .expect("Contacts must be present for ContactPage to be shown")
     }
+
+    /// Sends the message to the rows of the contact, one per group it is listed under.
+    fn send_rows(&self, name: &str, msg: &contact_row::Msg) {
+        let name = name.to_owned();
+
+        std::iter::once(&self.contacts_ui)
+            .chain(self.tagged_ui.values())
+            .filter(|rows| rows.get(&name).is_some())
+            .for_each(|rows| rows.send(&name, msg.clone()));
+    }
 }
 
 #[derive(Debug)]
@@ -48,16 +70,29 @@ pub enum Msg {
     ReceiverPickerResponse(PathBuf),
     DeleteContact(String),
     DeleteContactUI(String),
+    RenameContact(String, String),
+    TagContact(String, Vec<String>),
+    ExportOpen,
+    Export(PathBuf),
+    ImportOpen,
+    Import(PathBuf),
     AportureFinished(Result<ContactAction, AportureError>),
     Ignore,
 }
 
+#[derive(Debug)]
+pub enum FileResult {
+    Exported,
+    Imported(Vec<String>),
+    Failed(&'static str),
+}
+
 #[relm4::component(pub)]
 impl Component for ContactPage {
     type Init = ();
     type Input = Msg;
     type Output = app::Request;
-    type CommandOutput = ();
+    type CommandOutput = FileResult;
 
     view! {
         gtk::ScrolledWindow {
@@ -66,12 +101,38 @@ impl Component for ContactPage {
             set_vexpand: true,
 
             #[local_ref]
-            contacts_box -> adw::PreferencesGroup {
+            groups -> gtk::Box {
+                set_orientation: gtk::Orientation::Vertical,
+                set_spacing: 24,
                 set_margin_horizontal: 20,
                 set_margin_vertical: 50,
 
-                set_title: "Contacts",
-                set_description: Some("Choose a registered contact to send or receive files"),
+                #[local_ref]
+                contacts_box -> adw::PreferencesGroup {
+                    set_title: "Contacts",
+                    set_description: Some("Choose a registered contact to send or receive files"),
+
+                    #[wrap(Some)]
+                    set_header_suffix = &gtk::Box {
+                        set_spacing: 6,
+
+                        gtk::Button {
+                            add_css_class: "flat",
+
+                            set_label: "Import",
+                            set_tooltip_text: Some("Import contacts from an exported file"),
+                            connect_clicked => Msg::ImportOpen,
+                        },
+
+                        gtk::Button {
+                            add_css_class: "flat",
+
+                            set_label: "Export",
+                            set_tooltip_text: Some("Export contacts to move them to another device"),
+                            connect_clicked => Msg::ExportOpen,
+                        },
+                    },
+                },
             }
         }
     }
@@ -111,20 +172,41 @@ impl Component for ContactPage {
                 OpenDialogResponse::Cancel => Msg::Ignore,
             });
 
+        let export_dialog = SaveDialog::builder()
+            .transient_for_native(&root)
+            .launch(SaveDialogSettings::default())
+            .forward(sender.input_sender(), |response| match response {
+                SaveDialogResponse::Accept(path) => Msg::Export(path),
+                SaveDialogResponse::Cancel => Msg::Ignore,
+            });
+
+        let import_dialog = OpenDialog::builder()
+            .transient_for_native(&root)
+            .launch(OpenDialogSettings::default())
+            .forward(sender.input_sender(), |response| match response {
+                OpenDialogResponse::Accept(path) => Msg::Import(path),
+                OpenDialogResponse::Cancel => Msg::Ignore,
+            });
+
         let contacts_ui = FactoryHashMap::builder()
             .launch(adw::PreferencesGroup::default())
             .forward(sender.input_sender(), Msg::from);
 
         let model = Self {
             current_contact: String::default(),
+            groups: gtk::Box::default(),
             contacts_ui,
+            tagged_ui: BTreeMap::new(),
             contacts: None,
             sender_picker_dialog,
             sender_dir_picker_dialog,
             receiver_picker_dialog,
+            export_dialog,
+            import_dialog,
             peer: None,
         };
 
+        let groups = &model.groups;
         let contacts_box = model.contacts_ui.widget();
 
         let widgets = view_output!();
@@ -140,6 +222,10 @@ impl Component for ContactPage {
 
                     self.contacts_ui.clear();
 
+                    for rows in std::mem::take(&mut self.tagged_ui).into_values() {
+                        self.groups.remove(rows.widget());
+                    }
+
                     let destination = aporture::fs::downloads_directory();
 
                     if destination.is_none() {
@@ -151,14 +237,36 @@ impl Component for ContactPage {
                         emit!(toast => sender);
                     }
 
-                    contacts.blocking_lock().list().for_each(|(name, date)| {
-                        let data = contact_row::Input {
-                            date: date.format("%d/%m/%Y %H:%M").to_string(),
+                    let contacts = contacts.blocking_lock();
+
+                    for tag in contacts.tags() {
+                        let group = adw::PreferencesGroup::builder().title(tag).build();
+                        self.groups.append(&group);
+
+                        let rows = FactoryHashMap::builder()
+                            .launch(group)
+                            .forward(sender.input_sender(), Msg::from);
+
+                        self.tagged_ui.insert(tag.to_owned(), rows);
+                    }
+
+                    for contact in contacts.list_detailed() {
+                        let data = || contact_row::Input {
+                            date: contact.added.format("%d/%m/%Y %H:%M").to_string(),
+                            fingerprint: contact.fingerprint.clone(),
+                            tags: contact.tags.join(", "),
                             destination: destination.clone(),
                         };
 
-                        self.contacts_ui.insert(name.clone(), data);
-                    });
+                        if contact.tags.is_empty() {
+                            self.contacts_ui.insert(contact.name.to_owned(), data());
+                        }
+
+                        for tag in &contact.tags {
+                            let rows = self.tagged_ui.get_mut(*tag).expect("Group of every tag");
+                            rows.insert(contact.name.to_owned(), data());
+                        }
+                    }
                 }
             }
 
@@ -201,9 +309,9 @@ impl Component for ContactPage {
             Msg::SenderPickerResponse(path) => {
                 use contact_row::Msg as ContactMsg;
 
-                self.contacts_ui.send(
+                self.send_rows(
                     &self.current_contact,
-                    ContactMsg::SendFilePickerClosed(path),
+                    &ContactMsg::SendFilePickerClosed(path),
                 );
             }
 
@@ -216,9 +324,9 @@ impl Component for ContactPage {
             Msg::ReceiverPickerResponse(path) => {
                 use contact_row::Msg as ContactMsg;
 
-                self.contacts_ui.send(
+                self.send_rows(
                     &self.current_contact,
-                    ContactMsg::ReceiveFilePickerClosed(path),
+                    &ContactMsg::ReceiveFilePickerClosed(path),
                 );
             }
 
@@ -243,6 +351,122 @@ impl Component for ContactPage {
 
             Msg::DeleteContactUI(contact) => {
                 self.contacts_ui.remove(&contact);
+
+                for rows in self.tagged_ui.values_mut() {
+                    rows.remove(&contact);
+                }
+
+                // NOTE: A tag is only shown while some contact has it
+                let groups = &self.groups;
+                self.tagged_ui.retain(|_, rows| {
+                    if rows.is_empty() {
+                        groups.remove(rows.widget());
+                    }
+
+                    !rows.is_empty()
+                });
+            }
+
+            Msg::RenameContact(old, new) => {
+                let message = format!("rename contact \"{old}\" to \"{new}\"");
+                let contacts = self.contacts();
+
+                Confirmation::new(&message)
+                    .confirm("Rename")
+                    .deny("Cancel")
+                    .choose(root, move || {
+                        let mut guard = contacts.blocking_lock();
+
+                        if !guard.rename(&old, &new) {
+                            emit!(app::Request::ToastS("A contact with that name already exists", Severity::Warn) => sender);
+                            return;
+                        }
+
+                        let saved = guard.save_blocking();
+                        drop(guard);
+
+                        match saved {
+                            Ok(()) => sender.input(Msg::ContactsReady(Some(contacts))),
+                            Err(_) => emit!(app::Request::ToastS("Could not rename contact", Severity::Warn) => sender),
+                        }
+                    });
+            }
+
+            Msg::TagContact(name, tags) => {
+                let contacts = self.contacts();
+
+                let mut guard = contacts.blocking_lock();
+                guard.set_tags(&name, &tags);
+                let saved = guard.save_blocking();
+                drop(guard);
+
+                match saved {
+                    Ok(()) => sender.input(Msg::ContactsReady(Some(contacts))),
+                    Err(_) => {
+                        emit!(app::Request::ToastS("Could not save the tags", Severity::Warn) => sender)
+                    }
+                }
+            }
+
+            Msg::ExportOpen => self
+                .export_dialog
+                .emit(SaveDialogMsg::SaveAs("contacts.export".to_owned())),
+
+            Msg::Export(path) => {
+                let contacts = self.contacts();
+
+                password::prompt(
+                    root,
+                    "Enter password to encrypt the export",
+                    &[("export", "Export")],
+                    move |_, password| {
+                        sender.oneshot_command(async move {
+                            let contacts = contacts.lock().await;
+
+                            match contacts.export(&path, password.as_bytes()).await {
+                                Ok(()) => FileResult::Exported,
+                                Err(_) => FileResult::Failed("Could not export contacts"),
+                            }
+                        });
+                    },
+                );
+            }
+
+            Msg::ImportOpen => self.import_dialog.emit(OpenDialogMsg::Open),
+
+            Msg::Import(path) => {
+                let contacts = self.contacts();
+
+                password::prompt(
+                    root,
+                    "Enter password of the export",
+                    &[("keep", "Keep existing"), ("replace", "Replace existing")],
+                    move |response, password| {
+                        let conflict = if response == "replace" {
+                            Conflict::Overwrite
+                        } else {
+                            Conflict::Keep
+                        };
+
+                        sender.oneshot_command(async move {
+                            let mut contacts = contacts.lock().await;
+
+                            let result =
+                                contacts.import(&path, password.as_bytes(), conflict).await;
+
+                            match result {
+                                Ok(conflicts) => match contacts.save().await {
+                                    Ok(()) => FileResult::Imported(conflicts),
+                                    Err(_) => FileResult::Failed("Could not save contacts"),
+                                },
+                                Err(aporture::io::Error::Cipher(_)) => {
+                                    FileResult::Failed("Wrong password or corrupted export")
+                                }
+                                Err(_) => FileResult::Failed("Could not import contacts"),
+                            }
+                        });
+                    },
+                );
             }
 
             Msg::AportureFinished(result) => {
@@ -260,7 +484,7 @@ impl Component for ContactPage {
                         emit!(app::Request::ToastS("Peer refused to save contact", Severity::Warn) => sender);
                     }
                     Ok(ContactAction::NoOp) => {}
-                    Err(e @ AportureError::Cancel) => {
+                    Err(e @ (AportureError::Cancel | AportureError::PeerCancelled)) => {
                         emit!(app::Request::Toast(e.to_string(), Severity::Warn) => sender)
                     }
                     Err(e) => emit!(app::Request::Toast(e.to_string(), Severity::Error) => sender),
@@ -270,6 +494,35 @@ impl Component for ContactPage {
             Msg::Ignore => (),
         }
     }
+
+    fn update_cmd(
+        &mut self,
+        message: Self::CommandOutput,
+        sender: ComponentSender<Self>,
+        _: &Self::Root,
+    ) {
+        match message {
+            FileResult::Exported => {
+                emit!(app::Request::ToastS("Contacts exported", Severity::Success) => sender);
+            }
+            FileResult::Imported(conflicts) => {
+                if conflicts.is_empty() {
+                    emit!(app::Request::ToastS("Contacts imported", Severity::Success) => sender);
+                } else {
+                    let message = format!(
+                        "Contacts imported, {} had a name already in use",
+                        conflicts.join(", ")
+                    );
+                    emit!(app::Request::Toast(message, Severity::Warn) => sender);
+                }
+
+                sender.input(Msg::ContactsReady(self.contacts.clone()));
+            }
+            FileResult::Failed(message) => {
+                emit!(app::Request::ToastS(message, Severity::Error) => sender);
+            }
+        }
+    }
 }
 
 mod contact_row {
@@ -285,12 +538,14 @@ mod contact_row {
     pub struct Contact {
         name: String,
         date: String,
+        fingerprint: String,
+        tags: String,
         path: Option<PathBuf>,
         destination: Option<PathBuf>,
         expanded: bool,
     }
 
-    #[derive(Debug)]
+    #[derive(Debug, Clone)]
     pub enum Msg {
         SendFilePickerOpen,
         SendFilePickerClosed(PathBuf),
@@ -299,12 +554,17 @@ mod contact_row {
         ReceiveFilePickerClosed(PathBuf),
         ReceiveFile,
         Delete,
+        Rename(String),
+        Tag(String),
         Expand,
     }
 
     #[derive(Debug)]
     pub struct Input {
         pub date: String,
+        pub fingerprint: String,
+        /// Separated by commas
+        pub tags: String,
         pub destination: Option<PathBuf>,
     }
 
@@ -315,6 +575,8 @@ mod contact_row {
         ReceiveFilePicker(String),
         Receive(String, PathBuf),
         Delete(String),
+        Rename(String, String),
+        Tag(String, Vec<String>),
     }
 
     #[relm4::factory(pub)]
@@ -349,6 +611,35 @@ mod contact_row {
                     connect_clicked => Msg::Delete,
                 },
 
+                add_row = &adw::EntryRow {
+                    set_title: "Name",
+                    set_text: &self.name,
+                    set_show_apply_button: true,
+
+                    connect_apply[sender] => move |entry| {
+                        sender.input(Msg::Rename(entry.text().trim().to_owned()));
+                    },
+                },
+
+                add_row = &adw::EntryRow {
+                    set_title: "Tags, separated by commas",
+                    set_text: &self.tags,
+                    set_show_apply_button: true,
+
+                    connect_apply[sender] => move |entry| {
+                        sender.input(Msg::Tag(entry.text().to_string()));
+                    },
+                },
+
+                add_row = &adw::ActionRow {
+                    set_title: "Key fingerprint",
+                    set_subtitle: &self.fingerprint,
+                    set_subtitle_selectable: true,
+                    set_tooltip_text: Some("Compare it with the one your contact sees to confirm you paired with them"),
+
+                    add_css_class: "property",
+                },
+
                 add_row = &adw::ActionRow {
                     set_title: "Send",
                     #[watch]
@@ -412,6 +703,8 @@ mod contact_row {
                 expanded: false,
                 name: index.clone(),
                 date: value.date,
+                fingerprint: value.fingerprint,
+                tags: value.tags,
                 destination: value.destination,
                 path: None,
             }
@@ -450,6 +743,18 @@ mod contact_row {
                 Msg::Expand => self.expanded = !self.expanded,
 
                 Msg::Delete => emit!(Output::Delete(self.name.clone()) => sender),
+
+                Msg::Rename(name) => {
+                    if !name.is_empty() && name != self.name {
+                        emit!(Output::Rename(self.name.clone(), name) => sender);
+                    }
+                }
+
+                Msg::Tag(text) => {
+                    let tags = text.split(',').map(|t| t.trim().to_owned()).collect();
+
+                    emit!(Output::Tag(self.name.clone(), tags) => sender);
+                }
             }
         }
     }
@@ -462,6 +767,8 @@ mod contact_row {
                 Output::SendFilePicker(name) => Self::SenderPickerOpen(name),
                 Output::ReceiveFilePicker(name) => Self::ReceiverPickerOpen(name),
                 Output::Delete(name) => Self::DeleteContact(name),
+                Output::Rename(old, new) => Self::RenameContact(old, new),
+                Output::Tag(name, tags) => Self::TagContact(name, tags),
             }
         }
     }
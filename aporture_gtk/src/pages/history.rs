@@ -0,0 +1,135 @@
+use adw::prelude::*;
+use relm4::prelude::*;
+use relm4_icons::icon_names;
+
+use aporture::fs::history::{Direction, Entry, History};
+
+use crate::components::toaster::Severity;
+use crate::{app, emit};
+
+#[derive(Debug)]
+pub struct HistoryPage {
+    list: adw::PreferencesGroup,
+    rows: Vec<adw::ActionRow>,
+}
+
+#[derive(Debug)]
+pub enum Msg {
+    Refresh,
+}
+
+#[relm4::component(pub)]
+impl Component for HistoryPage {
+    type Init = ();
+    type Input = Msg;
+    type Output = app::Request;
+    type CommandOutput = Option<Vec<Entry>>;
+
+    view! {
+        gtk::ScrolledWindow {
+            set_hscrollbar_policy: gtk::PolicyType::Never,
+            set_min_content_height: 500,
+            set_vexpand: true,
+
+            #[local_ref]
+            list -> adw::PreferencesGroup {
+                set_margin_horizontal: 20,
+                set_margin_vertical: 50,
+
+                set_title: "History",
+                #[watch]
+                set_description: Some(if model.rows.is_empty() {
+                    "No transfers yet"
+                } else {
+                    "Recent transfers, from newest to oldest"
+                }),
+            }
+        }
+    }
+
+    fn init(
+        _init: Self::Init,
+        root: Self::Root,
+        sender: ComponentSender<Self>,
+    ) -> ComponentParts<Self> {
+        let model = Self {
+            list: adw::PreferencesGroup::default(),
+            rows: Vec::new(),
+        };
+
+        let list = &model.list;
+
+        let widgets = view_output!();
+
+        sender.input(Msg::Refresh);
+
+        ComponentParts { model, widgets }
+    }
+
+    fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>, _: &Self::Root) {
+        match msg {
+            Msg::Refresh => sender.oneshot_command(async {
+                let history = History::load().await.ok()?;
+
+                Some(history.entries().cloned().collect())
+            }),
+        }
+    }
+
+    fn update_cmd(
+        &mut self,
+        message: Self::CommandOutput,
+        sender: ComponentSender<Self>,
+        _: &Self::Root,
+    ) {
+        let Some(entries) = message else {
+            emit!(app::Request::ToastS("Could not read transfer history", Severity::Error) => sender);
+            return;
+        };
+
+        for row in self.rows.drain(..) {
+            self.list.remove(&row);
+        }
+
+        for entry in &entries {
+            let row = row(entry);
+            self.list.add(&row);
+            self.rows.push(row);
+        }
+    }
+}
+
+fn row(entry: &Entry) -> adw::ActionRow {
+    let (icon, direction) = match entry.direction {
+        Direction::Sent => (icon_names::SEND, "Sent to"),
+        Direction::Received => (icon_names::INBOX, "Received from"),
+    };
+
+    let title = if entry.file_name.is_empty() {
+        "Unknown file"
+    } else {
+        &entry.file_name
+    };
+
+    let subtitle = format!(
+        "{direction} {} · {} · {}",
+        entry.peer,
+        gtk::glib::format_size(entry.size),
+        entry.timestamp.format("%d/%m/%Y %H:%M"),
+    );
+
+    let row = adw::ActionRow::builder()
+        .title(gtk::glib::markup_escape_text(title))
+        .subtitle(gtk::glib::markup_escape_text(&subtitle))
+        .build();
+
+    row.add_prefix(&gtk::Image::from_icon_name(icon));
+
+    if !entry.success {
+        let failed = gtk::Label::new(Some("Failed"));
+        failed.add_css_class("error");
+        row.add_suffix(&failed);
+    }
+
+    row
+}
@@ -1,7 +1,9 @@
 pub mod contacts;
+pub mod history;
 pub mod receive;
 pub mod send;
 
 pub use contacts::ContactPage;
+pub use history::HistoryPage;
 pub use receive::ReceiverPage;
 pub use send::SenderPage;
@@ -8,6 +8,7 @@ use relm4_components::open_dialog;
 use relm4_icons::icon_names;
 use tokio::sync::Mutex;
 
+use aporture::fs::config::Config;
 use aporture::fs::contacts::Contacts;
 
 use crate::components::modal::aporture::{ContactAction, Params, PassphraseMethod, Peer};
@@ -19,6 +20,7 @@ use crate::{app, emit};
 pub struct ReceiverPage {
     passphrase_entry: adw::EntryRow,
     file_entry: adw::ActionRow,
+    save_as_entry: adw::EntryRow,
     save_contact: adw::SwitchRow,
     contact_entry: adw::EntryRow,
     passphrase_length: u32,
@@ -46,7 +48,8 @@ impl Component for ReceiverPage {
     type Init = ();
     type Input = Msg;
     type Output = app::Request;
-    type CommandOutput = ();
+    /// Download folder set in the preferences
+    type CommandOutput = Option<PathBuf>;
 
     view! {
         adw::PreferencesGroup {
@@ -95,6 +98,14 @@ impl Component for ReceiverPage {
                 },
             },
 
+            #[local_ref]
+            save_as_entry -> adw::EntryRow {
+                set_title: "Save as (optional)",
+                set_tooltip_text: Some("Name to save the received file or folder with"),
+                #[watch]
+                set_sensitive: !model.form_disabled,
+            },
+
             #[local_ref]
             save_contact -> adw::SwitchRow {
                 set_title: "Save contact",
@@ -136,6 +147,7 @@ impl Component for ReceiverPage {
         let model = Self {
             passphrase_entry: adw::EntryRow::default(),
             file_entry: adw::ActionRow::default(),
+            save_as_entry: adw::EntryRow::default(),
             save_contact: adw::SwitchRow::default(),
             contact_entry: adw::EntryRow::default(),
             passphrase_length: 0,
@@ -146,8 +158,11 @@ impl Component for ReceiverPage {
             peer: None,
         };
 
+        sender.oneshot_command(async { Config::get().await.download_dir() });
+
         let passphrase_entry = &model.passphrase_entry;
         let file_path_entry = &model.file_entry;
+        let save_as_entry = &model.save_as_entry;
         let save_contact = &model.save_contact;
         let contact_entry = &model.contact_entry;
 
@@ -175,11 +190,16 @@ impl Component for ReceiverPage {
 
                     (contact, contacts)
                 });
-                let path = self
+                let mut path = self
                     .destination
                     .clone()
                     .expect("Should have destination to be able to call send");
 
+                let save_as = self.save_as_entry.text();
+                if !save_as.trim().is_empty() {
+                    path.push(save_as.trim());
+                }
+
                 log::info!("Starting receiver worker");
 
                 let controller = Peer::builder()
@@ -205,7 +225,7 @@ impl Component for ReceiverPage {
                         emit!(app::Request::ToastS("Peer refused to save contact", Severity::Warn) => sender);
                     }
                     Ok(ContactAction::NoOp) => {}
-                    Err(e @ AportureError::Cancel) => {
+                    Err(e @ (AportureError::Cancel | AportureError::PeerCancelled)) => {
                         emit!(app::Request::Toast(e.to_string(), Severity::Warn) => sender)
                     }
                     Err(e) => emit!(app::Request::Toast(e.to_string(), Severity::Error) => sender),
@@ -243,4 +263,10 @@ impl Component for ReceiverPage {
             Msg::Ignore => (),
         }
     }
+
+    fn update_cmd(&mut self, dir: Self::CommandOutput, _: ComponentSender<Self>, _: &Self::Root) {
+        if dir.is_some() {
+            self.destination = dir;
+        }
+    }
 }
@@ -3,15 +3,18 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 use adw::prelude::*;
-use gtk::gdk::Display;
+use gtk::gdk::{Display, MemoryFormat, MemoryTexture};
+use gtk::glib::Bytes;
 use open_dialog::{OpenDialog, OpenDialogResponse, OpenDialogSettings};
+use qrcode::{Color, QrCode};
 use relm4::prelude::*;
 use relm4_components::open_dialog;
 use relm4_icons::icon_names;
 use tokio::sync::Mutex;
 
+use aporture::fs::config::Config;
 use aporture::fs::contacts::Contacts;
-use aporture::passphrase;
+use aporture::passphrase::{self, Wordlist};
 
 use crate::components::file_chooser;
 use crate::components::modal::aporture::{ContactAction, Params, PassphraseMethod, Peer};
@@ -19,15 +22,17 @@ use crate::components::modal::aporture::{Error as AportureError, TransferType};
 use crate::components::toaster::Severity;
 use crate::{app, emit};
 
-const PASSPHRASE_WORD_COUNT: usize = 3;
-
 #[derive(Debug)]
 pub struct SenderPage {
     passphrase_entry: adw::EntryRow,
     file_entry: adw::ActionRow,
     save_contact: adw::SwitchRow,
     contact_entry: adw::EntryRow,
+    exclude_entry: adw::EntryRow,
+    qr_code: gtk::Picture,
     passphrase_length: u32,
+    passphrase_words: usize,
+    wordlist: Wordlist,
     file_path: Option<PathBuf>,
     file_picker_dialog: Controller<OpenDialog>,
     directory_picker_dialog: Controller<OpenDialog>,
@@ -56,7 +61,7 @@ impl Component for SenderPage {
     type Init = ();
     type Input = Msg;
     type Output = app::Request;
-    type CommandOutput = ();
+    type CommandOutput = (usize, Wordlist);
 
     view! {
         adw::PreferencesGroup {
@@ -64,7 +69,11 @@ impl Component for SenderPage {
             set_margin_vertical: 50,
 
             set_title: "Send",
-            set_description: Some("Enter a passphrase or generate a random one"),
+            #[watch]
+            set_description: Some(&format!(
+                "Enter a passphrase or generate a random one ({:.0} bits of entropy)",
+                passphrase::entropy(model.passphrase_words, model.wordlist),
+            )),
             #[wrap(Some)]
             set_header_suffix = &gtk::Button {
                 add_css_class: "suggested-action",
@@ -78,7 +87,6 @@ impl Component for SenderPage {
             #[local_ref]
             passphrase_entry -> adw::EntryRow {
                 set_title: "Passphrase",
-                set_text: &passphrase::generate(PASSPHRASE_WORD_COUNT),
                 #[watch]
                 set_sensitive: !model.form_disabled,
 
@@ -124,6 +132,27 @@ impl Component for SenderPage {
 
                     connect_clicked => Msg::CopyPassword,
                 },
+
+                #[name = "qr"]
+                add_suffix = &gtk::MenuButton {
+                    set_icon_name: icon_names::QR_CODE,
+
+                    set_tooltip_text: Some("Show QR code"),
+
+                    add_css_class: "flat",
+                    add_css_class: "circular",
+
+                    set_valign: gtk::Align::Center,
+
+                    #[wrap(Some)]
+                    set_popover = &gtk::Popover {
+                        #[local_ref]
+                        qr_code -> gtk::Picture {
+                            set_can_shrink: false,
+                            set_alternative_text: Some("Passphrase QR code"),
+                        },
+                    },
+                },
             },
 
             #[local_ref]
@@ -144,6 +173,14 @@ impl Component for SenderPage {
                 },
             },
 
+            #[local_ref]
+            exclude_entry -> adw::EntryRow {
+                set_title: "Exclude (comma separated, e.g. target/, .git/)",
+
+                #[watch]
+                set_sensitive: !model.form_disabled,
+            },
+
             #[local_ref]
             save_contact -> adw::SwitchRow {
                 set_title: "Save contact",
@@ -195,7 +232,11 @@ impl Component for SenderPage {
             file_entry: adw::ActionRow::default(),
             save_contact: adw::SwitchRow::default(),
             contact_entry: adw::EntryRow::default(),
+            exclude_entry: adw::EntryRow::default(),
+            qr_code: gtk::Picture::default(),
             passphrase_length: 1,
+            passphrase_words: passphrase::DEFAULT_WORD_COUNT,
+            wordlist: Wordlist::default(),
             file_path: None,
             file_picker_dialog,
             directory_picker_dialog,
@@ -208,6 +249,10 @@ impl Component for SenderPage {
         let file_path_entry = &model.file_entry;
         let save_contact = &model.save_contact;
         let contact_entry = &model.contact_entry;
+        let exclude_entry = &model.exclude_entry;
+        let qr_code = &model.qr_code;
+
+        sender.input(Msg::GeneratePassphrase);
 
         let widgets = view_output!();
 
@@ -216,11 +261,15 @@ impl Component for SenderPage {
 
     fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>, root: &Self::Root) {
         match msg {
-            Msg::GeneratePassphrase => self
-                .passphrase_entry
-                .set_text(&passphrase::generate(PASSPHRASE_WORD_COUNT)),
+            // NOTE: Read on every generation so changes in preferences apply
+            Msg::GeneratePassphrase => {
+                sender.oneshot_command(async {
+                    let config = Config::get().await;
+                    (config.passphrase_words(), config.wordlist())
+                });
+            }
 
-            Msg::PassphraseChanged => self.passphrase_length = self.passphrase_entry.text_length(),
+            Msg::PassphraseChanged => self.passphrase_changed(),
 
             Msg::FocusEditPassword => {
                 self.passphrase_entry.grab_focus_without_selecting();
@@ -272,37 +321,7 @@ impl Component for SenderPage {
                 self.file_path = Some(path);
             }
 
-            Msg::SendFile => {
-                self.form_disabled = true;
-
-                let passphrase = self.passphrase_entry.text().to_string();
-
-                log::info!("Selected passphrase is {}", passphrase);
-
-                let passphrase = PassphraseMethod::Direct(passphrase.into_bytes());
-                let save = self.save_contact.is_active().then(|| {
-                    let contact = self.contact_entry.text().to_string();
-                    let contacts = self
-                        .contacts
-                        .clone()
-                        .expect("Should be loaded as save contact is true");
-
-                    (contact, contacts)
-                });
-                let path = self
-                    .file_path
-                    .clone()
-                    .expect("Should have file to be able to call send");
-
-                log::info!("Starting sender worker");
-
-                let controller = Peer::builder()
-                    .transient_for(root)
-                    .launch(TransferType::Send(Params::new(passphrase, path, save)))
-                    .forward(sender.input_sender(), Msg::AportureFinished);
-
-                self.peer = Some(controller);
-            }
+            Msg::SendFile => self.send_file(&sender, root),
 
             Msg::AportureFinished(result) => {
                 log::info!("Finished sender worker");
@@ -319,7 +338,7 @@ impl Component for SenderPage {
                         emit!(app::Request::ToastS("Peer refused to save contact", Severity::Warn) => sender);
                     }
                     Ok(ContactAction::NoOp) => {}
-                    Err(e @ AportureError::Cancel) => {
+                    Err(e @ (AportureError::Cancel | AportureError::PeerCancelled)) => {
                         emit!(app::Request::Toast(e.to_string(), Severity::Warn) => sender)
                     }
                     Err(e) => emit!(app::Request::Toast(e.to_string(), Severity::Error) => sender),
@@ -331,4 +350,113 @@ impl Component for SenderPage {
             Msg::Ignore => (),
         }
     }
+
+    fn update_cmd(
+        &mut self,
+        (words, wordlist): Self::CommandOutput,
+        _: ComponentSender<Self>,
+        _: &Self::Root,
+    ) {
+        self.passphrase_words = words;
+        self.wordlist = wordlist;
+        self.passphrase_entry
+            .set_text(&passphrase::generate(words, wordlist));
+    }
+}
+
+impl SenderPage {
+    /// Keeps the length and the QR code in sync with the passphrase entered.
+    fn passphrase_changed(&mut self) {
+        self.passphrase_length = self.passphrase_entry.text_length();
+
+        let uri = format!("{QR_SCHEME}:{}", self.passphrase_entry.text());
+        self.qr_code.set_paintable(qr_texture(&uri).as_ref());
+    }
+
+    /// Starts sending the selected file with the passphrase and options entered.
+    fn send_file(&mut self, sender: &ComponentSender<Self>, root: &adw::PreferencesGroup) {
+        self.form_disabled = true;
+
+        let passphrase = self.passphrase_entry.text().to_string();
+
+        log::info!("Selected passphrase is {}", passphrase);
+
+        let passphrase = PassphraseMethod::Direct(passphrase.into_bytes());
+        let save = self.save_contact.is_active().then(|| {
+            let contact = self.contact_entry.text().to_string();
+            let contacts = self
+                .contacts
+                .clone()
+                .expect("Should be loaded as save contact is true");
+
+            (contact, contacts)
+        });
+        let path = self
+            .file_path
+            .clone()
+            .expect("Should have file to be able to call send");
+        let exclude = self
+            .exclude_entry
+            .text()
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(ToOwned::to_owned)
+            .collect();
+
+        log::info!("Starting sender worker");
+
+        let params = Params::new(passphrase, path, save).with_exclude(exclude);
+
+        let controller = Peer::builder()
+            .transient_for(root)
+            .launch(TransferType::Send(params))
+            .forward(sender.input_sender(), Msg::AportureFinished);
+
+        self.peer = Some(controller);
+    }
+}
+
+/// Scheme of the URI encoded in the QR code, so other clients can recognize it when scanned
+const QR_SCHEME: &str = "aporture";
+
+/// Pixels per QR module
+const QR_SCALE: usize = 6;
+
+/// Empty modules around the code required by scanners
+const QR_QUIET_ZONE: usize = 4;
+
+/// Draws the text as a black on white QR code, or nothing if it is too long to encode.
+fn qr_texture(text: &str) -> Option<MemoryTexture> {
+    let code = QrCode::new(text).ok()?;
+
+    let modules = code.width() + 2 * QR_QUIET_ZONE;
+    let size = modules * QR_SCALE;
+    let colors = code.to_colors();
+
+    let mut pixels = Vec::with_capacity(size * size * 3);
+
+    for y in 0..size {
+        for x in 0..size {
+            let (mx, my) = (x / QR_SCALE, y / QR_SCALE);
+
+            let inside = QR_QUIET_ZONE..modules - QR_QUIET_ZONE;
+            let dark = inside.contains(&mx)
+                && inside.contains(&my)
+                && colors[(my - QR_QUIET_ZONE) * code.width() + mx - QR_QUIET_ZONE] == Color::Dark;
+
+            let value = if dark { 0 } else { u8::MAX };
+            pixels.extend([value; 3]);
+        }
+    }
+
+    let dimension = i32::try_from(size).ok()?;
+
+    Some(MemoryTexture::new(
+        dimension,
+        dimension,
+        MemoryFormat::R8g8b8,
+        &Bytes::from_owned(pixels),
+        size * 3,
+    ))
 }
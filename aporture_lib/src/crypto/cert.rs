@@ -19,7 +19,11 @@ impl Clone for CertificateKey {
 }
 
 #[derive(Debug, Clone)]
-pub struct Certificate(pub Arc<RootCertStore>);
+pub struct Certificate {
+    pub roots: Arc<RootCertStore>,
+    /// Exact certificate exchanged during negotiation, the peer must present it when connecting
+    pub pinned: CertificateDer<'static>,
+}
 
 impl Default for CertificateKey {
     fn default() -> Self {
@@ -29,11 +33,16 @@ impl Default for CertificateKey {
 
 impl From<Vec<u8>> for Certificate {
     fn from(value: Vec<u8>) -> Self {
+        let pinned = CertificateDer::from(value);
+
         let mut store = RootCertStore::empty();
 
-        store.add(value.into()).expect("Certificate is valid");
+        store.add(pinned.clone()).expect("Certificate is valid");
 
-        Self(Arc::new(store))
+        Self {
+            roots: Arc::new(store),
+            pinned,
+        }
     }
 }
 
@@ -52,3 +61,13 @@ impl CertificateKey {
         self.cert.to_vec()
     }
 }
+
+impl Certificate {
+    /// Checks the certificate chain presented by the peer starts with the pinned one.
+    #[must_use]
+    pub fn matches(&self, presented: &[CertificateDer<'_>]) -> bool {
+        presented
+            .first()
+            .is_some_and(|c| c.as_ref() == self.pinned.as_ref())
+    }
+}
@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 use aes_gcm_siv::aead::AeadInPlace;
 use aes_gcm_siv::{AeadCore, Aes256GcmSiv, KeyInit};
 
@@ -7,10 +9,59 @@ use super::Key;
 pub type Nonce = [u8; 12];
 pub type Tag = [u8; 16];
 
+/// Context of the derivation of every key replacing the previous one
+const REKEY_CONTEXT: &str = "aporture 2025-01-01 rekey";
+
+/// Authenticated encryption with AES-256-GCM-SIV.
+///
+/// Encrypting holds no state besides the key, each message gets a random 96 bit nonce from the
+/// thread local CSPRNG. So one cipher can be shared behind an [`Arc`](std::sync::Arc) by every
+/// stream of a transfer and used concurrently, without any lock or coordination between them.
+/// Its methods are synchronous, so a cancelled task can not leave it half updated.
+///
+/// NOTE: Random nonces are used instead of a counter as both peers encrypt with the same key,
+/// and a counter on each side would repeat the nonces of the other. A repeated nonce is
+/// unlikely before 2^32 messages with a key, well past any transfer with rekeying, and even
+/// then GCM-SIV only reveals whether the two messages were equal.
 #[derive(Clone)]
 pub struct Cipher {
     aead: Aes256GcmSiv,
+    key: Key,
     associated_data: Vec<u8>,
+    phase: Phase,
+}
+
+/// Stage of the protocol a cipher is used in, bound to every message it encrypts so one
+/// captured in a stage can not be replayed in another.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    #[default]
+    Pairing,
+    Transfer,
+}
+
+impl Phase {
+    const fn label(self) -> &'static [u8] {
+        match self {
+            // NOTE: Pairing only uses the associated data, as it did before phases existed
+            Self::Pairing => b"",
+            Self::Transfer => b"transfer",
+        }
+    }
+}
+
+/// Keys of both directions of a connection, replaced during long transfers.
+///
+/// The sender replaces its key with the next one of the ratchet after a number of messages
+/// and marks the first message using it, so the peer advances its own in lockstep.
+#[derive(Debug, Clone)]
+pub struct Rekey {
+    interval: Option<u64>,
+    sent: u64,
+    send: Cipher,
+    send_epoch: u64,
+    receive: Cipher,
+    receive_epoch: u64,
 }
 
 impl std::fmt::Debug for Cipher {
@@ -18,7 +69,8 @@ impl std::fmt::Debug for Cipher {
         f.debug_struct("Cipher")
             .field("aead", &"Hidden implementation")
             .field("associated_data", &self.associated_data)
-            .finish()
+            .field("phase", &self.phase)
+            .finish_non_exhaustive()
     }
 }
 
@@ -29,7 +81,26 @@ impl Cipher {
 
         Self {
             aead,
+            key: *key,
             associated_data: b"".to_vec(),
+            phase: Phase::default(),
+        }
+    }
+
+    /// Cipher with the key of the given epoch, derived from the current one.
+    #[must_use]
+    pub fn rekeyed(&self, epoch: u64) -> Self {
+        let mut hasher = blake3::Hasher::new_derive_key(REKEY_CONTEXT);
+        hasher.update(&self.key);
+        hasher.update(&epoch.to_be_bytes());
+
+        let key: Key = hasher.finalize().into();
+
+        Self {
+            aead: Aes256GcmSiv::new(&key.into()),
+            key,
+            associated_data: self.associated_data.clone(),
+            phase: self.phase,
         }
     }
 
@@ -37,26 +108,222 @@ impl Cipher {
         self.associated_data = associated_data;
     }
 
+    pub const fn set_phase(&mut self, phase: Phase) {
+        self.phase = phase;
+    }
+
     #[must_use]
     pub fn encrypt(&self, plain: &mut [u8]) -> ([u8; 12], [u8; 16]) {
+        self.encrypt_in(plain, &[])
+    }
+
+    /// Encrypts binding the message to the domain on top of the associated data and phase,
+    /// so it can only be decrypted with the same domain.
+    #[must_use]
+    pub fn encrypt_in(&self, plain: &mut [u8], domain: &[u8]) -> ([u8; 12], [u8; 16]) {
         let nonce = Aes256GcmSiv::generate_nonce(rand::thread_rng());
 
         let tag = self
             .aead
-            .encrypt_in_place_detached(&nonce, &self.associated_data, plain)
+            .encrypt_in_place_detached(&nonce, &self.aad(domain), plain)
             .expect("Associated data an plan are not bigger than expected in aes_gcm");
 
         (nonce.into(), tag.into())
     }
 
     pub fn decrypt(&self, cipher: &mut [u8], nonce: &Nonce, tag: &Tag) -> Result<(), Error> {
-        self.aead.decrypt_in_place_detached(
-            nonce.into(),
-            &self.associated_data,
-            cipher,
-            tag.into(),
-        )?;
+        self.decrypt_in(cipher, nonce, tag, &[])
+    }
+
+    /// Decrypts a message encrypted with [`Self::encrypt_in`] and the same domain.
+    pub fn decrypt_in(
+        &self,
+        cipher: &mut [u8],
+        nonce: &Nonce,
+        tag: &Tag,
+        domain: &[u8],
+    ) -> Result<(), Error> {
+        self.aead
+            .decrypt_in_place_detached(nonce.into(), &self.aad(domain), cipher, tag.into())?;
 
         Ok(())
     }
+
+    /// Associated data followed by the phase label and the domain, each prefixed by its length.
+    fn aad(&self, domain: &[u8]) -> Cow<'_, [u8]> {
+        let label = self.phase.label();
+
+        if label.is_empty() && domain.is_empty() {
+            return Cow::Borrowed(&self.associated_data);
+        }
+
+        let mut aad = self.associated_data.clone();
+
+        for part in [label, domain] {
+            aad.extend_from_slice(&(part.len() as u64).to_be_bytes());
+            aad.extend_from_slice(part);
+        }
+
+        Cow::Owned(aad)
+    }
+}
+
+impl Rekey {
+    #[must_use]
+    pub fn new(cipher: &Cipher) -> Self {
+        Self {
+            interval: None,
+            sent: 0,
+            send: cipher.clone(),
+            send_epoch: 0,
+            receive: cipher.clone(),
+            receive_epoch: 0,
+        }
+    }
+
+    /// Sets after how many messages sent the key is replaced. With `None` it is never
+    /// replaced, but the replacements made by the peer are still followed.
+    pub const fn set_interval(&mut self, interval: Option<u64>) {
+        self.interval = interval;
+    }
+
+    /// Cipher for the next message sent and whether it is the first one with a new key.
+    pub fn sending(&mut self) -> (&Cipher, bool) {
+        let rekeyed = self.interval.is_some_and(|i| self.sent >= i);
+
+        if rekeyed {
+            self.send_epoch += 1;
+            self.send = self.send.rekeyed(self.send_epoch);
+            self.sent = 0;
+
+            log::debug!("Replaced sending key, epoch {}", self.send_epoch);
+        }
+
+        self.sent += 1;
+
+        (&self.send, rekeyed)
+    }
+
+    /// Cipher for the next message received, advanced first if the peer replaced its key.
+    pub fn receiving(&mut self, rekeyed: bool) -> &Cipher {
+        if rekeyed {
+            self.receive_epoch += 1;
+            self.receive = self.receive.rekeyed(self.receive_epoch);
+
+            log::debug!("Replaced receiving key, epoch {}", self.receive_epoch);
+        }
+
+        &self.receive
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decryption_fails_in_another_context() {
+        let mut cipher = Cipher::new(&[b'a'; 32]);
+        cipher.set_associated_data(b"passphrase".to_vec());
+
+        let mut transfer = cipher.clone();
+        transfer.set_phase(Phase::Transfer);
+
+        let encrypted = |cipher: &Cipher, domain: &[u8]| {
+            let mut content = *b"file contents";
+            let (nonce, tag) = cipher.encrypt_in(&mut content, domain);
+            (content, nonce, tag)
+        };
+
+        let (mut content, nonce, tag) = encrypted(&transfer, &1_u64.to_be_bytes());
+        assert!(transfer
+            .decrypt_in(&mut content.clone(), &nonce, &tag, &2_u64.to_be_bytes())
+            .is_err());
+        assert!(transfer
+            .decrypt(&mut content.clone(), &nonce, &tag)
+            .is_err());
+        assert!(cipher
+            .decrypt_in(&mut content.clone(), &nonce, &tag, &1_u64.to_be_bytes())
+            .is_err());
+
+        transfer
+            .decrypt_in(&mut content, &nonce, &tag, &1_u64.to_be_bytes())
+            .expect("Same context");
+        assert_eq!(b"file contents", &content);
+
+        let (mut content, nonce, tag) = encrypted(&cipher, &[]);
+        assert!(transfer
+            .decrypt(&mut content.clone(), &nonce, &tag)
+            .is_err());
+        cipher
+            .decrypt(&mut content, &nonce, &tag)
+            .expect("Same phase");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_encryption_never_repeats_nonces() {
+        const TASKS: usize = 16;
+        const MESSAGES: usize = 2000;
+
+        let cipher = std::sync::Arc::new(Cipher::new(&[b'a'; 32]));
+
+        let tasks = (0..TASKS).map(|task| {
+            let cipher = std::sync::Arc::clone(&cipher);
+
+            tokio::spawn(async move {
+                (0..MESSAGES)
+                    .map(|i| {
+                        let mut content = [0; 8];
+                        let (nonce, tag) = cipher.encrypt_in(&mut content, &task.to_be_bytes());
+
+                        cipher
+                            .decrypt_in(&mut content, &nonce, &tag, &task.to_be_bytes())
+                            .expect("Same cipher and domain");
+                        assert_eq!([0; 8], content, "Message {i} of task {task}");
+
+                        nonce
+                    })
+                    .collect::<Vec<_>>()
+            })
+        });
+
+        let mut nonces = std::collections::HashSet::new();
+        for task in tasks {
+            nonces.extend(task.await.expect("Task finished"));
+        }
+
+        assert_eq!(TASKS * MESSAGES, nonces.len());
+    }
+
+    #[test]
+    fn test_rekey_advances_in_lockstep() {
+        let cipher = Cipher::new(&[b'a'; 32]);
+
+        let mut sender = Rekey::new(&cipher);
+        let mut receiver = Rekey::new(&cipher);
+        sender.set_interval(Some(2));
+
+        let mut markers = Vec::new();
+
+        for i in 0..7_u8 {
+            let mut content = [i; 16];
+
+            let (cipher, rekeyed) = sender.sending();
+            let (nonce, tag) = cipher.encrypt(&mut content);
+            markers.push(rekeyed);
+
+            receiver
+                .receiving(rekeyed)
+                .decrypt(&mut content, &nonce, &tag)
+                .expect("Same key as the sender");
+
+            assert_eq!([i; 16], content);
+        }
+
+        assert_eq!(vec![false, false, true, false, true, false, true], markers);
+
+        let mut content = [0; 16];
+        let (nonce, tag) = sender.sending().0.encrypt(&mut content);
+        assert!(cipher.decrypt(&mut content, &nonce, &tag).is_err());
+    }
 }
@@ -1,12 +1,17 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::{IpAddr, SocketAddr};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use generic_array::GenericArray;
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
 use tokio::sync::{OnceCell, RwLock, RwLockReadGuard};
 
 use crate::parse;
 use crate::parser::{Parser, SerdeIO};
+use crate::passphrase::Wordlist;
+use crate::transfer::{CompressionThreshold, OnConflict};
 
 use crate::fs::FileManager;
 
@@ -15,17 +20,183 @@ const CONFIG_FILE_NAME: &str = "config.app";
 const DEFAULT_SERVER_ADDRESS: Option<&str> = option_env!("SERVER_ADDRESS");
 const DEFAULT_SERVER_PORT: u16 = 8765;
 
+/// Public STUN servers asked for the external address when the server does not answer it
+pub const DEFAULT_STUN_SERVERS: [&str; 2] = ["stun.l.google.com:19302", "stun.cloudflare.com:3478"];
+
+/// Time a saved contact address is trusted, NATs and DHCP leases change it after a while
+pub const DEFAULT_HINT_AGE: Duration = Duration::from_hours(24);
+
 static CONFIG: OnceCell<RwLock<Config>> = OnceCell::const_new();
 
+#[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[non_exhaustive]
 pub struct Config {
+    /// Servers in the order they are tried, the first one is the primary
+    servers: Vec<Server>,
+    #[serde(default = "default_passphrase_words")]
+    passphrase_words: usize,
+    #[serde(default)]
+    wordlist: Wordlist,
+    // NOTE: Bencode has no booleans, so it is kept as text to read it back
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_notifications")]
+    notifications: bool,
+    #[serde(default)]
+    on_conflict: OnConflict,
+    #[serde(default = "default_stun_servers")]
+    stun_servers: Vec<String>,
+    #[serde(default)]
+    compression: CompressionThreshold,
+    /// Replaces the system download folder when set
+    #[serde(default)]
+    download_dir: Option<PathBuf>,
+    /// Seconds a saved contact address is tried for before pairing only through the server
+    #[serde(default = "default_hint_age")]
+    hint_age: u64,
+    /// Local address transfer sockets bind to, the system picks one when not set
+    #[serde(default)]
+    bind_ip: Option<IpAddr>,
+    /// Name shown to peers when pairing
+    #[serde(default)]
+    display_name: Option<String>,
+    /// Contacts accepted when listening, every saved one when empty
+    #[serde(default)]
+    listen_contacts: Vec<String>,
+    /// Whether pairing maps a port on the router with upnp
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_enabled")]
+    use_upnp: bool,
+    /// Whether pairing finds the external address for hole punching
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_enabled")]
+    use_hole_punching: bool,
+    #[serde(skip)]
+    source: ConfigSource,
+    /// Values read from the file, the rest took their default
+    #[serde(skip)]
+    saved: BTreeSet<String>,
+}
+
+const fn default_passphrase_words() -> usize {
+    crate::passphrase::DEFAULT_WORD_COUNT
+}
+
+const fn default_notifications() -> bool {
+    true
+}
+
+const fn default_enabled() -> bool {
+    true
+}
+
+const fn default_hint_age() -> u64 {
+    DEFAULT_HINT_AGE.as_secs()
+}
+
+fn default_stun_servers() -> Vec<String> {
+    DEFAULT_STUN_SERVERS.map(ToOwned::to_owned).to_vec()
+}
+
+parse!(Config);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Server {
+    domain: String,
+    address: IpAddr,
+    port: u16,
+}
+
+impl Server {
+    async fn resolve(domain: String) -> Result<Self, crate::io::Error> {
+        let address = lookup_host(&domain).await?;
+
+        Ok(Self {
+            domain,
+            address: address.ip(),
+            port: address.port(),
+        })
+    }
+
+    #[must_use]
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    #[must_use]
+    pub fn address(&self) -> SocketAddr {
+        (self.address, self.port).into()
+    }
+}
+
+/// Config written before multiple servers were supported, migrated when read
+#[allow(clippy::struct_field_names)]
+#[derive(Debug, Serialize, Deserialize)]
+struct LegacyConfig {
     server_domain: String,
     server_address: IpAddr,
     server_port: u16,
 }
 
-parse!(Config);
+parse!(LegacyConfig);
+
+/// Every value of a saved config by name, whatever its type
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedValues(BTreeMap<String, serde_bencode::value::Value>);
+
+parse!(SavedValues);
+
+impl SavedValues {
+    fn names(self) -> BTreeSet<String> {
+        self.0.into_keys().collect()
+    }
+}
+
+impl From<LegacyConfig> for Config {
+    fn from(legacy: LegacyConfig) -> Self {
+        let server = Server {
+            domain: legacy.server_domain,
+            address: legacy.server_address,
+            port: legacy.server_port,
+        };
+
+        Self {
+            servers: vec![server],
+            passphrase_words: default_passphrase_words(),
+            wordlist: Wordlist::default(),
+            notifications: default_notifications(),
+            on_conflict: OnConflict::default(),
+            stun_servers: default_stun_servers(),
+            compression: CompressionThreshold::DEFAULT,
+            download_dir: None,
+            hint_age: default_hint_age(),
+            bind_ip: None,
+            display_name: None,
+            listen_contacts: Vec::new(),
+            use_upnp: default_enabled(),
+            use_hole_punching: default_enabled(),
+            source: ConfigSource::File,
+            saved: BTreeSet::new(),
+        }
+    }
+}
+
+/// Where the loaded configuration came from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    File,
+    #[default]
+    Default,
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File => write!(f, "config file"),
+            Self::Default => write!(f, "defaults"),
+        }
+    }
+}
 
 impl Config {
     async fn default() -> Self {
@@ -37,10 +208,29 @@ impl Config {
             .await
             .unwrap_or_else(|_| ([127, 0, 0, 1], DEFAULT_SERVER_PORT).into());
 
+        let server = Server {
+            domain: server_domain,
+            address: address.ip(),
+            port: address.port(),
+        };
+
         Self {
-            server_domain,
-            server_address: address.ip(),
-            server_port: address.port(),
+            servers: vec![server],
+            passphrase_words: default_passphrase_words(),
+            wordlist: Wordlist::default(),
+            notifications: default_notifications(),
+            on_conflict: OnConflict::default(),
+            stun_servers: default_stun_servers(),
+            compression: CompressionThreshold::DEFAULT,
+            download_dir: None,
+            hint_age: default_hint_age(),
+            bind_ip: None,
+            display_name: None,
+            listen_contacts: Vec::new(),
+            use_upnp: default_enabled(),
+            use_hole_punching: default_enabled(),
+            source: ConfigSource::Default,
+            saved: BTreeSet::new(),
         }
     }
 
@@ -73,12 +263,142 @@ impl Config {
 
     #[must_use]
     pub fn server_address(&self) -> SocketAddr {
-        (self.server_address, self.server_port).into()
+        self.primary().address()
     }
 
     #[must_use]
     pub fn server_domain(&self) -> &str {
-        &self.server_domain
+        self.primary().domain()
+    }
+
+    /// Addresses of every configured server in the order they are tried.
+    #[must_use]
+    pub fn server_addresses(&self) -> Vec<SocketAddr> {
+        self.servers.iter().map(Server::address).collect()
+    }
+
+    #[must_use]
+    pub fn servers(&self) -> &[Server] {
+        &self.servers
+    }
+
+    /// Amount of words of generated passphrases.
+    #[must_use]
+    pub fn passphrase_words(&self) -> usize {
+        self.passphrase_words.max(crate::passphrase::MIN_WORD_COUNT)
+    }
+
+    /// List generated passphrases take their words from.
+    #[must_use]
+    pub const fn wordlist(&self) -> Wordlist {
+        self.wordlist
+    }
+
+    /// Whether desktop notifications are shown when a transfer finishes.
+    #[must_use]
+    pub const fn notifications(&self) -> bool {
+        self.notifications
+    }
+
+    /// What to do when a received file already exists.
+    #[must_use]
+    pub const fn on_conflict(&self) -> OnConflict {
+        self.on_conflict
+    }
+
+    /// Folders compressed before sending when compression is automatic.
+    #[must_use]
+    pub const fn compression_threshold(&self) -> CompressionThreshold {
+        self.compression
+    }
+
+    /// Folder received files are saved in when no destination is given, the configured one
+    /// or else the system download folder.
+    #[must_use]
+    pub fn download_dir(&self) -> Option<PathBuf> {
+        self.download_dir
+            .clone()
+            .or_else(crate::fs::downloads_directory)
+    }
+
+    /// Download folder set in the config, `None` if the system one is used.
+    #[must_use]
+    pub fn custom_download_dir(&self) -> Option<&Path> {
+        self.download_dir.as_deref()
+    }
+
+    /// How old a saved contact address can be to try it before the server, zero never tries it.
+    #[must_use]
+    pub const fn hint_age(&self) -> Duration {
+        Duration::from_secs(self.hint_age)
+    }
+
+    /// Local address transfer sockets bind to, `None` lets the system pick one.
+    #[must_use]
+    pub const fn bind_ip(&self) -> Option<IpAddr> {
+        self.bind_ip
+    }
+
+    /// Name shown to peers when pairing, `None` if not set.
+    #[must_use]
+    pub fn display_name(&self) -> Option<&str> {
+        self.display_name.as_deref()
+    }
+
+    /// Whether pairing maps a port on the router with upnp.
+    #[must_use]
+    pub const fn use_upnp(&self) -> bool {
+        self.use_upnp
+    }
+
+    /// Whether pairing finds the external address for hole punching.
+    #[must_use]
+    pub const fn use_hole_punching(&self) -> bool {
+        self.use_hole_punching
+    }
+
+    /// STUN servers asked in order for the external address when the server does not answer it.
+    #[must_use]
+    pub fn stun_servers(&self) -> &[String] {
+        &self.stun_servers
+    }
+
+    /// Contacts accepted when listening, empty to accept every saved one.
+    #[must_use]
+    pub fn listen_contacts(&self) -> &[String] {
+        &self.listen_contacts
+    }
+
+    fn primary(&self) -> &Server {
+        self.servers.first().expect("Config always has a server")
+    }
+
+    #[must_use]
+    pub const fn source(&self) -> ConfigSource {
+        self.source
+    }
+
+    /// Where the value saved with the name came from, values missing from the file take their
+    /// default.
+    #[must_use]
+    pub fn value_source(&self, name: &str) -> ConfigSource {
+        if self.saved.contains(name) {
+            ConfigSource::File
+        } else {
+            ConfigSource::Default
+        }
+    }
+
+    /// Names of every value written when the config is saved.
+    fn written(&self) -> BTreeSet<String> {
+        SavedValues::deserialize_from(&self.serialize_to())
+            .map(SavedValues::names)
+            .unwrap_or_default()
+    }
+
+    /// Resolves every address of the primary server.
+    pub async fn resolved_addresses(&self) -> Result<Vec<SocketAddr>, crate::io::Error> {
+        resolve(self.server_domain(), None).await
     }
 
     async fn from_file() -> Result<Self, crate::io::Error> {
@@ -88,9 +408,29 @@ impl Config {
 
         let mut manager = FileManager::new(path);
 
-        let config = manager.read_ser().await?;
+        let config = match manager.read_ser::<Self>().await {
+            Ok(config) if !config.servers.is_empty() => config,
+            _ => {
+                let config = Self::from(manager.read_ser::<LegacyConfig>().await?);
 
-        Ok(config)
+                log::info!("Migrating config to multiple servers");
+                config.save().await?;
+
+                config
+            }
+        };
+
+        let saved = manager
+            .read_ser::<SavedValues>()
+            .await
+            .map(SavedValues::names)
+            .unwrap_or_default();
+
+        Ok(Self {
+            source: ConfigSource::File,
+            saved,
+            ..config
+        })
     }
 
     async fn create_file() -> Result<Self, crate::io::Error> {
@@ -111,8 +451,276 @@ impl Config {
         Ok(config)
     }
 
+    /// Replaces the primary server.
     pub async fn update_address(
         address: String,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        let server = Server::resolve(address).await?;
+
+        Self::modify(|config| {
+            config.servers[0] = server;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Replaces every server, the first one becomes the primary.
+    pub async fn set_servers(
+        addresses: Vec<String>,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        let mut new = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            new.push(Server::resolve(address).await?);
+        }
+
+        if new.is_empty() {
+            return Err(crate::io::Error::Custom("At least one server is required"));
+        }
+
+        Self::modify(|config| {
+            config.servers = new;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Adds a fallback server tried after the current ones.
+    pub async fn add_server(
+        address: String,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        let server = Server::resolve(address).await?;
+
+        Self::modify(|config| {
+            config.servers.retain(|s| s.domain != server.domain);
+            config.servers.push(server);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Removes a server, the last one can not be removed.
+    pub async fn remove_server(
+        domain: &str,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        Self::modify(|config| {
+            let servers = &mut config.servers;
+            let index = position(servers, domain)?;
+
+            if servers.len() == 1 {
+                return Err(crate::io::Error::Custom(
+                    "The last server can not be removed",
+                ));
+            }
+
+            servers.remove(index);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Moves a server to a position in the order they are tried.
+    pub async fn move_server(
+        domain: &str,
+        to: usize,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        Self::modify(|config| {
+            let servers = &mut config.servers;
+            let index = position(servers, domain)?;
+
+            let server = servers.remove(index);
+            servers.insert(to.min(servers.len()), server);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets the amount of words of generated passphrases.
+    pub async fn update_passphrase_words(
+        words: usize,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        if words < crate::passphrase::MIN_WORD_COUNT {
+            return Err(crate::io::Error::Custom(
+                "Passphrases need at least 2 words",
+            ));
+        }
+
+        Self::modify(|config| {
+            config.passphrase_words = words;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets the list generated passphrases take their words from.
+    pub async fn update_wordlist(
+        wordlist: Wordlist,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        Self::modify(|config| {
+            config.wordlist = wordlist;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Enables or disables desktop notifications when a transfer finishes.
+    pub async fn update_notifications(
+        enabled: bool,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        Self::modify(|config| {
+            config.notifications = enabled;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets what to do when a received file already exists.
+    pub async fn update_on_conflict(
+        on_conflict: OnConflict,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        Self::modify(|config| {
+            config.on_conflict = on_conflict;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets the folders compressed before sending when compression is automatic.
+    pub async fn update_compression_threshold(
+        threshold: CompressionThreshold,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        Self::modify(|config| {
+            config.compression = threshold;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets the folder received files are saved in, `None` restores the system download folder.
+    ///
+    /// The folder must exist and be writable, it is saved as an absolute path.
+    pub async fn update_download_dir(
+        dir: Option<PathBuf>,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        let dir = dir.as_deref().map(writable_dir).transpose()?;
+
+        Self::modify(|config| {
+            config.download_dir = dir;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets how old a saved contact address can be to try it before the server.
+    pub async fn update_hint_age(
+        age: Duration,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        Self::modify(|config| {
+            config.hint_age = age.as_secs();
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets the local address transfer sockets bind to, `None` lets the system pick one again.
+    pub async fn update_bind_ip(
+        ip: Option<IpAddr>,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        Self::modify(|config| {
+            config.bind_ip = ip;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Sets the name shown to peers when pairing, `None` or a blank name stops sending one.
+    ///
+    /// It is truncated to [`crate::protocol::MAX_DISPLAY_NAME`] characters.
+    pub async fn update_display_name(
+        name: Option<String>,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        let name = name.as_deref().and_then(crate::protocol::display_name);
+
+        Self::modify(|config| {
+            config.display_name = name;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Enables or disables mapping a port on the router with upnp when pairing.
+    pub async fn update_upnp(
+        enabled: bool,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        Self::modify(|config| {
+            config.use_upnp = enabled;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Enables or disables finding the external address for hole punching when pairing.
+    pub async fn update_hole_punching(
+        enabled: bool,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        Self::modify(|config| {
+            config.use_hole_punching = enabled;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Replaces the STUN servers, an empty list restores the default ones.
+    pub async fn set_stun_servers(
+        servers: Vec<String>,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        let servers = if servers.is_empty() {
+            default_stun_servers()
+        } else {
+            servers
+        };
+
+        Self::modify(|config| {
+            config.stun_servers = servers;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Replaces the contacts accepted when listening, an empty list accepts every saved one.
+    pub async fn set_listen_contacts(
+        names: Vec<String>,
+    ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        Self::modify(|config| {
+            config.listen_contacts = names;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Replaces the config with the default one, resolving the default server again.
+    /// The saved config is never read, so this also recovers from one that can not be parsed.
+    pub async fn reset() -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
+        let mut defaults = Self {
+            source: ConfigSource::File,
+            ..Self::default().await
+        };
+        defaults.saved = defaults.written();
+
+        tokio::fs::create_dir_all(crate::fs::path()?).await?;
+        defaults.save().await?;
+
+        let lock = CONFIG
+            .get_or_init(|| async { RwLock::new(defaults.clone()) })
+            .await;
+
+        let mut config = lock.write().await;
+        *config = defaults;
+
+        Ok(config.downgrade())
+    }
+
+    async fn modify(
+        f: impl FnOnce(&mut Self) -> Result<(), crate::io::Error> + Send,
     ) -> Result<RwLockReadGuard<'static, Self>, crate::io::Error> {
         if !CONFIG.initialized() {
             let _ = Self::get().await;
@@ -120,13 +728,11 @@ impl Config {
 
         let mut config = CONFIG.get().expect("Should be created above").write().await;
 
-        let server_address = lookup_host(&address).await?;
-
-        config.server_domain = address;
-        config.server_address = server_address.ip();
-        config.server_port = server_address.port();
+        f(&mut config)?;
+        config.source = ConfigSource::File;
 
         config.save().await?;
+        config.saved = config.written();
 
         Ok(config.downgrade())
     }
@@ -143,7 +749,8 @@ impl Config {
         Ok(())
     }
 
-    fn path() -> Result<PathBuf, crate::io::Error> {
+    /// Location of the config file.
+    pub fn path() -> Result<PathBuf, crate::io::Error> {
         let mut path = crate::fs::path()?;
 
         path.push(CONFIG_FILE_NAME);
@@ -152,12 +759,192 @@ impl Config {
     }
 }
 
-async fn lookup_host(address: &str) -> Result<SocketAddr, crate::io::Error> {
-    if let Ok(a) = tokio::net::lookup_host(address.to_owned()).await {
+fn position(servers: &[Server], domain: &str) -> Result<usize, crate::io::Error> {
+    servers
+        .iter()
+        .position(|s| s.domain == domain)
+        .ok_or(crate::io::Error::Custom("Server not configured"))
+}
+
+fn writable_dir(path: &Path) -> Result<PathBuf, crate::io::Error> {
+    let path = std::fs::canonicalize(path)
+        .map_err(|_| crate::io::Error::Custom("The download folder does not exist"))?;
+
+    if !path.is_dir() {
+        return Err(crate::io::Error::Custom(
+            "The download folder is not a folder",
+        ));
+    }
+
+    tempfile::tempfile_in(&path)
+        .map_err(|_| crate::io::Error::Custom("The download folder is not writable"))?;
+
+    Ok(path)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    #[must_use]
+    pub const fn matches(self, address: &SocketAddr) -> bool {
+        match self {
+            Self::V4 => address.is_ipv4(),
+            Self::V6 => address.is_ipv6(),
+        }
+    }
+}
+
+/// Resolves every address of a server, restricted to a family if one is forced.
+pub async fn resolve(
+    address: &str,
+    family: Option<IpFamily>,
+) -> Result<Vec<SocketAddr>, crate::io::Error> {
+    let addresses = if let Ok(a) = tokio::net::lookup_host(address.to_owned()).await {
         a
     } else {
         tokio::net::lookup_host(format!("{address}:{DEFAULT_SERVER_PORT}")).await?
+    };
+
+    let addresses = filter_family(addresses, family);
+
+    if addresses.is_empty() {
+        return Err(crate::io::Error::Config);
+    }
+
+    Ok(addresses)
+}
+
+fn filter_family(
+    addresses: impl Iterator<Item = SocketAddr>,
+    family: Option<IpFamily>,
+) -> Vec<SocketAddr> {
+    addresses
+        .filter(|a| family.is_none_or(|f| f.matches(a)))
+        .collect()
+}
+
+async fn lookup_host(address: &str) -> Result<SocketAddr, crate::io::Error> {
+    resolve(address, Some(IpFamily::V4))
+        .await?
+        .into_iter()
+        .next()
+        .ok_or(crate::io::Error::Config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_legacy_config_is_migrated() {
+        let legacy = LegacyConfig {
+            server_domain: "aporture.example".to_owned(),
+            server_address: IpAddr::from([127, 0, 0, 1]),
+            server_port: 8765,
+        };
+
+        let serialized = legacy.serialize_to();
+
+        assert!(Config::deserialize_from(&serialized).is_err());
+
+        let config =
+            Config::from(LegacyConfig::deserialize_from(&serialized).expect("Valid legacy config"));
+
+        assert_eq!("aporture.example", config.server_domain());
+        assert_eq!(
+            SocketAddr::from(([127, 0, 0, 1], 8765)),
+            config.server_address()
+        );
+        assert_eq!(1, config.server_addresses().len());
+        assert_eq!(
+            crate::passphrase::DEFAULT_WORD_COUNT,
+            config.passphrase_words()
+        );
+        assert_eq!(Wordlist::Long, config.wordlist());
+        assert!(config.notifications());
+        assert_eq!(OnConflict::Rename, config.on_conflict());
+        assert_eq!(DEFAULT_STUN_SERVERS.as_slice(), config.stun_servers());
+        assert_eq!(
+            CompressionThreshold::DEFAULT,
+            config.compression_threshold()
+        );
+        assert_eq!(None, config.custom_download_dir());
+        assert!(config.use_upnp());
+        assert!(config.use_hole_punching());
+
+        let mut config = config;
+        config.download_dir = Some(PathBuf::from("/srv/downloads"));
+        config.use_upnp = false;
+
+        let config = Config::deserialize_from(&config.serialize_to()).expect("Valid config");
+        assert_eq!(Some(PathBuf::from("/srv/downloads")), config.download_dir());
+        assert!(!config.use_upnp());
+    }
+
+    #[test]
+    fn test_values_missing_from_the_file_are_defaults() {
+        let legacy = LegacyConfig {
+            server_domain: "aporture.example".to_owned(),
+            server_address: IpAddr::from([127, 0, 0, 1]),
+            server_port: 8765,
+        };
+        let mut config = Config::from(legacy);
+
+        let SavedValues(mut values) =
+            SavedValues::deserialize_from(&config.serialize_to()).expect("Valid config");
+        values.retain(|name, _| name == "servers" || name == "use_upnp");
+
+        let partial = SavedValues(values).serialize_to();
+        config = Config::deserialize_from(&partial).expect("Valid partial config");
+        config.saved = SavedValues::deserialize_from(&partial)
+            .expect("Valid partial config")
+            .names();
+
+        assert_eq!(ConfigSource::File, config.value_source("servers"));
+        assert_eq!(ConfigSource::File, config.value_source("use_upnp"));
+        assert_eq!(ConfigSource::Default, config.value_source("hint_age"));
+        assert_eq!(ConfigSource::Default, config.value_source("download_dir"));
+
+        let written = config.written();
+        assert!(written.contains("hint_age") && written.contains("use_hole_punching"));
+    }
+
+    #[test]
+    fn test_download_dir_must_be_writable_folder() {
+        let dir = tempfile::tempdir().expect("Temporary directory");
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").expect("Create file");
+
+        let relative = dir.path().join("nested").join("..");
+        std::fs::create_dir(dir.path().join("nested")).expect("Create folder");
+        let canonical = std::fs::canonicalize(dir.path()).expect("Canonical path");
+
+        assert_eq!(canonical, writable_dir(&relative).expect("Writable folder"));
+        assert!(writable_dir(&file).is_err());
+        assert!(writable_dir(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_forced_family_only_keeps_its_addresses() {
+        let addresses = [
+            SocketAddr::from(([127, 0, 0, 1], 8765)),
+            SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 8765)),
+            SocketAddr::from(([10, 0, 0, 1], 8765)),
+        ];
+
+        let v4 = filter_family(addresses.into_iter(), Some(IpFamily::V4));
+        assert_eq!(2, v4.len());
+        assert!(v4.iter().all(SocketAddr::is_ipv4));
+
+        let v6 = filter_family(addresses.into_iter(), Some(IpFamily::V6));
+        assert_eq!(1, v6.len());
+        assert!(v6.iter().all(SocketAddr::is_ipv6));
+
+        let any = filter_family(addresses.into_iter(), None);
+        assert_eq!(addresses.len(), any.len());
     }
-    .find(std::net::SocketAddr::is_ipv4)
-    .ok_or(crate::io::Error::Config)
 }
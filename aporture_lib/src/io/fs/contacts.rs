@@ -1,12 +1,15 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use chrono::{DateTime, Local};
 use generic_array::GenericArray;
 use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
 
 use crate::crypto::cipher::Cipher;
-use crate::crypto::hasher::Hasher;
+use crate::crypto::hasher::{self, Hasher};
 use crate::crypto::Key;
 use crate::fs::salt::Salt;
 use crate::fs::EncryptedFileManager;
@@ -15,16 +18,47 @@ use crate::parser::{EncryptedSerdeIO, Parser};
 
 const CONTACTS_FILE_NAME: &str = "contacts.app";
 
+/// Bytes of the key hash shown to compare keys with the contact
+const FINGERPRINT_SIZE: usize = 8;
+
 #[derive(Debug)]
 pub struct Contacts {
     content: Content,
     manager: EncryptedFileManager,
 }
 
+/// What to do with imported contacts whose name is already taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conflict {
+    Keep,
+    Overwrite,
+}
+
+/// A saved contact as shown when listing them, without its key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ContactDetails<'a> {
+    pub name: &'a str,
+    pub added: DateTime<Local>,
+    pub fingerprint: String,
+    pub tags: Vec<&'a str>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Contact {
     pub key: [u8; 32],
     pub timestamp: DateTime<Local>,
+    #[serde(default)]
+    pub hint: Option<AddressHint>,
+    /// Groups the contact is listed under, databases from before tags have none
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+}
+
+/// Address the contact was last reachable at directly, without the server.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct AddressHint {
+    address: SocketAddr,
+    seen: DateTime<Local>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -34,10 +68,26 @@ pub struct Content {
 
 parse!(Content);
 
+impl Content {
+    fn rename(&mut self, old: &str, new: &str) -> bool {
+        if self.map.contains_key(new) {
+            return false;
+        }
+
+        let Some(contact) = self.map.remove(old) else {
+            return false;
+        };
+
+        self.map.insert(new.to_owned(), contact);
+
+        true
+    }
+}
+
 impl Contacts {
     #[must_use]
     pub fn exists() -> bool {
-        path().map(|p| p.exists()).unwrap_or(false)
+        path().is_ok_and(|p| p.exists())
     }
 
     pub async fn empty(password: &[u8]) -> Result<Self, crate::io::Error> {
@@ -74,6 +124,8 @@ impl Contacts {
         })
     }
 
+    /// Writes the contacts to a temporary file that replaces the saved one once complete,
+    /// so a crash while saving keeps the previous contacts.
     pub async fn save(&mut self) -> Result<(), crate::io::Error> {
         log::info!("Saving contacts to {}", self.manager);
 
@@ -82,6 +134,7 @@ impl Contacts {
         Ok(())
     }
 
+    /// Same as [`Self::save`], for contexts that can not wait on a future.
     pub fn save_blocking(&mut self) -> Result<(), crate::io::Error> {
         log::info!("Saving contacts to {}", self.manager);
 
@@ -95,14 +148,79 @@ impl Contacts {
         self.content.map.get(name).map(|c| &c.key)
     }
 
+    /// Short hash of the key shared with the contact, the same on both ends of the pairing
+    /// so it can be compared to confirm who was paired with.
+    #[must_use]
+    pub fn fingerprint(&self, name: &str) -> Option<String> {
+        self.get(name).map(fingerprint)
+    }
+
     pub fn add(&mut self, name: String, key: Key) {
         let timestamp = chrono::Local::now();
 
-        let contact = Contact { key, timestamp };
+        let contact = Contact {
+            key,
+            timestamp,
+            hint: None,
+            tags: BTreeSet::new(),
+        };
 
         self.content.map.insert(name, contact);
     }
 
+    /// Replaces the tags of the contact, returning whether it exists.
+    /// Tags are trimmed and empty ones ignored, so no tags removes them all.
+    pub fn set_tags(&mut self, name: &str, tags: &[String]) -> bool {
+        let Some(contact) = self.content.map.get_mut(name) else {
+            return false;
+        };
+
+        contact.tags = tags
+            .iter()
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+            .map(str::to_owned)
+            .collect();
+
+        true
+    }
+
+    /// Every tag used by at least one contact, sorted.
+    #[must_use]
+    pub fn tags(&self) -> BTreeSet<&str> {
+        self.content
+            .map
+            .values()
+            .flat_map(|c| c.tags.iter().map(String::as_str))
+            .collect()
+    }
+
+    /// Saves the address the contact can be reached at directly, returning whether it exists.
+    pub fn set_hint(&mut self, name: &str, address: SocketAddr) -> bool {
+        let Some(contact) = self.content.map.get_mut(name) else {
+            return false;
+        };
+
+        contact.hint = Some(AddressHint {
+            address,
+            seen: chrono::Local::now(),
+        });
+
+        true
+    }
+
+    /// Address the contact was last reachable at, `None` if it was not seen within the age.
+    #[must_use]
+    pub fn hint(&self, name: &str, max_age: Duration) -> Option<SocketAddr> {
+        let hint = self.content.map.get(name)?.hint?;
+
+        let age = (chrono::Local::now() - hint.seen)
+            .to_std()
+            .unwrap_or_default();
+
+        (age < max_age).then_some(hint.address)
+    }
+
     pub fn replace(&mut self, new_name: String, old_name: Option<String>, key: Key) {
         if let Some(name) = old_name {
             self.delete(&name);
@@ -115,9 +233,134 @@ impl Contacts {
         self.content.map.remove(name).is_some()
     }
 
+    /// Renames a contact keeping its key and registration date.
+    ///
+    /// Fails if the contact does not exist or the new name is taken.
+    pub fn rename(&mut self, old: &str, new: &str) -> bool {
+        self.content.rename(old, new)
+    }
+
+    /// Re-encrypts the database with a key derived from a new password.
+    ///
+    /// The old password must decrypt the saved database for the change to be accepted.
+    pub async fn change_password(
+        &mut self,
+        old: &[u8],
+        new: &[u8],
+    ) -> Result<(), crate::io::Error> {
+        let salt = &Salt::get().await.0;
+
+        let old = Cipher::new(&Hasher::derive_key(old, salt));
+        let new = Cipher::new(&Hasher::derive_key(new, salt));
+
+        self.rekey(old, new).await
+    }
+
+    async fn rekey(&mut self, old: Cipher, new: Cipher) -> Result<(), crate::io::Error> {
+        let path = self.manager.manager.path.clone();
+
+        let _: Content = EncryptedFileManager::new(path.clone(), old)
+            .read_ser_enc()
+            .await?;
+
+        self.manager = EncryptedFileManager::new(path, new);
+
+        log::info!("Changing password of contacts at {}", self.manager);
+
+        self.save().await
+    }
+
+    /// Writes every contact to a portable file encrypted with its own password.
+    ///
+    /// The file holds a random salt followed by the nonce, ciphertext and tag.
+    pub async fn export(&self, path: &Path, password: &[u8]) -> Result<(), crate::io::Error> {
+        let salt = Salt::default().0;
+
+        let cipher = Cipher::new(&Hasher::derive_key(password, &salt));
+
+        let mut manager = EncryptedFileManager::with_header(path.to_owned(), cipher, salt.to_vec());
+
+        log::info!("Exporting contacts to {}", manager);
+
+        manager.write_ser_enc(&self.content).await
+    }
+
+    /// Adds the contacts of an exported file, returning the names that were already taken.
+    pub async fn import(
+        &mut self,
+        path: &Path,
+        password: &[u8],
+        conflict: Conflict,
+    ) -> Result<Vec<String>, crate::io::Error> {
+        let mut salt = hasher::Salt::default();
+        tokio::fs::File::open(path)
+            .await?
+            .read_exact(&mut salt)
+            .await?;
+
+        let cipher = Cipher::new(&Hasher::derive_key(password, &salt));
+
+        let mut manager = EncryptedFileManager::with_header(path.to_owned(), cipher, salt.to_vec());
+
+        log::info!("Importing contacts from {}", manager);
+
+        let imported: Content = manager.read_ser_enc().await?;
+
+        let mut conflicts = Vec::new();
+
+        for (name, contact) in imported.map {
+            if self.content.map.contains_key(&name) {
+                conflicts.push(name.clone());
+
+                if conflict == Conflict::Keep {
+                    continue;
+                }
+            }
+
+            self.content.map.insert(name, contact);
+        }
+
+        Ok(conflicts)
+    }
+
     pub fn list(&self) -> impl Iterator<Item = (&String, DateTime<Local>)> {
         self.content.map.iter().map(|(n, c)| (n, c.timestamp))
     }
+
+    /// Every contact sorted by name, with the fingerprint of its key.
+    pub fn list_detailed(&self) -> impl Iterator<Item = ContactDetails<'_>> {
+        let mut contacts = self
+            .content
+            .map
+            .iter()
+            .map(|(name, c)| ContactDetails {
+                name,
+                added: c.timestamp,
+                fingerprint: fingerprint(&c.key),
+                tags: c.tags.iter().map(String::as_str).collect(),
+            })
+            .collect::<Vec<_>>();
+
+        contacts.sort_unstable_by_key(|c| c.name);
+
+        contacts.into_iter()
+    }
+
+    /// Same as [`Self::list_detailed`], only with the contacts that have the tag.
+    pub fn list_by_tag<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = ContactDetails<'a>> {
+        self.list_detailed().filter(move |c| c.tags.contains(&tag))
+    }
+}
+
+/// Renders the start of the key hash as groups of four hex digits, like `1a2b-3c4d-5e6f-7a8b`.
+fn fingerprint(key: &[u8; 32]) -> String {
+    let hash = Hasher::hash(key);
+
+    hash[..FINGERPRINT_SIZE]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join("-")
 }
 
 fn path() -> Result<PathBuf, crate::io::Error> {
@@ -127,3 +370,258 @@ fn path() -> Result<PathBuf, crate::io::Error> {
 
     Ok(path)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn content(names: &[&str]) -> Content {
+        let map = names
+            .iter()
+            .enumerate()
+            .map(|(i, n)| {
+                let contact = Contact {
+                    key: [u8::try_from(i).expect("Few contacts"); 32],
+                    timestamp: chrono::Local::now(),
+                    hint: None,
+                    tags: BTreeSet::new(),
+                };
+
+                ((*n).to_owned(), contact)
+            })
+            .collect();
+
+        Content { map }
+    }
+
+    fn contacts(names: &[&str], path: PathBuf) -> Contacts {
+        Contacts {
+            content: content(names),
+            manager: EncryptedFileManager::new(path, Cipher::new(&[0; 32])),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_export_import_round_trip() {
+        let dir = tempfile::tempdir().expect("Temporary directory");
+        let file = dir.path().join("contacts.export");
+
+        let exported = contacts(&["alice", "bob"], dir.path().join("a.app"));
+        exported
+            .export(&file, b"secret")
+            .await
+            .expect("Export contacts");
+
+        let mut kept = contacts(&["bob"], dir.path().join("b.app"));
+        kept.content.map.get_mut("bob").expect("Contact").key = [9; 32];
+
+        let conflicts = kept
+            .import(&file, b"secret", Conflict::Keep)
+            .await
+            .expect("Import contacts");
+
+        assert_eq!(vec!["bob".to_owned()], conflicts);
+        assert_eq!(Some(&[0; 32]), kept.get("alice"));
+        assert_eq!(Some(&[9; 32]), kept.get("bob"));
+
+        let _ = kept
+            .import(&file, b"secret", Conflict::Overwrite)
+            .await
+            .expect("Import contacts");
+
+        assert_eq!(Some(&[1; 32]), kept.get("bob"));
+    }
+
+    #[tokio::test]
+    async fn test_import_wrong_password() {
+        let dir = tempfile::tempdir().expect("Temporary directory");
+        let file = dir.path().join("contacts.export");
+
+        let exported = contacts(&["alice"], dir.path().join("a.app"));
+        exported
+            .export(&file, b"secret")
+            .await
+            .expect("Export contacts");
+
+        let mut other = contacts(&[], dir.path().join("b.app"));
+        let result = other.import(&file, b"wrong", Conflict::Keep).await;
+
+        assert!(matches!(result, Err(crate::io::Error::Cipher(_))));
+        assert_eq!(0, other.list().count());
+    }
+
+    #[tokio::test]
+    async fn test_rekey_requires_old_password() {
+        let dir = tempfile::tempdir().expect("Temporary directory");
+        let path = dir.path().join("contacts.app");
+
+        let mut contacts = contacts(&["alice"], path.clone());
+        contacts.save().await.expect("Save contacts");
+
+        let result = contacts
+            .rekey(Cipher::new(&[1; 32]), Cipher::new(&[2; 32]))
+            .await;
+        assert!(matches!(result, Err(crate::io::Error::Cipher(_))));
+
+        contacts
+            .rekey(Cipher::new(&[0; 32]), Cipher::new(&[2; 32]))
+            .await
+            .expect("Change key");
+
+        let mut manager = EncryptedFileManager::new(path.clone(), Cipher::new(&[2; 32]));
+        let content: Content = manager.read_ser_enc().await.expect("Read with new key");
+        assert!(content.map.contains_key("alice"));
+
+        let mut manager = EncryptedFileManager::new(path, Cipher::new(&[0; 32]));
+        assert!(manager.read_ser_enc::<Content>().await.is_err());
+        assert_eq!(1, std::fs::read_dir(dir.path()).expect("Directory").count());
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_save_keeps_previous_contacts() {
+        let dir = tempfile::tempdir().expect("Temporary directory");
+        let path = dir.path().join("contacts.app");
+
+        let mut contacts = contacts(&["alice"], path.clone());
+        contacts.save().await.expect("Save contacts");
+
+        // NOTE: A crash after writing part of the next save, before it replaced the file
+        contacts.add("bob".to_owned(), [7; 32]);
+        std::fs::write(contacts.manager.temporary(), [1; 10]).expect("Partial write");
+
+        let mut manager = EncryptedFileManager::new(path.clone(), Cipher::new(&[0; 32]));
+        let content: Content = manager.read_ser_enc().await.expect("Previous contacts");
+        assert!(content.map.contains_key("alice"));
+        assert!(!content.map.contains_key("bob"));
+
+        contacts.save_blocking().expect("Save contacts");
+
+        let content: Content = manager.read_ser_enc().await.expect("New contacts");
+        assert!(content.map.contains_key("bob"));
+        assert_eq!(1, std::fs::read_dir(dir.path()).expect("Directory").count());
+    }
+
+    #[test]
+    fn test_list_detailed_sorted_by_name() {
+        let contacts = contacts(&["carol", "alice", "bob"], PathBuf::new());
+
+        let names = contacts.list_detailed().map(|c| c.name).collect::<Vec<_>>();
+        assert_eq!(vec!["alice", "bob", "carol"], names);
+
+        let alice = contacts.list_detailed().next().expect("Contact");
+        assert_eq!(contacts.fingerprint("alice"), Some(alice.fingerprint));
+    }
+
+    #[test]
+    fn test_rename_keeps_key_and_date() {
+        let mut content = content(&["alice"]);
+        let timestamp = content.map["alice"].timestamp;
+
+        assert!(content.rename("alice", "bob"));
+
+        assert!(!content.map.contains_key("alice"));
+        assert_eq!([0; 32], content.map["bob"].key);
+        assert_eq!(timestamp, content.map["bob"].timestamp);
+    }
+
+    #[test]
+    fn test_rename_collision_and_missing() {
+        let mut content = content(&["alice", "bob"]);
+
+        assert!(!content.rename("alice", "bob"));
+        assert_eq!([0; 32], content.map["alice"].key);
+        assert_eq!([1; 32], content.map["bob"].key);
+
+        assert!(!content.rename("carol", "dave"));
+        assert_eq!(2, content.map.len());
+    }
+
+    #[test]
+    fn test_hint_expires() {
+        let mut contacts = contacts(&["alice"], PathBuf::from("contacts.app"));
+        let address = SocketAddr::from(([192, 168, 1, 2], 4000));
+        let day = Duration::from_hours(24);
+
+        assert!(!contacts.set_hint("bob", address));
+        assert!(contacts.set_hint("alice", address));
+        assert_eq!(Some(address), contacts.hint("alice", day));
+        assert_eq!(None, contacts.hint("alice", Duration::ZERO));
+
+        let hint = &mut contacts.content.map.get_mut("alice").expect("Contact").hint;
+        hint.as_mut().expect("Hint").seen -= chrono::Duration::days(2);
+        assert_eq!(None, contacts.hint("alice", day));
+
+        contacts.add("alice".to_owned(), [1; 32]);
+        assert_eq!(None, contacts.hint("alice", day));
+    }
+
+    #[test]
+    fn test_fingerprint_depends_only_on_key() {
+        let dir = tempfile::tempdir().expect("Temporary directory");
+        let mut contacts = contacts(&["alice", "bob"], dir.path().join("contacts.app"));
+
+        let alice = contacts.fingerprint("alice").expect("Fingerprint");
+        let bob = contacts.fingerprint("bob").expect("Fingerprint");
+
+        assert_eq!(FINGERPRINT_SIZE * 2 + 3, alice.len());
+        assert_ne!(alice, bob);
+        assert_eq!(None, contacts.fingerprint("carol"));
+
+        assert!(contacts.rename("alice", "carol"));
+        assert_eq!(Some(alice), contacts.fingerprint("carol"));
+    }
+
+    #[test]
+    fn test_list_by_tag() {
+        let mut contacts = contacts(&["alice", "bob", "carol"], PathBuf::new());
+
+        let tags = [" work ".to_owned(), "family".to_owned(), String::new()];
+        assert!(contacts.set_tags("alice", &tags));
+        assert!(contacts.set_tags("carol", &["work".to_owned()]));
+        assert!(!contacts.set_tags("dave", &tags));
+
+        let work = contacts
+            .list_by_tag("work")
+            .map(|c| c.name)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["alice", "carol"], work);
+
+        let alice = contacts.list_detailed().next().expect("Contact");
+        assert_eq!(vec!["family", "work"], alice.tags);
+        assert_eq!(BTreeSet::from(["family", "work"]), contacts.tags());
+
+        assert!(contacts.set_tags("alice", &[]));
+        assert_eq!(0, contacts.list_by_tag("family").count());
+    }
+
+    #[test]
+    fn test_contacts_without_tags_are_read() {
+        #[derive(Serialize, Deserialize)]
+        struct Untagged {
+            key: [u8; 32],
+            timestamp: DateTime<Local>,
+            hint: Option<AddressHint>,
+        }
+
+        #[derive(Serialize, Deserialize)]
+        struct UntaggedContent {
+            map: HashMap<String, Untagged>,
+        }
+
+        parse!(UntaggedContent);
+
+        let untagged = Untagged {
+            key: [3; 32],
+            timestamp: chrono::Local::now(),
+            hint: None,
+        };
+        let old = UntaggedContent {
+            map: HashMap::from([("alice".to_owned(), untagged)]),
+        };
+
+        let content = Content::deserialize_from(&old.serialize_to()).expect("Old contacts");
+
+        assert_eq!([3; 32], content.map["alice"].key);
+        assert!(content.map["alice"].tags.is_empty());
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Local};
+use generic_array::GenericArray;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::crypto::hasher::Hasher;
+use crate::fs::FileManager;
+use crate::parse;
+use crate::parser::{Parser, SerdeIO};
+
+const HISTORY_FILE_NAME: &str = "history.app";
+
+/// Entries kept in the log, the oldest ones are rotated out once it is full
+pub const MAX_ENTRIES: usize = 500;
+
+/// Bytes of the passphrase hash kept to tell transfers apart without storing the passphrase
+const PASSPHRASE_ID_SIZE: usize = 4;
+
+#[derive(Debug)]
+pub struct History {
+    content: Content,
+    manager: FileManager,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Who the other end of a transfer was
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Peer {
+    Contact(String),
+    /// Start of the hash of the passphrase used
+    Passphrase(String),
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub direction: Direction,
+    pub peer: Peer,
+    pub file_name: String,
+    pub size: u64,
+    pub timestamp: DateTime<Local>,
+    #[serde_as(as = "DisplayFromStr")]
+    pub success: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Content {
+    entries: VecDeque<Entry>,
+}
+
+parse!(Content);
+
+impl Content {
+    fn push(&mut self, entry: Entry) {
+        if self.entries.len() >= MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+
+        self.entries.push_back(entry);
+    }
+}
+
+impl Peer {
+    #[must_use]
+    pub fn passphrase(passphrase: &[u8]) -> Self {
+        let hash = Hasher::hash(passphrase);
+
+        let id = hash[..PASSPHRASE_ID_SIZE]
+            .iter()
+            .fold(String::new(), |mut id, b| {
+                let _ = write!(id, "{b:02x}");
+                id
+            });
+
+        Self::Passphrase(id)
+    }
+}
+
+impl Entry {
+    #[must_use]
+    pub fn new(direction: Direction, peer: Peer, file_name: String, size: u64) -> Self {
+        Self {
+            direction,
+            peer,
+            file_name,
+            size,
+            timestamp: Local::now(),
+            success: true,
+        }
+    }
+
+    #[must_use]
+    pub const fn failed(mut self) -> Self {
+        self.success = false;
+        self
+    }
+}
+
+impl History {
+    /// Reads the log, starting an empty one if there is none yet.
+    pub async fn load() -> Result<Self, crate::io::Error> {
+        let path = path()?;
+
+        let mut manager = FileManager::new(path);
+
+        log::info!("Reading history from {}", manager);
+
+        let content = if tokio::fs::try_exists(&manager.path).await? {
+            manager.read_ser().await?
+        } else {
+            Content::default()
+        };
+
+        Ok(Self { content, manager })
+    }
+
+    pub async fn save(&mut self) -> Result<(), crate::io::Error> {
+        log::info!("Saving history to {}", self.manager);
+
+        if let Some(parent) = self.manager.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        self.manager.write_ser(&self.content).await
+    }
+
+    pub fn add(&mut self, entry: Entry) {
+        self.content.push(entry);
+    }
+
+    /// Appends a single entry to the log on disk.
+    pub async fn record(entry: Entry) -> Result<(), crate::io::Error> {
+        let mut history = Self::load().await?;
+
+        history.add(entry);
+
+        history.save().await
+    }
+
+    /// Removes every entry.
+    pub fn clear(&mut self) {
+        self.content.entries.clear();
+    }
+
+    /// Entries from the most recent to the oldest.
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.content.entries.iter().rev()
+    }
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sent => write!(f, "sent"),
+            Self::Received => write!(f, "received"),
+        }
+    }
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Contact(name) => write!(f, "{name}"),
+            Self::Passphrase(id) => write!(f, "passphrase {id}"),
+        }
+    }
+}
+
+fn path() -> Result<PathBuf, crate::io::Error> {
+    let mut path = crate::fs::path()?;
+
+    path.push(HISTORY_FILE_NAME);
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(file_name: &str) -> Entry {
+        Entry::new(
+            Direction::Sent,
+            Peer::passphrase(b"some-secret-words"),
+            file_name.to_owned(),
+            42,
+        )
+    }
+
+    #[test]
+    fn test_history_round_trip() {
+        let mut content = Content::default();
+        content.push(entry("a.txt"));
+        content.push(entry("b.txt").failed());
+        content.push(Entry::new(
+            Direction::Received,
+            Peer::Contact("friend".to_owned()),
+            "c.txt".to_owned(),
+            0,
+        ));
+
+        let content = Content::deserialize_from(&content.serialize_to()).expect("Valid history");
+
+        let entries = content.entries.iter().collect::<Vec<_>>();
+        assert_eq!(3, entries.len());
+        assert!(entries[0].success);
+        assert!(!entries[1].success);
+        assert_eq!(Direction::Received, entries[2].direction);
+        assert_eq!(Peer::Contact("friend".to_owned()), entries[2].peer);
+        assert_eq!(42, entries[0].size);
+    }
+
+    #[test]
+    fn test_history_rotates_oldest_entries() {
+        let mut content = Content::default();
+
+        for i in 0..=MAX_ENTRIES {
+            content.push(entry(&i.to_string()));
+        }
+
+        assert_eq!(MAX_ENTRIES, content.entries.len());
+        assert_eq!("1", content.entries[0].file_name);
+        assert_eq!(
+            MAX_ENTRIES.to_string(),
+            content.entries[MAX_ENTRIES - 1].file_name
+        );
+    }
+
+    #[test]
+    fn test_passphrase_is_not_stored() {
+        let Peer::Passphrase(id) = Peer::passphrase(b"some-secret-words") else {
+            panic!("Expected a passphrase peer");
+        };
+
+        assert_eq!(PASSPHRASE_ID_SIZE * 2, id.len());
+        assert!(!id.contains("secret"));
+        assert_eq!(Peer::Passphrase(id), Peer::passphrase(b"some-secret-words"));
+    }
+}
@@ -1,6 +1,7 @@
 use std::fmt::Display;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
@@ -9,6 +10,7 @@ use crate::parser::{EncryptedSerdeIO, Parser, SerdeIO};
 
 pub mod config;
 pub mod contacts;
+pub mod history;
 pub mod salt;
 
 #[derive(Debug)]
@@ -44,12 +46,22 @@ impl SerdeIO for FileManager {
 struct EncryptedFileManager {
     manager: FileManager,
     cipher: Cipher,
+    /// Plain bytes stored before the nonce, ciphertext and tag
+    header: Vec<u8>,
 }
 
 impl EncryptedFileManager {
     pub const fn new(path: PathBuf, cipher: Cipher) -> Self {
+        Self::with_header(path, cipher, Vec::new())
+    }
+
+    pub const fn with_header(path: PathBuf, cipher: Cipher, header: Vec<u8>) -> Self {
         let manager = FileManager::new(path);
-        Self { manager, cipher }
+        Self {
+            manager,
+            cipher,
+            header,
+        }
     }
 
     fn write_ser_enc_blocking<P: Parser + Sync>(&self, input: &P) -> Result<(), crate::io::Error> {
@@ -57,13 +69,27 @@ impl EncryptedFileManager {
 
         let (nonce, tag) = self.cipher.encrypt(&mut input);
 
-        let mut file = std::fs::File::create(&self.manager.path)?;
+        let temporary = self.temporary();
+
+        let mut file = std::fs::File::create(&temporary)?;
+        file.write_all(&self.header)?;
         file.write_all(&nonce)?;
         file.write_all(&input)?;
         file.write_all(&tag)?;
+        file.sync_all()?;
+
+        std::fs::rename(temporary, &self.manager.path)?;
 
         Ok(())
     }
+
+    /// Sibling file written first so a crash never leaves a partial file in place.
+    fn temporary(&self) -> PathBuf {
+        let mut name = self.manager.path.file_name().unwrap_or_default().to_owned();
+        name.push(".tmp");
+
+        self.manager.path.with_file_name(name)
+    }
 }
 
 impl SerdeIO for EncryptedFileManager {
@@ -86,20 +112,41 @@ impl EncryptedSerdeIO for EncryptedFileManager {
     }
 
     async fn write_enc(&mut self, input: &mut [u8]) -> Result<(), crate::io::Error> {
-        let (nonce, tag) = self.cipher.encrypt(input);
+        self.write_enc_in(input, &[]).await
+    }
 
-        let mut file = tokio::fs::File::create(&self.manager.path).await?;
+    async fn write_enc_in(
+        &mut self,
+        input: &mut [u8],
+        domain: &[u8],
+    ) -> Result<(), crate::io::Error> {
+        let (nonce, tag) = self.cipher.encrypt_in(input, domain);
+
+        let temporary = self.temporary();
+
+        let mut file = tokio::fs::File::create(&temporary).await?;
+        file.write_all(&self.header).await?;
         file.write_all(&nonce).await?;
         file.write_all(input).await?;
         file.write_all(&tag).await?;
+        file.sync_all().await?;
+
+        tokio::fs::rename(temporary, &self.manager.path).await?;
 
         Ok(())
     }
 
     async fn read_ser_enc<P: Parser + Sync>(&mut self) -> Result<P, crate::io::Error> {
+        const TRUNCATED: crate::io::Error =
+            crate::io::Error::Custom("Encrypted file is truncated or corrupted");
+
         let len = tokio::fs::metadata(&self.manager.path).await?.len();
 
-        let len = usize::try_from(len).expect("File size is bigger than system usize") - 12 - 16;
+        // NOTE: Shorter than the header, nonce and tag means it was cut while being written
+        let len = usize::try_from(len)
+            .ok()
+            .and_then(|len| len.checked_sub(self.header.len() + 12 + 16))
+            .ok_or(TRUNCATED)?;
 
         let mut buffer = vec![0; len];
 
@@ -111,22 +158,47 @@ impl EncryptedSerdeIO for EncryptedFileManager {
     }
 
     async fn read_enc(&mut self, buffer: &mut [u8]) -> Result<usize, crate::io::Error> {
+        self.read_enc_in(buffer, &[]).await
+    }
+
+    async fn read_enc_in(
+        &mut self,
+        buffer: &mut [u8],
+        domain: &[u8],
+    ) -> Result<usize, crate::io::Error> {
         let mut nonce = [0; 12];
         let mut tag = [0; 16];
 
         let mut file = tokio::fs::File::open(&self.manager.path).await?;
 
+        let mut header = vec![0; self.header.len()];
+        file.read_exact(&mut header).await?;
+
         file.read_exact(&mut nonce).await?;
         file.read_exact(buffer).await?;
         file.read_exact(&mut tag).await?;
 
-        self.cipher.decrypt(buffer, &nonce, &tag)?;
+        self.cipher.decrypt_in(buffer, &nonce, &tag, domain)?;
 
         Ok(buffer.len())
     }
 }
 
+static PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keeps the config, contacts and history in the folder instead of the one of the user.
+///
+/// Useful to isolate tests. Only the first call is used, and it must happen before any of
+/// them is read. Returns the folder back if one was already set.
+pub fn set_path(path: PathBuf) -> Result<(), PathBuf> {
+    PATH.set(path)
+}
+
 fn path() -> Result<PathBuf, crate::io::Error> {
+    if let Some(path) = PATH.get() {
+        return Ok(path.clone());
+    }
+
     let dirs = directories::ProjectDirs::from("dev", "msambartolomeo", "aporture")
         .ok_or(crate::io::Error::Config)?;
 
@@ -149,3 +221,40 @@ impl Display for EncryptedFileManager {
 pub fn downloads_directory() -> Option<PathBuf> {
     directories::UserDirs::new().and_then(|dirs| dirs.download_dir().map(Path::to_path_buf))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::Hash;
+
+    #[tokio::test]
+    async fn test_truncated_file_is_rejected() {
+        let dir = tempfile::tempdir().expect("Temporary directory");
+        let path = dir.path().join("file.app");
+
+        let mut manager =
+            EncryptedFileManager::with_header(path.clone(), Cipher::new(&[0; 32]), b"APP".to_vec());
+        manager.write_ser_enc(&Hash([1; 32])).await.expect("Write");
+
+        let written = tokio::fs::read(&path).await.expect("Read");
+
+        for len in [0, 3, 3 + 12 + 16 - 1] {
+            tokio::fs::write(&path, &written[..len])
+                .await
+                .expect("Truncate");
+
+            let result = manager.read_ser_enc::<Hash>().await;
+
+            assert!(matches!(result, Err(crate::io::Error::Custom(_))), "{len}");
+        }
+
+        // NOTE: Long enough to parse but missing content, so the tag does not match
+        tokio::fs::write(&path, &written[..written.len() - 1])
+            .await
+            .expect("Truncate");
+
+        let result = manager.read_ser_enc::<Hash>().await;
+
+        assert!(matches!(result, Err(crate::io::Error::Cipher(_))));
+    }
+}
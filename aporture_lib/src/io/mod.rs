@@ -30,6 +30,16 @@ pub enum Error {
     #[error("Unexpected message received from network")]
     UnexpectedMessage,
 
+    #[error("The peer cancelled the transfer")]
+    Cancelled,
+
+    #[error("The server stopped relaying, the pairing reached the most it relays")]
+    RelayLimit,
+
+    #[cfg(feature = "full")]
+    #[error("Peer certificate does not match the negotiated one")]
+    CertificateMismatch,
+
     #[error("{0}")]
     Custom(&'static str),
 }
@@ -42,6 +52,37 @@ impl<'a> From<message::Error<'a>> for Error {
             message::ErrorKind::CipherExpected
             | message::ErrorKind::InsufficientBuffer
             | message::ErrorKind::InvalidMessage => Self::UnexpectedMessage,
+            message::ErrorKind::Cancelled => Self::Cancelled,
+            message::ErrorKind::RelayLimit => Self::RelayLimit,
+        }
+    }
+}
+
+impl Error {
+    /// Whether the peer ended the connection because it cancelled the transfer,
+    /// either with a cancel message or by closing the QUIC connection.
+    #[must_use]
+    pub fn is_cancelled(&self) -> bool {
+        match self {
+            Self::Cancelled => true,
+            #[cfg(feature = "full")]
+            Self::Quic(e) => net::quic::is_cancelled(e),
+            #[cfg(feature = "full")]
+            Self::IO(e) => net::quic::connection_error(e).is_some_and(net::quic::is_cancelled),
+            _ => false,
+        }
+    }
+
+    /// Whether the connection with the peer was lost, for example after a network change it
+    /// could not recover from.
+    #[must_use]
+    pub fn is_connection_lost(&self) -> bool {
+        match self {
+            #[cfg(feature = "full")]
+            Self::Quic(e) => net::quic::is_lost(e),
+            #[cfg(feature = "full")]
+            Self::IO(e) => net::quic::connection_error(e).is_some_and(net::quic::is_lost),
+            _ => false,
         }
     }
 }
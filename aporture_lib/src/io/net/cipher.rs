@@ -5,26 +5,31 @@ use tokio::net::TcpStream;
 
 use super::peer::{Encryptable, Peer};
 use super::plain::NetworkPeer;
-use crate::crypto::cipher::Cipher;
+use crate::crypto::cipher::{Cipher, Rekey};
 
 pub struct EncryptedNetworkPeer {
     cipher: Arc<Cipher>,
+    rekey: Box<Rekey>,
     peer: NetworkPeer,
 }
 
 impl NetworkPeer {
     #[must_use]
-    pub const fn add_cipher(self, cipher: Arc<Cipher>) -> EncryptedNetworkPeer {
-        EncryptedNetworkPeer { cipher, peer: self }
+    pub fn add_cipher(self, cipher: Arc<Cipher>) -> EncryptedNetworkPeer {
+        let rekey = Box::new(Rekey::new(&cipher));
+
+        EncryptedNetworkPeer {
+            cipher,
+            rekey,
+            peer: self,
+        }
     }
 }
 
 impl EncryptedNetworkPeer {
     #[must_use]
-    pub const fn new(stream: TcpStream, cipher: Arc<Cipher>) -> Self {
-        let peer = NetworkPeer::new(stream);
-
-        Self { cipher, peer }
+    pub fn new(stream: TcpStream, cipher: Arc<Cipher>) -> Self {
+        NetworkPeer::new(stream).add_cipher(cipher)
     }
 
     #[must_use]
@@ -47,4 +52,59 @@ impl Encryptable for EncryptedNetworkPeer {
     fn cipher(&self) -> impl AsRef<Cipher> {
         &self.cipher
     }
+
+    fn rekey(&mut self) -> Option<&mut Rekey> {
+        Some(&mut self.rekey)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::EncryptedSerdeIO;
+    use crate::protocol::Hash;
+
+    /// Both ends of a connection over the loopback interface.
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Bind");
+        let address = listener.local_addr().expect("Address");
+
+        let (connected, accepted) = tokio::join!(TcpStream::connect(address), listener.accept());
+
+        (connected.expect("Connect"), accepted.expect("Accept").0)
+    }
+
+    #[tokio::test]
+    async fn test_peer_follows_rekeys() {
+        let (sender, receiver) = connected_pair().await;
+
+        let cipher = Arc::new(Cipher::new(&[b'a'; 32]));
+        let mut sender = EncryptedNetworkPeer::new(sender, cipher.clone());
+        let mut receiver = EncryptedNetworkPeer::new(receiver, cipher);
+
+        sender
+            .rekey()
+            .expect("Supports rekey")
+            .set_interval(Some(2));
+
+        for i in 0..5 {
+            sender.write_ser_enc(&Hash([i; 32])).await.expect("Write");
+            sender.write_enc(&mut [i; 64]).await.expect("Write");
+        }
+
+        for i in 0..5 {
+            let Hash(hash) = receiver.read_ser_enc::<Hash>().await.expect("Read");
+            assert_eq!([i; 32], hash);
+
+            let mut buffer = [0; 64];
+            let n = receiver.read_enc(&mut buffer).await.expect("Read");
+            assert_eq!([i; 64], buffer[..n]);
+        }
+
+        receiver.write_ser_enc(&Hash([9; 32])).await.expect("Write");
+        let Hash(hash) = sender.read_ser_enc::<Hash>().await.expect("Read");
+        assert_eq!([9; 32], hash);
+    }
 }
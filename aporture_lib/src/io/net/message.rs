@@ -8,6 +8,76 @@ const FLAG_SIZE: usize = 1;
 const NONCE_SIZE: usize = 12;
 const TAG_SIZE: usize = 16;
 
+/// Set on the flag when it also carries a check of the header
+const CHECKED: u8 = 0b1000_0000;
+const KIND_MASK: u8 = 0b0000_0011;
+/// Kind of the messages without content that close the connection, the length tells why
+const CANCEL: u8 = 3;
+/// Length of the closing message of the server when the pairing reached its relay limit
+const RELAY_LIMIT: u16 = 1;
+
+/// Size of the length and flag every message starts with
+pub const HEADER_SIZE: usize = LENGTH_SIZE + FLAG_SIZE;
+/// Size of the largest message, encrypted with a full payload
+pub const MAX_MESSAGE_SIZE: usize = HEADER_SIZE + NONCE_SIZE + u16::MAX as usize + TAG_SIZE;
+
+/// Flag of a checked message, its kind plus a 5 bit CRC of the length and kind.
+const fn checked_flag(length: [u8; LENGTH_SIZE], kind: u8) -> u8 {
+    let header = [length[0], length[1], kind];
+
+    let mut crc = 0u8;
+    let mut i = 0;
+    while i < header.len() {
+        crc ^= header[i];
+
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x80 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x07
+            };
+            bit += 1;
+        }
+        i += 1;
+    }
+
+    CHECKED | ((crc & 0b1_1111) << 2) | kind
+}
+
+/// Kind of message of the flag, `None` if it is unknown or its check does not match.
+const fn kind(flag: u8, length: [u8; LENGTH_SIZE]) -> Option<u8> {
+    let kind = if flag & CHECKED == 0 {
+        flag
+    } else {
+        flag & KIND_MASK
+    };
+
+    if kind > CANCEL || (flag & CHECKED != 0 && flag != checked_flag(length, kind)) {
+        return None;
+    }
+
+    Some(kind)
+}
+
+/// Size of the whole message starting with the header, `None` if its flag is not valid.
+#[must_use]
+pub const fn message_size(header: [u8; HEADER_SIZE]) -> Option<usize> {
+    let length = [header[0], header[1]];
+
+    let Some(kind) = kind(header[2], length) else {
+        return None;
+    };
+
+    let content = u16::from_be_bytes(length) as usize;
+
+    Some(match kind {
+        CANCEL => HEADER_SIZE,
+        0 => HEADER_SIZE + content,
+        _ => HEADER_SIZE + NONCE_SIZE + content + TAG_SIZE,
+    })
+}
+
 #[derive(Debug)]
 pub struct Message<'a> {
     length: [u8; LENGTH_SIZE],
@@ -63,8 +133,14 @@ impl<'a> Message<'a> {
     }
 
     #[cfg(feature = "full")]
-    #[allow(clippy::cast_possible_truncation)] // As truncation is checked explicitly
     pub fn new_encrypted(content: &'a mut [u8], cipher: &Cipher) -> Self {
+        Self::new_encrypted_in(content, cipher, &[])
+    }
+
+    /// Encrypts the content bound to the domain, see [`Cipher::encrypt_in`].
+    #[cfg(feature = "full")]
+    #[allow(clippy::cast_possible_truncation)] // As truncation is checked explicitly
+    pub fn new_encrypted_in(content: &'a mut [u8], cipher: &Cipher, domain: &[u8]) -> Self {
         let length = content.len();
 
         assert!(
@@ -74,7 +150,7 @@ impl<'a> Message<'a> {
 
         let length = (length as u16).to_be_bytes();
 
-        let (nonce, tag) = cipher.encrypt(content);
+        let (nonce, tag) = cipher.encrypt_in(content, domain);
 
         Self {
             length,
@@ -83,11 +159,56 @@ impl<'a> Message<'a> {
         }
     }
 
+    /// Message telling the peer that the sender cancelled, read as [`ErrorKind::Cancelled`]
+    /// whatever the peer expected instead.
+    #[must_use]
+    pub fn cancel() -> Message<'static> {
+        let mut message = Message::new(&mut []);
+        message.encrypted = EncryptedContent::Plain { bit: [CANCEL] };
+        message.set_checked();
+
+        message
+    }
+
+    /// Message the server closes both peers with when the pairing reached its relay limit,
+    /// read as [`ErrorKind::RelayLimit`].
+    #[must_use]
+    pub fn relay_limit() -> Message<'static> {
+        let mut message = Message::new(&mut []);
+        message.length = RELAY_LIMIT.to_be_bytes();
+        message.encrypted = EncryptedContent::Plain { bit: [CANCEL] };
+        message.set_checked();
+
+        message
+    }
+
     #[must_use]
     pub const fn into_buf(self) -> MessageBuffer<'a> {
         MessageBuffer::new(self)
     }
 
+    /// Marks the message as the first one encrypted with the next key of the sender.
+    #[cfg(feature = "full")]
+    pub const fn mark_rekeyed(&mut self) {
+        if let EncryptedContent::Encrypted { bit, .. } = &mut self.encrypted {
+            *bit = if bit[0] & CHECKED == 0 {
+                [2]
+            } else {
+                [checked_flag(self.length, 2)]
+            };
+        }
+    }
+
+    /// Adds a check of the length to the flag, so a corrupted header is rejected as soon as it
+    /// arrives instead of desynchronizing the stream. Peers check every message they write, but
+    /// readers still accept both kinds of flag.
+    pub const fn set_checked(&mut self) {
+        let (EncryptedContent::Plain { bit } | EncryptedContent::Encrypted { bit, .. }) =
+            &mut self.encrypted;
+
+        *bit = [checked_flag(self.length, bit[0] & KIND_MASK)];
+    }
+
     #[must_use]
     pub const fn is_encrypted(&self) -> bool {
         match self.encrypted {
@@ -103,6 +224,18 @@ impl<'a> Message<'a> {
         }
     }
 
+    /// Whether the message closes the connection instead of carrying content.
+    const fn is_closing(&self) -> bool {
+        let bit = self.get_encryption_bit()[0];
+        let kind = if bit & CHECKED == 0 {
+            bit
+        } else {
+            bit & KIND_MASK
+        };
+
+        kind == CANCEL
+    }
+
     fn length(&self) -> usize {
         u16::from_be_bytes(self.length).into()
     }
@@ -137,6 +270,10 @@ pub enum ErrorKind {
     InsufficientBuffer,
     #[error("The message received is invalid")]
     InvalidMessage,
+    #[error("The peer cancelled")]
+    Cancelled,
+    #[error("The server reached its relay limit")]
+    RelayLimit,
 }
 
 #[derive(Debug, Error)]
@@ -184,8 +321,20 @@ impl<'a> MessageBuffer<'a> {
         Ok(length)
     }
 
+    /// Whether the sender replaced its key before encrypting the message.
+    #[must_use]
+    pub const fn is_rekeyed(&self) -> bool {
+        self.message.get_encryption_bit()[0] & KIND_MASK == 2
+    }
+
     #[cfg(feature = "full")]
     pub fn consume_encrypted(self, cipher: &Cipher) -> Result<usize, Error<'a>> {
+        self.consume_encrypted_in(cipher, &[])
+    }
+
+    /// Decrypts the content bound to the domain, see [`Cipher::decrypt_in`].
+    #[cfg(feature = "full")]
+    pub fn consume_encrypted_in(self, cipher: &Cipher, domain: &[u8]) -> Result<usize, Error<'a>> {
         if let Some(err) = self.error {
             return Err(Error(err, self));
         }
@@ -196,7 +345,7 @@ impl<'a> MessageBuffer<'a> {
         match &self.message.encrypted {
             EncryptedContent::Encrypted { nonce, tag, .. } => {
                 cipher
-                    .decrypt(content, nonce, tag)
+                    .decrypt_in(content, nonce, tag, domain)
                     .map_err(move |e| Error(e.into(), self))?;
             }
             EncryptedContent::Plain { .. } => (),
@@ -209,6 +358,8 @@ impl<'a> MessageBuffer<'a> {
         match state {
             State::Length => LENGTH_SIZE + FLAG_SIZE,
             State::Encrypt => FLAG_SIZE,
+            // NOTE: Closing messages have no content, their length only tells why
+            State::Content if self.message.is_closing() => 0,
             State::Nonce if self.message.is_encrypted() => NONCE_SIZE + self.message.length() + TAG_SIZE,
             State::Content if self.message.is_encrypted() => self.message.length(),
             State::Tag if self.message.is_encrypted() => TAG_SIZE,
@@ -279,7 +430,7 @@ impl Buf for MessageBuffer<'_> {
                     if self.message.is_encrypted() {
                         State::Tag
                     } else {
-                        self.cursor = self.message.length();
+                        self.cursor = self.total_remaining(State::Content);
                         break;
                     }
                 }
@@ -320,6 +471,22 @@ unsafe impl BufMut for MessageBuffer<'_> {
             self.state = match self.state {
                 State::Length => State::Encrypt,
                 State::Encrypt => {
+                    let bit = self.message.get_encryption_bit();
+
+                    let Some(kind) = kind(bit[0], self.message.length) else {
+                        self.error = Some(ErrorKind::InvalidMessage);
+                        break;
+                    };
+
+                    if kind == CANCEL {
+                        self.error = Some(match u16::from_be_bytes(self.message.length) {
+                            0 => ErrorKind::Cancelled,
+                            RELAY_LIMIT => ErrorKind::RelayLimit,
+                            _ => ErrorKind::InvalidMessage,
+                        });
+                        break;
+                    }
+
                     let content_length = self.message.length();
                     let available_length = self.message.content.len();
                     if available_length < content_length {
@@ -327,22 +494,18 @@ unsafe impl BufMut for MessageBuffer<'_> {
                         break;
                     }
 
-                    match self.message.get_encryption_bit() {
-                        [0] => {
-                            self.message.encrypted = EncryptedContent::plain();
-
-                            State::Content
-                        }
-                        [1] => {
-                            self.message.encrypted =
-                                EncryptedContent::encrypted([0; NONCE_SIZE], [0; TAG_SIZE]);
-
-                            State::Nonce
-                        }
-                        _ => {
-                            self.error = Some(ErrorKind::InvalidMessage);
-                            break;
-                        }
+                    if kind == 0 {
+                        self.message.encrypted = EncryptedContent::Plain { bit };
+
+                        State::Content
+                    } else {
+                        self.message.encrypted = EncryptedContent::Encrypted {
+                            bit,
+                            nonce: [0; NONCE_SIZE],
+                            tag: [0; TAG_SIZE],
+                        };
+
+                        State::Nonce
                     }
                 }
                 State::Nonce => State::Content,
@@ -598,4 +761,130 @@ mod test {
 
         Ok(())
     }
+
+    fn feed<'a>(input: &[u8], buffer: &'a mut [u8]) -> MessageBuffer<'a> {
+        let mut writer = Message::new(buffer).into_buf().writer();
+
+        let mut ptr = input;
+        while let Ok(n @ 1..) = writer.write(ptr) {
+            ptr = &ptr[n..];
+        }
+
+        writer.into_inner()
+    }
+
+    #[test]
+    fn writing_checked() -> Result<(), Box<dyn std::error::Error>> {
+        let mut input = *b"Hello";
+
+        let mut message = Message::new(&mut input);
+        message.set_checked();
+
+        let mut output = Vec::new();
+        message.into_buf().reader().read_to_end(&mut output)?;
+
+        assert_ne!(0, output[2] & CHECKED);
+
+        let mut buffer = [0; 1000];
+        let n = feed(&output, &mut buffer)
+            .consume()
+            .map_err(Error::ignore)?;
+
+        assert_eq!(b"Hello", &buffer[..n]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_checked_header_is_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        let mut input = *b"Hello";
+
+        let mut message = Message::new(&mut input);
+        message.set_checked();
+
+        let mut output = Vec::new();
+        message.into_buf().reader().read_to_end(&mut output)?;
+
+        for bit in 0..(LENGTH_SIZE + FLAG_SIZE) * 8 {
+            let mut corrupted = output.clone();
+            corrupted[bit / 8] ^= 1 << (bit % 8);
+
+            let mut buffer = [0; 1000];
+            let result = feed(&corrupted, &mut buffer)
+                .consume()
+                .map_err(Error::ignore);
+
+            assert!(
+                matches!(result, Err(ErrorKind::InvalidMessage)),
+                "bit {bit}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_is_reported() -> Result<(), Box<dyn std::error::Error>> {
+        let mut output = Vec::new();
+        Message::cancel()
+            .into_buf()
+            .reader()
+            .read_to_end(&mut output)?;
+
+        let mut buffer = [0; 1000];
+        let result = feed(&output, &mut buffer).consume().map_err(Error::ignore);
+
+        assert!(matches!(result, Err(ErrorKind::Cancelled)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn relay_limit_is_reported() -> Result<(), Box<dyn std::error::Error>> {
+        let mut output = Vec::new();
+        Message::relay_limit()
+            .into_buf()
+            .reader()
+            .read_to_end(&mut output)?;
+
+        assert_eq!(HEADER_SIZE, output.len());
+        assert_eq!(
+            Some(HEADER_SIZE),
+            message_size([output[0], output[1], output[2]])
+        );
+
+        let mut buffer = [0; 1000];
+        let result = feed(&output, &mut buffer).consume().map_err(Error::ignore);
+
+        assert!(matches!(result, Err(ErrorKind::RelayLimit)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn garbage_flag_is_rejected() {
+        let length = 5u16.to_be_bytes();
+        let checked = [0, 1, 2].map(|kind| checked_flag(length, kind));
+
+        for flag in (3..=u8::MAX).filter(|f| !checked.contains(f)) {
+            let mut buffer = [0; 1000];
+            let result = feed(&[0, 5, flag, 72, 101, 108, 108, 111], &mut buffer)
+                .consume()
+                .map_err(Error::ignore);
+
+            assert!(
+                matches!(result, Err(ErrorKind::InvalidMessage)),
+                "flag {flag}"
+            );
+        }
+
+        let valid = checked[0];
+        let mut buffer = [0; 1000];
+        let n = feed(&[0, 5, valid, 72, 101, 108, 108, 111], &mut buffer)
+            .consume()
+            .map_err(Error::ignore)
+            .expect("Checked plain message");
+
+        assert_eq!(b"Hello", &buffer[..n]);
+    }
 }
@@ -1,11 +1,13 @@
 use bytes::BufMut;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use crate::net::message::Message;
+use crate::net::message::{Message, MessageBuffer};
 use crate::parser::SerdeIO;
 
 #[cfg(feature = "full")]
-use crate::crypto::cipher::Cipher;
+use crate::crypto::cipher::{Cipher, Rekey};
+#[cfg(feature = "full")]
+use crate::net::message;
 #[cfg(feature = "full")]
 use crate::parser::EncryptedSerdeIO;
 
@@ -17,6 +19,75 @@ pub trait Peer {
 #[cfg(feature = "full")]
 pub trait Encryptable {
     fn cipher(&self) -> impl AsRef<Cipher>;
+
+    /// Keys replaced during the connection, `None` if it always uses the same one.
+    fn rekey(&mut self) -> Option<&mut Rekey> {
+        None
+    }
+
+    /// Whether messages are sent without the cipher, only for already encrypted connections.
+    fn is_plain(&self) -> bool {
+        false
+    }
+}
+
+/// Tells the peer that this side cancelled and closes the writing half. Failures are ignored,
+/// as the connection is dropped right after anyway.
+pub async fn send_cancel<T: Peer>(peer: &mut T) {
+    send_closing(peer, Message::cancel()).await;
+}
+
+/// Tells the peer that the server stopped relaying as the pairing reached its limit and
+/// closes the writing half. Failures are ignored, as the connection is dropped right after.
+pub async fn send_relay_limit<T: Peer>(peer: &mut T) {
+    send_closing(peer, Message::relay_limit()).await;
+}
+
+async fn send_closing<T: Peer>(peer: &mut T, message: Message<'static>) {
+    let mut buf = message.into_buf();
+
+    let mut writer = peer.writer();
+    let _ = writer.write_all_buf(&mut buf).await;
+    let _ = writer.shutdown().await;
+}
+
+/// Reads from the peer until the message is complete, failing if the peer closes first.
+async fn read_message<T: Peer + Send>(
+    peer: &mut T,
+    buf: &mut MessageBuffer<'_>,
+) -> Result<(), crate::io::Error> {
+    while buf.has_remaining_mut() {
+        if peer.reader().read_buf(buf).await? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+
+    Ok(())
+}
+
+impl<T: Peer> Peer for &mut T {
+    fn writer(&mut self) -> impl AsyncWriteExt + Unpin + Send {
+        (**self).writer()
+    }
+
+    fn reader(&mut self) -> impl AsyncReadExt + Unpin + Send {
+        (**self).reader()
+    }
+}
+
+#[cfg(feature = "full")]
+impl<T: Encryptable> Encryptable for &mut T {
+    fn cipher(&self) -> impl AsRef<Cipher> {
+        (**self).cipher()
+    }
+
+    fn rekey(&mut self) -> Option<&mut Rekey> {
+        (**self).rekey()
+    }
+
+    fn is_plain(&self) -> bool {
+        (**self).is_plain()
+    }
 }
 
 impl<T: Peer + Send> SerdeIO for T {
@@ -26,7 +97,8 @@ impl<T: Peer + Send> SerdeIO for T {
     ) -> Result<(), crate::io::Error> {
         let mut serialized = input.serialize_to();
 
-        let message = Message::new(&mut serialized);
+        let mut message = Message::new(&mut serialized);
+        message.set_checked();
 
         let mut buf = message.into_buf();
 
@@ -41,23 +113,20 @@ impl<T: Peer + Send> SerdeIO for T {
 
             let mut buf = message.into_buf();
 
-            while buf.has_remaining_mut() {
-                self.reader().read_buf(&mut buf).await?;
-            }
+            read_message(self, &mut buf).await?;
 
             let n = buf.consume()?;
 
             Ok(P::deserialize_from(&buffer[..n])?)
         } else {
+            // NOTE: The length prefix is 16 bits, so no message can ask for a bigger buffer
             let mut buffer = vec![0; u16::MAX as usize];
 
             let message = Message::new(&mut buffer);
 
             let mut buf = message.into_buf();
 
-            while buf.has_remaining_mut() {
-                self.reader().read_buf(&mut buf).await?;
-            }
+            read_message(self, &mut buf).await?;
 
             let n = buf.consume()?;
 
@@ -74,7 +143,7 @@ impl<T: Peer + Encryptable + Send> EncryptedSerdeIO for T {
     ) -> Result<(), crate::io::Error> {
         let mut serialized = input.serialize_to();
 
-        let message = Message::new_encrypted(&mut serialized, self.cipher().as_ref());
+        let message = encrypt(self, &mut serialized, &[]);
 
         let mut buf = message.into_buf();
 
@@ -84,7 +153,15 @@ impl<T: Peer + Encryptable + Send> EncryptedSerdeIO for T {
     }
 
     async fn write_enc(&mut self, input: &mut [u8]) -> Result<(), crate::io::Error> {
-        let message = Message::new_encrypted(input, self.cipher().as_ref());
+        self.write_enc_in(input, &[]).await
+    }
+
+    async fn write_enc_in(
+        &mut self,
+        input: &mut [u8],
+        domain: &[u8],
+    ) -> Result<(), crate::io::Error> {
+        let message = encrypt(self, input, domain);
 
         self.writer().write_all_buf(&mut message.into_buf()).await?;
 
@@ -99,11 +176,9 @@ impl<T: Peer + Encryptable + Send> EncryptedSerdeIO for T {
 
             let mut buf = message.into_buf();
 
-            while buf.has_remaining_mut() {
-                self.reader().read_buf(&mut buf).await?;
-            }
+            read_message(self, &mut buf).await?;
 
-            let n = buf.consume_encrypted(self.cipher().as_ref())?;
+            let n = decrypt(self, buf, &[])?;
 
             Ok(P::deserialize_from(&buffer[..n])?)
         } else {
@@ -113,27 +188,78 @@ impl<T: Peer + Encryptable + Send> EncryptedSerdeIO for T {
 
             let mut buf = message.into_buf();
 
-            while buf.has_remaining_mut() {
-                self.reader().read_buf(&mut buf).await?;
-            }
+            read_message(self, &mut buf).await?;
 
-            let n = buf.consume_encrypted(self.cipher().as_ref())?;
+            let n = decrypt(self, buf, &[])?;
 
             Ok(P::deserialize_from(&buffer[..n])?)
         }
     }
 
     async fn read_enc(&mut self, buffer: &mut [u8]) -> Result<usize, crate::io::Error> {
+        self.read_enc_in(buffer, &[]).await
+    }
+
+    async fn read_enc_in(
+        &mut self,
+        buffer: &mut [u8],
+        domain: &[u8],
+    ) -> Result<usize, crate::io::Error> {
         let message = Message::new_encrypted(buffer, self.cipher().as_ref());
 
         let mut buf = message.into_buf();
 
-        while buf.has_remaining_mut() {
-            self.reader().read_buf(&mut buf).await?;
-        }
+        read_message(self, &mut buf).await?;
 
-        let n = buf.consume_encrypted(self.cipher().as_ref())?;
+        let n = decrypt(self, buf, domain)?;
 
         Ok(n)
     }
 }
+
+/// Encrypts the message with the current sending key, marking it if the key was just replaced.
+/// Plain peers send it as is.
+///
+/// Every message carries the check of its header, as plain ones have nothing else that would
+/// reject a corrupted length before it desynchronizes the stream.
+#[cfg(feature = "full")]
+fn encrypt<'a, T: Encryptable>(peer: &mut T, content: &'a mut [u8], domain: &[u8]) -> Message<'a> {
+    let mut message = if peer.is_plain() {
+        Message::new(content)
+    } else if let Some(rekey) = peer.rekey() {
+        let (cipher, rekeyed) = rekey.sending();
+        let mut message = Message::new_encrypted_in(content, cipher, domain);
+
+        if rekeyed {
+            message.mark_rekeyed();
+        }
+
+        message
+    } else {
+        Message::new_encrypted_in(content, peer.cipher().as_ref(), domain)
+    };
+
+    message.set_checked();
+
+    message
+}
+
+/// Decrypts the message with the current receiving key, following the sender if it replaced it.
+/// Plain peers only accept unencrypted messages.
+#[cfg(feature = "full")]
+fn decrypt<'a, T: Encryptable>(
+    peer: &mut T,
+    buf: MessageBuffer<'a>,
+    domain: &[u8],
+) -> Result<usize, message::Error<'a>> {
+    if peer.is_plain() {
+        return buf.consume();
+    }
+
+    let rekeyed = buf.is_rekeyed();
+
+    match peer.rekey() {
+        Some(rekey) => buf.consume_encrypted_in(rekey.receiving(rekeyed), domain),
+        None => buf.consume_encrypted_in(peer.cipher().as_ref(), domain),
+    }
+}
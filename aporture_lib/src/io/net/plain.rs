@@ -14,7 +14,7 @@ impl NetworkPeer {
         Self { stream }
     }
 
-    pub fn inner(&mut self) -> &mut TcpStream {
+    pub const fn inner(&mut self) -> &mut TcpStream {
         &mut self.stream
     }
 }
@@ -28,3 +28,96 @@ impl Peer for NetworkPeer {
         &mut self.stream
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::parser::SerdeIO;
+    use crate::protocol::{Hash, PairingResponseCode};
+
+    /// Both ends of a connection over the loopback interface.
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Bind");
+        let address = listener.local_addr().expect("Address");
+
+        let (connected, accepted) = tokio::join!(TcpStream::connect(address), listener.accept());
+
+        (connected.expect("Connect"), accepted.expect("Accept").0)
+    }
+
+    #[tokio::test]
+    async fn test_vectors_are_framed_by_their_length() {
+        let (sender, receiver) = connected_pair().await;
+
+        let mut sender = NetworkPeer::new(sender);
+        let mut receiver = NetworkPeer::new(receiver);
+
+        let many = (0..500)
+            .map(|port| SocketAddr::from(([10, 0, 0, 1], port)))
+            .collect::<Vec<_>>();
+        let sent = [Vec::new(), vec![SocketAddr::from(([10, 0, 0, 1], 0))], many];
+
+        for addresses in &sent {
+            sender.write_ser(addresses).await.expect("Write");
+            sender
+                .write_ser(&PairingResponseCode::Ok)
+                .await
+                .expect("Write");
+        }
+
+        for addresses in sent {
+            let read = receiver.read_ser::<Vec<SocketAddr>>().await.expect("Read");
+            assert_eq!(addresses, read);
+
+            // NOTE: Nothing of the next message was consumed with the vector
+            let code = receiver
+                .read_ser::<PairingResponseCode>()
+                .await
+                .expect("Read");
+            assert_eq!(PairingResponseCode::Ok, code);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_length_is_rejected() {
+        let (mut sender, receiver) = connected_pair().await;
+
+        let mut receiver = NetworkPeer::new(receiver);
+
+        // NOTE: Largest possible length, unencrypted, far bigger than the response code
+        sender.write_all(&[0xff, 0xff, 0]).await.expect("Write");
+
+        let result = receiver.read_ser::<PairingResponseCode>().await;
+
+        assert!(matches!(result, Err(crate::io::Error::UnexpectedMessage)));
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_length_is_rejected_before_the_content() {
+        let (sender, mut relay) = connected_pair().await;
+        let (mut relay_out, receiver) = connected_pair().await;
+
+        let mut sender = NetworkPeer::new(sender);
+        let mut receiver = NetworkPeer::new(receiver);
+
+        sender.write_ser(&Hash([7; 32])).await.expect("Write");
+        drop(sender);
+
+        let mut frame = Vec::new();
+        relay.read_to_end(&mut frame).await.expect("Read");
+
+        // NOTE: One bit flipped in transit, which would otherwise cut the content short
+        frame[1] ^= 1;
+
+        relay_out.write_all(&frame).await.expect("Write");
+        relay_out.shutdown().await.expect("Shutdown");
+
+        let result = receiver.read_ser::<Hash>().await;
+
+        assert!(matches!(result, Err(crate::io::Error::UnexpectedMessage)));
+    }
+}
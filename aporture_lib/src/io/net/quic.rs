@@ -5,13 +5,14 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use quinn::crypto::rustls::QuicClientConfig;
-use quinn::{ClientConfig, ServerConfig, TokioRuntime, TransportConfig};
+use quinn::rustls::pki_types::CertificateDer;
+use quinn::{ClientConfig, IdleTimeout, ServerConfig, TokioRuntime, TransportConfig};
 use quinn::{Connection, Endpoint, EndpointConfig, RecvStream, SendStream};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::task::JoinHandle;
 
 use crate::crypto::cert::{Certificate, CertificateKey};
-use crate::crypto::cipher::Cipher;
+use crate::crypto::cipher::{Cipher, Rekey};
 use crate::net::peer::{Encryptable, Peer};
 
 #[derive(Debug)]
@@ -20,10 +21,29 @@ pub struct QuicConnection {
     cipher: Arc<Cipher>,
     endpoint: Endpoint,
     connection: Connection,
-    keepalive_handle: JoinHandle<()>,
+    keepalive: Keepalive,
+    /// Task following the network the connection runs on, see `follow_network`
+    network: Option<Keepalive>,
     kind: Kind,
 }
 
+/// Task keeping the path to the peer open, stopped when dropped so a cancelled
+/// connection attempt or transfer does not leave it running.
+#[derive(Debug)]
+pub struct Keepalive(JoinHandle<()>);
+
+impl From<JoinHandle<()>> for Keepalive {
+    fn from(handle: JoinHandle<()>) -> Self {
+        Self(handle)
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
 #[derive(Debug)]
 enum Kind {
     Server,
@@ -33,35 +53,99 @@ enum Kind {
 #[derive(Debug)]
 pub struct QuicNetworkPeer {
     cipher: Arc<Cipher>,
+    rekey: Box<Rekey>,
+    plain: bool,
     sender: SendStream,
     receiver: RecvStream,
 }
 
+impl QuicNetworkPeer {
+    /// Skips the application cipher, leaving the data protected only by the QUIC encryption.
+    /// Both peers must enable it, messages are not understood otherwise.
+    pub const fn set_plain(&mut self, enabled: bool) {
+        self.plain = enabled;
+    }
+}
+
+/// Application code of a connection closed because the transfer was cancelled
+const CANCELLED: u32 = 1;
+
+/// Application code of a connection closed because the address it used is gone, so both
+/// peers reconnect right away instead of waiting for it to time out
+const NETWORK_CHANGED: u32 = 2;
+
+/// Time a connection survives without hearing from the peer, the QUIC idle timeout
+pub const DEFAULT_RECONNECT_WINDOW: Duration = Duration::from_secs(30);
+
+/// Frequent enough that long pauses between large files never reach the idle timeout
+pub const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Liveness settings of the QUIC connection with the peer.
+///
+/// The keep alive packets sent here are QUIC pings, which reset the idle timeout on both
+/// sides. They are independent of the raw UDP packets sent while the connection is being
+/// established to keep the NAT mapping open, which QUIC ignores and so never extend it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transport {
+    /// Time without hearing from the peer before the connection is considered lost
+    pub idle_timeout: Duration,
+    /// Interval between pings while idle, `None` disables them.
+    /// It is capped to half the idle timeout so a single lost ping does not drop the connection
+    pub keep_alive_interval: Option<Duration>,
+}
+
+impl Transport {
+    pub const DEFAULT: Self = Self {
+        idle_timeout: DEFAULT_RECONNECT_WINDOW,
+        keep_alive_interval: Some(DEFAULT_KEEP_ALIVE_INTERVAL),
+    };
+
+    fn keep_alive(self) -> Option<Duration> {
+        self.keep_alive_interval
+            .map(|i| i.min(self.idle_timeout / 2))
+    }
+
+    fn config(self) -> Arc<TransportConfig> {
+        let mut transport_config = TransportConfig::default();
+
+        transport_config.keep_alive_interval(self.keep_alive());
+        transport_config.max_idle_timeout(IdleTimeout::try_from(self.idle_timeout).ok());
+
+        Arc::new(transport_config)
+    }
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
 impl QuicConnection {
     pub async fn client(
         server_address: SocketAddr,
         socket: UdpSocket,
         cipher: Arc<Cipher>,
         certificate: Certificate,
-        keepalive_handle: JoinHandle<()>,
+        keepalive: Keepalive,
+        transport: Transport,
     ) -> Result<Self, crate::io::Error> {
         let mut config = ClientConfig::new(Arc::new(
             QuicClientConfig::try_from(
                 quinn::rustls::ClientConfig::builder()
-                    .with_root_certificates(certificate.0)
+                    .with_root_certificates(certificate.roots.clone())
                     .with_no_client_auth(),
             )
             .expect("Valid quinn client configuration"),
         ));
-        config.transport_config(Self::transport_config());
+        config.transport_config(transport.config());
 
         let mut endpoint = Endpoint::new(
             EndpointConfig::default(),
             None,
             socket,
             Arc::new(TokioRuntime),
-        )
-        .inspect_err(|_| keepalive_handle.abort())?;
+        )?;
         endpoint.set_default_client_config(config);
 
         let mut connection = Err(quinn::ConnectionError::TimedOut);
@@ -85,14 +169,28 @@ impl QuicConnection {
             }
         }
 
-        let connection = connection.inspect_err(|_| keepalive_handle.abort())?;
+        let connection = connection?;
+
+        // NOTE: Pin the exact certificate, so trusting it as root is not enough to impersonate the peer
+        let presented = connection
+            .peer_identity()
+            .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok());
+
+        if !presented.is_some_and(|chain| certificate.matches(&chain)) {
+            log::error!("Peer at {server_address} presented an unexpected certificate");
+
+            connection.close(0u16.into(), &[]);
+
+            return Err(crate::io::Error::CertificateMismatch);
+        }
 
         Ok(Self {
             connection_address: server_address,
             cipher,
             endpoint,
             connection,
-            keepalive_handle,
+            keepalive,
+            network: None,
             kind: Kind::Client,
         })
     }
@@ -102,42 +200,70 @@ impl QuicConnection {
         socket: UdpSocket,
         cipher: Arc<Cipher>,
         certificate: CertificateKey,
-        keepalive_handle: JoinHandle<()>,
+        keepalive: Keepalive,
+        transport: Transport,
     ) -> Result<Self, crate::io::Error> {
         let mut config = ServerConfig::with_single_cert(vec![certificate.cert], certificate.key)
             .expect("Valid quinn server configuration");
-        config.transport_config(Self::transport_config());
+        config.transport_config(transport.config());
+        // NOTE: Allow the peer to keep the connection when its network changes
+        config.migration(true);
 
         let endpoint = Endpoint::new(
             EndpointConfig::default(),
             Some(config),
             socket,
             Arc::new(TokioRuntime),
-        )
-        .inspect_err(|_| keepalive_handle.abort())?;
+        )?;
 
         let connection = endpoint
             .accept()
             .await
             .expect("Valid quinn endpoint configuration")
-            .await
-            .inspect_err(|_| keepalive_handle.abort())?;
+            .await?;
 
         Ok(Self {
             connection_address,
             cipher,
             endpoint,
             connection,
-            keepalive_handle,
+            keepalive,
+            network: None,
             kind: Kind::Server,
         })
     }
 
-    fn transport_config() -> Arc<TransportConfig> {
-        let transport_config = TransportConfig::default();
-        // NOTE: Keep default timeout
-        // transport_config.max_idle_timeout(None);
-        Arc::new(transport_config)
+    /// Whether this side opened the connection, the only one that can move it to a new path.
+    #[must_use]
+    pub const fn is_client(&self) -> bool {
+        matches!(self.kind, Kind::Client)
+    }
+
+    /// Local address of the socket the connection currently runs on.
+    pub fn local_address(&self) -> Result<SocketAddr, std::io::Error> {
+        self.endpoint.local_addr()
+    }
+
+    /// Address the peer is currently reached at, which changes when it migrates.
+    #[must_use]
+    pub fn remote_address(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+
+    /// Endpoint of the connection, to move it to a new socket with [`Endpoint::rebind`]
+    /// when the network changes, keeping the session.
+    pub(crate) fn endpoint(&self) -> Endpoint {
+        self.endpoint.clone()
+    }
+
+    /// Connection the task following the network closes if it can not be moved.
+    pub(crate) fn handle(&self) -> Connection {
+        self.connection.clone()
+    }
+
+    /// Keeps the task following the network until the connection ends.
+    pub(crate) fn set_network_watch(&mut self, watch: Keepalive) {
+        self.network = Some(watch);
     }
 
     pub async fn finish(self) {
@@ -148,7 +274,19 @@ impl QuicConnection {
             Kind::Client => self.connection.close(0u16.into(), &[]),
         }
 
-        self.keepalive_handle.abort();
+        drop(self.keepalive);
+        drop(self.network);
+
+        self.endpoint.wait_idle().await;
+    }
+
+    /// Closes the connection telling the peer the transfer was cancelled, which fails
+    /// whatever it is doing on any stream right away.
+    pub async fn cancel(self) {
+        self.connection.close(CANCELLED.into(), b"cancelled");
+
+        drop(self.keepalive);
+        drop(self.network);
 
         self.endpoint.wait_idle().await;
     }
@@ -161,6 +299,8 @@ impl QuicConnection {
 
         Ok(QuicNetworkPeer {
             cipher: Arc::clone(&self.cipher),
+            rekey: Box::new(Rekey::new(&self.cipher)),
+            plain: false,
             sender,
             receiver,
         })
@@ -186,4 +326,246 @@ impl Encryptable for QuicNetworkPeer {
     fn cipher(&self) -> impl AsRef<Cipher> {
         &self.cipher
     }
+
+    fn rekey(&mut self) -> Option<&mut Rekey> {
+        Some(&mut self.rekey)
+    }
+
+    fn is_plain(&self) -> bool {
+        self.plain
+    }
+}
+
+/// Whether the connection was closed by a peer that cancelled the transfer.
+#[must_use]
+pub fn is_cancelled(error: &quinn::ConnectionError) -> bool {
+    matches!(
+        error,
+        quinn::ConnectionError::ApplicationClosed(close) if close.error_code == CANCELLED.into()
+    )
+}
+
+/// Closes the connection because the address it used is gone, see [`is_lost`].
+pub(crate) fn close_network_changed(connection: &Connection) {
+    connection.close(NETWORK_CHANGED.into(), b"network changed");
+}
+
+/// Whether the connection stopped answering or was reset, which happens when either peer
+/// cannot be reached for longer than the idle timeout, or was closed by either peer as the
+/// address it used is gone.
+///
+/// A connection closed on this side mid-transfer is also lost, as only a network change
+/// closes it before the transfer ends.
+#[must_use]
+pub fn is_lost(error: &quinn::ConnectionError) -> bool {
+    match error {
+        quinn::ConnectionError::TimedOut
+        | quinn::ConnectionError::Reset
+        | quinn::ConnectionError::LocallyClosed => true,
+        quinn::ConnectionError::ApplicationClosed(close) => {
+            close.error_code == NETWORK_CHANGED.into()
+        }
+        _ => false,
+    }
+}
+
+/// Connection error behind a failed read or write on one of the streams, if any.
+#[must_use]
+pub fn connection_error(error: &std::io::Error) -> Option<&quinn::ConnectionError> {
+    let inner = error.get_ref()?;
+
+    if let Some(quinn::ReadError::ConnectionLost(e)) = inner.downcast_ref() {
+        return Some(e);
+    }
+
+    if let Some(quinn::WriteError::ConnectionLost(e)) = inner.downcast_ref() {
+        return Some(e);
+    }
+
+    inner.downcast_ref()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::EncryptedSerdeIO;
+
+    fn socket() -> UdpSocket {
+        UdpSocket::bind(("127.0.0.1", 0)).expect("Bind ephemeral port")
+    }
+
+    #[tokio::test]
+    async fn test_transfer_continues_after_path_change() {
+        let cipher = Arc::new(Cipher::new(&[0; 32]));
+        let certificate = CertificateKey::new(vec!["127.0.0.1".into()]).expect("Certificate");
+        let root = Certificate::from(certificate.cert_der());
+
+        let (server_socket, client_socket) = (socket(), socket());
+        let server_address = server_socket.local_addr().expect("Address");
+        let client_address = client_socket.local_addr().expect("Address");
+
+        let transport = Transport {
+            idle_timeout: Duration::from_secs(5),
+            ..Transport::DEFAULT
+        };
+
+        let (server, client) = tokio::join!(
+            QuicConnection::server(
+                client_address,
+                server_socket,
+                cipher.clone(),
+                certificate,
+                tokio::spawn(async {}).into(),
+                transport,
+            ),
+            QuicConnection::client(
+                server_address,
+                client_socket,
+                cipher,
+                root,
+                tokio::spawn(async {}).into(),
+                transport,
+            ),
+        );
+        let (server, client) = (server.expect("Server"), client.expect("Client"));
+
+        let mut sender = client.new_stream().await.expect("Open stream");
+        sender.writer().write_all(b"before").await.expect("Write");
+
+        let mut receiver = server.new_stream().await.expect("Accept stream");
+        let mut buffer = [0; 6];
+        receiver
+            .reader()
+            .read_exact(&mut buffer)
+            .await
+            .expect("Read");
+        assert_eq!(b"before", &buffer);
+
+        client.endpoint().rebind(socket()).expect("Rebind");
+
+        sender.writer().write_all(b"after!").await.expect("Write");
+        receiver
+            .reader()
+            .read_exact(&mut buffer)
+            .await
+            .expect("Read");
+        assert_eq!(b"after!", &buffer);
+
+        assert_ne!(client_address, server.connection.remote_address());
+    }
+
+    #[tokio::test]
+    async fn test_client_rejects_substituted_certificate() {
+        let cipher = Arc::new(Cipher::new(&[0; 32]));
+        let certificate = CertificateKey::new(vec!["127.0.0.1".into()]).expect("Certificate");
+        let negotiated = CertificateKey::new(vec!["127.0.0.1".into()]).expect("Certificate");
+
+        // NOTE: Trusted as root but different from the certificate exchanged while pairing
+        let root = Certificate {
+            pinned: negotiated.cert,
+            ..Certificate::from(certificate.cert_der())
+        };
+
+        let (server_socket, client_socket) = (socket(), socket());
+        let server_address = server_socket.local_addr().expect("Address");
+        let client_address = client_socket.local_addr().expect("Address");
+
+        let (_, client) = tokio::join!(
+            QuicConnection::server(
+                client_address,
+                server_socket,
+                cipher.clone(),
+                certificate,
+                tokio::spawn(async {}).into(),
+                Transport::DEFAULT,
+            ),
+            QuicConnection::client(
+                server_address,
+                client_socket,
+                cipher,
+                root,
+                tokio::spawn(async {}).into(),
+                Transport::DEFAULT,
+            ),
+        );
+
+        assert!(matches!(client, Err(crate::io::Error::CertificateMismatch)));
+    }
+
+    #[tokio::test]
+    async fn test_plain_peers_skip_the_cipher() {
+        let cipher = Arc::new(Cipher::new(&[0; 32]));
+        let certificate = CertificateKey::new(vec!["127.0.0.1".into()]).expect("Certificate");
+        let root = Certificate::from(certificate.cert_der());
+
+        let (server_socket, client_socket) = (socket(), socket());
+        let server_address = server_socket.local_addr().expect("Address");
+        let client_address = client_socket.local_addr().expect("Address");
+
+        let (server, client) = tokio::join!(
+            QuicConnection::server(
+                client_address,
+                server_socket,
+                cipher.clone(),
+                certificate,
+                tokio::spawn(async {}).into(),
+                Transport::DEFAULT,
+            ),
+            QuicConnection::client(
+                server_address,
+                client_socket,
+                cipher,
+                root,
+                tokio::spawn(async {}).into(),
+                Transport::DEFAULT,
+            ),
+        );
+        let (server, client) = (server.expect("Server"), client.expect("Client"));
+
+        let mut sender = client.new_stream().await.expect("Open stream");
+        sender.set_plain(true);
+        sender
+            .write_enc(&mut b"hello".to_owned())
+            .await
+            .expect("Write");
+
+        // NOTE: Length, checked unencrypted flag and the content as is
+        let mut receiver = server.new_stream().await.expect("Accept stream");
+        let mut raw = [0; 8];
+        receiver.reader().read_exact(&mut raw).await.expect("Read");
+        assert_eq!(b"\0\x05", &raw[..2]);
+        assert_eq!(0b1000_0000, raw[2] & 0b1000_0011);
+        assert_eq!(b"hello", &raw[3..]);
+
+        receiver.set_plain(true);
+        sender
+            .write_enc(&mut b"again".to_owned())
+            .await
+            .expect("Write");
+
+        let mut buffer = [0; 5];
+        let n = receiver.read_enc(&mut buffer).await.expect("Read");
+        assert_eq!(b"again", &buffer[..n]);
+    }
+
+    #[test]
+    fn test_keep_alive_is_capped_by_idle_timeout() {
+        let transport = Transport {
+            idle_timeout: Duration::from_secs(4),
+            keep_alive_interval: Some(Duration::from_secs(10)),
+        };
+
+        assert_eq!(Some(Duration::from_secs(2)), transport.keep_alive());
+        assert_eq!(
+            Some(DEFAULT_KEEP_ALIVE_INTERVAL),
+            Transport::DEFAULT.keep_alive()
+        );
+
+        let disabled = Transport {
+            keep_alive_interval: None,
+            ..Transport::DEFAULT
+        };
+
+        assert_eq!(None, disabled.keep_alive());
+    }
 }
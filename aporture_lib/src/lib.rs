@@ -14,8 +14,13 @@ pub mod pairing;
 #[cfg(feature = "full")]
 pub mod passphrase;
 #[cfg(feature = "full")]
+pub mod simple;
+#[cfg(feature = "full")]
 pub mod transfer;
 
+#[cfg(feature = "full")]
+pub use simple::{receive_file, send_file};
+
 pub trait State {}
 pub struct Sender {}
 impl State for Sender {}
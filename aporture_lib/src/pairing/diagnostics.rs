@@ -0,0 +1,117 @@
+//! Checks of each service pairing depends on, to tell which one is failing.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::{error, say_hello, server_seen_address, stun_address, upnp, ANY_ADDR};
+use super::{DEFAULT_ADDRESS_ATTEMPTS, DEFAULT_CONNECT_TIMEOUT};
+use crate::protocol::{Hello, PairKind, PairingResponseCode};
+
+pub use upnp::Error as UPnPError;
+
+const SERVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// External address of the same socket as seen by the server and by STUN.
+#[derive(Debug)]
+pub struct ExternalAddress {
+    /// `None` if the server did not answer
+    pub server: Option<SocketAddr>,
+    /// Times the server was asked until it answered, or all of them if it did not
+    pub server_attempts: u32,
+    pub stun: Result<SocketAddr, crate::io::Error>,
+}
+
+/// Greets the server as the receiver of a pairing nobody started, which it answers
+/// without keeping anything.
+pub async fn server(address: SocketAddr) -> Result<(), error::Hello> {
+    let hello = Hello::new(PairKind::Receiver, rand::random());
+
+    let greeting = say_hello(address, &hello, DEFAULT_CONNECT_TIMEOUT);
+
+    let (_, response) = tokio::time::timeout(SERVER_TIMEOUT, greeting)
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+    match response {
+        PairingResponseCode::NoPeer
+        | PairingResponseCode::Ok
+        | PairingResponseCode::OkSamePublicIP => Ok(()),
+        PairingResponseCode::UnsupportedVersion => Err(error::Hello::ServerUnsupportedVersion),
+        PairingResponseCode::MalformedMessage => Err(error::Hello::ClientError),
+        PairingResponseCode::ServerBusy => Err(error::Hello::ServerBusy),
+        PairingResponseCode::PeerDisconnected | PairingResponseCode::Expired => {
+            Err(crate::io::Error::UnexpectedMessage.into())
+        }
+    }
+}
+
+/// Looks for a gateway that can open ports with upnp.
+pub async fn upnp() -> Result<(), UPnPError> {
+    upnp::Gateway::new().await.map(drop)
+}
+
+/// Finds the external address of a new socket, as done before every pairing.
+pub async fn external_address(server: SocketAddr) -> Result<ExternalAddress, crate::io::Error> {
+    let socket = tokio::net::UdpSocket::bind(ANY_ADDR).await?;
+
+    let (seen, attempts) = server_seen_address(&socket, server, DEFAULT_ADDRESS_ATTEMPTS).await?;
+
+    let stun = stun_address(&socket).await;
+
+    Ok(ExternalAddress {
+        server: seen,
+        server_attempts: attempts,
+        stun,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::net::TcpListener;
+
+    use crate::net::NetworkPeer;
+    use crate::parser::SerdeIO;
+    use crate::protocol::SupportedVersion;
+
+    async fn answering(response: PairingResponseCode, version: Option<u8>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("Bind");
+        let address = listener.local_addr().expect("Address");
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.expect("Accept");
+            let mut peer = NetworkPeer::new(stream);
+
+            let hello = peer.read_ser::<Hello>().await.expect("Hello");
+            assert_eq!(PairKind::Receiver, hello.kind);
+
+            peer.write_ser(&response).await.expect("Response");
+
+            if let Some(version) = version {
+                let version = SupportedVersion(version);
+                peer.write_ser(&version).await.expect("Version");
+            }
+        });
+
+        address
+    }
+
+    #[tokio::test]
+    async fn test_server_check() {
+        let address = answering(PairingResponseCode::NoPeer, None).await;
+        assert!(server(address).await.is_ok());
+
+        let address = answering(PairingResponseCode::UnsupportedVersion, None).await;
+        assert!(matches!(
+            server(address).await,
+            Err(error::Hello::ServerUnsupportedVersion)
+        ));
+
+        let address = answering(PairingResponseCode::UnsupportedVersion, Some(7)).await;
+        assert!(matches!(
+            server(address).await,
+            Err(error::Hello::VersionMismatch { server: 7 })
+        ));
+    }
+}
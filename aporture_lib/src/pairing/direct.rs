@@ -0,0 +1,145 @@
+//! Pairing with a contact at the address it was last reachable at, without the server.
+//!
+//! The receiver greets the sender the way the server would, so the rest of the pairing runs
+//! over this connection the same as over the server.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::{TcpListener, TcpStream};
+
+use super::error;
+use crate::crypto::hasher::Hasher;
+use crate::net::NetworkPeer;
+use crate::parser::SerdeIO;
+use crate::protocol::{Hello, PairKind, PairingResponseCode, PROTOCOL_VERSION};
+
+/// Time given to a saved address to accept the connection, stale ones usually never answer
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time given to the peer to answer the greeting, it may be busy asking the server for others
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts contacts pairing directly, its address is shared with them while pairing.
+#[derive(Debug)]
+pub struct Listener {
+    listener: TcpListener,
+    address: SocketAddr,
+}
+
+impl Listener {
+    /// Listens on the port on every interface, zero picks any free one.
+    pub async fn bind(port: u16) -> Result<Self, crate::io::Error> {
+        let ip = local_ip_address::local_ip()
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::AddrNotAvailable))?;
+
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+
+        let address = SocketAddr::new(ip, listener.local_addr()?.port());
+
+        log::info!("Accepting direct pairing at {address}");
+
+        Ok(Self { listener, address })
+    }
+
+    /// Address the contacts are told to try next time.
+    #[must_use]
+    pub const fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Waits for a peer and reads its greeting.
+    pub async fn accept(&self) -> Result<Incoming, crate::io::Error> {
+        let (stream, from) = self.listener.accept().await?;
+
+        log::info!("Direct pairing request from {from}");
+
+        let mut peer = NetworkPeer::new(stream);
+
+        let hello = tokio::time::timeout(CONNECT_TIMEOUT, peer.read_ser::<Hello>())
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+        Ok(Incoming { peer, hello })
+    }
+}
+
+/// A peer that connected directly and is waiting for an answer.
+pub struct Incoming {
+    pub(super) peer: NetworkPeer,
+    hello: Hello,
+}
+
+impl Incoming {
+    /// Whether the peer is a sender pairing with the passphrase, like a contact key.
+    #[must_use]
+    pub fn is_for(&self, passphrase: &[u8]) -> bool {
+        self.hello.version == PROTOCOL_VERSION
+            && self.hello.kind == PairKind::Sender
+            && self.hello.pair_id == Hasher::hash(passphrase)
+    }
+
+    /// Tells the peer it is not expected, so it goes to the server instead.
+    pub async fn refuse(mut self) {
+        let _ = self.peer.write_ser(&PairingResponseCode::NoPeer).await;
+    }
+}
+
+/// Greets the peer at the address, returning the connection if it accepts the pairing.
+pub(super) async fn connect(
+    address: SocketAddr,
+    hello: &Hello,
+) -> Result<NetworkPeer, error::Hello> {
+    log::info!("Trying the peer directly at {address}");
+
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+    let mut peer = NetworkPeer::new(stream);
+
+    peer.write_ser(hello).await?;
+
+    let response = tokio::time::timeout(ANSWER_TIMEOUT, peer.read_ser::<PairingResponseCode>())
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+    match response {
+        PairingResponseCode::OkSamePublicIP => Ok(peer),
+        _ => Err(error::Hello::NoPeer),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_listener_only_accepts_its_passphrase() {
+        let listener = Listener::bind(0).await.expect("Bind");
+        let address = SocketAddr::from(([127, 0, 0, 1], listener.address().port()));
+
+        let accepting = tokio::spawn(async move {
+            for _ in 0..2 {
+                let incoming = listener.accept().await.expect("Accept");
+
+                if incoming.is_for(b"key") {
+                    let mut peer = incoming.peer;
+                    let response = PairingResponseCode::OkSamePublicIP;
+                    peer.write_ser(&response).await.expect("Answer");
+                } else {
+                    incoming.refuse().await;
+                }
+            }
+        });
+
+        let other = Hello::new(PairKind::Sender, Hasher::hash(b"other"));
+        let result = connect(address, &other).await;
+        assert!(matches!(result, Err(error::Hello::NoPeer)));
+
+        let hello = Hello::new(PairKind::Sender, Hasher::hash(b"key"));
+        assert!(connect(address, &hello).await.is_ok());
+
+        accepting.await.expect("Listener task");
+    }
+}
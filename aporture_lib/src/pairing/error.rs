@@ -10,6 +10,8 @@ pub enum Error {
     KeyExchange(#[from] KeyExchange),
     #[error(transparent)]
     AddressExchange(#[from] Negotiation),
+    #[error("Timed out pairing with the peer")]
+    Timeout,
 }
 
 #[derive(Debug, Error)]
@@ -20,10 +22,21 @@ pub enum Hello {
     NoPeer,
     #[error("The selected server does not implement APP version {PROTOCOL_VERSION}")]
     ServerUnsupportedVersion,
+    #[error(
+        "The server uses protocol version {server} and this app version {PROTOCOL_VERSION}, \
+        update whichever is older"
+    )]
+    VersionMismatch { server: u8 },
     #[error("Server behaved incorrectly on connection: {0}")]
     ServerError(#[from] crate::io::Error),
     #[error("Message send to server was invalid")]
     ClientError,
+    #[error("The peer disconnected before pairing")]
+    PeerDisconnected,
+    #[error("Waited too long for the peer, the pairing expired")]
+    Expired,
+    #[error("The server is too busy to pair right now, try again later or use another server")]
+    ServerBusy,
 }
 
 #[derive(Debug, Error)]
@@ -46,6 +59,11 @@ pub enum Negotiation {
     Network(#[from] crate::io::Error),
     #[error("Invalid key derivation")]
     CertificateCreation,
+    #[error(
+        "The peer uses protocol version {peer} and this app version {PROTOCOL_VERSION}, \
+        update whichever is older"
+    )]
+    VersionMismatch { peer: u8 },
 }
 
 impl From<crate::crypto::Error> for Negotiation {
@@ -1,15 +1,23 @@
 use std::collections::HashSet;
+use std::future::Future;
 use std::marker::PhantomData;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
 use std::sync::Arc;
 use std::time::Duration;
 
+use tokio::time::Instant;
+
+use rand::Rng;
+use serde::Serialize;
+use socket2::{SockRef, TcpKeepalive};
 use spake2::{Ed25519Group, Identity, Password, Spake2};
+use stunclient::StunClient;
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 
 use crate::crypto::cert::{Certificate, CertificateKey};
-use crate::crypto::cipher::Cipher;
+use crate::crypto::cipher::{Cipher, Phase};
 use crate::crypto::hasher::Hasher;
 use crate::crypto::Key;
 use crate::fs::config::Config;
@@ -17,23 +25,95 @@ use crate::net::{EncryptedNetworkPeer, NetworkPeer};
 use crate::parser::{EncryptedSerdeIO, Parser, SerdeIO};
 use crate::protocol::{
     Hello, HolePunchingRequest, KeyExchangePayload, NegotiationPayload, PairKind,
-    PairingResponseCode,
+    PairingResponseCode, SupportedVersion,
 };
 use crate::{Receiver, Sender, State};
 
 mod upnp;
 
+pub mod diagnostics;
+pub mod direct;
+
 pub mod error;
 pub use error::Error;
 
-const ANY_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
+const ANY_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// Pause before asking the server again for a peer that has not arrived, doubled on every attempt
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Longest pause between attempts while waiting for the peer
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time given to each server to accept the connection before trying the next one
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Idle time before the server connection is probed, so a server that silently dropped it
+/// is noticed while waiting for the peer
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Times the server is asked for the external address of a socket before using STUN instead
+pub const DEFAULT_ADDRESS_ATTEMPTS: u32 = 5;
+
+/// Time waited for the first answer with the external address, doubled on every attempt
+const ADDRESS_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Longest time waited for each answer with the external address
+const MAX_ADDRESS_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// Time given to each STUN server to answer before asking the next one
+const STUN_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[allow(clippy::struct_excessive_bools)] // Independent settings of the pairing
 pub struct AporturePairingProtocolState {
     protocol_version: u8,
     kind: PairKind,
     passphrase: Vec<u8>,
     save_contact: bool,
+    peer_save_contact: bool,
+    insecure_lan: bool,
+    relay_only: bool,
     same_public_ip: bool,
+    timeout: Option<Duration>,
+    connect_timeout: Duration,
+    wait: Duration,
+    poll_interval: Duration,
+    address_attempts: u32,
+    wait_notifier: Option<mpsc::Sender<Duration>>,
+    address_hint: Option<SocketAddr>,
+    direct_address: Option<SocketAddr>,
+    peer_direct_address: Option<SocketAddr>,
+    display_name: Option<String>,
+    peer_display_name: Option<String>,
+    bind_ip: Option<IpAddr>,
+    upnp: Option<bool>,
+    hole_punching: Option<bool>,
+    /// Servers tried in order, empty to use the configured ones
+    servers: Vec<SocketAddr>,
+}
+
+impl AporturePairingProtocolState {
+    /// Local address the sockets of the transfer bind to, any port.
+    fn bind_address(&self) -> SocketAddr {
+        self.bind_ip.map_or(ANY_ADDR, |ip| SocketAddr::new(ip, 0))
+    }
+
+    /// Fills the settings not given for this pairing with the configured ones.
+    fn configure(&mut self, config: &Config) {
+        self.bind_ip = self.bind_ip.or_else(|| config.bind_ip());
+        self.display_name = self
+            .display_name
+            .take()
+            .or_else(|| config.display_name().map(ToOwned::to_owned));
+        self.upnp = self.upnp.or_else(|| Some(config.use_upnp()));
+        self.hole_punching = self
+            .hole_punching
+            .or_else(|| Some(config.use_hole_punching()));
+
+        if self.servers.is_empty() {
+            self.servers = config.server_addresses();
+        }
+    }
 }
 
 pub struct AporturePairingProtocol<S: State> {
@@ -54,6 +134,24 @@ impl AporturePairingProtocol<Sender> {
             passphrase,
             same_public_ip: false,
             save_contact,
+            peer_save_contact: false,
+            insecure_lan: false,
+            relay_only: false,
+            timeout: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            wait: Duration::ZERO,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            address_attempts: DEFAULT_ADDRESS_ATTEMPTS,
+            wait_notifier: None,
+            address_hint: None,
+            direct_address: None,
+            peer_direct_address: None,
+            display_name: None,
+            peer_display_name: None,
+            bind_ip: None,
+            upnp: None,
+            hole_punching: None,
+            servers: Vec::new(),
         };
 
         AporturePairingProtocol {
@@ -77,6 +175,24 @@ impl AporturePairingProtocol<Receiver> {
             passphrase,
             same_public_ip: false,
             save_contact,
+            peer_save_contact: false,
+            insecure_lan: false,
+            relay_only: false,
+            timeout: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            wait: Duration::ZERO,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            address_attempts: DEFAULT_ADDRESS_ATTEMPTS,
+            wait_notifier: None,
+            address_hint: None,
+            direct_address: None,
+            peer_direct_address: None,
+            display_name: None,
+            peer_display_name: None,
+            bind_ip: None,
+            upnp: None,
+            hole_punching: None,
+            servers: Vec::new(),
         };
 
         AporturePairingProtocol {
@@ -90,65 +206,185 @@ pub struct Start<K: Kind>(PhantomData<K>);
 
 impl<K: Kind> State for Start<K> {}
 
+impl<K: Kind> AporturePairingProtocol<Start<K>> {
+    /// Fails with [`Error::Timeout`] if pairing takes longer, including any wait for the peer.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.data.timeout = Some(timeout);
+    }
+
+    /// Sets how long each server is given to accept the connection before trying the next one.
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.data.connect_timeout = timeout;
+    }
+
+    /// Keeps asking the server for a peer that has not arrived yet until the wait runs out,
+    /// pausing longer between every attempt.
+    pub fn set_wait(&mut self, wait: Duration) {
+        self.data.wait = wait;
+    }
+
+    /// Sets the longest pause between attempts while waiting for the peer.
+    pub fn set_poll_interval(&mut self, interval: Duration) {
+        self.data.poll_interval = interval;
+    }
+
+    /// Sets how many times the server is asked for the external address of each socket, waiting
+    /// longer for every answer, before using STUN instead.
+    pub fn set_address_attempts(&mut self, attempts: u32) {
+        self.data.address_attempts = attempts.max(1);
+    }
+
+    /// Notifies the time left to wait every time the peer is found missing and asked for again.
+    pub fn add_wait_notifier(&mut self, notifier: mpsc::Sender<Duration>) {
+        self.data.wait_notifier = Some(notifier);
+    }
+
+    /// Offers to send data on direct connections without the application cipher, relying only
+    /// on the QUIC encryption. Used only if the peer offers it too, never through the server.
+    ///
+    /// Over loopback it raises throughput from about 155 to 275 MB/s, links slower than the
+    /// cipher see little difference.
+    pub fn set_insecure_lan(&mut self, enabled: bool) {
+        self.data.insecure_lan = enabled;
+    }
+
+    /// Offers no direct candidates and ignores the ones of the peer, so the transfer always goes
+    /// through the server relay. Useful to debug the relay or when direct connections never work.
+    pub fn set_relay_only(&mut self, enabled: bool) {
+        self.data.relay_only = enabled;
+    }
+
+    /// Binds the sockets of the transfer to the local address, so it goes through its interface
+    /// instead of the one the system picks, for example the LAN instead of a VPN.
+    /// Defaults to the configured one.
+    pub fn set_bind_ip(&mut self, ip: IpAddr) {
+        self.data.bind_ip = Some(ip);
+    }
+
+    /// Whether to map a port on the router with upnp, which can take a couple of seconds on
+    /// networks without it. Defaults to the configured one.
+    pub fn set_upnp(&mut self, enabled: bool) {
+        self.data.upnp = Some(enabled);
+    }
+
+    /// Whether to find the external address for hole punching. Defaults to the configured one.
+    pub fn set_hole_punching(&mut self, enabled: bool) {
+        self.data.hole_punching = Some(enabled);
+    }
+
+    /// Pairs through these servers, tried in order, instead of the configured ones.
+    pub fn set_servers(&mut self, servers: Vec<SocketAddr>) {
+        self.data.servers = servers;
+    }
+
+    /// Name shown to the peer, truncated to [`crate::protocol::MAX_DISPLAY_NAME`] characters.
+    /// Defaults to the configured one.
+    pub fn set_display_name(&mut self, name: &str) {
+        self.data.display_name = crate::protocol::display_name(name);
+    }
+}
+
 impl AporturePairingProtocol<Start<Sender>> {
+    /// Tries the peer directly at the address before the servers, usually the address saved
+    /// with its contact.
+    pub fn set_address_hint(&mut self, address: SocketAddr) {
+        self.data.address_hint = Some(address);
+    }
+
     pub async fn pair(self) -> Result<PairInfo, Error> {
-        let mut address_collector = self.connect().await?.exchange_key().await?;
+        let timeout = self.data.timeout;
 
-        if let Err(e) = address_collector.enable_upnp().await {
-            log::warn!("Could not enable upnp - {e}");
-        }
+        let pairing = async {
+            let mut address_collector = self.connect().await?.exchange_key().await?;
 
-        if let Err(e) = address_collector.enable_hole_punching().await {
-            log::warn!("Could not enable hole punching - {e}");
-        }
+            if address_collector.data.relay_only {
+                log::info!("Relay only, not offering any direct candidate");
+            } else {
+                address_collector.enable_external().await;
+            }
 
-        let pair_info = address_collector.exchange().await?;
+            let pair_info = address_collector.exchange().await?;
 
-        Ok(pair_info)
+            Ok(pair_info)
+        };
+
+        with_timeout(timeout, pairing).await
     }
 }
 
 impl AporturePairingProtocol<Start<Receiver>> {
+    /// Shares the address of a [`direct::Listener`] with the peer, which tries it before the
+    /// servers the next time it pairs with this contact.
+    pub fn set_direct_address(&mut self, address: SocketAddr) {
+        self.data.direct_address = Some(address);
+    }
+
     pub async fn pair(self) -> Result<PairInfo, Error> {
-        let mut address_collector = self.connect().await?.exchange_key().await?;
+        let timeout = self.data.timeout;
 
-        if let Err(e) = address_collector.enable_upnp().await {
-            log::warn!("Could not enable upnp - {e}");
-        }
+        let pairing = async { self.connect().await?.exchange_key().await?.gather().await };
 
-        if let Err(e) = address_collector.enable_hole_punching().await {
-            log::warn!("Could not enable hole punching - {e}");
+        with_timeout(timeout, pairing).await
+    }
+
+    /// Pairs with a peer that connected to a [`direct::Listener`] instead of the server.
+    pub async fn pair_direct(mut self, incoming: direct::Incoming) -> Result<PairInfo, Error> {
+        if !incoming.is_for(&self.data.passphrase) {
+            incoming.refuse().await;
+            return Err(error::Hello::NoPeer.into());
         }
 
-        if address_collector.data.same_public_ip {
-            let result = address_collector.enable_local();
+        let timeout = self.data.timeout;
 
-            if result.is_err() {
-                log::warn!("Could not get a private ip from system");
-            }
-        }
+        let pairing = async {
+            // NOTE: Addresses are still found through the server, only its relay is replaced
+            self.data.configure(&*Config::get().await);
+            let server_address = self.data.servers[0];
 
-        let pair_info = address_collector.exchange().await?;
+            let mut peer = incoming.peer;
+            let response = PairingResponseCode::OkSamePublicIP;
+            peer.write_ser(&response)
+                .await
+                .map_err(error::Hello::from)?;
 
-        Ok(pair_info)
-    }
-}
+            let id = Hasher::hash(&self.data.passphrase);
 
-impl<K: Kind + Send> AporturePairingProtocol<Start<K>> {
-    pub async fn connect(self) -> Result<AporturePairingProtocol<KeyExchange<K>>, error::Hello> {
-        let config = Config::get().await;
+            let app = self.greeted(id, server_address, peer, response)?;
 
-        let address = config.server_address();
+            app.exchange_key().await?.gather().await
+        };
 
-        log::info!("Connecting to server at {address}");
+        with_timeout(timeout, pairing).await
+    }
+}
 
-        let server = TcpStream::connect(address).await?;
-        drop(config);
+async fn with_timeout<F>(timeout: Option<Duration>, pairing: F) -> Result<PairInfo, Error>
+where
+    F: Future<Output = Result<PairInfo, Error>> + Send,
+{
+    let Some(timeout) = timeout else {
+        return pairing.await;
+    };
 
-        log::info!("Connected to server");
+    tokio::time::timeout(timeout, pairing)
+        .await
+        .unwrap_or_else(|_| {
+            log::warn!("Pairing did not finish in {timeout:?}");
+            Err(Error::Timeout)
+        })
+}
 
-        let mut server = NetworkPeer::new(server);
+impl<K: Kind + Send> AporturePairingProtocol<Start<K>> {
+    /// Connects to the configured servers in order until one accepts the pairing.
+    /// If the peer has not arrived to any of them they are asked again until the wait runs out.
+    pub async fn connect(
+        mut self,
+    ) -> Result<AporturePairingProtocol<KeyExchange<K>>, error::Hello> {
+        self.data.configure(&*Config::get().await);
+        let addresses = self.data.servers.clone();
 
+        // NOTE: Peers with different passphrases, including different keys for the same
+        // contact, never meet at the server so the key exchange never sees a mismatch
         let id = Hasher::hash(&self.data.passphrase);
 
         let hello = Hello {
@@ -157,15 +393,78 @@ impl<K: Kind + Send> AporturePairingProtocol<Start<K>> {
             pair_id: id,
         };
 
-        server.write_ser(&hello).await?;
+        // NOTE: A direct pairing replaces the server relay, so it is skipped when only relaying
+        let hint = self.data.address_hint.filter(|_| !self.data.relay_only);
 
-        let response = server.read_ser::<PairingResponseCode>().await?;
+        if let (Some(hint), Some(&server_address)) = (hint, addresses.first()) {
+            match direct::connect(hint, &hello).await {
+                Ok(peer) => {
+                    log::info!("Peer accepted pairing directly at {hint}");
+                    let response = PairingResponseCode::OkSamePublicIP;
+                    return self.greeted(id, server_address, peer, response);
+                }
+                Err(e) => log::warn!("Could not pair directly at {hint}, using the server - {e}"),
+            }
+        }
+
+        let deadline = Instant::now() + self.data.wait;
+        let mut backoff = INITIAL_BACKOFF.min(self.data.poll_interval);
+
+        loop {
+            let mut no_peer = false;
+            let mut last_error = None;
+
+            for &address in &addresses {
+                match say_hello(address, &hello, self.data.connect_timeout).await {
+                    Ok((_, PairingResponseCode::NoPeer)) => {
+                        log::warn!("Peer not found at server {address}");
+                        no_peer = true;
+                    }
+                    Ok((server, response)) => return self.greeted(id, address, server, response),
+                    Err(e) => {
+                        log::warn!("Could not use server {address} - {e}");
+                        last_error = Some(e);
+                    }
+                }
+            }
+
+            if !no_peer {
+                return Err(last_error
+                    .unwrap_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound).into()));
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            if remaining.is_zero() {
+                return Err(error::Hello::NoPeer);
+            }
+
+            if let Some(notifier) = &self.data.wait_notifier {
+                let _ = notifier.send(remaining).await;
+            }
+
+            let pause = backoff.min(remaining);
+
+            log::info!("Peer has not arrived, asking again in {pause:?}");
+
+            tokio::time::sleep(pause).await;
+            backoff = (backoff * 2).min(self.data.poll_interval);
+        }
+    }
 
+    fn greeted(
+        self,
+        id: [u8; 32],
+        server_address: SocketAddr,
+        server: NetworkPeer,
+        response: PairingResponseCode,
+    ) -> Result<AporturePairingProtocol<KeyExchange<K>>, error::Hello> {
         let mut app = AporturePairingProtocol {
             data: self.data,
             state: KeyExchange {
                 id,
                 server,
+                server_address,
                 marker: PhantomData,
             },
         };
@@ -179,13 +478,55 @@ impl<K: Kind + Send> AporturePairingProtocol<Start<K>> {
             PairingResponseCode::UnsupportedVersion => Err(error::Hello::ServerUnsupportedVersion),
             PairingResponseCode::NoPeer => Err(error::Hello::NoPeer),
             PairingResponseCode::MalformedMessage => Err(error::Hello::ClientError),
+            PairingResponseCode::PeerDisconnected => Err(error::Hello::PeerDisconnected),
+            PairingResponseCode::Expired => Err(error::Hello::Expired),
+            PairingResponseCode::ServerBusy => Err(error::Hello::ServerBusy),
         }
     }
 }
 
+async fn say_hello(
+    address: SocketAddr,
+    hello: &Hello,
+    timeout: Duration,
+) -> Result<(NetworkPeer, PairingResponseCode), error::Hello> {
+    log::info!("Connecting to server at {address}");
+
+    let server = tokio::time::timeout(timeout, TcpStream::connect(address))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+    log::info!("Connected to server");
+
+    let keepalive = TcpKeepalive::new()
+        .with_time(KEEPALIVE)
+        .with_interval(KEEPALIVE);
+
+    if let Err(e) = SockRef::from(&server).set_tcp_keepalive(&keepalive) {
+        log::warn!("Could not enable keepalive on the server connection - {e}");
+    }
+
+    let mut server = NetworkPeer::new(server);
+
+    server.write_ser(hello).await?;
+
+    let response = server.read_ser::<PairingResponseCode>().await?;
+
+    if response == PairingResponseCode::UnsupportedVersion {
+        // NOTE: Servers that do not report their version close the connection instead
+        return Err(match server.read_ser::<SupportedVersion>().await {
+            Ok(SupportedVersion(version)) => error::Hello::VersionMismatch { server: version },
+            Err(_) => error::Hello::ServerUnsupportedVersion,
+        });
+    }
+
+    Ok((server, response))
+}
+
 pub struct KeyExchange<K: Kind> {
     id: [u8; 32],
     server: NetworkPeer,
+    server_address: SocketAddr,
     marker: PhantomData<K>,
 }
 
@@ -224,7 +565,7 @@ impl<K: Kind + Send> AporturePairingProtocol<KeyExchange<K>> {
 
         Ok(AporturePairingProtocol {
             data: self.data,
-            state: Negotiation::new(server, key),
+            state: Negotiation::new(server, self.state.server_address, key),
         })
     }
 }
@@ -232,6 +573,7 @@ impl<K: Kind + Send> AporturePairingProtocol<KeyExchange<K>> {
 pub struct Negotiation<K: Kind> {
     key: Key,
     server: EncryptedNetworkPeer,
+    server_address: SocketAddr,
     addresses: Vec<TransferInfo>,
     marker: PhantomData<K>,
 }
@@ -239,10 +581,11 @@ pub struct Negotiation<K: Kind> {
 impl<K: Kind> State for Negotiation<K> {}
 
 impl<K: Kind> Negotiation<K> {
-    const fn new(server: EncryptedNetworkPeer, key: Key) -> Self {
+    const fn new(server: EncryptedNetworkPeer, server_address: SocketAddr, key: Key) -> Self {
         Self {
             key,
             server,
+            server_address,
             addresses: Vec::new(),
             marker: PhantomData::<K>,
         }
@@ -253,11 +596,14 @@ impl AporturePairingProtocol<Negotiation<Sender>> {
     pub async fn exchange(mut self) -> Result<PairInfo, error::Negotiation> {
         log::info!("Starting APP Negotiation");
 
+        self.check_version().await?;
+
         let remote_addresses = self.send_addresses().await?;
         let connecting_sockets = self.receive_addresses().await?;
         let (self_cert, peer_cert) = self.get_certs().await?;
 
         let (server, cipher) = self.state.server.extract_cipher();
+        let cipher = transfer_cipher(cipher);
         let binding_sockets = self
             .state
             .addresses
@@ -274,19 +620,44 @@ impl AporturePairingProtocol<Negotiation<Sender>> {
             self_cert,
             peer_cert,
             save_contact: self.data.save_contact,
+            peer_save_contact: self.data.peer_save_contact,
+            peer_direct_address: self.data.peer_direct_address,
+            peer_display_name: self.data.peer_display_name.take(),
+            insecure_lan: self.data.insecure_lan,
+            same_public_ip: self.data.same_public_ip,
+            session: false,
         })
     }
 }
 
 impl AporturePairingProtocol<Negotiation<Receiver>> {
+    /// Gathers every address the peer can connect to and exchanges them.
+    async fn gather(mut self) -> Result<PairInfo, Error> {
+        if self.data.relay_only {
+            log::info!("Relay only, not offering any direct candidate");
+            return Ok(self.exchange().await?);
+        }
+
+        self.enable_external().await;
+
+        if self.data.same_public_ip && self.enable_local().is_err() {
+            log::warn!("Could not get a private ip from system");
+        }
+
+        Ok(self.exchange().await?)
+    }
+
     pub async fn exchange(mut self) -> Result<PairInfo, error::Negotiation> {
         log::info!("Starting APP Negotiation");
 
+        self.check_version().await?;
+
         let connecting_sockets = self.receive_addresses().await?;
         let remote_addresses = self.send_addresses().await?;
         let (self_cert, peer_cert) = self.get_certs().await?;
 
         let (server, cipher) = self.state.server.extract_cipher();
+        let cipher = transfer_cipher(cipher);
         let binding_sockets = self
             .state
             .addresses
@@ -303,37 +674,64 @@ impl AporturePairingProtocol<Negotiation<Receiver>> {
             self_cert,
             peer_cert,
             save_contact: self.data.save_contact,
+            peer_save_contact: self.data.peer_save_contact,
+            peer_direct_address: self.data.peer_direct_address,
+            peer_display_name: self.data.peer_display_name.take(),
+            insecure_lan: self.data.insecure_lan,
+            same_public_ip: self.data.same_public_ip,
+            session: false,
         })
     }
 
     pub fn enable_local(&mut self) -> Result<(), std::io::Error> {
-        let ip = local_ip_address::local_ip()
-            .map_err(|_| std::io::Error::from(std::io::ErrorKind::AddrNotAvailable))?;
+        let info = TransferInfo::Socket(self.local_socket()?);
 
-        let socket = UdpSocket::bind(ANY_ADDR)?;
+        self.state.addresses.push(info);
 
-        let port = socket.local_addr()?.port();
+        Ok(())
+    }
+}
 
-        let external_address = (ip, port).into();
+impl<K: Kind + Send> AporturePairingProtocol<Negotiation<K>> {
+    /// Socket reached through the private address, for a peer in the same network.
+    fn local_socket(&self) -> Result<UdpSocketAddr, std::io::Error> {
+        let ip = match self.data.bind_ip {
+            Some(ip) => ip,
+            None => local_ip_address::local_ip()
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::AddrNotAvailable))?,
+        };
 
-        let info = TransferInfo::Socket(UdpSocketAddr {
+        let socket = UdpSocket::bind(self.data.bind_address())?;
+
+        let port = socket.local_addr()?.port();
+
+        Ok(UdpSocketAddr {
             socket,
-            external_address,
+            external_address: (ip, port).into(),
             handle: None,
-        });
+        })
+    }
 
-        self.state.addresses.push(info);
+    /// Adds the candidates reachable from outside the local network, skipping the disabled ones.
+    async fn enable_external(&mut self) {
+        if self.data.upnp == Some(false) {
+            log::info!("UPnP disabled, not mapping a port");
+        } else if let Err(e) = self.enable_upnp().await {
+            log::warn!("Could not enable upnp - {e}");
+        }
 
-        Ok(())
+        if self.data.hole_punching == Some(false) {
+            log::info!("Hole punching disabled, not finding the external address");
+        } else if let Err(e) = self.enable_hole_punching().await {
+            log::warn!("Could not enable hole punching - {e}");
+        }
     }
-}
 
-impl<K: Kind + Send> AporturePairingProtocol<Negotiation<K>> {
     pub async fn enable_upnp(&mut self) -> Result<(), upnp::Error> {
-        let mut gateway =
-            tokio::time::timeout(Duration::from_secs(2), upnp::Gateway::new()).await??;
+        let gateway = upnp::Gateway::bound(self.data.bind_ip);
+        let mut gateway = tokio::time::timeout(Duration::from_secs(2), gateway).await??;
 
-        let socket = UdpSocket::bind(ANY_ADDR)?;
+        let socket = UdpSocket::bind(self.data.bind_address())?;
         let local_port = socket.local_addr()?.port();
 
         let external_address =
@@ -357,7 +755,9 @@ impl<K: Kind + Send> AporturePairingProtocol<Negotiation<K>> {
     }
 
     pub async fn enable_hole_punching(&mut self) -> Result<(), crate::io::Error> {
-        let socket = get_external_socket().await?;
+        let attempts = self.data.address_attempts;
+        let bind = self.data.bind_address();
+        let socket = get_external_socket(self.state.server_address, bind, attempts).await?;
 
         let info = TransferInfo::Socket(socket);
 
@@ -366,6 +766,25 @@ impl<K: Kind + Send> AporturePairingProtocol<Negotiation<K>> {
         Ok(())
     }
 
+    /// Tells the peer the protocol version of this side and checks it uses the same one, as the
+    /// server relays the negotiation without looking at it.
+    async fn check_version(&mut self) -> Result<(), error::Negotiation> {
+        let version = self.data.protocol_version;
+        self.state
+            .server
+            .write_ser_enc(&SupportedVersion(version))
+            .await?;
+
+        let SupportedVersion(peer) = self.state.server.read_ser_enc().await?;
+
+        if peer == version {
+            Ok(())
+        } else {
+            log::warn!("Peer uses protocol version {peer}, this side {version}");
+            Err(error::Negotiation::VersionMismatch { peer })
+        }
+    }
+
     async fn send_addresses(&mut self) -> Result<Vec<SocketAddr>, error::Negotiation> {
         let addresses = self
             .state
@@ -377,6 +796,9 @@ impl<K: Kind + Send> AporturePairingProtocol<Negotiation<K>> {
         let payload = NegotiationPayload {
             addresses,
             save_contact: self.data.save_contact,
+            insecure_lan: self.data.insecure_lan,
+            direct_address: self.data.direct_address,
+            display_name: self.data.display_name.clone(),
         };
 
         self.state.server.write_ser_enc(&payload).await?;
@@ -391,11 +813,38 @@ impl<K: Kind + Send> AporturePairingProtocol<Negotiation<K>> {
     ) -> Result<Vec<(UdpSocketAddr, SocketAddr)>, error::Negotiation> {
         let payload: NegotiationPayload = self.state.server.read_ser_enc().await?;
 
+        // NOTE: The receiver answers with the agreed value, which is its own when the sender saves
+        self.data.peer_save_contact = payload.save_contact;
+        self.data.peer_direct_address = payload.direct_address;
+        self.data.peer_display_name = payload
+            .display_name
+            .as_deref()
+            .and_then(crate::protocol::display_name);
         self.data.save_contact = self.data.save_contact && payload.save_contact;
+        self.data.insecure_lan = self.data.insecure_lan && payload.insecure_lan;
+
+        if self.data.relay_only && !payload.addresses.is_empty() {
+            log::info!(
+                "Relay only, ignoring {} peer candidates",
+                payload.addresses.len()
+            );
+        }
+
+        let peer_addresses = if self.data.relay_only {
+            Vec::new()
+        } else {
+            payload.addresses
+        };
 
         let mut info = Vec::new();
-        for a in payload.addresses {
-            let socket = get_external_socket().await?;
+        for a in peer_addresses {
+            // NOTE: A private candidate is answered from the private address, STUN can not see it
+            let socket = if self.data.same_public_ip && is_non_routable(a) {
+                self.local_socket().map_err(crate::io::Error::from)?
+            } else {
+                let (bind, attempts) = (self.data.bind_address(), self.data.address_attempts);
+                get_external_socket(self.state.server_address, bind, attempts).await?
+            };
 
             info.push((socket, a));
         }
@@ -436,37 +885,36 @@ impl<K: Kind + Send> AporturePairingProtocol<Negotiation<K>> {
     }
 }
 
-async fn get_external_socket() -> Result<UdpSocketAddr, crate::io::Error> {
-    let config = Config::get().await;
-    let server_address = config.server_address();
-    drop(config);
+/// Address of the network interface with the name, preferring IPv4 like the rest of the pairing.
+pub fn interface_ip(name: &str) -> Result<IpAddr, std::io::Error> {
+    let interfaces = local_ip_address::list_afinet_netifas()
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::AddrNotAvailable))?;
 
-    let socket = tokio::net::UdpSocket::bind(ANY_ADDR).await?;
+    let mut addresses = interfaces
+        .into_iter()
+        .filter(|(interface, _)| interface == name)
+        .map(|(_, ip)| ip)
+        .collect::<Vec<_>>();
 
-    let request = HolePunchingRequest::Address.serialize_to();
+    addresses.sort_by_key(IpAddr::is_ipv6);
 
-    let mut address = None;
+    addresses
+        .first()
+        .copied()
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))
+}
 
-    for _ in 0..5 {
-        socket.send_to(&request, server_address).await?;
+async fn get_external_socket(
+    server_address: SocketAddr,
+    bind: SocketAddr,
+    attempts: u32,
+) -> Result<UdpSocketAddr, crate::io::Error> {
+    let socket = tokio::net::UdpSocket::bind(bind).await?;
 
-        let mut buf = vec![0; 32];
+    let (address, tries) = server_seen_address(&socket, server_address, attempts).await?;
+    log::info!("Asked the server for the external address {tries} times");
 
-        if let Ok(Ok((len, from))) =
-            tokio::time::timeout(Duration::from_millis(500), socket.recv_from(&mut buf)).await
-        {
-            if from != server_address {
-                continue;
-            }
-
-            if let Ok(a) = SocketAddr::deserialize_from(&buf[..len]) {
-                if !is_private_ip(a) {
-                    address = Some(a);
-                }
-                break;
-            };
-        }
-    }
+    let address = address.filter(|&a| !is_non_routable(a));
 
     let (socket, external_address, handle) = if let Some(address) = address {
         let socket = socket.into_std()?;
@@ -481,12 +929,11 @@ async fn get_external_socket() -> Result<UdpSocketAddr, crate::io::Error> {
         });
 
         (socket, address, Some(handle))
-    } else
-    // TODO: CHANGE
-    {
-        let (socket, address) = stunclient::just_give_me_the_udp_socket_and_its_external_address();
+    } else {
+        // NOTE: The same socket is used so the address found is the one of its mapping
+        let address = stun_address(&socket).await?;
 
-        (socket, address, None)
+        (socket.into_std()?, address, None)
     };
 
     Ok(UdpSocketAddr {
@@ -496,14 +943,112 @@ async fn get_external_socket() -> Result<UdpSocketAddr, crate::io::Error> {
     })
 }
 
-fn is_private_ip(socket_addr: SocketAddr) -> bool {
+/// Asks the server which address it sees the socket sending from, `None` if it does not answer.
+/// Also returns how many times it was asked.
+async fn server_seen_address(
+    socket: &tokio::net::UdpSocket,
+    server_address: SocketAddr,
+    attempts: u32,
+) -> Result<(Option<SocketAddr>, u32), crate::io::Error> {
+    let request = HolePunchingRequest::Address.serialize_to();
+
+    for attempt in 0..attempts {
+        socket.send_to(&request, server_address).await?;
+
+        let mut buf = vec![0; SocketAddr::serialized_size().expect("Bounded size")];
+
+        let timeout = address_timeout(attempt);
+
+        if let Ok(Ok((len, from))) = tokio::time::timeout(timeout, socket.recv_from(&mut buf)).await
+        {
+            if from != server_address {
+                // NOTE: Usually a NAT that rewrites the source of the answer
+                log::warn!("Ignoring address answer from {from}, the server is {server_address}");
+                continue;
+            }
+
+            if let Ok(a) = SocketAddr::deserialize_from(&buf[..len]) {
+                return Ok((Some(a), attempt + 1));
+            }
+        }
+    }
+
+    Ok((None, attempts))
+}
+
+/// Time waited for an answer with the external address, doubled on every attempt plus up to
+/// a quarter more at random, so sockets that lost the same packets do not retry together.
+fn address_timeout(attempt: u32) -> Duration {
+    let base = ADDRESS_TIMEOUT
+        .saturating_mul(2_u32.saturating_pow(attempt))
+        .min(MAX_ADDRESS_TIMEOUT);
+
+    base.mul_f64(1.0 + rand::thread_rng().gen_range(0.0..=0.25))
+}
+
+/// Asks the configured STUN servers in order for the external address of the socket.
+async fn stun_address(socket: &tokio::net::UdpSocket) -> Result<SocketAddr, crate::io::Error> {
+    let servers = Config::get().await.stun_servers().to_vec();
+
+    for server in servers {
+        let address = tokio::net::lookup_host(&server)
+            .await
+            .ok()
+            .and_then(|mut addresses| addresses.find(SocketAddr::is_ipv4));
+
+        let Some(address) = address else {
+            log::warn!("Could not resolve STUN server {server}");
+            continue;
+        };
+
+        let mut client = StunClient::new(address);
+        client.set_timeout(STUN_TIMEOUT);
+
+        match client.query_external_address_async(socket).await {
+            Ok(external) if is_non_routable(external) => {
+                log::warn!("STUN server {server} returned unreachable address {external}");
+            }
+            Ok(external) => {
+                log::info!("STUN server {server} found external address {external}");
+                return Ok(external);
+            }
+            Err(e) => log::warn!("STUN server {server} failed: {e}"),
+        }
+    }
+
+    Err(crate::io::Error::Custom(
+        "Could not find the external address, every STUN server failed",
+    ))
+}
+
+/// Cipher of the pairing moved to the transfer phase, so messages of one are not valid in the other.
+fn transfer_cipher(cipher: Arc<Cipher>) -> Arc<Cipher> {
+    let mut cipher = Arc::unwrap_or_clone(cipher);
+    cipher.set_phase(Phase::Transfer);
+
+    Arc::new(cipher)
+}
+
+/// Addresses a peer in another network can not reach, useless as connection candidates.
+fn is_non_routable(socket_addr: SocketAddr) -> bool {
     match socket_addr.ip() {
-        IpAddr::V4(ipv4) => ipv4.is_private(),
+        IpAddr::V4(ipv4) => {
+            // NOTE: Shared address space of carrier grade NAT, 100.64.0.0/10
+            let [first, second, ..] = ipv4.octets();
+            let shared = first == 100 && second & 0b1100_0000 == 64;
+
+            ipv4.is_private()
+                || shared
+                || ipv4.is_loopback()
+                || ipv4.is_link_local()
+                || ipv4.is_unspecified()
+        }
         IpAddr::V6(_) => unreachable!("No ipv6 support"),
     }
 }
 
 #[derive(Debug)]
+#[allow(clippy::struct_excessive_bools)] // Independent facts found while pairing
 pub struct PairInfo {
     key: Key,
     cipher: Arc<Cipher>,
@@ -513,6 +1058,47 @@ pub struct PairInfo {
     self_cert: CertificateKey,
     peer_cert: Certificate,
     pub save_contact: bool,
+    peer_save_contact: bool,
+    peer_direct_address: Option<SocketAddr>,
+    peer_display_name: Option<String>,
+    insecure_lan: bool,
+    same_public_ip: bool,
+    session: bool,
+}
+
+/// Diagnostic summary of a pairing, useful to report connection problems.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[allow(clippy::struct_excessive_bools)] // Independent facts found while pairing
+pub struct PairSummary {
+    /// Both peers reached the server from the same public address
+    pub same_public_ip: bool,
+    /// Addresses gathered on this side, where the peer connects to
+    pub local_candidates: Vec<Candidate>,
+    /// Addresses gathered by the peer, where this side connects to
+    pub peer_candidates: Vec<Candidate>,
+    /// Whether the server can still relay the transfer if no candidate connects
+    pub relay_available: bool,
+    pub save_contact: bool,
+    pub insecure_lan: bool,
+    pub session: bool,
+}
+
+/// A pair of addresses tried to connect directly with the peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Candidate {
+    pub method: ConnectionMethod,
+    pub self_address: SocketAddr,
+    pub peer_address: SocketAddr,
+}
+
+impl From<ConnectionIdentifier<'_>> for Candidate {
+    fn from(id: ConnectionIdentifier<'_>) -> Self {
+        Self {
+            method: id.method,
+            self_address: id.self_address,
+            peer_address: id.peer_address,
+        }
+    }
 }
 
 impl PairInfo {
@@ -521,10 +1107,67 @@ impl PairInfo {
         self.cipher.clone()
     }
 
-    pub(crate) fn fallback(&mut self) -> Option<NetworkPeer> {
+    pub(crate) const fn fallback(&mut self) -> Option<NetworkPeer> {
         self.server_fallback.take()
     }
 
+    /// Gives back the server connection after a relayed transfer, kept only during a session.
+    pub(crate) fn keep_fallback(&mut self, peer: EncryptedNetworkPeer) {
+        if self.session {
+            self.server_fallback = Some(peer.extract_cipher().0);
+        }
+    }
+
+    /// Keeps the port mappings and the server connection open between transfers, so the
+    /// pairing can be used for several of them until [`Self::close`] is called.
+    pub const fn set_session(&mut self, enabled: bool) {
+        self.session = enabled;
+    }
+
+    #[must_use]
+    pub const fn in_session(&self) -> bool {
+        self.session
+    }
+
+    /// Serializable description of what the pairing found, without any key material.
+    #[must_use]
+    pub fn summary(&self) -> PairSummary {
+        PairSummary {
+            same_public_ip: self.same_public_ip,
+            local_candidates: self.binding_sockets().map(Candidate::from).collect(),
+            peer_candidates: self.connecting_sockets().map(Candidate::from).collect(),
+            relay_available: self.server_fallback.is_some(),
+            save_contact: self.save_contact,
+            insecure_lan: self.insecure_lan,
+            session: self.session,
+        }
+    }
+
+    /// Whether the peer asked to save the contact. When it did not, the contact can still be
+    /// kept on this side with the key returned by [`Self::finalize`].
+    #[must_use]
+    pub const fn peer_save_contact(&self) -> bool {
+        self.peer_save_contact
+    }
+
+    /// Where the peer accepts pairing directly, to try it first next time.
+    #[must_use]
+    pub const fn peer_direct_address(&self) -> Option<SocketAddr> {
+        self.peer_direct_address
+    }
+
+    /// Name the peer shows itself as, `None` if it did not set one.
+    #[must_use]
+    pub fn peer_display_name(&self) -> Option<&str> {
+        self.peer_display_name.as_deref()
+    }
+
+    /// Whether both peers agreed to skip the application cipher on direct connections.
+    #[must_use]
+    pub const fn insecure_lan(&self) -> bool {
+        self.insecure_lan
+    }
+
     pub fn connecting_sockets(&self) -> impl Iterator<Item = ConnectionIdentifier<'_>> {
         self.connecting_sockets
             .iter()
@@ -550,12 +1193,18 @@ impl PairInfo {
         self.self_cert.clone()
     }
 
-    pub async fn finalize(self) -> Key {
+    /// Releases the port mappings and connections of the pairing, returning its key.
+    pub async fn close(self) -> Key {
         for (info, _) in self.binding_sockets {
             info.finalize().await;
         }
         self.key
     }
+
+    /// Ends the pairing after its transfer, same as [`Self::close`].
+    pub async fn finalize(self) -> Key {
+        self.close().await
+    }
 }
 
 #[derive(Debug)]
@@ -575,6 +1224,25 @@ pub struct ConnectionIdentifier<'a> {
     pub local_socket: &'a UdpSocket,
     pub self_address: SocketAddr,
     pub peer_address: SocketAddr,
+    pub method: ConnectionMethod,
+}
+
+/// How the local address of a connection candidate was obtained
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionMethod {
+    HolePunching,
+    #[serde(rename = "upnp")]
+    UPnP,
+}
+
+impl std::fmt::Display for ConnectionMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HolePunching => write!(f, "hole punching"),
+            Self::UPnP => write!(f, "UPnP"),
+        }
+    }
 }
 
 impl<'a> From<&'a (TransferInfo, SocketAddr)> for ConnectionIdentifier<'a> {
@@ -583,6 +1251,7 @@ impl<'a> From<&'a (TransferInfo, SocketAddr)> for ConnectionIdentifier<'a> {
             local_socket: t.get_socket(),
             self_address: t.get_connection_address(),
             peer_address: *a,
+            method: t.method(),
         }
     }
 }
@@ -593,6 +1262,7 @@ impl<'a> From<&'a (UdpSocketAddr, SocketAddr)> for ConnectionIdentifier<'a> {
             local_socket: &s.socket,
             self_address: s.external_address,
             peer_address: *a,
+            method: ConnectionMethod::HolePunching,
         }
     }
 }
@@ -623,6 +1293,14 @@ impl TransferInfo {
         }
     }
 
+    #[must_use]
+    pub const fn method(&self) -> ConnectionMethod {
+        match self {
+            Self::Socket(_) => ConnectionMethod::HolePunching,
+            Self::UPnP { .. } => ConnectionMethod::UPnP,
+        }
+    }
+
     #[must_use]
     pub const fn get_socket(&self) -> &UdpSocket {
         match self {
@@ -642,3 +1320,154 @@ impl TransferInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::PROTOCOL_VERSION;
+
+    fn socket(external_address: SocketAddr) -> UdpSocketAddr {
+        UdpSocketAddr {
+            socket: UdpSocket::bind(ANY_ADDR).expect("Bind ephemeral port"),
+            external_address,
+            handle: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peers_with_different_versions_do_not_pair() {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .expect("Bind ephemeral port");
+        let address = listener.local_addr().expect("Bound address");
+
+        let (connected, accepted) =
+            tokio::join!(tokio::net::TcpStream::connect(address), listener.accept());
+
+        let cipher = Arc::new(Cipher::new(&[0; 32]));
+        let connected = EncryptedNetworkPeer::new(connected.expect("Connect"), cipher.clone());
+        let accepted = EncryptedNetworkPeer::new(accepted.expect("Accept").0, cipher);
+
+        let mut sender = AporturePairingProtocol {
+            data: AporturePairingProtocol::<Sender>::new(Vec::new(), false).data,
+            state: Negotiation::<Sender>::new(connected, address, [0; 32]),
+        };
+        sender.data.protocol_version = PROTOCOL_VERSION - 1;
+
+        let receiver = AporturePairingProtocol {
+            data: AporturePairingProtocol::<Receiver>::new(Vec::new(), false).data,
+            state: Negotiation::<Receiver>::new(accepted, address, [0; 32]),
+        };
+
+        let (sender, receiver) = tokio::join!(sender.exchange(), receiver.exchange());
+
+        assert!(matches!(
+            sender,
+            Err(error::Negotiation::VersionMismatch {
+                peer: PROTOCOL_VERSION
+            })
+        ));
+        assert!(matches!(
+            receiver,
+            Err(error::Negotiation::VersionMismatch { peer }) if peer == PROTOCOL_VERSION - 1
+        ));
+    }
+
+    #[test]
+    fn test_summary_lists_candidates() {
+        let local = SocketAddr::from(([203, 0, 113, 1], 4000));
+        let remote = SocketAddr::from(([198, 51, 100, 2], 5000));
+        let certificate = CertificateKey::new(vec!["127.0.0.1".into()]).expect("Certificate");
+
+        let pair_info = PairInfo {
+            key: [0; 32],
+            cipher: Arc::new(Cipher::new(&[0; 32])),
+            connecting_sockets: vec![(socket(local), remote)],
+            binding_sockets: vec![(TransferInfo::Socket(socket(local)), remote)],
+            server_fallback: None,
+            peer_cert: Certificate::from(certificate.cert_der()),
+            self_cert: certificate,
+            save_contact: true,
+            peer_save_contact: true,
+            peer_direct_address: None,
+            peer_display_name: None,
+            insecure_lan: false,
+            same_public_ip: true,
+            session: false,
+        };
+
+        let summary = pair_info.summary();
+
+        let candidate = Candidate {
+            method: ConnectionMethod::HolePunching,
+            self_address: local,
+            peer_address: remote,
+        };
+
+        assert_eq!(vec![candidate.clone()], summary.local_candidates);
+        assert_eq!(vec![candidate], summary.peer_candidates);
+        assert!(summary.same_public_ip);
+        assert!(!summary.relay_available);
+        assert!(summary.save_contact);
+    }
+
+    #[test]
+    fn test_sockets_bind_to_pinned_ip() {
+        let mut app = AporturePairingProtocol::<Sender>::new(b"key".to_vec(), false);
+        assert_eq!(ANY_ADDR, app.data.bind_address());
+
+        app.set_bind_ip(IpAddr::from([192, 168, 1, 20]));
+        assert_eq!(
+            SocketAddr::from(([192, 168, 1, 20], 0)),
+            app.data.bind_address()
+        );
+
+        assert!(interface_ip("not an interface").is_err());
+    }
+
+    #[test]
+    fn test_address_timeout_backs_off_with_jitter() {
+        let timeouts = (0..8).map(address_timeout).collect::<Vec<_>>();
+
+        for (attempt, timeout) in (0..).zip(&timeouts) {
+            let base = ADDRESS_TIMEOUT
+                .saturating_mul(2_u32.pow(attempt))
+                .min(MAX_ADDRESS_TIMEOUT);
+
+            assert!(*timeout >= base);
+            assert!(*timeout <= base.mul_f64(1.25));
+        }
+
+        assert!(timeouts[1] > timeouts[0]);
+        assert!(*timeouts.last().expect("Timeouts") <= MAX_ADDRESS_TIMEOUT.mul_f64(1.25));
+    }
+
+    #[test]
+    fn test_non_routable_addresses() {
+        let non_routable = [
+            [10, 1, 2, 3],
+            [172, 16, 0, 1],
+            [192, 168, 1, 1],
+            [100, 64, 0, 1],
+            [100, 127, 255, 254],
+            [127, 0, 0, 1],
+            [169, 254, 10, 10],
+            [0, 0, 0, 0],
+        ];
+
+        for ip in non_routable {
+            assert!(is_non_routable(SocketAddr::from((ip, 80))), "{ip:?}");
+        }
+
+        let routable = [
+            [8, 8, 8, 8],
+            [100, 63, 255, 255],
+            [100, 128, 0, 0],
+            [203, 0, 113, 1],
+        ];
+
+        for ip in routable {
+            assert!(!is_non_routable(SocketAddr::from((ip, 80))), "{ip:?}");
+        }
+    }
+}
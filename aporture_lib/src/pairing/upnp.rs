@@ -15,12 +15,25 @@ pub struct Gateway {
 
 impl Gateway {
     pub async fn new() -> Result<Self, Error> {
-        let search_options = SearchOptions {
+        Self::bound(None).await
+    }
+
+    /// Looks for the gateway from the local address and maps ports to it, or from the one the
+    /// system picks if `None`.
+    pub async fn bound(ip: Option<IpAddr>) -> Result<Self, Error> {
+        let mut search_options = SearchOptions {
             timeout: Some(Duration::from_secs(2)),
             ..Default::default()
         };
 
-        let ip = local_ip_address::local_ip()?;
+        if let Some(ip) = ip {
+            search_options.bind_addr = (ip, 0).into();
+        }
+
+        let ip = match ip {
+            Some(ip) => ip,
+            None => local_ip_address::local_ip()?,
+        };
 
         let igd = igd::aio::tokio::search_gateway(search_options).await?;
 
@@ -72,7 +85,7 @@ impl Gateway {
             self.igd
                 .remove_port(igd::PortMappingProtocol::UDP, port)
                 .await?;
-        };
+        }
 
         Ok(())
     }
@@ -1,37 +1,134 @@
 use rand::distributions::{Distribution, Uniform};
+use serde::{Deserialize, Serialize};
 
+mod short_wordlist;
 mod wordlist;
+use short_wordlist::SHORT_WORDLIST;
 use wordlist::WORDLIST;
 
+pub const DEFAULT_WORD_COUNT: usize = 3;
+
+/// Fewer words than this make passphrases too easy to guess
+pub const MIN_WORD_COUNT: usize = 2;
+
+const SEPARATOR: char = '-';
+
+/// Built-in lists passphrases are generated from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Wordlist {
+    /// EFF long wordlist
+    #[default]
+    Long,
+    /// Words of up to five letters, easier to type but less entropy per word
+    Short,
+}
+
+impl Wordlist {
+    pub const ALL: [Self; 2] = [Self::Long, Self::Short];
+
+    #[must_use]
+    pub const fn words(self) -> &'static [&'static str] {
+        match self {
+            Self::Long => WORDLIST,
+            Self::Short => SHORT_WORDLIST,
+        }
+    }
+}
+
+impl std::fmt::Display for Wordlist {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Long => write!(f, "long"),
+            Self::Short => write!(f, "short"),
+        }
+    }
+}
+
+impl std::str::FromStr for Wordlist {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|w| w.to_string() == s)
+            .ok_or("Unknown wordlist, expected long or short")
+    }
+}
+
+/// Bits of entropy of a generated passphrase with the given amount of words.
 #[must_use]
-pub fn generate(word_count: usize) -> String {
-    Uniform::new(0, WORDLIST.len())
+#[allow(clippy::cast_precision_loss)]
+pub fn entropy(word_count: usize, wordlist: Wordlist) -> f64 {
+    word_count as f64 * (wordlist.words().len() as f64).log2()
+}
+
+#[must_use]
+pub fn generate(word_count: usize, wordlist: Wordlist) -> String {
+    let words = wordlist.words();
+
+    Uniform::new(0, words.len())
         .sample_iter(rand::thread_rng())
         .take(word_count)
-        .map(|i| WORDLIST[i])
+        .map(|i| words[i])
         .collect::<Vec<&str>>()
-        .join("-")
+        .join(&SEPARATOR.to_string())
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashSet;
+
     use super::*;
 
     const WORD_COUNT: usize = 4;
 
     #[test]
     fn test_password_generation_from_wordlist() {
-        let pass = generate(WORD_COUNT);
+        for wordlist in Wordlist::ALL {
+            let pass = generate(WORD_COUNT, wordlist);
 
-        for w in pass.split('-') {
-            assert!(WORDLIST.contains(&w));
+            for w in pass.split(SEPARATOR) {
+                assert!(wordlist.words().contains(&w));
+            }
         }
     }
 
     #[test]
     fn test_password_generation_length() {
-        let pass = generate(WORD_COUNT);
+        let pass = generate(WORD_COUNT, Wordlist::default());
+
+        assert_eq!(WORD_COUNT, pass.split(SEPARATOR).count());
+    }
+
+    #[test]
+    fn test_entropy_grows_with_words() {
+        let bits = entropy(MIN_WORD_COUNT, Wordlist::Short);
+
+        assert!(bits > 20.0);
+        assert!(entropy(MIN_WORD_COUNT + 1, Wordlist::Short) > bits);
+        assert!(entropy(MIN_WORD_COUNT, Wordlist::Long) > bits);
+    }
+
+    #[test]
+    fn test_wordlists_are_valid() {
+        for wordlist in Wordlist::ALL {
+            let words = wordlist.words();
 
-        assert_eq!(WORD_COUNT, pass.split('-').count());
+            let unique = words.iter().collect::<HashSet<_>>();
+            assert_eq!(words.len(), unique.len(), "{wordlist} has duplicates");
+
+            for w in words {
+                assert!(!w.is_empty());
+                assert!(!w.contains(SEPARATOR), "{w} in {wordlist} has separator");
+                assert!(!w.contains(char::is_whitespace));
+            }
+        }
+    }
+
+    #[test]
+    fn test_wordlist_name_round_trip() {
+        for wordlist in Wordlist::ALL {
+            assert_eq!(Ok(wordlist), wordlist.to_string().parse());
+        }
     }
 }
@@ -7746,11 +7746,11 @@ pub static WORDLIST: &[&str] = &[
     "yield",
     "yin",
     "yippee",
-    "yoyo",
     "yodel",
     "yoga",
     "yogurt",
     "yonder",
+    "yowl",
     "yoyo",
     "yummy",
     "zap",
@@ -10,7 +10,11 @@ use serde_with::{serde_as, Bytes, DisplayFromStr};
 pub mod parser;
 use parser::Parser;
 
-pub const PROTOCOL_VERSION: u8 = 1;
+/// Bumped whenever a peer or the server of the previous version could not understand this one.
+///
+/// Version 2 binds every encrypted message to its context, checks every header and changed the
+/// transfer messages.
+pub const PROTOCOL_VERSION: u8 = 2;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize_repr, Serialize_repr)]
 #[repr(u8)]
@@ -32,7 +36,8 @@ pub struct Hello {
     #[serde_as(as = "Bytes")]
     pub pair_id: [u8; 32],
 }
-parse!(Hello, size: n::U67);
+// NOTE: Versions of up to three digits
+parse!(Hello, size: n::U69);
 
 impl Hello {
     #[must_use]
@@ -56,9 +61,21 @@ pub enum PairingResponseCode {
     UnsupportedVersion = 1,
     NoPeer = 4,
     MalformedMessage = 5,
+    /// The peer left before the pairing could start
+    PeerDisconnected = 6,
+    /// Waited too long for the peer and the server gave up
+    Expired = 7,
+    /// The server is holding as many pairings as it allows
+    ServerBusy = 8,
 }
 parse!(PairingResponseCode, size: n::U3);
 
+/// Protocol version implemented by the server, sent after [`PairingResponseCode::UnsupportedVersion`].
+/// Peers also exchange it before negotiating, as the server relays any version
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SupportedVersion(pub u8);
+parse!(SupportedVersion, size: n::U5);
+
 #[serde_as]
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct KeyExchangePayload(#[serde_as(as = "Bytes")] pub [u8; 33]);
@@ -70,20 +87,99 @@ pub struct NegotiationPayload {
     pub addresses: Vec<SocketAddr>,
     #[serde_as(as = "DisplayFromStr")]
     pub save_contact: bool,
+    /// Skip the application cipher on direct connections, used only if both peers offer it
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub insecure_lan: bool,
+    /// Where the peer accepts pairing directly, to be tried before the server next time
+    #[serde(default)]
+    pub direct_address: Option<SocketAddr>,
+    /// Name the peer shows itself as, such as the name of its device
+    #[serde(default)]
+    pub display_name: Option<String>,
 }
 parse!(NegotiationPayload);
 
+/// Longest display name exchanged, in characters, so it does not grow the negotiation
+pub const MAX_DISPLAY_NAME: usize = 64;
+
+/// Cleans a display name to be shown, without control characters and truncated to
+/// [`MAX_DISPLAY_NAME`]. Blank names give `None`.
+#[must_use]
+pub fn display_name(name: &str) -> Option<String> {
+    let name = name.chars().filter(|c| !c.is_control()).collect::<String>();
+
+    let name = name
+        .trim()
+        .chars()
+        .take(MAX_DISPLAY_NAME)
+        .collect::<String>();
+
+    let name = name.trim_end();
+
+    (!name.is_empty()).then(|| name.to_owned())
+}
+
 #[serde_as]
 #[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(clippy::struct_excessive_bools)] // Independent modes of the transfer
 pub struct TransferData {
     pub total_files: u64,
 
     pub total_size: u64,
 
     pub root_name: String,
+
+    /// Directories in the transfer, including the root folder
+    #[serde(default)]
+    pub total_dirs: u64,
+
+    /// Symbolic links preserved by the sender
+    #[serde(default)]
+    pub total_links: u64,
+
+    /// Verify the whole transfer with a single hash sent after every file
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub stream_hash: bool,
+
+    /// Raw bytes of unknown size follow instead of files, ended by an empty chunk
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub piped: bool,
+
+    /// The folder follows as a single gzip compressed tar archive
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub compressed: bool,
+
+    /// Extra streams of the direct connection the files of a folder are spread over,
+    /// while directories and links are sent first on this one
+    #[serde(default)]
+    pub streams: u64,
+
+    /// Only the information and hash of every file and link follow, so the receiver checks
+    /// the copy it already has
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub verify_only: bool,
+
+    /// The sender waits for the receiver to answer whether it merges the folder into its copy,
+    /// announcing every entry before sending only those requested
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub mergeable: bool,
 }
 parse!(TransferData);
 
+/// First message of both peers on every extra stream of a transfer,
+/// as a stream only reaches the peer once the side that opened it writes
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamHello {
+    pub index: u64,
+}
+parse!(StreamHello);
+
 #[serde_as]
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FileData {
@@ -92,6 +188,9 @@ pub struct FileData {
     pub file_name: String,
     #[serde_as(as = "DisplayFromStr")]
     pub is_file: bool,
+    /// Target of the entry if it is a symbolic link preserved by the sender
+    #[serde(default)]
+    pub link: Option<String>,
 }
 parse!(FileData);
 
@@ -101,9 +200,30 @@ pub enum TransferResponseCode {
     Ok = 0,
     HashMismatch = 1,
     TransferFail = 2,
+    /// Every file arrived, the manifest roots are exchanged next
+    Verify = 3,
+    /// The receiver merges the folder into its copy, so entries are announced first
+    Merge = 4,
 }
 parse!(TransferResponseCode, size: n::U3);
 
+/// Files of a verification whose copy at the receiver is not the one sent
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifyResult {
+    pub mismatched: Vec<String>,
+    pub missing: Vec<String>,
+}
+parse!(VerifyResult);
+
+/// Entries a merging receiver does not have, each requested next with its information
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeRequest {
+    pub entries: u64,
+    /// Bytes of the requested files
+    pub size: u64,
+}
+parse!(MergeRequest);
+
 #[serde_as]
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Hash(#[serde_as(as = "Bytes")] pub [u8; 32]);
@@ -120,7 +240,8 @@ pub enum HolePunchingRequest {
 }
 parse!(HolePunchingRequest, size: n::U3);
 
-parse!(SocketAddr, size: n::U24);
+// NOTE: Sent as text, the longest is an IPv6 address with a scope
+parse!(SocketAddr, size: n::U61);
 
 #[cfg(test)]
 mod test {
@@ -167,6 +288,8 @@ mod test {
 
     test_parsed!(PairKind, PairKind::Sender);
 
+    test_parsed!(SupportedVersion, SupportedVersion(u8::MAX));
+
     test_parsed!(KeyExchangePayload, KeyExchangePayload([0; 33]));
 
     test_parsed!(
@@ -174,18 +297,55 @@ mod test {
         NegotiationPayload {
             addresses: vec![SocketAddr::from(([0, 0, 0, 0], 0))],
             save_contact: true,
+            insecure_lan: true,
+            direct_address: Some(SocketAddr::from(([192, 168, 0, 2], 8766))),
+            display_name: Some("Alice's Laptop".to_owned()),
         }
     );
 
+    #[test]
+    fn test_display_name_is_cleaned() {
+        assert_eq!(
+            Some("Alice's Laptop".to_owned()),
+            display_name("  Alice's\n Laptop\t ")
+        );
+        assert_eq!(None, display_name(" \u{7}  "));
+        assert_eq!(
+            MAX_DISPLAY_NAME,
+            display_name(&"ñ".repeat(100))
+                .expect("Not blank")
+                .chars()
+                .count()
+        );
+    }
+
     test_parsed!(
         TransferData,
         TransferData {
             total_files: 1,
             total_size: 2,
             root_name: "/hello".to_owned(),
+            total_dirs: 3,
+            total_links: 4,
+            stream_hash: true,
+            piped: true,
+            compressed: true,
+            streams: 5,
+            verify_only: true,
+            mergeable: true,
         }
     );
 
+    test_parsed!(
+        VerifyResult,
+        VerifyResult {
+            mismatched: vec!["a/b".to_owned()],
+            missing: vec!["c".to_owned(), "d".to_owned()],
+        }
+    );
+
+    test_parsed!(StreamHello, StreamHello { index: 2 });
+
     test_parsed!(
         FileData,
         FileData {
@@ -193,14 +353,162 @@ mod test {
             id: 0,
             is_file: false,
             file_name: "pepe".to_owned(),
+            link: Some("../target".to_owned()),
         }
     );
 
+    #[test]
+    fn test_every_pairing_response_code_round_trips() {
+        for code in [
+            PairingResponseCode::Ok,
+            PairingResponseCode::OkSamePublicIP,
+            PairingResponseCode::UnsupportedVersion,
+            PairingResponseCode::NoPeer,
+            PairingResponseCode::MalformedMessage,
+            PairingResponseCode::PeerDisconnected,
+            PairingResponseCode::Expired,
+            PairingResponseCode::ServerBusy,
+        ] {
+            let serialized = code.serialize_to();
+
+            let len = PairingResponseCode::serialized_size().expect("Fixed size");
+            assert!(len >= serialized.len());
+
+            let deserialized =
+                PairingResponseCode::deserialize_from(&serialized).expect("Valid code");
+            assert_eq!(code, deserialized);
+        }
+    }
+
     test_parsed!(TransferResponseCode, TransferResponseCode::Ok);
 
+    #[test]
+    fn test_transfer_response_codes_are_stable() {
+        let codes = [
+            (TransferResponseCode::Ok, 0),
+            (TransferResponseCode::HashMismatch, 1),
+            (TransferResponseCode::TransferFail, 2),
+            (TransferResponseCode::Verify, 3),
+            (TransferResponseCode::Merge, 4),
+        ];
+
+        for (code, discriminant) in codes {
+            assert_eq!(discriminant, code as u8);
+
+            let parsed = TransferResponseCode::deserialize_from(&code.serialize_to());
+            assert_eq!(code, parsed.expect("Valid code"));
+        }
+    }
+
+    test_parsed!(
+        MergeRequest,
+        MergeRequest {
+            entries: 2,
+            size: 1024
+        }
+    );
+
     test_parsed!(Hash, Hash([0; 32]));
 
     test_parsed!(SocketAddr, ([200, 200, 200, 200], 65535).into());
 
     test_parsed!(HolePunchingRequest, HolePunchingRequest::Address);
+
+    /// Checks every value fits in the declared size, as a read of a bigger one fails.
+    macro_rules! test_bounded {
+        ($type:ident, $values:expr) => {
+            paste! {
+                #[test]
+                #[allow(non_snake_case)]
+                fn [<test_ $type _fits_declared_size>]() {
+                    let size = $type::serialized_size().expect("Declared size");
+
+                    for value in $values {
+                        let len = value.serialize_to().len();
+                        assert!(len <= size, "{value:?} takes {len} bytes, declared {size}");
+                    }
+                }
+            }
+        };
+    }
+
+    fn random_bytes<const N: usize>() -> impl Iterator<Item = [u8; N]> {
+        let random = (0..100).map(|_| {
+            let mut bytes = [0; N];
+            rand::Rng::fill(&mut rand::thread_rng(), &mut bytes[..]);
+            bytes
+        });
+
+        [[0; N], [u8::MAX; N]].into_iter().chain(random)
+    }
+
+    test_bounded!(PairKind, [PairKind::Sender, PairKind::Receiver]);
+
+    test_bounded!(
+        Hello,
+        (0..=u8::MAX).flat_map(|version| {
+            random_bytes().take(3).flat_map(move |pair_id| {
+                [PairKind::Sender, PairKind::Receiver].map(|kind| Hello {
+                    version,
+                    kind,
+                    pair_id,
+                })
+            })
+        })
+    );
+
+    test_bounded!(
+        PairingResponseCode,
+        [
+            PairingResponseCode::Ok,
+            PairingResponseCode::OkSamePublicIP,
+            PairingResponseCode::UnsupportedVersion,
+            PairingResponseCode::NoPeer,
+            PairingResponseCode::MalformedMessage,
+            PairingResponseCode::PeerDisconnected,
+            PairingResponseCode::Expired,
+            PairingResponseCode::ServerBusy,
+        ]
+    );
+
+    test_bounded!(SupportedVersion, (0..=u8::MAX).map(SupportedVersion));
+
+    test_bounded!(KeyExchangePayload, random_bytes().map(KeyExchangePayload));
+
+    test_bounded!(
+        TransferResponseCode,
+        [
+            TransferResponseCode::Ok,
+            TransferResponseCode::HashMismatch,
+            TransferResponseCode::TransferFail,
+            TransferResponseCode::Verify,
+            TransferResponseCode::Merge,
+        ]
+    );
+
+    test_bounded!(Hash, random_bytes().map(Hash));
+
+    test_bounded!(
+        HolePunchingRequest,
+        [
+            HolePunchingRequest::Address,
+            HolePunchingRequest::Relay,
+            HolePunchingRequest::None,
+        ]
+    );
+
+    test_bounded!(
+        SocketAddr,
+        random_bytes::<6>()
+            .map(|[a, b, c, d, high, low]| {
+                SocketAddr::from(([a, b, c, d], u16::from_be_bytes([high, low])))
+            })
+            .chain(random_bytes::<18>().map(|bytes| {
+                let (ip, port) = bytes.split_at(16);
+                let ip = <[u8; 16]>::try_from(ip).expect("16 bytes");
+                let port = u16::from_be_bytes([port[0], port[1]]);
+
+                SocketAddr::V6(std::net::SocketAddrV6::new(ip.into(), port, 0, u32::MAX))
+            }))
+    );
 }
@@ -4,11 +4,19 @@ use std::future::Future;
 use generic_array::{ArrayLength, GenericArray};
 use serde::{Deserialize, Serialize};
 
+/// Types sent as a single message.
+///
+/// Types with a bounded size are read into a buffer of that size, every other type is read
+/// into one as big as the largest message. The length of the message is always the one sent,
+/// so a value smaller than the bound is framed correctly.
 pub trait Parser: Serialize + for<'a> Deserialize<'a> {
+    /// Largest serialized size of any value, zero if it is not bounded.
     type MaximumSerializedSize: ArrayLength;
 
+    /// Buffer big enough to read any value, `None` if the size is not bounded.
     fn buffer() -> Option<GenericArray<u8, Self::MaximumSerializedSize>>;
 
+    /// Largest serialized size of any value, `None` if it is not bounded.
     fn serialized_size() -> Option<usize>;
 
     fn serialize_to(&self) -> Vec<u8> {
@@ -32,8 +40,20 @@ pub trait SerdeIO {
 #[cfg(feature = "full")]
 pub trait EncryptedSerdeIO: SerdeIO {
     fn read_enc(&mut self, buffer: &mut [u8]) -> impl Future<Output = Result<usize, Error>> + Send;
+    /// Reads a message encrypted with [`Self::write_enc_in`] and the same domain.
+    fn read_enc_in(
+        &mut self,
+        buffer: &mut [u8],
+        domain: &[u8],
+    ) -> impl Future<Output = Result<usize, Error>> + Send;
     fn read_ser_enc<P: Parser + Sync>(&mut self) -> impl Future<Output = Result<P, Error>> + Send;
     fn write_enc(&mut self, input: &mut [u8]) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Writes a message that can only be read in the same domain, for example the same file.
+    fn write_enc_in(
+        &mut self,
+        input: &mut [u8],
+        domain: &[u8],
+    ) -> impl Future<Output = Result<(), Error>> + Send;
     fn write_ser_enc<P>(&mut self, input: &P) -> impl Future<Output = Result<(), Error>> + Send
     where
         P: Parser + Sync;
@@ -69,6 +89,7 @@ macro_rules! parse {
     };
 }
 
+// NOTE: The number of elements is not bounded, so neither is the size even if `P` is
 impl<P: Parser> Parser for Vec<P> {
     type MaximumSerializedSize = generic_array::typenum::U0;
 
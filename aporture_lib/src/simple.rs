@@ -0,0 +1,131 @@
+//! Pairing and transfer in a single call, for embedders that do not need every option of
+//! [`AporturePairingProtocol`] and [`AportureTransferProtocol`].
+
+use std::path::Path;
+use std::time::Duration;
+
+use thiserror::Error;
+use tokio::sync::{mpsc, watch};
+
+use crate::crypto::Key;
+use crate::pairing::{self, AporturePairingProtocol, PairInfo};
+use crate::transfer::{AportureTransferProtocol, ChannelMessage, OnConflict, TransferOptions};
+use crate::transfer::{ReceiveError, SendError, SendReport, TransferReport};
+use crate::{Receiver, Sender};
+
+/// Options of [`send_file`] and [`receive_file`].
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Ask the peer to save each other as contacts, see [`Outcome::contact_key`]
+    pub save_contact: bool,
+    /// Notified of the progress of the transfer
+    pub progress: Option<mpsc::Sender<ChannelMessage>>,
+    /// Halts the transfer while it is true
+    pub pause: Option<watch::Receiver<bool>>,
+    /// Entries of a folder to leave out when sending, as .gitignore style patterns
+    pub exclude: Vec<String>,
+    /// What the receiver does when the destination already exists
+    pub on_conflict: OnConflict,
+    /// Time the receiver keeps waiting for a sender that has not started yet
+    pub wait: Option<Duration>,
+}
+
+/// Result of a completed transfer.
+#[derive(Debug)]
+pub struct Outcome<R> {
+    pub report: R,
+    /// Key to save the peer as a contact, only present if both peers asked for it
+    pub contact_key: Option<Key>,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Pairing(#[from] pairing::Error),
+    #[error(transparent)]
+    Send(#[from] SendError),
+    #[error(transparent)]
+    Receive(#[from] ReceiveError),
+}
+
+/// Pairs with the peer using the passphrase and sends the file or folder.
+pub async fn send_file(
+    passphrase: Vec<u8>,
+    path: &Path,
+    options: Options,
+) -> Result<Outcome<SendReport>, Error> {
+    let transfer_options = TransferOptions::default().exclude(&options.exclude)?;
+
+    let app = AporturePairingProtocol::<Sender>::new(passphrase, options.save_contact);
+
+    let mut pair_info = app.pair().await?;
+
+    let mut atp =
+        AportureTransferProtocol::<Sender>::with_options(&mut pair_info, path, transfer_options);
+
+    if let Some(progress) = options.progress {
+        atp.add_progress_notifier(progress);
+    }
+
+    if let Some(pause) = options.pause {
+        atp.add_pause_control(pause);
+    }
+
+    let report = atp.transfer().await?;
+
+    let contact_key = finish(pair_info).await;
+
+    Ok(Outcome {
+        report,
+        contact_key,
+    })
+}
+
+/// Pairs with the peer using the passphrase and receives into the destination, which is
+/// either a folder to save the transfer in or the exact path to save it as.
+pub async fn receive_file(
+    passphrase: Vec<u8>,
+    destination: &Path,
+    options: Options,
+) -> Result<Outcome<TransferReport>, Error> {
+    let mut app = AporturePairingProtocol::<Receiver>::new(passphrase, options.save_contact);
+
+    if let Some(wait) = options.wait {
+        app.set_wait(wait);
+    }
+
+    let mut pair_info = app.pair().await?;
+
+    let transfer_options = TransferOptions::default().on_conflict(options.on_conflict);
+
+    let mut atp = AportureTransferProtocol::<Receiver>::with_options(
+        &mut pair_info,
+        destination,
+        transfer_options,
+    );
+
+    if let Some(progress) = options.progress {
+        atp.add_progress_notifier(progress);
+    }
+
+    if let Some(pause) = options.pause {
+        atp.add_pause_control(pause);
+    }
+
+    let report = atp.transfer().await?;
+
+    let contact_key = finish(pair_info).await;
+
+    Ok(Outcome {
+        report,
+        contact_key,
+    })
+}
+
+async fn finish(pair_info: PairInfo) -> Option<Key> {
+    let save_contact = pair_info.save_contact;
+
+    let key = pair_info.finalize().await;
+
+    save_contact.then_some(key)
+}
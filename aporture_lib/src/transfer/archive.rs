@@ -0,0 +1,748 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use tempfile::NamedTempFile;
+use tokio::io::DuplexStream;
+use tokio::task::JoinHandle;
+use tokio_util::io::SyncIoBridge;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use super::ArchiveFormat;
+use crate::protocol::{FileData, TransferData};
+
+/// Bytes of the contents buffered between the thread reading the archive and the peer
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Writes the received entries into an archive instead of the destination, each one once it
+/// is verified. Committed files are removed from the staging folder, so it never holds them all.
+#[derive(Clone)]
+pub struct Sink {
+    writer: Arc<Mutex<Writer>>,
+    root: String,
+    format: ArchiveFormat,
+    committed: Arc<AtomicU64>,
+}
+
+impl Sink {
+    /// Starts an archive in a temporary file inside the folder, with every entry under the root.
+    pub fn new(format: ArchiveFormat, base: &Path, root: &str) -> Result<Self, std::io::Error> {
+        let file = BufWriter::new(NamedTempFile::new_in(base)?);
+
+        let writer = match format {
+            ArchiveFormat::Tar => {
+                let mut tar = tar::Builder::new(file);
+                tar.follow_symlinks(false);
+
+                Writer::Tar(tar)
+            }
+            ArchiveFormat::Zip => Writer::Zip(Box::new(ZipWriter::new(file))),
+        };
+
+        Ok(Self {
+            writer: Arc::new(Mutex::new(writer)),
+            root: root.to_owned(),
+            format,
+            committed: Arc::default(),
+        })
+    }
+
+    pub const fn format(&self) -> ArchiveFormat {
+        self.format
+    }
+
+    /// Files committed so far, as they are no longer in the staging folder.
+    pub fn committed(&self) -> u64 {
+        self.committed.load(Ordering::Relaxed)
+    }
+
+    /// Appends the verified file as the name and removes it.
+    pub async fn commit(&self, path: &Path, name: &str) -> Result<(), std::io::Error> {
+        let (writer, path, name) = (self.writer.clone(), path.to_owned(), self.entry(name));
+
+        tokio::task::spawn_blocking(move || {
+            writer
+                .lock()
+                .expect("Lock is not poisoned")
+                .append(&path, &name)?;
+
+            std::fs::remove_file(path)
+        })
+        .await
+        .expect("Task was aborted")?;
+
+        self.committed.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Appends every entry left inside the folder, named relative to it.
+    pub async fn commit_all(&self, dir: &Path) -> Result<(), std::io::Error> {
+        let (sink, dir) = (self.clone(), dir.to_owned());
+
+        tokio::task::spawn_blocking(move || {
+            let mut writer = sink.writer.lock().expect("Lock is not poisoned");
+
+            for entry in walkdir::WalkDir::new(&dir).min_depth(1).sort_by_file_name() {
+                let entry = entry?;
+
+                let name = entry
+                    .path()
+                    .strip_prefix(&dir)
+                    .expect("Walked entries are inside the folder")
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+
+                writer.append(entry.path(), &sink.entry(&name))?;
+            }
+
+            drop(writer);
+
+            Ok(())
+        })
+        .await
+        .expect("Task was aborted")
+    }
+
+    /// Completes the archive, returning the temporary file holding it.
+    pub async fn finish(self) -> Result<NamedTempFile, std::io::Error> {
+        let writer = Arc::into_inner(self.writer).expect("Every other sink was dropped");
+        let writer = writer.into_inner().expect("Lock is not poisoned");
+
+        tokio::task::spawn_blocking(move || {
+            let file = match writer {
+                Writer::Tar(tar) => tar.into_inner()?,
+                Writer::Zip(zip) => zip.finish()?,
+            };
+
+            file.into_inner()
+                .map_err(std::io::IntoInnerError::into_error)
+        })
+        .await
+        .expect("Task was aborted")
+    }
+
+    fn entry(&self, name: &str) -> String {
+        if self.root.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}/{name}", self.root)
+        }
+    }
+}
+
+enum Writer {
+    Tar(tar::Builder<BufWriter<NamedTempFile>>),
+    Zip(Box<ZipWriter<BufWriter<NamedTempFile>>>),
+}
+
+impl Writer {
+    fn append(&mut self, path: &Path, name: &str) -> Result<(), std::io::Error> {
+        match self {
+            Self::Tar(tar) => tar.append_path_with_name(path, name),
+            Self::Zip(zip) => append_zip(zip, path, name),
+        }
+    }
+}
+
+/// Adds the entry to the zip archive without compressing it.
+/// Links are stored as links, with their target as the contents like most archivers do.
+fn append_zip<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    path: &Path,
+    name: &str,
+) -> Result<(), std::io::Error> {
+    let metadata = std::fs::symlink_metadata(path)?;
+
+    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    let modified = chrono::DateTime::<chrono::Local>::from(modified).naive_local();
+
+    // NOTE: Times the format can not hold, like those before 1980, are left as its earliest
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .last_modified_time(zip::DateTime::try_from(modified).unwrap_or_default())
+        .unix_permissions(mode(&metadata))
+        .large_file(metadata.len() >= u64::from(u32::MAX));
+
+    if metadata.is_dir() {
+        zip.add_directory(name, options)?;
+    } else if metadata.is_symlink() {
+        let target = std::fs::read_link(path)?;
+
+        zip.add_symlink(name, target.to_string_lossy(), options)?;
+    } else {
+        zip.start_file(name, options)?;
+
+        std::io::copy(&mut File::open(path)?, zip)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn mode(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.mode()
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &std::fs::Metadata) -> u32 {
+    if metadata.is_dir() {
+        0o40755
+    } else if metadata.is_symlink() {
+        0o120777
+    } else {
+        0o100644
+    }
+}
+
+/// Reads the entries of an archive to send them as a folder, without extracting it.
+///
+/// Directories come first, including those only implied by the names of other entries, so
+/// they exist before anything inside them arrives. Files and links keep the archive order,
+/// so their contents are read in a single pass. If every entry is inside the same folder it
+/// is used as the root, otherwise the root is named after the archive.
+pub struct Source {
+    path: PathBuf,
+    format: ArchiveFormat,
+    root: String,
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    name: String,
+    size: u64,
+    is_file: bool,
+    link: Option<String>,
+    /// Position inside the archive
+    index: usize,
+}
+
+impl Entry {
+    const fn is_dir(&self) -> bool {
+        !self.is_file && self.link.is_none()
+    }
+}
+
+impl Source {
+    /// Lists the entries of the tar or zip archive, told apart by its first bytes.
+    pub async fn open(path: &Path) -> Result<Self, std::io::Error> {
+        let path = path.to_owned();
+
+        tokio::task::spawn_blocking(move || {
+            let mut magic = [0; 4];
+            let read = File::open(&path)?.read(&mut magic)?;
+
+            let format = if read == 4 && (magic == *b"PK\x03\x04" || magic == *b"PK\x05\x06") {
+                ArchiveFormat::Zip
+            } else {
+                ArchiveFormat::Tar
+            };
+
+            let entries = match format {
+                ArchiveFormat::Tar => list_tar(&path)?,
+                ArchiveFormat::Zip => list_zip(&path)?,
+            };
+
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let (root, entries) = arrange(entries, &stem)?;
+
+            Ok(Self {
+                path,
+                format,
+                root,
+                entries,
+            })
+        })
+        .await
+        .expect("Task was aborted")
+    }
+
+    pub fn transfer_data(&self) -> TransferData {
+        let mut data = TransferData {
+            root_name: self.root.clone(),
+            // NOTE: The root folder is counted like when walking a folder
+            total_dirs: 1,
+            ..Default::default()
+        };
+
+        for entry in &self.entries {
+            if entry.is_file {
+                data.total_files += 1;
+                data.total_size += entry.size;
+            } else if entry.is_dir() {
+                data.total_dirs += 1;
+            } else {
+                data.total_links += 1;
+            }
+        }
+
+        data
+    }
+
+    pub const fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Information sent before the entry with the id.
+    pub fn file_data(&self, id: usize) -> Option<FileData> {
+        self.entries.get(id).map(|entry| FileData {
+            id: id as u64,
+            file_size: entry.size,
+            file_name: entry.name.clone(),
+            is_file: entry.is_file,
+            link: entry.link.clone(),
+        })
+    }
+
+    /// Reads the contents of every file in order, or only of the one with the id, into the
+    /// returned stream. The task fails if the archive does not hold the sizes it listed.
+    pub fn contents(&self, only: Option<usize>) -> (DuplexStream, JoinHandle<std::io::Result<()>>) {
+        let files = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(id, entry)| entry.is_file && only.is_none_or(|only| only == *id))
+            .map(|(_, entry)| (entry.index, entry.size))
+            .collect::<Vec<_>>();
+
+        let (reader, writer) = tokio::io::duplex(BUFFER_SIZE);
+        let mut writer = SyncIoBridge::new(writer);
+
+        let (path, format) = (self.path.clone(), self.format);
+        let reading = tokio::task::spawn_blocking(move || {
+            match format {
+                ArchiveFormat::Tar => read_tar(&path, &files, &mut writer)?,
+                ArchiveFormat::Zip => read_zip(&path, &files, &mut writer)?,
+            }
+
+            writer.flush()
+        });
+
+        (reader, reading)
+    }
+}
+
+fn list_tar(path: &Path) -> Result<Vec<Entry>, std::io::Error> {
+    let mut archive = tar::Archive::new(File::open(path)?);
+    let mut entries = Vec::new();
+
+    for (index, entry) in archive.entries_with_seek()?.enumerate() {
+        let entry = entry?;
+
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let kind = entry.header().entry_type();
+
+        let (is_file, link) = if kind.is_file() {
+            (true, None)
+        } else if kind.is_dir() {
+            (false, None)
+        } else if kind.is_symlink() {
+            let target = entry.link_name()?.unwrap_or_default();
+
+            (false, Some(target.to_string_lossy().into_owned()))
+        } else {
+            log::warn!("Skipping entry {name} of the archive as it is not a file, folder or link");
+            continue;
+        };
+
+        let size = if is_file { entry.size() } else { 0 };
+
+        entries.push(Entry {
+            name,
+            size,
+            is_file,
+            link,
+            index,
+        });
+    }
+
+    Ok(entries)
+}
+
+fn read_tar(
+    path: &Path,
+    files: &[(usize, u64)],
+    writer: &mut impl Write,
+) -> Result<(), std::io::Error> {
+    let mut archive = tar::Archive::new(File::open(path)?);
+    let mut files = files.iter().peekable();
+
+    for (index, entry) in archive.entries_with_seek()?.enumerate() {
+        let Some((_, size)) = files.next_if(|(wanted, _)| *wanted == index) else {
+            if files.peek().is_none() {
+                break;
+            }
+
+            continue;
+        };
+
+        copy_exactly(&mut entry?, writer, *size)?;
+    }
+
+    Ok(())
+}
+
+fn list_zip(path: &Path) -> Result<Vec<Entry>, std::io::Error> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut entries = Vec::with_capacity(archive.len());
+
+    for index in 0..archive.len() {
+        let entry = archive.by_index_raw(index)?;
+
+        if entry.encrypted() {
+            return Err(unsupported("Encrypted zip entries are not supported"));
+        }
+
+        if !matches!(
+            entry.compression(),
+            CompressionMethod::Stored | CompressionMethod::Deflated
+        ) {
+            return Err(unsupported(
+                "Only stored and deflated zip entries are supported",
+            ));
+        }
+
+        let (name, size) = (entry.name().to_owned(), entry.size());
+        let (is_dir, is_symlink) = (entry.is_dir(), entry.is_symlink());
+
+        drop(entry);
+
+        // NOTE: Links are stored with their target as the contents
+        let (is_file, link) = if is_dir {
+            (false, None)
+        } else if is_symlink {
+            let mut target = String::new();
+            archive.by_index(index)?.read_to_string(&mut target)?;
+
+            (false, Some(target))
+        } else {
+            (true, None)
+        };
+
+        entries.push(Entry {
+            name,
+            size: if is_file { size } else { 0 },
+            is_file,
+            link,
+            index,
+        });
+    }
+
+    Ok(entries)
+}
+
+fn read_zip(
+    path: &Path,
+    files: &[(usize, u64)],
+    writer: &mut impl Write,
+) -> Result<(), std::io::Error> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+
+    for &(index, size) in files {
+        copy_exactly(&mut archive.by_index(index)?, writer, size)?;
+    }
+
+    Ok(())
+}
+
+fn copy_exactly(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    size: u64,
+) -> Result<(), std::io::Error> {
+    let copied = std::io::copy(&mut reader.take(size), writer)?;
+
+    if copied == size {
+        Ok(())
+    } else {
+        Err(invalid("Archive entry is shorter than its size"))
+    }
+}
+
+/// Normalizes the names of the entries, adds the folders they imply and picks the root.
+fn arrange(entries: Vec<Entry>, stem: &str) -> Result<(String, Vec<Entry>), std::io::Error> {
+    let mut arranged = Vec::with_capacity(entries.len());
+
+    for mut entry in entries {
+        entry.name = normalize(&entry.name)
+            .ok_or_else(|| invalid(format!("Entry {} is outside the archive", entry.name)))?;
+
+        if !entry.name.is_empty() {
+            arranged.push(entry);
+        }
+    }
+
+    let mut dirs = arranged
+        .iter()
+        .filter(|e| e.is_dir())
+        .map(|e| e.name.clone())
+        .collect::<BTreeSet<_>>();
+
+    let implied = arranged
+        .iter()
+        .flat_map(|e| {
+            e.name
+                .match_indices('/')
+                .map(|(i, _)| e.name[..i].to_owned())
+        })
+        .collect::<BTreeSet<_>>();
+
+    for name in implied {
+        if dirs.insert(name.clone()) {
+            arranged.push(Entry {
+                name,
+                size: 0,
+                is_file: false,
+                link: None,
+                index: usize::MAX,
+            });
+        }
+    }
+
+    let tops = arranged
+        .iter()
+        .map(|e| e.name.split('/').next().unwrap_or_default())
+        .collect::<BTreeSet<_>>();
+
+    let root = match tops.first() {
+        Some(&top) if tops.len() == 1 && dirs.contains(top) => {
+            let root = top.to_owned();
+
+            arranged.retain(|e| e.name != root);
+            for entry in &mut arranged {
+                entry.name.drain(..=root.len());
+            }
+
+            root
+        }
+        _ => stem.to_owned(),
+    };
+
+    // NOTE: Folders sorted by name come before anything inside them
+    arranged.sort_by(|a, b| {
+        let (a_dir, b_dir) = (a.is_dir(), b.is_dir());
+
+        b_dir.cmp(&a_dir).then_with(|| {
+            if a_dir {
+                a.name.cmp(&b.name)
+            } else {
+                a.index.cmp(&b.index)
+            }
+        })
+    });
+
+    Ok((root, arranged))
+}
+
+/// Name relative to the archive with `/` separators, or `None` if it escapes it.
+fn normalize(name: &str) -> Option<String> {
+    let mut parts = Vec::new();
+
+    for part in name.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => return None,
+            part => parts.push(part),
+        }
+    }
+
+    Some(parts.join("/"))
+}
+
+fn invalid(message: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.into())
+}
+
+fn unsupported(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Unsupported, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_zip_entries_are_stored() {
+        let dir = tempfile::tempdir().expect("Temp dir");
+        std::fs::create_dir(dir.path().join("a")).expect("Create dir");
+        std::fs::write(dir.path().join("a/file"), b"hello").expect("Write file");
+
+        let mut zip = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        append_zip(&mut zip, &dir.path().join("a/file"), "a/file").expect("Append file");
+        append_zip(&mut zip, &dir.path().join("a"), "a").expect("Append dir");
+
+        let mut archive = ZipArchive::new(zip.finish().expect("Finish")).expect("Valid archive");
+        assert_eq!(2, archive.len());
+
+        let mut file = archive.by_index(0).expect("File entry");
+        assert_eq!("a/file", file.name());
+        assert_eq!(CompressionMethod::Stored, file.compression());
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).expect("Read file");
+        assert_eq!("hello", contents);
+        drop(file);
+
+        let folder = archive.by_index(1).expect("Folder entry");
+        assert_eq!("a/", folder.name());
+        assert!(folder.is_dir());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_zip_links_keep_their_target() {
+        let dir = tempfile::tempdir().expect("Temp dir");
+        std::os::unix::fs::symlink("target", dir.path().join("link")).expect("Create link");
+
+        let mut zip = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        append_zip(&mut zip, &dir.path().join("link"), "link").expect("Append link");
+
+        let mut archive = ZipArchive::new(zip.finish().expect("Finish")).expect("Valid archive");
+
+        let mut link = archive.by_index(0).expect("Link entry");
+        assert!(link.is_symlink());
+
+        let mut target = String::new();
+        link.read_to_string(&mut target).expect("Read target");
+        assert_eq!("target", target);
+    }
+
+    #[test]
+    fn test_zip64_archives_are_listed() {
+        let dir = tempfile::tempdir().expect("Temp dir");
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"hello").expect("Write file");
+
+        let count = usize::from(u16::MAX) + 1;
+
+        let mut zip = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for i in 0..count {
+            append_zip(&mut zip, &path, &format!("{i}")).expect("Append file");
+        }
+
+        let archive = zip.finish().expect("Finish").into_inner();
+
+        // NOTE: The classic end record can not count them all, so they are in the zip64 one
+        let end = &archive[archive.len() - 22..];
+        assert_eq!(0x0605_4b50_u32.to_le_bytes(), end[..4]);
+        assert_eq!(u16::MAX.to_le_bytes(), end[10..12]);
+
+        let path = dir.path().join("archive.zip");
+        std::fs::write(&path, archive).expect("Write archive");
+
+        let entries = list_zip(&path).expect("List archive");
+        assert_eq!(count, entries.len());
+        assert_eq!("65535", entries[count - 1].name);
+        assert_eq!(5, entries[count - 1].size);
+
+        let mut contents = Vec::new();
+        read_zip(&path, &[(count - 1, 5)], &mut contents).expect("Read contents");
+        assert_eq!(b"hello", contents.as_slice());
+    }
+
+    /// Archive as other tools write them, with a deflated file, a folder and a link.
+    fn foreign_zip() -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+
+        let stored = zip::write::SimpleFileOptions::default();
+        let deflated = stored.compression_method(CompressionMethod::Deflated);
+
+        zip.add_directory("data", stored).expect("Add dir");
+        zip.start_file("data/file", deflated).expect("Start file");
+        zip.write_all(&b"hello ".repeat(100)).expect("Write file");
+        zip.add_symlink("data/link", "file", stored)
+            .expect("Add link");
+
+        zip.finish().expect("Finish").into_inner()
+    }
+
+    #[test]
+    fn test_foreign_zip_is_listed_and_read() {
+        let dir = tempfile::tempdir().expect("Temp dir");
+        let archive = dir.path().join("archive.zip");
+        std::fs::write(&archive, foreign_zip()).expect("Write archive");
+
+        let entries = list_zip(&archive).expect("List archive");
+
+        let names = entries.iter().map(|e| e.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["data/", "data/file", "data/link"], names);
+        assert!(entries[0].is_dir());
+        assert!(entries[1].is_file);
+        assert_eq!(600, entries[1].size);
+        assert_eq!(Some("file"), entries[2].link.as_deref());
+
+        let mut contents = Vec::new();
+        read_zip(&archive, &[(1, 600)], &mut contents).expect("Read contents");
+        assert_eq!(b"hello ".repeat(100), contents);
+    }
+
+    #[test]
+    fn test_malformed_zip_is_rejected() {
+        let dir = tempfile::tempdir().expect("Temp dir");
+        let archive = dir.path().join("archive.zip");
+
+        let valid = foreign_zip();
+        let end = valid.len() - 22;
+
+        // NOTE: Central directory past the end of the archive
+        let mut offset = valid.clone();
+        offset[end + 16..end + 20].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        // NOTE: Zip64 locator pointing past the end of the archive
+        let mut locator = valid[..end].to_vec();
+        locator.extend(0x0706_4b50_u32.to_le_bytes());
+        locator.extend(0_u32.to_le_bytes());
+        locator.extend(u64::MAX.to_le_bytes());
+        locator.extend(1_u32.to_le_bytes());
+        locator.extend(&offset[end..]);
+
+        // NOTE: Comment longer than what is left of the archive
+        let mut comment = valid.clone();
+        comment[end + 20..].copy_from_slice(&u16::MAX.to_le_bytes());
+
+        for malformed in [offset, locator, comment, valid[..end].to_vec()] {
+            std::fs::write(&archive, malformed).expect("Write archive");
+
+            assert!(list_zip(&archive).is_err());
+        }
+    }
+
+    #[test]
+    fn test_archive_entries_are_arranged() {
+        let entry = |name: &str, is_file, index| Entry {
+            name: name.to_owned(),
+            size: 0,
+            is_file,
+            link: None,
+            index,
+        };
+
+        let names = |entries: &[Entry]| entries.iter().map(|e| e.name.clone()).collect::<Vec<_>>();
+
+        let entries = vec![
+            entry("./data/b/file", true, 0),
+            entry("data/a", true, 1),
+            entry("data/", false, 2),
+        ];
+        let (root, entries) = arrange(entries, "backup").expect("Arranged");
+        assert_eq!("data", root);
+        assert_eq!(vec!["b", "b/file", "a"], names(&entries));
+
+        let entries = vec![entry("a", true, 0), entry("b/c", true, 1)];
+        let (root, entries) = arrange(entries, "backup").expect("Arranged");
+        assert_eq!("backup", root);
+        assert_eq!(vec!["b", "a", "b/c"], names(&entries));
+
+        let entries = vec![entry("data/../../escape", true, 0)];
+        assert!(arrange(entries, "backup").is_err());
+    }
+}
@@ -1,16 +1,107 @@
-pub type Channel = tokio::sync::mpsc::Sender<Message>;
+use tokio::sync::{mpsc, watch};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Connects a transfer with its caller, notifying the progress and following pause and
+/// cancel requests.
+#[derive(Debug, Clone, Default)]
+pub struct Channel {
+    pub(super) progress: Option<mpsc::Sender<Message>>,
+    /// The transfer halts between chunks while it is true
+    pub(super) paused: Option<watch::Receiver<bool>>,
+    /// The transfer stops, telling the peer, once it is true
+    pub(super) cancelled: Option<watch::Receiver<bool>>,
+}
+
+impl Channel {
+    /// Keeps only the pause and cancel controls, for loops whose progress is notified elsewhere.
+    pub fn control(&self) -> Self {
+        Self {
+            progress: None,
+            paused: self.paused.clone(),
+            cancelled: self.cancelled.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Message {
     Compression,
     ProgressSize(usize),
+    /// A file starts, the progress that follows belongs to it until the next one.
+    /// Not sent for the files of a compressed folder, which are packed as a single stream
+    FileStarted {
+        name: String,
+        size: u64,
+    },
     Progress(usize),
-    Uncompressing,
     Finished,
+    /// The direct connection failed and the transfer goes through the server instead
+    UsingRelay,
+    /// The connection was lost and the transfer starts again on a new one, the sizes and
+    /// progress that follow replace the earlier ones
+    Reconnecting,
 }
 
 pub async fn send(channel: Option<&Channel>, message: Message) {
-    if let Some(channel) = channel {
-        let _ = channel.send(message).await;
+    if let Some(progress) = channel.and_then(|c| c.progress.as_ref()) {
+        let _ = progress.send(message).await;
+    }
+}
+
+/// Same as [`send`] for threads outside the runtime.
+pub fn blocking_send(channel: Option<&Channel>, message: Message) {
+    if let Some(progress) = channel.and_then(|c| c.progress.as_ref()) {
+        let _ = progress.blocking_send(message);
+    }
+}
+
+/// Waits until the transfer is not paused.
+pub async fn resumed(channel: Option<&Channel>) {
+    if let Some(paused) = channel.and_then(|c| c.paused.as_ref()) {
+        // NOTE: A dropped control can not resume anymore, so the transfer continues
+        let _ = paused.clone().wait_for(|paused| !paused).await;
+    }
+}
+
+/// Waits until the transfer is cancelled, forever if it can not be.
+pub async fn cancelled(channel: Option<&Channel>) {
+    if let Some(cancelled) = channel.and_then(|c| c.cancelled.as_ref()) {
+        // NOTE: A dropped control can not cancel anymore
+        if cancelled
+            .clone()
+            .wait_for(|cancelled| *cancelled)
+            .await
+            .is_ok()
+        {
+            return;
+        }
+    }
+
+    std::future::pending::<()>().await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_resumed_waits_while_paused() {
+        let (pause, paused) = watch::channel(true);
+        let channel = Channel {
+            progress: None,
+            paused: Some(paused),
+            cancelled: None,
+        };
+
+        let waiting = tokio::time::timeout(Duration::from_millis(50), resumed(Some(&channel)));
+        assert!(waiting.await.is_err());
+
+        pause.send_replace(false);
+        resumed(Some(&channel)).await;
+
+        pause.send_replace(true);
+        drop(pause);
+        resumed(Some(&channel)).await;
     }
 }
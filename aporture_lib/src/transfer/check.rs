@@ -0,0 +1,157 @@
+//! Checking a copy received before against the original files, sending only their hashes.
+
+use std::path::{Path, PathBuf};
+
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, BufReader};
+
+use crate::crypto::hasher::Hasher;
+use crate::parser::EncryptedSerdeIO;
+use crate::protocol::{FileData, Hash, TransferData, VerifyResult};
+use crate::transfer::channel::{Channel, Message};
+use crate::transfer::file::{self, BUFFER_SIZE};
+use crate::transfer::ignore::{self, Filter};
+use crate::transfer::{channel, path};
+
+/// Sends the information and hash of every file and link of the path, without their contents.
+pub async fn send<Ep>(
+    peer: &mut Ep,
+    path: &Path,
+    filter: &Filter,
+    channel: Option<&Channel>,
+) -> Result<(), super::error::Send>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    for (id, entry) in ignore::walk(path, filter).enumerate() {
+        let entry = entry?;
+        let file_data = file::describe(id, &entry, path).await?;
+
+        let hash = if let Some(target) = &file_data.link {
+            Hasher::hash(target.as_bytes())
+        } else if file_data.is_file {
+            hash(entry.path(), channel).await?
+        } else {
+            continue;
+        };
+
+        peer.write_ser_enc(&file_data).await?;
+        peer.write_ser_enc(&Hash(hash)).await?;
+    }
+
+    Ok(())
+}
+
+/// Compares every file and link announced by the peer with the one under the root.
+pub async fn receive<Ep>(
+    peer: &mut Ep,
+    root: &Path,
+    transfer_data: &TransferData,
+    channel: Option<&Channel>,
+) -> Result<VerifyResult, super::error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let mut result = VerifyResult::default();
+
+    for _ in 0..transfer_data.total_files + transfer_data.total_links {
+        let file_data = peer.read_ser_enc::<FileData>().await?;
+        let Hash(expected) = peer.read_ser_enc::<Hash>().await?;
+
+        // NOTE: A single file is sent with an empty name, as it is the root itself
+        let path = if file_data.file_name.is_empty() {
+            root.to_owned()
+        } else {
+            let path = path::received(root, &file_data.file_name)
+                .ok_or(super::error::Receive::Destination)?;
+
+            PathBuf::from(path.as_str())
+        };
+
+        match local_hash(root, &path, &file_data, channel).await? {
+            Some(hash) if hash == expected => (),
+            Some(_) => {
+                log::warn!("File {} does not match the sender", file_data.file_name);
+                result.mismatched.push(file_data.file_name);
+            }
+            None => {
+                log::warn!("File {} is missing", file_data.file_name);
+                result.missing.push(file_data.file_name);
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Hash of the local copy of the entry, `None` if there is none.
+/// A copy of another kind or size gets a hash that can not match, without reading it.
+pub async fn local_hash(
+    root: &Path,
+    path: &Path,
+    file_data: &FileData,
+    channel: Option<&Channel>,
+) -> Result<Option<crate::crypto::hasher::Hash>, std::io::Error> {
+    let Ok(metadata) = tokio::fs::symlink_metadata(path).await else {
+        channel::send(channel, progress(file_data)).await;
+        return Ok(None);
+    };
+
+    let unchecked = [0; 32];
+
+    if file_data.link.is_some() {
+        let local = if metadata.is_symlink() {
+            Some(tokio::fs::read_link(path).await?)
+        } else {
+            None
+        };
+
+        let hash = local
+            .as_deref()
+            .and_then(Path::to_str)
+            .map_or(unchecked, |target| Hasher::hash(target.as_bytes()));
+
+        return Ok(Some(hash));
+    }
+
+    // NOTE: A link inside the root could make the sender learn about files outside of it
+    if path::through_link(root, path)
+        || !metadata.is_file()
+        || metadata.len() != file_data.file_size
+    {
+        channel::send(channel, progress(file_data)).await;
+        return Ok(Some(unchecked));
+    }
+
+    hash(path, channel).await.map(Some)
+}
+
+const fn progress(file_data: &FileData) -> Message {
+    #[allow(clippy::cast_possible_truncation)]
+    Message::Progress(file_data.file_size as usize)
+}
+
+/// Hashes the contents of the file, notifying the progress.
+pub async fn hash(
+    path: &Path,
+    channel: Option<&Channel>,
+) -> Result<crate::crypto::hasher::Hash, std::io::Error> {
+    let file = File::open(path).await?;
+
+    let mut reader = BufReader::with_capacity(10 * BUFFER_SIZE, file);
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut hasher = Hasher::default();
+
+    loop {
+        let count = reader.read(&mut buffer).await?;
+        if count == 0 {
+            break;
+        }
+
+        channel::send(channel, Message::Progress(count)).await;
+
+        hasher.add(&buffer[..count]);
+    }
+
+    Ok(hasher.finalize())
+}
@@ -1,21 +1,39 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::sync::Arc;
 use std::time::Duration;
 
-use tokio::task::{JoinHandle, JoinSet};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task::JoinSet;
+use tokio_util::either::Either;
 
+use super::Route;
 use crate::crypto::cert::{Certificate, CertificateKey};
-use crate::crypto::cipher::Cipher;
-use crate::net::quic::QuicConnection;
-use crate::pairing::PairInfo;
+use crate::crypto::cipher::{Cipher, Rekey};
+use crate::net::peer::{send_cancel, Encryptable, Peer};
+use crate::net::quic::{
+    close_network_changed, Keepalive, QuicConnection, QuicNetworkPeer, Transport,
+};
+use crate::net::{EncryptedNetworkPeer, NetworkPeer};
+use crate::pairing::{ConnectionMethod, PairInfo};
 
 const RETRIES: usize = 15;
 
+/// Time given to the candidates to connect before falling back to the server
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(75);
+
+/// Interval between checks of the local address the peer is reached from
+const NETWORK_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+const ANY_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+const ANY_ADDR_V6: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+
 type AddressError = (crate::io::Error, SocketAddr);
+type Candidate = (QuicConnection, String, ConnectionMethod);
 
 fn options_factory(
     pair_info: &PairInfo,
-) -> Result<JoinSet<Result<QuicConnection, AddressError>>, crate::io::Error> {
+    transport: Transport,
+) -> Result<JoinSet<Result<Candidate, AddressError>>, crate::io::Error> {
     let binding_sockets = pair_info.binding_sockets();
     let connecting_sockets = pair_info.connecting_sockets();
 
@@ -27,10 +45,12 @@ fn options_factory(
         let socket = id.local_socket.try_clone()?;
         let destination = id.peer_address;
         let address = id.self_address;
+        let method = id.method;
+        let name = format!("{method} connecting from {address} to {destination}");
 
-        let fut = connect(socket, destination, address, cipher, peer_cert);
+        let fut = connect(socket, destination, address, cipher, peer_cert, transport);
 
-        set.spawn(fut);
+        set.spawn(async move { fut.await.map(|c| (c, name, method)) });
     }
 
     for id in binding_sockets {
@@ -39,36 +59,254 @@ fn options_factory(
         let socket = id.local_socket.try_clone()?;
         let destination = id.peer_address;
         let address = id.self_address;
+        let method = id.method;
+        let name = format!("{method} waiting on {address} for {destination}");
 
-        let fut = bind(socket, destination, address, cipher, self_cert);
+        let fut = bind(socket, destination, address, cipher, self_cert, transport);
 
-        set.spawn(fut);
+        set.spawn(async move { fut.await.map(|c| (c, name, method)) });
     }
 
     Ok(set)
 }
 
-pub async fn find(pair_info: &mut PairInfo) -> Option<QuicConnection> {
+/// Connects directly to the peer, returning how the winning candidate was obtained.
+pub async fn find(
+    pair_info: &mut PairInfo,
+    timeout: Duration,
+    transport: Transport,
+) -> Option<(QuicConnection, ConnectionMethod)> {
+    let Ok(winner) = tokio::time::timeout(timeout, race(pair_info, transport)).await else {
+        log::warn!("No candidate connected in {}s", timeout.as_secs());
+        return None;
+    };
+
+    let (mut peer, name, method) = winner?;
+
+    // NOTE: Drop fallback if unused, unless later transfers of the session may need it
+    if !pair_info.in_session() {
+        drop(pair_info.fallback());
+    }
+
+    log::info!("Connected on {} using {name}", peer.address());
+
+    follow_network(&mut peer);
+
+    Some((peer, method))
+}
+
+/// Peer a transfer runs over, reached directly or through the server relay.
+pub struct Link {
+    pub peer: LinkPeer,
+    /// Direct connection the stream belongs to, `None` through the relay
+    pub connection: Option<QuicConnection>,
+    pub route: Route,
+}
+
+impl Link {
+    pub fn direct(
+        connection: QuicConnection,
+        peer: QuicNetworkPeer,
+        method: ConnectionMethod,
+        cipher: Arc<Cipher>,
+    ) -> Self {
+        Self {
+            peer: LinkPeer {
+                cipher,
+                stream: Either::Left(peer),
+            },
+            connection: Some(connection),
+            route: method.into(),
+        }
+    }
+
+    pub fn relay(peer: NetworkPeer, cipher: Arc<Cipher>) -> Self {
+        Self {
+            peer: LinkPeer {
+                stream: Either::Right(peer.add_cipher(Arc::clone(&cipher))),
+                cipher,
+            },
+            connection: None,
+            route: Route::Relay,
+        }
+    }
+
+    /// Closes the direct connection once the transfer is done, or gives the server connection
+    /// back to the pairing so later transfers of a session can use it.
+    pub async fn finish(self, pair_info: &mut PairInfo) {
+        match self.peer.stream {
+            Either::Left(stream) => {
+                // NOTE: Finishes the stream before the connection is closed
+                drop(stream);
+
+                if let Some(connection) = self.connection {
+                    connection.finish().await;
+                }
+            }
+            Either::Right(peer) => pair_info.keep_fallback(peer),
+        }
+    }
+
+    /// Tells the peer the transfer was cancelled and drops the connection.
+    pub async fn cancel(mut self) {
+        match self.connection {
+            Some(connection) => connection.cancel().await,
+            None => send_cancel(&mut self.peer).await,
+        }
+    }
+}
+
+/// Stream of a [`Link`], on the direct connection or the server relay.
+pub struct LinkPeer {
+    // NOTE: Both routes encrypt with the cipher of the pairing
+    cipher: Arc<Cipher>,
+    stream: Either<QuicNetworkPeer, EncryptedNetworkPeer>,
+}
+
+impl Peer for LinkPeer {
+    fn writer(&mut self) -> impl AsyncWriteExt + Unpin + Send {
+        match &mut self.stream {
+            Either::Left(peer) => Either::Left(peer.writer()),
+            Either::Right(peer) => Either::Right(peer.writer()),
+        }
+    }
+
+    fn reader(&mut self) -> impl AsyncReadExt + Unpin + Send {
+        match &mut self.stream {
+            Either::Left(peer) => Either::Left(peer.reader()),
+            Either::Right(peer) => Either::Right(peer.reader()),
+        }
+    }
+}
+
+impl Encryptable for LinkPeer {
+    fn cipher(&self) -> impl AsRef<Cipher> {
+        &self.cipher
+    }
+
+    fn rekey(&mut self) -> Option<&mut Rekey> {
+        match &mut self.stream {
+            Either::Left(peer) => peer.rekey(),
+            Either::Right(peer) => peer.rekey(),
+        }
+    }
+
+    fn is_plain(&self) -> bool {
+        match &self.stream {
+            Either::Left(peer) => peer.is_plain(),
+            Either::Right(peer) => peer.is_plain(),
+        }
+    }
+}
+
+/// Follows the network used to reach the peer, so a change, for example from Wi-Fi to
+/// cellular, does not leave the connection waiting on the old path until it times out.
+///
+/// The connecting side moves the connection to a new socket and the peer follows it. The other
+/// side can not move it, as the peer drops packets from addresses it does not know, so if the
+/// address it used is gone the connection is closed and both peers reconnect to resume the
+/// transfer. Sockets bound to a specific address stay on it while it exists.
+fn follow_network(connection: &mut QuicConnection) {
+    let Ok(local) = connection.local_address() else {
+        return;
+    };
+
+    let endpoint = connection.endpoint();
+    let handle = connection.handle();
+    let migrates = connection.is_client();
+    let mut network = Network::new(route(handle.remote_address()));
+
+    let watch = tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(NETWORK_CHECK_INTERVAL).await;
+
+            let Some((previous, current)) = network.changed(route(handle.remote_address())) else {
+                continue;
+            };
+
+            log::info!("Network changed, reaching the peer from {current} instead of {previous}");
+
+            if migrates {
+                let socket = SocketAddr::new(local.ip(), 0);
+                match UdpSocket::bind(socket).and_then(|socket| endpoint.rebind(socket)) {
+                    Ok(()) => continue,
+                    Err(e) => log::warn!("Could not move the connection to the new network: {e}"),
+                }
+            }
+
+            let address = if local.ip().is_unspecified() {
+                previous
+            } else {
+                local.ip()
+            };
+
+            if !is_assigned(address) {
+                log::warn!("Address {address} is gone, closing the connection to reconnect");
+                close_network_changed(&handle);
+
+                return;
+            }
+        }
+    });
+
+    connection.set_network_watch(watch.into());
+}
+
+/// Local address the system currently reaches the peer from, which changes with the network.
+fn route(peer: SocketAddr) -> Option<IpAddr> {
+    // NOTE: Connecting a UDP socket only picks the route, nothing is sent
+    let unspecified = match peer {
+        SocketAddr::V4(_) => ANY_ADDR,
+        SocketAddr::V6(_) => ANY_ADDR_V6,
+    };
+
+    let probe = UdpSocket::bind(unspecified).ok()?;
+    probe.connect(peer).ok()?;
+
+    probe.local_addr().ok().map(|a| a.ip())
+}
+
+/// Whether the address still belongs to this machine, as only those can be bound.
+fn is_assigned(address: IpAddr) -> bool {
+    UdpSocket::bind(SocketAddr::new(address, 0)).is_ok()
+}
+
+/// Last route seen to the peer.
+struct Network {
+    current: Option<IpAddr>,
+}
+
+impl Network {
+    const fn new(current: Option<IpAddr>) -> Self {
+        Self { current }
+    }
+
+    /// Records the route just seen, returning the previous one and it if it moved to another
+    /// address. Losing the route is not a change, the connection is kept until a new one appears.
+    fn changed(&mut self, route: Option<IpAddr>) -> Option<(IpAddr, IpAddr)> {
+        let route = route?;
+
+        let previous = self.current.replace(route)?;
+
+        (previous != route).then_some((previous, route))
+    }
+}
+
+/// Races every candidate concurrently, the first successful handshake wins
+/// and the rest are aborted when the set is dropped.
+async fn race(pair_info: &PairInfo, transport: Transport) -> Option<Candidate> {
     for _ in 0..RETRIES {
-        let Ok(mut options) = options_factory(pair_info) else {
+        let Ok(mut options) = options_factory(pair_info, transport) else {
             break;
         };
 
         loop {
             match options.join_next().await {
-                Some(Ok(Ok(peer))) => {
-                    // NOTE: Drop fallback if unused
-                    drop(pair_info.fallback());
-
-                    log::info!("Connected on {}", peer.address());
-
-                    return Some(peer);
-                }
+                Some(Ok(Ok(candidate))) => return Some(candidate),
                 Some(Ok(Err((e, a)))) => {
                     log::warn!("Could not connect to peer from ip {a}: {e}");
-                    continue;
                 }
-                Some(_) => continue,
+                Some(_) => (),
                 None => break,
             }
         }
@@ -83,6 +321,7 @@ pub async fn bind(
     a: SocketAddr,
     cipher: Arc<Cipher>,
     certificate: CertificateKey,
+    transport: Transport,
 ) -> Result<QuicConnection, AddressError> {
     log::info!(
         "Waiting for peer on {}, port {}; Peer address is {destination}",
@@ -95,7 +334,7 @@ pub async fn bind(
 
     let timeout = tokio::time::timeout(
         Duration::from_secs(5),
-        QuicConnection::server(destination, socket, cipher, certificate, handle),
+        QuicConnection::server(destination, socket, cipher, certificate, handle, transport),
     );
 
     let peer = timeout
@@ -112,6 +351,7 @@ pub async fn connect(
     source: SocketAddr,
     cipher: Arc<Cipher>,
     certificate: Certificate,
+    transport: Transport,
 ) -> Result<QuicConnection, AddressError> {
     log::info!(
         "Trying to connect to peer on {}, port {}; My address is {source}",
@@ -124,7 +364,7 @@ pub async fn connect(
 
     let timeout = tokio::time::timeout(
         Duration::from_secs(5),
-        QuicConnection::client(a, socket, cipher, certificate, handle),
+        QuicConnection::client(a, socket, cipher, certificate, handle, transport),
     );
 
     let peer = timeout
@@ -135,8 +375,8 @@ pub async fn connect(
     Ok(peer)
 }
 
-fn keepalive(socket: UdpSocket, peer: SocketAddr) -> JoinHandle<()> {
-    tokio::spawn(async move {
+fn keepalive(socket: UdpSocket, peer: SocketAddr) -> Keepalive {
+    let handle = tokio::spawn(async move {
         let _ = socket.send_to(b"ka", peer);
         let _ = socket.send_to(b"ka", peer);
         let _ = socket.send_to(b"ka", peer);
@@ -146,5 +386,51 @@ fn keepalive(socket: UdpSocket, peer: SocketAddr) -> JoinHandle<()> {
             let _ = socket.send_to(b"ka", peer);
             tokio::time::sleep(Duration::from_secs(10)).await;
         }
-    })
+    });
+
+    handle.into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_network_change_is_detected_once() {
+        let wifi = IpAddr::from([192, 168, 1, 20]);
+        let cellular = IpAddr::from([10, 64, 0, 7]);
+
+        let mut network = Network::new(Some(wifi));
+
+        let changes = [Some(wifi), None, Some(cellular), Some(cellular), Some(wifi)]
+            .into_iter()
+            .map(|route| network.changed(route))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                None,
+                None,
+                Some((wifi, cellular)),
+                None,
+                Some((cellular, wifi))
+            ],
+            changes
+        );
+    }
+
+    #[test]
+    fn test_only_local_addresses_are_assigned() {
+        assert!(is_assigned(IpAddr::from([127, 0, 0, 1])));
+
+        // NOTE: Reserved for documentation, so never assigned to this machine
+        assert!(!is_assigned(IpAddr::from([192, 0, 2, 1])));
+    }
+
+    #[test]
+    fn test_route_to_loopback_peer() {
+        let peer = SocketAddr::from(([127, 0, 0, 1], 9));
+
+        assert_eq!(Some(IpAddr::from([127, 0, 0, 1])), route(peer));
+    }
 }
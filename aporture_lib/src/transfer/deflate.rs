@@ -1,66 +1,138 @@
-use std::fs::File;
-use std::path::{Path, PathBuf};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::Duration;
 
 use flate2::Compression;
-use tempfile::NamedTempFile;
+use tokio_util::io::SyncIoBridge;
 
+use super::ignore::{self, Filter};
+use super::manifest::Manifest;
+use super::{pipe, SymlinkMode};
 use crate::parser::EncryptedSerdeIO;
-use crate::protocol::TransferData;
 use crate::transfer::channel::{self, Channel, Message};
 
-#[allow(unused)]
-async fn compress_folder<Ep>(
-    transfer_data: &mut TransferData,
-    path: &Path,
+/// Bytes of the archive buffered between the thread packing or unpacking it and the peer
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Sends the folder as a gzip compressed tar archive built while it is sent, so it is never
+/// stored whole. Progress is notified in bytes of the files packed.
+pub async fn send<Ep>(
     peer: &mut Ep,
+    path: &Path,
+    filter: &Filter,
+    manifest: &mut Manifest,
     channel: Option<&Channel>,
-) -> Result<NamedTempFile, super::error::Send>
+) -> Result<(), super::error::Send>
 where
     Ep: EncryptedSerdeIO + Send,
 {
-    channel::send(channel, Message::Compression).await;
-    // transfer_data.compressed = true;
+    let (reader, writer) = tokio::io::duplex(BUFFER_SIZE);
+
+    let (folder, filter, progress) = (path.to_owned(), filter.clone(), channel.cloned());
+    let packing = tokio::task::spawn_blocking(move || {
+        compress(&folder, &filter, SyncIoBridge::new(writer), |n| {
+            notify(progress.as_ref(), n);
+        })
+    });
+
+    let control = channel.map(Channel::control);
+    let sent = pipe::send(peer, reader, manifest, control.as_ref()).await;
 
-    log::info!("Sending original data information {transfer_data:?}");
-    peer.write_ser_enc(&*transfer_data).await?;
+    // NOTE: A failed packing ends the stream early, so its error is the cause of any other
+    packing.await.expect("Task was aborted")?;
+    sent?;
 
-    log::info!("Folder will be compressed as it is too big");
+    Ok(())
+}
+
+/// Unpacks the archive into the folder while it is received, returning whether its hash matched.
+/// Progress is notified in bytes of the files unpacked.
+pub async fn receive<Ep>(
+    peer: &mut Ep,
+    dest: &Path,
+    manifest: &mut Manifest,
+    (stall, channel): (Duration, Option<&Channel>),
+) -> Result<bool, super::error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let (reader, writer) = tokio::io::duplex(BUFFER_SIZE);
 
-    let p = path.to_owned();
-    let tar_file = tokio::task::spawn_blocking(move || compress(&p))
-        .await
-        .expect("Task was aborted")?;
+    let (folder, progress) = (dest.to_owned(), channel.cloned());
+    let unpacking = tokio::task::spawn_blocking(move || {
+        uncompress(SyncIoBridge::new(reader), &folder, |n| {
+            notify(progress.as_ref(), n);
+        })
+    });
 
-    let metadata = tar_file.as_file().metadata()?;
+    let control = channel.map(Channel::control);
+    let received = pipe::receive(peer, writer, manifest, (stall, control.as_ref())).await;
 
-    transfer_data.total_files = 1;
-    transfer_data.total_size = metadata.len();
+    // NOTE: A failed unpacking stops reading the stream, so its error is the cause of any other
+    unpacking.await.expect("Task was aborted")?;
+    let (_, valid) = received?;
 
-    Ok(tar_file)
+    Ok(valid)
 }
 
-#[allow(unused)]
-pub fn compress(path: &Path) -> Result<NamedTempFile, std::io::Error> {
-    let file = tempfile::NamedTempFile::new()?;
+fn notify(channel: Option<&Channel>, bytes: u64) {
+    #[allow(clippy::cast_possible_truncation)]
+    channel::blocking_send(channel, Message::Progress(bytes as usize));
+}
 
-    let enc = flate2::write::GzEncoder::new(file, Compression::default());
+/// Packs the entries of the folder kept by the filter, calling progress with every file size.
+fn compress<W: Write>(
+    path: &Path,
+    filter: &Filter,
+    writer: W,
+    mut progress: impl FnMut(u64),
+) -> Result<(), std::io::Error> {
+    let enc = flate2::write::GzEncoder::new(writer, Compression::default());
 
     let mut tar = tar::Builder::new(enc);
 
-    tar.append_dir_all("", path)?;
+    // NOTE: Preserved links are archived as links, like when sending entries one by one
+    tar.follow_symlinks(filter.symlinks == SymlinkMode::Follow);
+
+    for entry in ignore::walk(path, filter).skip(1) {
+        let entry = entry?;
 
-    let file = tar.into_inner()?.finish()?;
+        let name = entry
+            .path()
+            .strip_prefix(path)
+            .expect("Walked entries are inside the folder");
 
-    Ok(file)
+        tar.append_path_with_name(entry.path(), name)?;
+
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            progress(metadata.len());
+        }
+    }
+
+    tar.into_inner()?.finish()?.flush()
 }
 
-#[allow(unused)]
-pub fn uncompress(file: &mut File, dest: PathBuf) -> Result<PathBuf, std::io::Error> {
-    let dec = flate2::read::GzDecoder::new(file);
+/// Unpacks an archive created by [`compress`], calling progress with every file size.
+fn uncompress<R: Read>(
+    reader: R,
+    dest: &Path,
+    mut progress: impl FnMut(u64),
+) -> Result<(), std::io::Error> {
+    let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(reader));
+
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+
+        entry.unpack_in(dest)?;
 
-    let mut tar = tar::Archive::new(dec);
+        if entry.header().entry_type().is_file() {
+            progress(entry.size());
+        }
+    }
 
-    tar.unpack(&dest)?;
+    // NOTE: Read the rest of the stream so the sender is never left blocked writing it
+    std::io::copy(&mut tar.into_inner(), &mut std::io::sink())?;
 
-    Ok(dest)
+    Ok(())
 }
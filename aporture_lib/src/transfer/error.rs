@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use thiserror::Error;
 
@@ -8,12 +8,59 @@ pub enum Send {
     Path,
     #[error("Path {0} is not valid or you do not have permission to access it")]
     Subpath(PathBuf),
+    #[error("The name of {0} is not valid UTF-8, rename it to send it")]
+    NonUtf8Name(PathBuf),
     #[error("Could not open file to send")]
     File(#[from] std::io::Error),
     #[error("Could not send file to peer over the network")]
-    Network(#[from] crate::io::Error),
+    Network(crate::io::Error),
     #[error("Hash mismatch informed by the receiver")]
     HashMismatch,
+    #[error("The receiver could not complete the transfer")]
+    PeerFailed,
+    #[error("The transfer was cancelled")]
+    Cancelled,
+    #[error("The receiver cancelled the transfer")]
+    PeerCancelled,
+    #[error("Invalid exclude pattern: {0}")]
+    Pattern(#[from] globset::Error),
+    #[error(
+        "Chunk size {0} is not between {min} and {max} bytes, the most a message can carry",
+        min = super::MIN_CHUNK_SIZE,
+        max = super::MAX_CHUNK_SIZE
+    )]
+    ChunkSize(usize),
+    #[error("Could not connect to the peer and the server relay was already used")]
+    NoRelay,
+    #[error("The connection with the receiver was lost, the network may have changed")]
+    ConnectionLost,
+    #[error("The server stopped relaying the transfer as it reached its limit")]
+    RelayLimit,
+}
+
+impl From<crate::io::Error> for Send {
+    fn from(value: crate::io::Error) -> Self {
+        if value.is_cancelled() {
+            Self::PeerCancelled
+        } else if matches!(value, crate::io::Error::RelayLimit) {
+            Self::RelayLimit
+        } else if value.is_connection_lost() {
+            Self::ConnectionLost
+        } else {
+            Self::Network(value)
+        }
+    }
+}
+
+impl Send {
+    /// Whether the connection was lost, so the transfer can resume on a new one.
+    pub(super) fn is_connection_lost(&self) -> bool {
+        match self {
+            Self::ConnectionLost => true,
+            Self::File(e) => lost(e),
+            _ => false,
+        }
+    }
 }
 
 impl From<walkdir::Error> for Send {
@@ -28,6 +75,14 @@ impl From<walkdir::Error> for Send {
 pub enum Receive {
     #[error("Target Destination not valid")]
     Destination,
+    #[error("Target Destination is no longer available, the drive may have been removed")]
+    DestinationUnavailable,
+    #[error("Target Destination is read only, the drive may be failing")]
+    DestinationReadOnly,
+    #[error("The folder containing {0} does not exist")]
+    MissingParent(PathBuf),
+    #[error("Cannot save a folder as {0} because it is a file")]
+    FolderIntoFile(PathBuf),
     #[error("Could not write file to disk")]
     File(#[from] std::io::Error),
     #[error("Could not receive file from peer over the network")]
@@ -36,12 +91,47 @@ pub enum Receive {
     Cipher(crate::crypto::Error),
     #[error("The hash of the transferred file and the received hash are not the same")]
     HashMismatch,
+    #[error("Timed out waiting for the next file from the peer")]
+    Timeout,
+    #[error("The peer stopped sending in the middle of a file or stream")]
+    Stalled,
+    #[error("Received {received} files and links but the peer announced {expected}")]
+    FileCount { expected: u64, received: u64 },
+    #[error("The received files do not match the files sent by the peer")]
+    ManifestMismatch,
+    #[error("The peer is sending files instead of a stream")]
+    NotPiped,
+    #[error("The peer is only verifying files, it is not sending them")]
+    Verifying,
+    #[error("The peer is sending files instead of verifying them")]
+    NotVerifying,
+    #[error("Could not connect to the peer and the server relay was already used")]
+    NoRelay,
+    #[error("The transfer was cancelled")]
+    Cancelled,
+    #[error("The sender cancelled the transfer")]
+    PeerCancelled,
+    #[error("The connection with the sender was lost, the network may have changed")]
+    ConnectionLost,
+    #[error("The server stopped relaying the transfer as it reached its limit")]
+    RelayLimit,
 }
 
 impl From<crate::io::Error> for Receive {
     fn from(value: crate::io::Error) -> Self {
+        if value.is_cancelled() {
+            return Self::PeerCancelled;
+        }
+
+        if value.is_connection_lost() {
+            return Self::ConnectionLost;
+        }
+
         match value {
+            crate::io::Error::RelayLimit => Self::RelayLimit,
             crate::io::Error::UnexpectedMessage
+            | crate::io::Error::Cancelled
+            | crate::io::Error::CertificateMismatch
             | crate::io::Error::Quic(_)
             | crate::io::Error::IO(_)
             | crate::io::Error::SerDe(_) => Self::Network(value),
@@ -50,3 +140,73 @@ impl From<crate::io::Error> for Receive {
         }
     }
 }
+
+impl Receive {
+    /// Whether the connection was lost, so the transfer can resume on a new one.
+    pub(super) fn is_connection_lost(&self) -> bool {
+        match self {
+            Self::ConnectionLost => true,
+            Self::File(e) => lost(e),
+            _ => false,
+        }
+    }
+
+    /// Reports the error as an unavailable destination if the destination base disappeared.
+    pub(super) fn check_destination(self, base: &Path) -> Self {
+        let kind = match &self {
+            Self::File(e) | Self::Network(crate::io::Error::IO(e)) => Some(e.kind()),
+            Self::Destination => None,
+            _ => return self,
+        };
+
+        if kind == Some(std::io::ErrorKind::ReadOnlyFilesystem) {
+            log::warn!("Destination {} is now read only", base.display());
+            return Self::DestinationReadOnly;
+        }
+
+        if base.exists() {
+            self
+        } else {
+            log::warn!("Destination {} is no longer available", base.display());
+            Self::DestinationUnavailable
+        }
+    }
+}
+
+/// Whether a failed read or write on a stream was caused by losing the connection.
+fn lost(error: &std::io::Error) -> bool {
+    crate::net::quic::connection_error(error).is_some_and(crate::net::quic::is_lost)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_destination_vanished_mid_write() {
+        let dir = tempfile::tempdir().expect("Temp dir");
+        let base = dir.path().join("drive");
+        std::fs::create_dir(&base).expect("Create destination");
+
+        std::fs::write(base.join("first"), b"written").expect("Write to destination");
+
+        std::fs::remove_dir_all(&base).expect("Remove destination");
+
+        let error = std::fs::write(base.join("second"), b"lost").expect_err("Destination vanished");
+        let error = Receive::File(error).check_destination(&base);
+
+        assert!(matches!(error, Receive::DestinationUnavailable));
+    }
+
+    #[test]
+    fn test_destination_available_keeps_error() {
+        let dir = tempfile::tempdir().expect("Temp dir");
+        std::fs::create_dir(dir.path().join("taken")).expect("Create folder");
+
+        let error =
+            std::fs::write(dir.path().join("taken"), b"file").expect_err("Path is a folder");
+        let error = Receive::File(error).check_destination(dir.path());
+
+        assert!(matches!(error, Receive::File(_)));
+    }
+}
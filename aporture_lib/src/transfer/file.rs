@@ -1,102 +1,258 @@
 use std::path::Path;
+use std::time::Duration;
 
 use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
-use typed_path::{Utf8PlatformPath, Utf8UnixPathBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use walkdir::DirEntry;
 
-use crate::crypto;
 use crate::crypto::hasher::Hasher;
 use crate::parser::EncryptedSerdeIO;
 use crate::protocol::{FileData, Hash};
 use crate::transfer::channel::{Channel, Message};
+use crate::transfer::manifest::Manifest;
 use crate::transfer::{channel, path};
 
-const BUFFER_SIZE: usize = 16 * 1024;
+pub const BUFFER_SIZE: usize = 16 * 1024;
 
+/// Size of the chunks files are sent in unless set otherwise
+pub const DEFAULT_CHUNK_SIZE: usize = BUFFER_SIZE;
+
+/// Smallest chunk size, below it the overhead of every message dominates
+pub const MIN_CHUNK_SIZE: usize = 1024;
+
+/// Largest chunk size, as much as a single message can carry with its 16 bit length
+pub const MAX_CHUNK_SIZE: usize = u16::MAX as usize;
+
+/// Pause between the empty chunks a paused sender sends so the receiver knows it is there
+pub(super) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Shortest stall timeout, so a couple of lost heartbeats do not fail a paused transfer
+pub const MIN_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the receiver waits for the peer while receiving files.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// For the information of the next file
+    pub entry: Duration,
+    /// For the next chunk of a file
+    pub stall: Duration,
+}
+
+/// Sends a file to the peer, recording its hash in the manifest.
+/// If a stream hasher is given the file is added to it instead of sending its own hash.
+/// Entries walked as links are sent as the link itself, hashing its target.
 pub async fn send<Ep>(
     peer: &mut Ep,
     id: usize,
-    path: &Path,
-    base: &Utf8PlatformPath,
-    channel: Option<&Channel>,
+    entry: &DirEntry,
+    base: &Path,
+    stream: Option<&mut Hasher>,
+    manifest: &mut Manifest,
+    progress: (usize, Option<&Channel>),
 ) -> Result<(), super::error::Send>
 where
     Ep: EncryptedSerdeIO + Send,
 {
-    let is_file = path.is_file();
-    let file_size = if is_file { path.metadata()?.len() } else { 0 };
-
-    let path = path::platform(path);
+    let file_data = describe(id, entry, base).await?;
 
-    let file_name = path
-        .strip_prefix(base)
-        .expect("Path must be a subpath from base")
-        .with_unix_encoding()
-        .to_string();
+    let path = entry.path();
 
-    log::info!("Sending file {}", path);
+    log::info!("Sending file {}", path.display());
 
-    let file_data = FileData {
-        id: id as u64,
-        file_size,
-        file_name,
-        is_file,
+    let file = if file_data.is_file {
+        Some(OpenOptions::new().read(true).open(path).await?)
+    } else {
+        None
     };
 
+    send_entry(peer, file_data, file, stream, manifest, progress).await
+}
+
+/// Sends an entry described by the file data, reading its contents from the reader if it is
+/// a file. Recorded in the manifest and the stream hasher like [`send`] does.
+pub async fn send_entry<Ep, R>(
+    peer: &mut Ep,
+    file_data: FileData,
+    contents: Option<R>,
+    mut stream: Option<&mut Hasher>,
+    manifest: &mut Manifest,
+    (chunk_size, channel): (usize, Option<&Channel>),
+) -> Result<(), super::error::Send>
+where
+    Ep: EncryptedSerdeIO + Send,
+    R: AsyncRead + Unpin + Send,
+{
+    let file_size = file_data.file_size;
+
     peer.write_ser_enc(&file_data).await?;
 
-    // NOTE: If it is a directory finish after sending name
-    if !is_file {
+    if let Some(hasher) = stream.as_deref_mut() {
+        hash_header(hasher, &file_data);
+    }
+
+    if let Some(target) = &file_data.link {
+        manifest.add(file_data.file_name, Hasher::hash(target.as_bytes()));
+
         return Ok(());
     }
 
-    let file = OpenOptions::new().read(true).open(&path).await?;
+    // NOTE: If it is a directory finish after sending name
+    let Some(contents) = contents.filter(|_| file_data.is_file) else {
+        return Ok(());
+    };
 
-    let hash = hash_and_send(file, peer, channel).await?;
+    started(channel, &file_data).await;
 
-    peer.write_ser_enc(&Hash(hash)).await?;
+    let mut hasher = Hasher::default();
+
+    let domain = file_data.id.to_be_bytes();
+    hash_and_send(
+        contents,
+        file_size,
+        peer,
+        &domain,
+        &mut hasher,
+        stream.as_deref_mut(),
+        (chunk_size, channel),
+    )
+    .await?;
+
+    let hash = hasher.finalize();
+
+    if stream.is_none() {
+        peer.write_ser_enc(&Hash(hash)).await?;
+    }
+
+    manifest.add(file_data.file_name, hash);
 
     Ok(())
 }
 
+/// Information sent before an entry, with its name relative to the base.
+pub async fn describe(
+    id: usize,
+    entry: &DirEntry,
+    base: &Path,
+) -> Result<FileData, super::error::Send> {
+    let path = entry.path();
+
+    let link = if entry.file_type().is_symlink() {
+        let target = tokio::fs::read_link(path).await?;
+        let target = target
+            .to_str()
+            .ok_or_else(|| super::error::Send::Subpath(path.to_owned()))?;
+
+        Some(target.to_owned())
+    } else {
+        None
+    };
+
+    let is_file = link.is_none() && path.is_file();
+    let file_size = if is_file { path.metadata()?.len() } else { 0 };
+
+    let file_name = path::relative_name(base, path)
+        .ok_or_else(|| super::error::Send::NonUtf8Name(path.to_owned()))?;
+
+    Ok(FileData {
+        id: id as u64,
+        file_size,
+        file_name,
+        is_file,
+        link,
+    })
+}
+
+/// Receives a file from the peer, returning whether its hash did not match.
+/// The hash of the written file is recorded in the manifest.
+/// If a stream hasher is given the file is added to it and no hash is expected.
+/// Fails if the file information or any of its chunks do not arrive before their timeout.
 pub async fn receive<Ep>(
     dest: &Path,
     peer: &mut Ep,
+    mut stream: Option<&mut Hasher>,
+    manifest: &mut Manifest,
+    timeout: Timeouts,
     channel: Option<&Channel>,
 ) -> Result<(FileData, bool), super::error::Receive>
 where
     Ep: EncryptedSerdeIO + Send,
 {
-    let file_data = peer.read_ser_enc::<FileData>().await?;
-    let received_path = Utf8UnixPathBuf::from(&file_data.file_name)
-        .normalize()
-        .with_platform_encoding();
-    let mut path = path::platform(dest);
+    let file_data = tokio::time::timeout(timeout.entry, peer.read_ser_enc::<FileData>())
+        .await
+        .map_err(|_| super::error::Receive::Timeout)??;
+
+    if let Some(hasher) = stream.as_deref_mut() {
+        hash_header(hasher, &file_data);
+    }
+
+    let received_path = &file_data.file_name;
 
     let file = if dest.is_dir() {
-        path.push(&received_path);
+        let path = path::received(dest, received_path).ok_or(super::error::Receive::Destination)?;
+
+        // NOTE: A link received before must not redirect writes outside the destination
+        if path::through_link(dest, path.as_ref()) {
+            log::warn!("Refusing to write {} through a link", received_path);
+
+            return Err(super::error::Receive::Destination);
+        }
 
+        if let Some(target) = &file_data.link {
+            path::symlink(target, path.as_ref()).await?;
+
+            manifest.add(file_data.file_name.clone(), Hasher::hash(target.as_bytes()));
+
+            return Ok((file_data, false));
+        }
+
+        // NOTE: Directories are created explicitly so empty ones are not lost
         if !file_data.is_file {
-            tokio::fs::create_dir(&path).await?;
+            tokio::fs::create_dir_all(&path).await?;
 
             return Ok((file_data, false));
         }
 
+        // NOTE: Truncate as a retried file overwrites its failed attempt
         OpenOptions::new()
             .write(true)
-            .create_new(true)
+            .create(true)
+            .truncate(true)
             .open(&path)
             .await?
     } else {
-        OpenOptions::new().write(true).open(&path).await?
+        OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(dest)
+            .await?
     };
 
     log::info!("Receiving file {}", &received_path);
 
-    let hash = hash_and_receive(file, file_data.file_size, peer, channel).await?;
+    started(channel, &file_data).await;
+
+    let mut hasher = Hasher::default();
+
+    hash_and_receive(
+        file,
+        &file_data,
+        peer,
+        &mut hasher,
+        stream.as_deref_mut(),
+        (timeout.stall, channel),
+    )
+    .await?;
 
     log::info!("File received");
 
+    let hash = hasher.finalize();
+
+    manifest.add(file_data.file_name.clone(), hash);
+
+    if stream.is_some() {
+        return Ok((file_data, false));
+    }
+
     let received_hash = peer.read_ser_enc::<Hash>().await?;
 
     if hash != received_hash.0 {
@@ -110,65 +266,171 @@ where
     Ok((file_data, hash != received_hash.0))
 }
 
-async fn hash_and_send<Ep>(
-    file: File,
+async fn started(channel: Option<&Channel>, file_data: &FileData) {
+    let message = Message::FileStarted {
+        name: file_data.file_name.clone(),
+        size: file_data.file_size,
+    };
+
+    channel::send(channel, message).await;
+}
+
+/// Adds the file metadata to a stream hash so names and file boundaries are covered too.
+fn hash_header(hasher: &mut Hasher, file_data: &FileData) {
+    hasher.add(&file_data.file_size.to_be_bytes());
+    hasher.add(&(file_data.file_name.len() as u64).to_be_bytes());
+    hasher.add(file_data.file_name.as_bytes());
+    hasher.add(&[u8::from(file_data.is_file)]);
+
+    if let Some(target) = &file_data.link {
+        hasher.add(&(target.len() as u64).to_be_bytes());
+        hasher.add(target.as_bytes());
+    }
+}
+
+/// Sends the file contents adding them to its own hash and to the stream hash if any.
+/// Every chunk is bound to the domain, the id of the file, so it can not be replayed in another.
+async fn hash_and_send<Ep, R>(
+    contents: R,
+    size: u64,
     sender: &mut Ep,
-    channel: Option<&Channel>,
-) -> Result<crypto::hasher::Hash, crate::io::Error>
+    domain: &[u8],
+    hasher: &mut Hasher,
+    mut stream: Option<&mut Hasher>,
+    (chunk_size, channel): (usize, Option<&Channel>),
+) -> Result<(), crate::io::Error>
 where
     Ep: EncryptedSerdeIO + Send,
+    R: AsyncRead + Unpin,
 {
-    let mut reader = BufReader::with_capacity(10 * BUFFER_SIZE, file);
-    let mut hasher = Hasher::default();
-    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut reader = BufReader::with_capacity(10 * chunk_size, contents);
+    let mut buffer = vec![0; chunk_size];
+    let mut sent = 0;
 
     loop {
+        // NOTE: Once the whole file is sent the receiver expects its hash, not chunks
+        if sent < size {
+            resumed_with_heartbeat(sender, domain, channel).await?;
+        } else {
+            channel::resumed(channel).await;
+        }
+
         let count = reader.read(&mut buffer).await?;
         if count == 0 {
             break;
         }
 
+        sent += count as u64;
+
         channel::send(channel, Message::Progress(count)).await;
 
         hasher.add(&buffer[..count]);
-        sender.write_enc(&mut buffer[..count]).await?;
+        if let Some(stream) = stream.as_deref_mut() {
+            stream.add(&buffer[..count]);
+        }
+        sender.write_enc_in(&mut buffer[..count], domain).await?;
+    }
+
+    Ok(())
+}
+
+/// Waits until the transfer is resumed, sending empty chunks meanwhile so the receiver
+/// knows the sender did not stop.
+async fn resumed_with_heartbeat<Ep>(
+    sender: &mut Ep,
+    domain: &[u8],
+    channel: Option<&Channel>,
+) -> Result<(), crate::io::Error>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    while tokio::time::timeout(HEARTBEAT_INTERVAL, channel::resumed(channel))
+        .await
+        .is_err()
+    {
+        sender.write_enc_in(&mut [], domain).await?;
     }
 
-    Ok(hasher.finalize())
+    Ok(())
 }
 
+/// Receives the file contents adding them to its own hash and to the stream hash if any.
+/// Fails if a chunk does not arrive before the stall timeout, not counting local pauses.
+/// Chunks of any size the sender chose are accepted.
 async fn hash_and_receive<Ep>(
     file: File,
-    file_size: u64,
+    file_data: &FileData,
     receiver: &mut Ep,
-    channel: Option<&Channel>,
-) -> Result<crypto::hasher::Hash, crate::io::Error>
+    hasher: &mut Hasher,
+    mut stream: Option<&mut Hasher>,
+    (stall, channel): (Duration, Option<&Channel>),
+) -> Result<(), super::error::Receive>
 where
     Ep: EncryptedSerdeIO + Send,
 {
     let mut writer = BufWriter::with_capacity(10 * BUFFER_SIZE, file);
-    let mut hasher = Hasher::default();
-    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut buffer = vec![0; MAX_CHUNK_SIZE];
 
-    let file_size = usize::try_from(file_size).expect("u64 does not fit in usize");
+    let file_size = usize::try_from(file_data.file_size).expect("u64 does not fit in usize");
+    let domain = file_data.id.to_be_bytes();
     let mut read = 0;
 
     while read < file_size {
-        let count = receiver.read_enc(&mut buffer).await?;
+        channel::resumed(channel).await;
 
-        read += count;
+        let count = tokio::time::timeout(stall, receiver.read_enc_in(&mut buffer, &domain))
+            .await
+            .map_err(|_| super::error::Receive::Stalled)??;
 
+        // NOTE: Empty chunks only tell that a paused sender is still there
         if count == 0 {
-            return Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into());
+            continue;
         }
 
+        read += count;
+
         channel::send(channel, Message::Progress(count)).await;
 
         hasher.add(&buffer[..count]);
+        if let Some(stream) = stream.as_deref_mut() {
+            stream.add(&buffer[..count]);
+        }
         writer.write_all(&buffer[..count]).await?;
     }
 
     writer.flush().await?;
 
-    Ok(hasher.finalize())
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stream_hash(files: &[(&str, &[u8])]) -> crate::crypto::hasher::Hash {
+        let mut hasher = Hasher::default();
+
+        for (id, (name, content)) in files.iter().enumerate() {
+            let file_data = FileData {
+                file_size: content.len() as u64,
+                id: id as u64,
+                file_name: (*name).to_owned(),
+                is_file: true,
+                link: None,
+            };
+
+            hash_header(&mut hasher, &file_data);
+            hasher.add(content);
+        }
+
+        hasher.finalize()
+    }
+
+    #[test]
+    fn test_stream_hash_covers_file_boundaries() {
+        let expected = stream_hash(&[("a", b"ab"), ("b", b"c")]);
+
+        assert_ne!(expected, stream_hash(&[("a", b"a"), ("b", b"bc")]));
+        assert_ne!(expected, stream_hash(&[("b", b"ab"), ("a", b"c")]));
+    }
 }
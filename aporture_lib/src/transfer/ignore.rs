@@ -0,0 +1,179 @@
+use std::path::Path;
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use walkdir::{DirEntry, WalkDir};
+
+use super::SymlinkMode;
+
+#[derive(Debug, Clone)]
+pub struct Ignore {
+    set: GlobSet,
+}
+
+impl Ignore {
+    /// Builds the matcher from `.gitignore` style patterns.
+    /// Patterns without a separator match at any depth, a trailing `/` is ignored.
+    pub fn new(patterns: &[String]) -> Result<Self, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+
+        for pattern in patterns {
+            let pattern = pattern.trim_end_matches('/');
+            let pattern = pattern.strip_prefix('/').map_or_else(
+                || {
+                    if pattern.contains('/') {
+                        pattern.to_owned()
+                    } else {
+                        format!("**/{pattern}")
+                    }
+                },
+                ToOwned::to_owned,
+            );
+
+            let glob = GlobBuilder::new(&pattern).literal_separator(true).build()?;
+
+            builder.add(glob);
+        }
+
+        Ok(Self {
+            set: builder.build()?,
+        })
+    }
+
+    fn is_match(&self, root: &Path, entry: &DirEntry) -> bool {
+        entry
+            .path()
+            .strip_prefix(root)
+            .is_ok_and(|p| !p.as_os_str().is_empty() && self.set.is_match(p))
+    }
+}
+
+/// Selects which entries of a folder are sent.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pub ignore: Option<Ignore>,
+    /// Send directories without any entry so they are recreated by the receiver
+    pub empty_dirs: bool,
+    pub symlinks: SymlinkMode,
+}
+
+impl Filter {
+    pub const DEFAULT: Self = Self {
+        ignore: None,
+        empty_dirs: true,
+        symlinks: SymlinkMode::Follow,
+    };
+
+    fn keeps(&self, root: &Path, entry: &DirEntry) -> bool {
+        if self
+            .ignore
+            .as_ref()
+            .is_some_and(|i| i.is_match(root, entry))
+        {
+            return false;
+        }
+
+        if self.symlinks == SymlinkMode::Skip && entry.depth() != 0 && entry.path_is_symlink() {
+            return false;
+        }
+
+        self.empty_dirs || entry.depth() == 0 || !is_empty_dir(entry)
+    }
+}
+
+fn is_empty_dir(entry: &DirEntry) -> bool {
+    entry.file_type().is_dir()
+        && std::fs::read_dir(entry.path()).is_ok_and(|mut d| d.next().is_none())
+}
+
+/// Walks the path in the order files are sent, pruning filtered entries.
+/// Every pass over the files must use this so ids and totals stay consistent.
+pub fn walk<'a>(
+    path: &'a Path,
+    filter: &'a Filter,
+) -> impl Iterator<Item = Result<DirEntry, walkdir::Error>> + 'a {
+    WalkDir::new(path)
+        .follow_links(filter.symlinks == SymlinkMode::Follow)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(move |e| filter.keeps(path, e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn matches(patterns: &[&str], path: &str) -> bool {
+        let patterns = patterns.iter().map(ToString::to_string).collect::<Vec<_>>();
+        let ignore = Ignore::new(&patterns).expect("Valid patterns");
+
+        ignore.set.is_match(path)
+    }
+
+    #[test]
+    fn test_ignore_matches_name_at_any_depth() {
+        assert!(matches(&["target/"], "target"));
+        assert!(matches(&["target/"], "crate/target"));
+        assert!(matches(&["*.log"], "logs/out.log"));
+        assert!(!matches(&["target/"], "targets"));
+    }
+
+    #[test]
+    fn test_walk_skips_empty_dirs_only_when_asked() {
+        let dir = tempfile::tempdir().expect("Temp dir");
+        std::fs::create_dir(dir.path().join("empty")).expect("Create dir");
+        std::fs::create_dir(dir.path().join("full")).expect("Create dir");
+        std::fs::write(dir.path().join("full/file"), b"content").expect("Write file");
+
+        let names = |filter: &Filter| {
+            walk(dir.path(), filter)
+                .skip(1)
+                .map(|e| e.expect("Entry").file_name().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["empty", "full", "file"], names(&Filter::DEFAULT));
+
+        let filter = Filter {
+            ignore: None,
+            empty_dirs: false,
+            symlinks: SymlinkMode::Follow,
+        };
+        assert_eq!(vec!["full", "file"], names(&filter));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_walk_symlink_modes() {
+        let dir = tempfile::tempdir().expect("Temp dir");
+        std::fs::create_dir(dir.path().join("full")).expect("Create dir");
+        std::fs::write(dir.path().join("full/file"), b"content").expect("Write file");
+        std::os::unix::fs::symlink("full", dir.path().join("link")).expect("Link");
+
+        let names = |symlinks| {
+            let filter = Filter {
+                symlinks,
+                ..Filter::DEFAULT
+            };
+
+            walk(dir.path(), &filter)
+                .skip(1)
+                .map(|e| e.expect("Entry").file_name().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec!["full", "file", "link", "file"],
+            names(SymlinkMode::Follow)
+        );
+        assert_eq!(vec!["full", "file", "link"], names(SymlinkMode::Preserve));
+        assert_eq!(vec!["full", "file"], names(SymlinkMode::Skip));
+    }
+
+    #[test]
+    fn test_ignore_anchored_patterns() {
+        assert!(matches(&["/build"], "build"));
+        assert!(!matches(&["/build"], "src/build"));
+        assert!(matches(&["src/*.rs"], "src/main.rs"));
+        assert!(!matches(&["src/*.rs"], "src/bin/main.rs"));
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+
+use crate::crypto::hasher::{Hash, Hasher};
+
+/// Hashes of every file of a transfer by name, so both peers can confirm the whole set matched.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    files: BTreeMap<String, Hash>,
+}
+
+/// Combined hash of every file of a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Root(pub Hash);
+
+impl Manifest {
+    /// Records the hash of a file, replacing the one of a previous attempt.
+    pub fn add(&mut self, file_name: String, hash: Hash) {
+        self.files.insert(file_name, hash);
+    }
+
+    /// Adds every file of another manifest, like the one of a stream received concurrently.
+    pub fn extend(&mut self, other: Self) {
+        self.files.extend(other.files);
+    }
+
+    pub fn remove(&mut self, file_name: &str) {
+        self.files.remove(file_name);
+    }
+
+    /// Hashes every file hash together with its name in name order,
+    /// so the root does not depend on the order files were received in.
+    pub fn root(&self) -> Root {
+        let mut hasher = Hasher::default();
+
+        for (name, hash) in &self.files {
+            let mut leaf = Hasher::default();
+            leaf.add(&(name.len() as u64).to_be_bytes());
+            leaf.add(name.as_bytes());
+            leaf.add(hash);
+
+            hasher.add(&leaf.finalize());
+        }
+
+        Root(hasher.finalize())
+    }
+}
+
+impl std::fmt::Display for Root {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_root_ignores_order_but_not_content() {
+        let mut first = Manifest::default();
+        first.add("a".to_owned(), [1; 32]);
+        first.add("b/c".to_owned(), [2; 32]);
+
+        let mut second = Manifest::default();
+        second.add("b/c".to_owned(), [2; 32]);
+        second.add("a".to_owned(), [1; 32]);
+
+        assert_eq!(first.root(), second.root());
+
+        second.add("a".to_owned(), [3; 32]);
+        assert_ne!(first.root(), second.root());
+
+        second.add("a".to_owned(), [1; 32]);
+        second.add("d".to_owned(), [1; 32]);
+        assert_ne!(first.root(), second.root());
+
+        second.remove("d");
+        assert_eq!(first.root(), second.root());
+        assert_eq!(64, first.root().to_string().len());
+    }
+}
@@ -0,0 +1,196 @@
+//! Merging a folder into a copy the receiver already has, sending only the files that differ.
+
+use std::path::{Path, PathBuf};
+
+use crate::crypto::hasher::Hasher;
+use crate::parser::EncryptedSerdeIO;
+use crate::protocol::{FileData, Hash, MergeRequest, TransferData};
+use crate::transfer::channel::{Channel, Message};
+use crate::transfer::ignore::{self, Filter};
+use crate::transfer::manifest::Manifest;
+use crate::transfer::{channel, check, file, path};
+
+/// Sends the information of every entry of the folder, followed by the hash of files and links,
+/// which are added to the manifest.
+pub async fn announce<Ep>(
+    peer: &mut Ep,
+    path: &Path,
+    filter: &Filter,
+    manifest: &mut Manifest,
+) -> Result<(), super::error::Send>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    // NOTE: The root folder is not sent
+    for (id, entry) in ignore::walk(path, filter).enumerate().skip(1) {
+        let entry = entry?;
+        let file_data = file::describe(id, &entry, path).await?;
+
+        let hash = if let Some(target) = &file_data.link {
+            Some(Hasher::hash(target.as_bytes()))
+        } else if file_data.is_file {
+            Some(check::hash(entry.path(), None).await?)
+        } else {
+            None
+        };
+
+        peer.write_ser_enc(&file_data).await?;
+
+        if let Some(hash) = hash {
+            peer.write_ser_enc(&Hash(hash)).await?;
+            manifest.add(file_data.file_name, hash);
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends the entries requested by the receiver, returning how many and their size.
+pub async fn send<Ep>(
+    peer: &mut Ep,
+    path: &Path,
+    filter: &Filter,
+    manifest: &mut Manifest,
+    (chunk_size, channel): (usize, Option<&Channel>),
+) -> Result<(u64, u64), super::error::Send>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let request = peer.read_ser_enc::<MergeRequest>().await?;
+
+    log::info!(
+        "Receiver requested {} entries of {} bytes",
+        request.entries,
+        request.size
+    );
+
+    #[allow(clippy::cast_possible_truncation)]
+    channel::send(channel, Message::ProgressSize(request.size as usize)).await;
+
+    // NOTE: Every request is read before sending, as the receiver writes them all first
+    let mut ids = Vec::new();
+    for _ in 0..request.entries {
+        ids.push(peer.read_ser_enc::<FileData>().await?.id);
+    }
+    ids.sort_unstable();
+
+    let mut requested = ids.into_iter().peekable();
+
+    for (id, entry) in ignore::walk(path, filter).enumerate() {
+        if requested.next_if_eq(&(id as u64)).is_none() {
+            continue;
+        }
+
+        file::send(
+            peer,
+            id,
+            &entry?,
+            path,
+            None,
+            manifest,
+            (chunk_size, channel),
+        )
+        .await?;
+    }
+
+    Ok((request.entries, request.size))
+}
+
+/// Reads the entries announced by the sender, creating the missing folders under the root.
+/// Returns the files and links whose copy differs, adding the rest to the manifest.
+pub async fn compare<Ep>(
+    peer: &mut Ep,
+    root: &Path,
+    transfer_data: &TransferData,
+    manifest: &mut Manifest,
+) -> Result<Vec<FileData>, super::error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    // NOTE: The root folder is not sent
+    let total_dirs = transfer_data.total_dirs.saturating_sub(1);
+    let total = transfer_data.total_files + total_dirs + transfer_data.total_links;
+
+    let mut changed = Vec::new();
+
+    for _ in 0..total {
+        let file_data = peer.read_ser_enc::<FileData>().await?;
+        let path = entry_path(root, &file_data.file_name)?;
+
+        if !file_data.is_file && file_data.link.is_none() {
+            // NOTE: A file or link where the sender has a folder is replaced by it
+            if tokio::fs::symlink_metadata(&path)
+                .await
+                .is_ok_and(|m| !m.is_dir())
+            {
+                tokio::fs::remove_file(&path).await?;
+            }
+
+            tokio::fs::create_dir_all(&path).await?;
+            continue;
+        }
+
+        let Hash(expected) = peer.read_ser_enc::<Hash>().await?;
+
+        if check::local_hash(root, &path, &file_data, None).await? == Some(expected) {
+            manifest.add(file_data.file_name, expected);
+        } else {
+            changed.push(file_data);
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Asks the sender for the changed entries, clearing whatever is in the way of each of them.
+pub async fn request<Ep>(
+    peer: &mut Ep,
+    root: &Path,
+    changed: &[FileData],
+) -> Result<(), super::error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    for file_data in changed {
+        let path = entry_path(root, &file_data.file_name)?;
+
+        let Ok(metadata) = tokio::fs::symlink_metadata(&path).await else {
+            continue;
+        };
+
+        // NOTE: A regular file is truncated when received, anything else must be removed first
+        if metadata.is_dir() {
+            tokio::fs::remove_dir_all(&path).await?;
+        } else if metadata.is_symlink() || file_data.link.is_some() {
+            tokio::fs::remove_file(&path).await?;
+        }
+    }
+
+    let request = MergeRequest {
+        entries: changed.len() as u64,
+        size: changed.iter().map(|f| f.file_size).sum(),
+    };
+
+    peer.write_ser_enc(&request).await?;
+
+    for file_data in changed {
+        peer.write_ser_enc(file_data).await?;
+    }
+
+    Ok(())
+}
+
+/// Path of an announced entry, refusing any that would leave the root.
+fn entry_path(root: &Path, file_name: &str) -> Result<PathBuf, super::error::Receive> {
+    let path = path::received(root, file_name).ok_or(super::error::Receive::Destination)?;
+    let path = PathBuf::from(path.as_str());
+
+    // NOTE: A link inside the copy must not redirect writes outside of it, the entry itself is replaced
+    if path.parent().is_some_and(|p| path::through_link(root, p)) {
+        log::warn!("Refusing to merge {file_name} through a link");
+
+        return Err(super::error::Receive::Destination);
+    }
+
+    Ok(path)
+}
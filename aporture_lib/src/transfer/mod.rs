@@ -1,352 +1,3910 @@
-use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use typed_path::Utf8UnixPathBuf;
-use walkdir::WalkDir;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::task::JoinSet;
 
 use self::channel::{Channel, Message};
+use self::connection::Link;
+use self::ignore::Filter;
+use self::manifest::Manifest;
+use crate::crypto::hasher::Hasher;
 use crate::net::peer::{Encryptable, Peer};
-use crate::pairing::PairInfo;
+use crate::net::quic::{QuicConnection, QuicNetworkPeer};
+use crate::pairing::{ConnectionMethod, PairInfo};
 use crate::parser::EncryptedSerdeIO;
-use crate::protocol::{FileData, TransferData, TransferResponseCode};
+use crate::protocol::{
+    FileData, Hash, StreamHello, TransferData, TransferResponseCode, VerifyResult,
+};
 use crate::{Receiver, Sender, State};
 
+mod archive;
 mod channel;
+mod check;
 mod connection;
 mod deflate;
 mod error;
 mod file;
+mod ignore;
+mod manifest;
+mod merge;
+mod options;
 mod path;
+mod pipe;
 
 pub use channel::Message as ChannelMessage;
+pub use connection::DEFAULT_TIMEOUT as DEFAULT_CONNECTION_TIMEOUT;
 pub use error::{Receive as ReceiveError, Send as SendError};
+pub use file::{DEFAULT_CHUNK_SIZE, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+pub use manifest::Root as ManifestRoot;
+pub use options::TransferOptions;
 
 pub struct AportureTransferProtocol<'a, S: State> {
     pair_info: &'a mut PairInfo,
     path: &'a Path,
     channel: Option<Channel>,
-    _phantom: PhantomData<S>,
+    options: TransferOptions<S>,
 }
 
-impl<S: State> AportureTransferProtocol<'_, S> {
-    pub fn add_progress_notifier(&mut self, channel: Channel) {
-        self.channel = Some(channel);
+/// Time the receiver waits for the sender to start the next file
+const DEFAULT_ENTRY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time the receiver waits for the next chunk of a file before deciding the sender is gone
+const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_mins(1);
+
+/// Extra streams the files of a folder are spread over on a direct connection
+const DEFAULT_STREAMS: u64 = 4;
+
+/// Extra streams the receiver accepts, so the sender can not make it open any amount
+const MAX_STREAMS: u64 = 16;
+
+/// Times a transfer reconnects to the peer after losing the connection before giving up
+const RECONNECTS: usize = 3;
+
+/// Defines how the receiver handles files whose hash does not match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Times a mismatched file is requested again before giving up on it
+    pub retries: usize,
+    /// Skip files that still mismatch after every retry instead of aborting the transfer
+    pub skip_failed: bool,
+}
+
+impl RetryPolicy {
+    const DEFAULT: Self = Self {
+        retries: 1,
+        skip_failed: false,
+    };
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
     }
 }
 
-impl<'a> AportureTransferProtocol<'a, Sender> {
-    pub fn new(pair_info: &'a mut PairInfo, path: &'a Path) -> Self {
-        AportureTransferProtocol {
-            pair_info,
-            path,
-            channel: None,
-            _phantom: PhantomData,
+/// Defines what the receiver does when the destination already exists.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OnConflict {
+    /// Save next to it with a numbered suffix
+    #[default]
+    Rename,
+    /// Replace it with the received one
+    Overwrite,
+    /// Keep it and discard the received one
+    Skip,
+    /// Add the received folder into it, only receiving the files that differ.
+    /// Files are replaced, and a folder that can not be merged is renamed instead
+    Merge,
+}
+
+impl OnConflict {
+    pub const ALL: [Self; 4] = [Self::Rename, Self::Overwrite, Self::Skip, Self::Merge];
+}
+
+impl std::fmt::Display for OnConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rename => write!(f, "rename"),
+            Self::Overwrite => write!(f, "overwrite"),
+            Self::Skip => write!(f, "skip"),
+            Self::Merge => write!(f, "merge"),
         }
     }
+}
 
-    pub async fn transfer(self) -> Result<(), error::Send> {
-        let connection = connection::find(self.pair_info).await;
+impl std::str::FromStr for OnConflict {
+    type Err = &'static str;
 
-        if let Some(connection) = connection {
-            let peer = connection.new_stream().await?;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|c| c.to_string() == s)
+            .ok_or("Unknown conflict policy, expected rename, overwrite, skip or merge")
+    }
+}
 
-            self.transfer_peer(peer).await?;
+/// Archive the receiver writes the transfer into instead of extracting it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    /// Entries are stored without compression
+    Zip,
+}
 
-            connection.finish().await;
-        } else {
-            log::info!("Timeout waiting for peer connection, using server fallback");
-            let peer = self
-                .pair_info
-                .fallback()
-                .expect("Connection to server must exist")
-                .add_cipher(self.pair_info.cipher());
+impl ArchiveFormat {
+    pub const ALL: [Self; 2] = [Self::Tar, Self::Zip];
+}
 
-            self.transfer_peer(peer).await?;
+impl std::fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tar => write!(f, "tar"),
+            Self::Zip => write!(f, "zip"),
         }
+    }
+}
+
+impl std::str::FromStr for ArchiveFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|a| a.to_string() == s)
+            .ok_or("Unknown archive format, expected tar or zip")
+    }
+}
+
+/// Defines how the sender handles symbolic links inside a folder.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkMode {
+    /// Send the entry the link points to as if it was inside the folder
+    #[default]
+    Follow,
+    /// Send the link itself so the receiver recreates it
+    Preserve,
+    /// Leave links out of the transfer
+    Skip,
+}
 
-        Ok(())
+impl SymlinkMode {
+    pub const ALL: [Self; 3] = [Self::Follow, Self::Preserve, Self::Skip];
+}
+
+impl std::fmt::Display for SymlinkMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Follow => write!(f, "follow"),
+            Self::Preserve => write!(f, "preserve"),
+            Self::Skip => write!(f, "skip"),
+        }
     }
+}
 
-    async fn transfer_peer<Ep>(self, mut peer: Ep) -> Result<(), error::Send>
-    where
-        Ep: Encryptable + Peer + Send,
-    {
-        let path = path::sanitize(self.path).map_err(|_| error::Send::Path)?;
+impl std::str::FromStr for SymlinkMode {
+    type Err = &'static str;
 
-        log::info!("Sending file {}", path.display());
-        let transfer_data = get_transfer_data(&path)?;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|m| m.to_string() == s)
+            .ok_or("Unknown symlink mode, expected follow, preserve or skip")
+    }
+}
 
-        log::info!("Sending transfer data information {transfer_data:?}");
-        peer.write_ser_enc(&transfer_data).await?;
+/// Defines when the sender packs a folder into a single compressed archive before sending it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Compress folders within the [`CompressionThreshold`]
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
 
-        #[allow(clippy::cast_possible_truncation)]
-        let progress_len = transfer_data.total_size as usize;
-        channel::send(self.channel.as_ref(), Message::ProgressSize(progress_len)).await;
+impl Compression {
+    pub const ALL: [Self; 3] = [Self::Auto, Self::Always, Self::Never];
+
+    /// Whether a folder with the transfer data is compressed.
+    #[must_use]
+    pub const fn applies(self, threshold: CompressionThreshold, data: &TransferData) -> bool {
+        match self {
+            Self::Auto => data.total_files >= threshold.files && data.total_size <= threshold.size,
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
 
-        let base = path::platform(&path);
-        let is_dir = transfer_data.total_files > 1;
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Always => write!(f, "always"),
+            Self::Never => write!(f, "never"),
+        }
+    }
+}
 
-        log::info!("Sending files...");
+impl std::str::FromStr for Compression {
+    type Err = &'static str;
 
-        for (id, entry) in WalkDir::new(&path)
-            .follow_links(true)
-            .sort_by_file_name()
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
             .into_iter()
-            .enumerate()
-            .filter(|(id, _)| !is_dir || *id != 0)
-        {
-            file::send(&mut peer, id, entry?.path(), &base, self.channel.as_ref()).await?;
+            .find(|c| c.to_string() == s)
+            .ok_or("Unknown compression, expected auto, always or never")
+    }
+}
+
+/// Folders compressed with [`Compression::Auto`]. Many small files gain the most from being
+/// sent as one archive, while large folders take too long to compress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressionThreshold {
+    /// Least amount of files in the folder
+    pub files: u64,
+    /// Largest total size of the folder in bytes
+    pub size: u64,
+}
+
+impl CompressionThreshold {
+    pub const DEFAULT: Self = Self {
+        files: 1000,
+        size: 1 << 30,
+    };
+}
+
+impl Default for CompressionThreshold {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(Debug)]
+pub struct TransferReport {
+    pub destination: PathBuf,
+    pub total_files: u64,
+    /// Bytes of every file received
+    pub total_size: u64,
+    /// Names of the files skipped because their hash did not match
+    pub skipped: Vec<String>,
+    /// Combined hash of every file received
+    pub root: ManifestRoot,
+    /// Whether the sender computed the same root, only false when files were skipped
+    pub verified: bool,
+    /// Whether the destination already existed and was kept, see [`OnConflict::Skip`]
+    pub kept_existing: bool,
+    /// Files and links already identical in the copy merged into, see [`OnConflict::Merge`]
+    pub unchanged: u64,
+    pub route: Route,
+}
+
+#[derive(Debug)]
+pub struct SendReport {
+    pub total_files: u64,
+    /// Bytes of every file sent
+    pub total_size: u64,
+    /// Combined hash of every file sent
+    pub root: ManifestRoot,
+    /// Whether the receiver computed the same root
+    pub verified: bool,
+    pub route: Route,
+}
+
+#[derive(Debug)]
+pub struct VerifyReport {
+    /// Path of the originals at the sender, of the checked copy at the receiver
+    pub path: PathBuf,
+    /// Files and links compared
+    pub total_files: u64,
+    /// Names of the files whose copy differs from the original
+    pub mismatched: Vec<String>,
+    /// Names of the files without a copy
+    pub missing: Vec<String>,
+    pub route: Route,
+}
+
+impl VerifyReport {
+    /// Whether every file has a copy identical to the original.
+    #[must_use]
+    pub const fn is_intact(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// How the transfer reached the peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Route {
+    /// Directly, through the address found by hole punching
+    HolePunching,
+    /// Directly, through a port opened with upnp
+    #[serde(rename = "upnp")]
+    UPnP,
+    /// Through the server, after no direct connection succeeded
+    Relay,
+}
+
+impl From<ConnectionMethod> for Route {
+    fn from(method: ConnectionMethod) -> Self {
+        match method {
+            ConnectionMethod::HolePunching => Self::HolePunching,
+            ConnectionMethod::UPnP => Self::UPnP,
         }
+    }
+}
 
-        loop {
-            let res = peer.read_ser_enc::<TransferResponseCode>().await?;
-
-            match res {
-                TransferResponseCode::Ok => break,
-                TransferResponseCode::HashMismatch => {
-                    let res = peer.read_ser_enc::<FileData>().await?;
-
-                    #[allow(clippy::cast_possible_truncation)]
-                    let id = res.id as usize;
-
-                    let Some(entry) = WalkDir::new(&path)
-                        .follow_links(true)
-                        .sort_by_file_name()
-                        .into_iter()
-                        .nth(id)
-                    else {
-                        return Err(error::Send::HashMismatch);
-                    };
-
-                    file::send(&mut peer, id, entry?.path(), &base, self.channel.as_ref()).await?;
-                }
-                TransferResponseCode::TransferFail => return Err(error::Send::HashMismatch),
-            }
+impl std::fmt::Display for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HolePunching => write!(f, "direct (hole punching)"),
+            Self::UPnP => write!(f, "direct (UPnP)"),
+            Self::Relay => write!(f, "server relay"),
+        }
+    }
+}
+
+impl<S: State> AportureTransferProtocol<'_, S> {
+    pub fn add_progress_notifier(&mut self, channel: tokio::sync::mpsc::Sender<Message>) {
+        self.channel.get_or_insert_default().progress = Some(channel);
+    }
+
+    /// Halts the transfer between chunks while the value is true, resuming once it is false.
+    /// The connection is kept open meanwhile by its keep alive, so a long pause drops it
+    /// if the interval is disabled.
+    pub fn add_pause_control(&mut self, paused: tokio::sync::watch::Receiver<bool>) {
+        self.channel.get_or_insert_default().paused = Some(paused);
+    }
+
+    /// Stops the transfer once the value is true, telling the peer so it fails with a
+    /// cancelled error instead of a broken connection.
+    pub fn add_cancel_control(&mut self, cancelled: tokio::sync::watch::Receiver<bool>) {
+        self.channel.get_or_insert_default().cancelled = Some(cancelled);
+    }
+
+    /// Connects directly to the peer, falling back to the server relay when no candidate
+    /// connects in time. `None` if the relay was already used by an earlier transfer.
+    async fn connect(&mut self) -> Result<Option<Link>, std::io::Error> {
+        let connection = connection::find(
+            self.pair_info,
+            self.options.connection_timeout,
+            self.options.transport,
+        )
+        .await;
+
+        if let Some((connection, method)) = connection {
+            let mut peer = connection.new_stream().await?;
+            plain(&mut peer, self.pair_info);
+
+            let cipher = self.pair_info.cipher();
+            return Ok(Some(Link::direct(connection, peer, method, cipher)));
         }
 
-        channel::send(self.channel.as_ref(), Message::Finished).await;
+        log::info!("Timeout waiting for peer connection, using server fallback");
+        channel::send(self.channel.as_ref(), Message::UsingRelay).await;
+
+        let cipher = self.pair_info.cipher();
+        Ok(self
+            .pair_info
+            .fallback()
+            .map(|peer| Link::relay(peer, cipher)))
+    }
+
+    fn start_rekey<Ep: Encryptable>(&self, peer: &mut Ep) {
+        if let Some(rekey) = peer.rekey() {
+            rekey.set_interval(self.options.rekey_interval);
+        }
+    }
 
-        Ok(())
+    /// Applies the settings of the main stream to an extra one.
+    fn prepare_lane(&self, lane: &mut QuicNetworkPeer) {
+        plain(lane, self.pair_info);
+        self.start_rekey(lane);
     }
 }
 
-impl<'a> AportureTransferProtocol<'a, Receiver> {
-    pub fn new(pair_info: &'a mut PairInfo, dest: &'a Path) -> Self {
+impl<'a> AportureTransferProtocol<'a, Sender> {
+    pub const fn new(pair_info: &'a mut PairInfo, path: &'a Path) -> Self {
+        Self::with_options(pair_info, path, TransferOptions::DEFAULT)
+    }
+
+    pub const fn with_options(
+        pair_info: &'a mut PairInfo,
+        path: &'a Path,
+        options: TransferOptions<Sender>,
+    ) -> Self {
         AportureTransferProtocol {
             pair_info,
-            path: dest,
+            path,
             channel: None,
-            _phantom: PhantomData,
+            options,
         }
     }
 
-    pub async fn transfer(self) -> Result<PathBuf, error::Receive> {
-        let connection = connection::find(self.pair_info).await;
+    /// Sends the path, reconnecting to start again if the connection is lost, see
+    /// [`AportureTransferProtocol::<Receiver>::transfer`].
+    pub async fn transfer(mut self) -> Result<SendReport, error::Send> {
+        let mut reconnects = 0;
 
-        let destination = if let Some(connection) = connection {
-            let peer = connection.new_stream().await?;
+        loop {
+            let Some(mut link) = self.connect().await? else {
+                return Err(if reconnects == 0 {
+                    error::Send::NoRelay
+                } else {
+                    error::Send::ConnectionLost
+                });
+            };
+
+            let report = tokio::select! {
+                report = self.transfer_peer(&mut link.peer, link.connection.as_ref(), link.route) => report,
+                () = channel::cancelled(self.channel.as_ref()) => {
+                    link.cancel().await;
+                    return Err(error::Send::Cancelled);
+                }
+            };
 
-            let destination = self.transfer_peer(peer).await?;
+            match report {
+                Err(e) if e.is_connection_lost() && reconnects < RECONNECTS => {
+                    reconnects += 1;
+                    log::warn!("Connection lost, reconnecting ({reconnects}/{RECONNECTS})");
 
-            connection.finish().await;
+                    channel::send(self.channel.as_ref(), Message::Reconnecting).await;
+                }
+                report => {
+                    let report = report?;
+                    link.finish(self.pair_info).await;
 
-            destination
-        } else {
-            log::info!("Timeout waiting for peer connection, using server fallback");
-            let peer = self
-                .pair_info
-                .fallback()
-                .expect("Connection to server must exist")
-                .add_cipher(self.pair_info.cipher());
+                    return Ok(report);
+                }
+            }
+        }
+    }
 
-            self.transfer_peer(peer).await?
+    /// Sends everything read from the reader, for example a pipe, instead of the path.
+    /// The size is not known in advance so only progress increments are notified.
+    pub async fn transfer_from<R>(mut self, reader: R) -> Result<SendReport, error::Send>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let Some(mut link) = self.connect().await? else {
+            return Err(error::Send::NoRelay);
+        };
+
+        let report = tokio::select! {
+            report = self.pipe_peer(&mut link.peer, reader, link.route) => report?,
+            () = channel::cancelled(self.channel.as_ref()) => {
+                link.cancel().await;
+                return Err(error::Send::Cancelled);
+            }
         };
 
-        Ok(destination)
+        link.finish(self.pair_info).await;
+
+        Ok(report)
     }
 
-    async fn transfer_peer<Ep>(self, mut peer: Ep) -> Result<PathBuf, error::Receive>
+    async fn pipe_peer<Ep, R>(
+        &self,
+        mut peer: Ep,
+        reader: R,
+        route: Route,
+    ) -> Result<SendReport, error::Send>
     where
         Ep: Encryptable + Peer + Send,
+        R: AsyncRead + Unpin + Send,
     {
-        let dest = path::sanitize(self.path).map_err(|_| error::Receive::Destination)?;
-
-        log::info!("File will try to be saved to {}", dest.display());
+        self.start_rekey(&mut peer);
 
-        log::info!("Receiving Transfer information");
-        let transfer_data = peer.read_ser_enc::<TransferData>().await?;
-        log::info!("Transfer data received: {transfer_data:?}");
-
-        #[allow(clippy::cast_possible_truncation)]
-        let progress_len = transfer_data.total_size as usize;
-        channel::send(self.channel.as_ref(), Message::ProgressSize(progress_len)).await;
-
-        let dest = if transfer_data.total_files == 1 {
-            receive_file(dest, &transfer_data, &mut peer, self.channel.as_ref()).await?
-        } else {
-            receive_folder(dest, transfer_data, &mut peer, self.channel.as_ref()).await?
+        let transfer_data = TransferData {
+            total_files: 1,
+            root_name: pipe::NAME.to_owned(),
+            piped: true,
+            ..Default::default()
         };
 
-        Ok(dest)
-    }
-}
+        log::info!("Sending transfer data information {transfer_data:?}");
+        peer.write_ser_enc(&transfer_data).await?;
 
-fn get_transfer_data(path: &Path) -> Result<TransferData, error::Send> {
-    let mut transfer_data = WalkDir::new(path).follow_links(true).into_iter().try_fold(
-        TransferData::default(),
-        |mut data, entry| -> Result<TransferData, error::Send> {
-            let metadata = entry?.metadata()?;
+        let channel = self.channel.as_ref();
+        let mut manifest = Manifest::default();
 
-            if metadata.is_file() {
-                let file_length = metadata.len();
+        log::info!("Sending piped data...");
 
-                data.total_files += 1;
-                data.total_size += file_length;
-            }
-            Ok(data)
-        },
-    )?;
+        let total_size = pipe::send(&mut peer, reader, &mut manifest, channel).await?;
 
-    path.file_name()
-        .expect("Should have a File Name as path it was sanitized")
-        .to_str()
-        .expect("Should be valid utf8 as it was santized")
-        .clone_into(&mut transfer_data.root_name);
+        let verified = confirm(&mut peer, &manifest).await?;
 
-    Ok(transfer_data)
-}
+        channel::send(channel, Message::Finished).await;
 
-async fn receive_file<Ep>(
-    mut dest: PathBuf,
-    transfer_data: &TransferData,
-    peer: &mut Ep,
-    channel: Option<&Channel>,
-) -> Result<PathBuf, error::Receive>
-where
-    Ep: EncryptedSerdeIO + Send,
-{
-    let file = if dest.is_dir() {
-        tempfile::NamedTempFile::new_in(&dest)?
-    } else {
-        let parent_path = dest
-            .parent()
-            .expect("Parent must exist as path is sanitized");
+        Ok(SendReport {
+            total_files: transfer_data.total_files,
+            total_size,
+            root: manifest.root(),
+            verified,
+            route,
+        })
+    }
 
-        tempfile::NamedTempFile::new_in(parent_path)?
-    };
+    async fn transfer_peer<Ep>(
+        &self,
+        mut peer: Ep,
+        connection: Option<&QuicConnection>,
+        route: Route,
+    ) -> Result<SendReport, error::Send>
+    where
+        Ep: Encryptable + Peer + Send,
+    {
+        let path = path::sanitize(self.path).map_err(|_| error::Send::Path)?;
 
-    let (data, retry) = file::receive(file.path(), peer, channel).await?;
-    if retry {
-        peer.write_ser_enc(&TransferResponseCode::HashMismatch)
-            .await?;
-        peer.write_ser_enc(&data).await?;
+        self.start_rekey(&mut peer);
 
-        let (_, mismatch) = file::receive(file.path(), peer, channel).await?;
+        // NOTE: Boxed as the entries being read would make every transfer future larger
+        if self.options.from_archive {
+            return Box::pin(self.transfer_unpacked(peer, &path, route)).await;
+        }
 
-        if mismatch {
-            peer.write_ser_enc(&TransferResponseCode::TransferFail)
-                .await?;
+        log::info!("Sending file {}", path.display());
+        let mut transfer_data = get_transfer_data(&path, &self.options.filter)?;
+        transfer_data.stream_hash = self.options.stream_hash;
+
+        let (compression, threshold) =
+            (self.options.compression, self.options.compression_threshold);
+        if path.is_dir() && compression.applies(threshold, &transfer_data) {
+            return self
+                .transfer_archive(peer, &path, transfer_data, route)
+                .await;
+        }
 
-            return Err(error::Receive::HashMismatch);
+        // NOTE: A stream hash covers every file in order, so it needs a single stream
+        if connection.is_some() && path.is_dir() && !transfer_data.stream_hash {
+            transfer_data.streams = self.options.streams.min(transfer_data.total_files);
         }
-    }
 
-    channel::send(channel, Message::Finished).await;
+        transfer_data.mergeable = path.is_dir() && !transfer_data.stream_hash;
 
-    if dest.is_dir() {
-        let path = Utf8UnixPathBuf::from(&transfer_data.root_name)
-            .normalize()
-            .with_platform_encoding();
+        log::info!("Sending transfer data information {transfer_data:?}");
+        peer.write_ser_enc(&transfer_data).await?;
 
-        dest.push(path);
-    }
+        if transfer_data.mergeable {
+            match peer.read_ser_enc::<TransferResponseCode>().await? {
+                TransferResponseCode::Ok => {}
+                TransferResponseCode::Merge => {
+                    return self.transfer_merge(peer, &path, route).await;
+                }
+                TransferResponseCode::TransferFail => return Err(error::Send::PeerFailed),
+                _ => return Err(crate::io::Error::UnexpectedMessage.into()),
+            }
+        }
 
-    let dest = path::non_existant(dest).await;
+        #[allow(clippy::cast_possible_truncation)]
+        let progress_len = transfer_data.total_size as usize;
+        channel::send(self.channel.as_ref(), Message::ProgressSize(progress_len)).await;
 
-    log::info!("Persisting file to path {}", dest.display());
+        let channel = self.channel.as_ref();
+        let progress = (self.options.chunk_size, channel);
 
-    file.persist(&dest)
-        .map_err(|_| error::Receive::Destination)?;
+        log::info!("Sending files...");
 
-    peer.write_ser_enc(&TransferResponseCode::Ok).await?;
+        let mut manifest = Manifest::default();
+        let (filter, stream_hash) = (&self.options.filter, self.options.stream_hash);
 
-    Ok(dest)
-}
+        let mut lanes = open_lanes(connection, transfer_data.streams).await?;
+        for lane in &mut lanes {
+            self.prepare_lane(lane);
+        }
 
-async fn receive_folder<Ep>(
-    mut dest: PathBuf,
-    transfer_data: TransferData,
-    peer: &mut Ep,
-    channel: Option<&Channel>,
-) -> Result<PathBuf, error::Receive>
-where
-    Ep: EncryptedSerdeIO + Send,
-{
-    let parent = dest
-        .parent()
-        .expect("Parent must exist as path is sanitized");
+        if lanes.is_empty() {
+            send_files(
+                &mut peer,
+                &path,
+                filter,
+                stream_hash,
+                &mut manifest,
+                progress,
+            )
+            .await?;
+        } else {
+            send_lanes(&mut peer, lanes, &path, filter, &mut manifest, progress).await?;
+        }
 
-    if dest.is_file() || !parent.exists() {
-        return Err(error::Receive::Destination);
+        let verified = respond(
+            &mut peer,
+            &path,
+            filter,
+            stream_hash,
+            &mut manifest,
+            progress,
+        )
+        .await?;
+
+        channel::send(channel, Message::Finished).await;
+
+        Ok(SendReport {
+            total_files: transfer_data.total_files,
+            total_size: transfer_data.total_size,
+            root: manifest.root(),
+            verified,
+            route,
+        })
     }
+}
 
-    let base_path = if tokio::fs::try_exists(&dest)
-        .await
-        .map_err(|_| error::Receive::Destination)?
+impl AportureTransferProtocol<'_, Sender> {
+    /// Announces every entry to a receiver merging into its copy, sending only those it requests.
+    async fn transfer_merge<Ep>(
+        &self,
+        mut peer: Ep,
+        path: &Path,
+        route: Route,
+    ) -> Result<SendReport, error::Send>
+    where
+        Ep: Encryptable + Peer + Send,
     {
-        &dest
-    } else {
-        parent
-    };
+        log::info!("Receiver merges into its copy, announcing every entry");
 
-    let dir = tempfile::tempdir_in(base_path)?;
+        let mut manifest = Manifest::default();
+        let (filter, channel) = (&self.options.filter, self.channel.as_ref());
+        let progress = (self.options.chunk_size, channel);
 
-    let mut files = 0;
+        merge::announce(&mut peer, path, filter, &mut manifest).await?;
 
-    let mut retries = Vec::new();
+        let (total_files, total_size) =
+            merge::send(&mut peer, path, filter, &mut manifest, progress).await?;
 
-    while files < transfer_data.total_files {
-        let (file_data, retry) = file::receive(dir.path(), peer, channel).await?;
+        let verified = respond(&mut peer, path, filter, false, &mut manifest, progress).await?;
 
-        if file_data.is_file {
-            files += 1;
-        }
+        channel::send(channel, Message::Finished).await;
 
-        if retry {
-            retries.push(file_data);
-        }
+        Ok(SendReport {
+            total_files,
+            total_size,
+            root: manifest.root(),
+            verified,
+            route,
+        })
     }
 
-    for data in retries {
-        peer.write_ser_enc(&TransferResponseCode::HashMismatch)
-            .await?;
-        peer.write_ser_enc(&data).await?;
+    /// Sends only the hash of every file instead of its contents, so the receiver checks the
+    /// copy it got in an earlier transfer and both learn which files differ.
+    pub async fn verify(mut self) -> Result<VerifyReport, error::Send> {
+        let Some(mut link) = self.connect().await? else {
+            return Err(error::Send::NoRelay);
+        };
 
-        let (_, mismatch) = file::receive(dir.path(), peer, channel).await?;
+        let report = tokio::select! {
+            report = self.verify_peer(&mut link.peer, link.route) => report?,
+            () = channel::cancelled(self.channel.as_ref()) => {
+                link.cancel().await;
+                return Err(error::Send::Cancelled);
+            }
+        };
 
-        if mismatch {
-            peer.write_ser_enc(&TransferResponseCode::TransferFail)
-                .await?;
-            return Err(error::Receive::HashMismatch);
-        }
+        link.finish(self.pair_info).await;
+
+        Ok(report)
     }
 
-    channel::send(channel, Message::Finished).await;
+    async fn verify_peer<Ep>(&self, mut peer: Ep, route: Route) -> Result<VerifyReport, error::Send>
+    where
+        Ep: Encryptable + Peer + Send,
+    {
+        let path = path::sanitize(self.path).map_err(|_| error::Send::Path)?;
 
-    if dest.is_dir() {
-        let path = Utf8UnixPathBuf::from(&transfer_data.root_name)
-            .normalize()
-            .with_platform_encoding();
+        self.start_rekey(&mut peer);
 
-        dest.push(path);
-    }
+        let mut transfer_data = get_transfer_data(&path, &self.options.filter)?;
+        transfer_data.verify_only = true;
 
-    let dest = path::non_existant(dest).await;
+        log::info!("Sending verification information {transfer_data:?}");
+        peer.write_ser_enc(&transfer_data).await?;
 
-    let tmp = dir.into_path();
-    tokio::fs::rename(tmp, &dest).await?;
+        // NOTE: Both peers hash the files, which is the progress of the verification
+        let channel = self.channel.as_ref();
 
-    peer.write_ser_enc(&TransferResponseCode::Ok).await?;
+        #[allow(clippy::cast_possible_truncation)]
+        let progress_len = transfer_data.total_size as usize;
+        channel::send(channel, Message::ProgressSize(progress_len)).await;
+
+        check::send(&mut peer, &path, &self.options.filter, channel).await?;
+
+        match peer.read_ser_enc::<TransferResponseCode>().await? {
+            TransferResponseCode::Ok => (),
+            TransferResponseCode::TransferFail => return Err(error::Send::PeerFailed),
+            _ => return Err(crate::io::Error::UnexpectedMessage.into()),
+        }
+
+        let result = peer.read_ser_enc::<VerifyResult>().await?;
+
+        channel::send(channel, Message::Finished).await;
 
-    Ok(dest)
+        Ok(VerifyReport {
+            path,
+            total_files: transfer_data.total_files + transfer_data.total_links,
+            mismatched: result.mismatched,
+            missing: result.missing,
+            route,
+        })
+    }
+
+    /// Sends the folder packed in a single compressed archive, which the receiver unpacks.
+    async fn transfer_archive<Ep>(
+        &self,
+        mut peer: Ep,
+        path: &Path,
+        transfer_data: TransferData,
+        route: Route,
+    ) -> Result<SendReport, error::Send>
+    where
+        Ep: Encryptable + Peer + Send,
+    {
+        let channel = self.channel.as_ref();
+
+        // NOTE: The archive is built while it is sent, so it is piped as its size is unknown
+        let archive_data = TransferData {
+            compressed: true,
+            piped: true,
+            stream_hash: false,
+            ..transfer_data
+        };
+
+        log::info!("Sending transfer data information {archive_data:?}");
+        peer.write_ser_enc(&archive_data).await?;
+
+        channel::send(channel, Message::Compression).await;
+
+        #[allow(clippy::cast_possible_truncation)]
+        let progress_len = archive_data.total_size as usize;
+        channel::send(channel, Message::ProgressSize(progress_len)).await;
+
+        log::info!("Compressing and sending folder {}", path.display());
+
+        let mut manifest = Manifest::default();
+        let filter = &self.options.filter;
+
+        deflate::send(&mut peer, path, filter, &mut manifest, channel).await?;
+
+        let verified = confirm(&mut peer, &manifest).await?;
+
+        channel::send(channel, Message::Finished).await;
+
+        Ok(SendReport {
+            total_files: archive_data.total_files,
+            total_size: archive_data.total_size,
+            root: manifest.root(),
+            verified,
+            route,
+        })
+    }
+
+    /// Sends the entries of the archive at the path as a folder, without extracting it.
+    async fn transfer_unpacked<Ep>(
+        &self,
+        mut peer: Ep,
+        path: &Path,
+        route: Route,
+    ) -> Result<SendReport, error::Send>
+    where
+        Ep: Encryptable + Peer + Send,
+    {
+        log::info!("Sending the entries of archive {}", path.display());
+
+        let source = archive::Source::open(path).await?;
+
+        let mut transfer_data = source.transfer_data();
+        transfer_data.stream_hash = self.options.stream_hash;
+
+        log::info!("Sending transfer data information {transfer_data:?}");
+        peer.write_ser_enc(&transfer_data).await?;
+
+        let channel = self.channel.as_ref();
+        let progress = (self.options.chunk_size, channel);
+
+        #[allow(clippy::cast_possible_truncation)]
+        let progress_len = transfer_data.total_size as usize;
+        channel::send(channel, Message::ProgressSize(progress_len)).await;
+
+        let mut manifest = Manifest::default();
+        let mut stream = transfer_data.stream_hash.then(Hasher::default);
+
+        send_unpacked(
+            &mut peer,
+            &source,
+            None,
+            stream.as_mut(),
+            &mut manifest,
+            progress,
+        )
+        .await?;
+
+        if let Some(hasher) = stream {
+            peer.write_ser_enc(&Hash(hasher.finalize())).await?;
+        }
+
+        let stream_hash = transfer_data.stream_hash;
+        let verified =
+            respond_unpacked(&mut peer, &source, stream_hash, &mut manifest, progress).await?;
+
+        channel::send(channel, Message::Finished).await;
+
+        Ok(SendReport {
+            total_files: transfer_data.total_files,
+            total_size: transfer_data.total_size,
+            root: manifest.root(),
+            verified,
+            route,
+        })
+    }
+}
+
+impl<'a> AportureTransferProtocol<'a, Receiver> {
+    pub const fn new(pair_info: &'a mut PairInfo, dest: &'a Path) -> Self {
+        Self::with_options(pair_info, dest, TransferOptions::DEFAULT)
+    }
+
+    pub const fn with_options(
+        pair_info: &'a mut PairInfo,
+        dest: &'a Path,
+        options: TransferOptions<Receiver>,
+    ) -> Self {
+        AportureTransferProtocol {
+            pair_info,
+            path: dest,
+            channel: None,
+            options,
+        }
+    }
+
+    /// Receives into the destination, reconnecting if the connection is lost so the sender
+    /// starts again. A folder then merges into the entries that already arrived, so only the
+    /// rest is sent, while anything else is received whole.
+    pub async fn transfer(mut self) -> Result<TransferReport, error::Receive> {
+        let mut reconnects = 0;
+        let mut staged = None;
+
+        loop {
+            let Some(mut link) = self.connect().await? else {
+                return Err(if reconnects == 0 {
+                    error::Receive::NoRelay
+                } else {
+                    error::Receive::ConnectionLost
+                });
+            };
+
+            let connection = link.connection.as_ref();
+            let report = tokio::select! {
+                report = self.transfer_peer(&mut link.peer, connection, link.route, &mut staged) => report,
+                () = channel::cancelled(self.channel.as_ref()) => {
+                    link.cancel().await;
+                    return Err(error::Receive::Cancelled);
+                }
+            };
+
+            match report {
+                Err(e) if e.is_connection_lost() && reconnects < RECONNECTS => {
+                    reconnects += 1;
+                    log::warn!("Connection lost, reconnecting ({reconnects}/{RECONNECTS})");
+
+                    channel::send(self.channel.as_ref(), Message::Reconnecting).await;
+                }
+                report => {
+                    let report = report?;
+                    link.finish(self.pair_info).await;
+
+                    return Ok(report);
+                }
+            }
+        }
+    }
+
+    /// Writes a piped transfer into the writer, for example standard output, instead of
+    /// the destination. Fails if the peer sends a path instead.
+    pub async fn transfer_into<W>(mut self, writer: W) -> Result<TransferReport, error::Receive>
+    where
+        W: AsyncWrite + Unpin + Send,
+    {
+        let Some(mut link) = self.connect().await? else {
+            return Err(error::Receive::NoRelay);
+        };
+
+        let report = tokio::select! {
+            report = self.pipe_peer(&mut link.peer, writer, link.route) => report?,
+            () = channel::cancelled(self.channel.as_ref()) => {
+                link.cancel().await;
+                return Err(error::Receive::Cancelled);
+            }
+        };
+
+        link.finish(self.pair_info).await;
+
+        Ok(report)
+    }
+
+    /// Checks the copy at the destination against the hashes sent by the peer, see
+    /// [`AportureTransferProtocol::<Sender>::verify`]. The destination is the copy itself or
+    /// the folder it was saved in.
+    pub async fn verify(mut self) -> Result<VerifyReport, error::Receive> {
+        let Some(mut link) = self.connect().await? else {
+            return Err(error::Receive::NoRelay);
+        };
+
+        let report = tokio::select! {
+            report = self.verify_peer(&mut link.peer, link.route) => report?,
+            () = channel::cancelled(self.channel.as_ref()) => {
+                link.cancel().await;
+                return Err(error::Receive::Cancelled);
+            }
+        };
+
+        link.finish(self.pair_info).await;
+
+        Ok(report)
+    }
+
+    async fn verify_peer<Ep>(
+        &self,
+        mut peer: Ep,
+        route: Route,
+    ) -> Result<VerifyReport, error::Receive>
+    where
+        Ep: Encryptable + Peer + Send,
+    {
+        let dest = destination(self.path)?;
+
+        self.start_rekey(&mut peer);
+
+        let transfer_data = peer.read_ser_enc::<TransferData>().await?;
+        log::info!("Verification data received: {transfer_data:?}");
+
+        if !transfer_data.verify_only {
+            let _ = peer
+                .write_ser_enc(&TransferResponseCode::TransferFail)
+                .await;
+
+            return Err(error::Receive::NotVerifying);
+        }
+
+        let root_name = transfer_data.root_name.as_str();
+        let root = if dest.file_name().is_some_and(|name| name == root_name) {
+            dest
+        } else {
+            let root = path::received(&dest, root_name).ok_or(error::Receive::Destination)?;
+
+            PathBuf::from(root.as_str())
+        };
+
+        log::info!("Verifying {} against the peer", root.display());
+
+        let channel = self.channel.as_ref();
+
+        #[allow(clippy::cast_possible_truncation)]
+        let progress_len = transfer_data.total_size as usize;
+        channel::send(channel, Message::ProgressSize(progress_len)).await;
+
+        let result = check::receive(&mut peer, &root, &transfer_data, channel).await?;
+
+        peer.write_ser_enc(&TransferResponseCode::Ok).await?;
+        peer.write_ser_enc(&result).await?;
+
+        channel::send(channel, Message::Finished).await;
+
+        Ok(VerifyReport {
+            path: root,
+            total_files: transfer_data.total_files + transfer_data.total_links,
+            mismatched: result.mismatched,
+            missing: result.missing,
+            route,
+        })
+    }
+
+    async fn pipe_peer<Ep, W>(
+        &self,
+        mut peer: Ep,
+        writer: W,
+        route: Route,
+    ) -> Result<TransferReport, error::Receive>
+    where
+        Ep: Encryptable + Peer + Send,
+        W: AsyncWrite + Unpin + Send,
+    {
+        self.start_rekey(&mut peer);
+
+        log::info!("Receiving Transfer information");
+        let transfer_data = peer.read_ser_enc::<TransferData>().await?;
+        log::info!("Transfer data received: {transfer_data:?}");
+
+        if !transfer_data.piped {
+            let _ = peer
+                .write_ser_enc(&TransferResponseCode::TransferFail)
+                .await;
+
+            return Err(error::Receive::NotPiped);
+        }
+
+        let channel = self.channel.as_ref();
+        let mut manifest = Manifest::default();
+
+        let stall = self.options.timeouts().stall;
+        let (total_size, valid) =
+            pipe::receive(&mut peer, writer, &mut manifest, (stall, channel)).await?;
+
+        if !valid {
+            peer.write_ser_enc(&TransferResponseCode::TransferFail)
+                .await?;
+
+            return Err(error::Receive::HashMismatch);
+        }
+
+        verify(&mut peer, &manifest, true).await?;
+
+        channel::send(channel, Message::Finished).await;
+
+        peer.write_ser_enc(&TransferResponseCode::Ok).await?;
+
+        Ok(TransferReport {
+            destination: self.path.to_owned(),
+            total_files: transfer_data.total_files,
+            total_size,
+            skipped: Vec::new(),
+            root: manifest.root(),
+            verified: true,
+            kept_existing: false,
+            unchanged: 0,
+            route,
+        })
+    }
+
+    async fn transfer_peer<Ep>(
+        &self,
+        mut peer: Ep,
+        connection: Option<&QuicConnection>,
+        route: Route,
+        staged: &mut Option<Staged>,
+    ) -> Result<TransferReport, error::Receive>
+    where
+        Ep: Encryptable + Peer + Send,
+    {
+        let dest = destination(self.path)?;
+
+        self.start_rekey(&mut peer);
+
+        log::info!("File will try to be saved to {}", dest.display());
+
+        log::info!("Receiving Transfer information");
+        let transfer_data = peer.read_ser_enc::<TransferData>().await?;
+        log::info!("Transfer data received: {transfer_data:?}");
+
+        if transfer_data.verify_only {
+            let _ = peer
+                .write_ser_enc(&TransferResponseCode::TransferFail)
+                .await;
+
+            return Err(error::Receive::Verifying);
+        }
+
+        #[allow(clippy::cast_possible_truncation)]
+        let progress_len = transfer_data.total_size as usize;
+        channel::send(self.channel.as_ref(), Message::ProgressSize(progress_len)).await;
+
+        let streams = transfer_data.streams;
+        if streams > MAX_STREAMS || streams > 0 && connection.is_none() {
+            let _ = peer
+                .write_ser_enc(&TransferResponseCode::TransferFail)
+                .await;
+
+            return Err(crate::io::Error::UnexpectedMessage.into());
+        }
+
+        let options = &self.options;
+        let channel = self.channel.as_ref();
+
+        // NOTE: A folder left by a lost connection only resumes the same transfer
+        if staged
+            .as_ref()
+            .is_some_and(|s| !transfer_data.mergeable || s.root_name != transfer_data.root_name)
+        {
+            *staged = None;
+        }
+
+        if transfer_data.mergeable {
+            // NOTE: An archive is always written whole, so there is nothing to merge into
+            let root = match staged {
+                Some(staged) => Some(staged.dir.path().to_owned()),
+                None => merge_root(&dest, &transfer_data, options.on_conflict)
+                    .filter(|_| options.archive.is_none()),
+            };
+
+            if let Some(root) = root {
+                peer.write_ser_enc(&TransferResponseCode::Merge).await?;
+
+                let data = transfer_data;
+                return receive_merge(root, data, &mut peer, options, route, channel, staged)
+                    .await
+                    .map_err(|e| e.check_destination(&dest));
+            }
+
+            peer.write_ser_enc(&TransferResponseCode::Ok).await?;
+        }
+
+        let mut lanes = open_lanes(connection, streams).await?;
+        for lane in &mut lanes {
+            self.prepare_lane(lane);
+        }
+
+        let base = if dest.is_dir() {
+            dest.clone()
+        } else {
+            dest.parent()
+                .expect("Parent must exist as path is sanitized")
+                .to_owned()
+        };
+
+        let report = if transfer_data.compressed {
+            receive_archive(dest, &transfer_data, &mut peer, options, route, channel).await
+        } else if transfer_data.total_dirs == 0 && transfer_data.total_files == 1 {
+            let data = &transfer_data;
+            receive_file(dest, data, &mut peer, options, route, channel).await
+        } else {
+            let data = transfer_data;
+            receive_folder(
+                dest,
+                data,
+                &mut peer,
+                lanes,
+                options,
+                (route, channel),
+                staged,
+            )
+            .await
+        };
+
+        report.map_err(|e| e.check_destination(&base))
+    }
+}
+
+/// Skips the application cipher on the direct connection if both peers agreed to it.
+fn plain(peer: &mut QuicNetworkPeer, pair_info: &PairInfo) {
+    if pair_info.insecure_lan() {
+        log::info!("Sending data without the application cipher, only QUIC encrypts it");
+        peer.set_plain(true);
+    }
+}
+
+/// Resolves where to save the transfer, an existing folder or the exact path of the result.
+fn destination(path: &Path) -> Result<PathBuf, error::Receive> {
+    path::sanitize(path).map_err(|_| match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !parent.exists() => {
+            error::Receive::MissingParent(path.to_owned())
+        }
+        _ => error::Receive::Destination,
+    })
+}
+
+fn get_transfer_data(path: &Path, filter: &Filter) -> Result<TransferData, error::Send> {
+    let mut transfer_data = ignore::walk(path, filter).try_fold(
+        TransferData::default(),
+        |mut data, entry| -> Result<TransferData, error::Send> {
+            let entry = entry?;
+
+            // NOTE: Rejected before anything is sent, instead of in the middle of the transfer
+            if entry.path().to_str().is_none() {
+                return Err(error::Send::NonUtf8Name(entry.into_path()));
+            }
+
+            // NOTE: Links are only reported as such when they are preserved
+            let metadata = entry.metadata()?;
+
+            if metadata.is_file() {
+                let file_length = metadata.len();
+
+                data.total_files += 1;
+                data.total_size += file_length;
+            } else if metadata.is_dir() {
+                data.total_dirs += 1;
+            } else if metadata.is_symlink() {
+                data.total_links += 1;
+            }
+            Ok(data)
+        },
+    )?;
+
+    path.file_name()
+        .expect("Should have a File Name as path it was sanitized")
+        .to_str()
+        .expect("Should be valid utf8 as it was santized")
+        .clone_into(&mut transfer_data.root_name);
+
+    Ok(transfer_data)
+}
+
+/// Sends every entry of the path, skipping the root folder as the receiver creates it.
+async fn send_files<Ep>(
+    peer: &mut Ep,
+    path: &Path,
+    filter: &Filter,
+    stream_hash: bool,
+    manifest: &mut Manifest,
+    (chunk_size, channel): (usize, Option<&Channel>),
+) -> Result<(), error::Send>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let is_dir = path.is_dir();
+
+    let mut stream = stream_hash.then(Hasher::default);
+
+    for (id, entry) in ignore::walk(path, filter)
+        .enumerate()
+        .filter(|(id, _)| !is_dir || *id != 0)
+    {
+        let entry = entry?;
+
+        let progress = (chunk_size, channel);
+        file::send(peer, id, &entry, path, stream.as_mut(), manifest, progress).await?;
+    }
+
+    if let Some(hasher) = stream {
+        peer.write_ser_enc(&Hash(hasher.finalize())).await?;
+    }
+
+    Ok(())
+}
+
+/// Sends the directories and links of the folder first, so they exist before any file,
+/// and spreads its files over the lanes balancing their size.
+async fn send_lanes<Ep>(
+    peer: &mut Ep,
+    lanes: Vec<QuicNetworkPeer>,
+    path: &Path,
+    filter: &Filter,
+    manifest: &mut Manifest,
+    (chunk_size, channel): (usize, Option<&Channel>),
+) -> Result<(), error::Send>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let base = path.to_owned();
+
+    let mut files: Vec<(Vec<_>, u64)> = lanes.iter().map(|_| (Vec::new(), 0)).collect();
+
+    // NOTE: The root folder is not sent
+    for (id, entry) in ignore::walk(path, filter).enumerate().skip(1) {
+        let entry = entry?;
+
+        if entry.file_type().is_symlink() || !entry.path().is_file() {
+            file::send(
+                peer,
+                id,
+                &entry,
+                path,
+                None,
+                manifest,
+                (chunk_size, channel),
+            )
+            .await?;
+            continue;
+        }
+
+        let (entries, size) = files
+            .iter_mut()
+            .min_by_key(|(_, size)| *size)
+            .expect("There is at least one lane");
+
+        *size += entry.metadata()?.len();
+        entries.push((id, entry));
+    }
+
+    let mut set = JoinSet::new();
+
+    for (mut lane, (entries, total_size)) in lanes.into_iter().zip(files) {
+        let (base, channel) = (base.clone(), channel.cloned());
+
+        set.spawn(async move {
+            let lane_data = TransferData {
+                total_files: entries.len() as u64,
+                total_size,
+                ..Default::default()
+            };
+            lane.write_ser_enc(&lane_data).await?;
+
+            let mut manifest = Manifest::default();
+            let progress = (chunk_size, channel.as_ref());
+
+            for (id, entry) in entries {
+                file::send(&mut lane, id, &entry, &base, None, &mut manifest, progress).await?;
+            }
+
+            Ok::<_, error::Send>(manifest)
+        });
+    }
+
+    while let Some(sent) = set.join_next().await {
+        manifest.extend(sent.expect("Task was aborted")?);
+    }
+
+    Ok(())
+}
+
+/// Opens the extra streams of the connection the transfer is spread over, greeting the peer
+/// on each as a stream only reaches the peer once the side that opened it writes.
+async fn open_lanes(
+    connection: Option<&QuicConnection>,
+    count: u64,
+) -> Result<Vec<QuicNetworkPeer>, crate::io::Error> {
+    let mut lanes = Vec::new();
+
+    let Some(connection) = connection else {
+        return Ok(lanes);
+    };
+
+    for index in 0..count {
+        let mut lane = connection.new_stream().await?;
+
+        lane.write_ser_enc(&StreamHello { index }).await?;
+
+        if lane.read_ser_enc::<StreamHello>().await?.index != index {
+            return Err(crate::io::Error::UnexpectedMessage);
+        }
+
+        lanes.push(lane);
+    }
+
+    Ok(lanes)
+}
+
+/// Answers the receiver, sending again the files it requests until it accepts the transfer.
+/// Returns whether the receiver computed the same manifest root.
+async fn respond<Ep>(
+    peer: &mut Ep,
+    path: &Path,
+    filter: &Filter,
+    stream_hash: bool,
+    manifest: &mut Manifest,
+    (chunk_size, channel): (usize, Option<&Channel>),
+) -> Result<bool, error::Send>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let mut verified = false;
+
+    loop {
+        let res = peer.read_ser_enc::<TransferResponseCode>().await?;
+
+        match res {
+            TransferResponseCode::Ok => return Ok(verified),
+            TransferResponseCode::HashMismatch if stream_hash => {
+                log::info!("Transfer hash mismatch, sending every file again");
+
+                let progress = (chunk_size, channel);
+                send_files(peer, path, filter, true, manifest, progress).await?;
+            }
+            TransferResponseCode::HashMismatch => {
+                let res = peer.read_ser_enc::<FileData>().await?;
+
+                #[allow(clippy::cast_possible_truncation)]
+                let id = res.id as usize;
+
+                let Some(entry) = ignore::walk(path, filter).nth(id) else {
+                    return Err(error::Send::HashMismatch);
+                };
+
+                let entry = entry?;
+
+                file::send(
+                    peer,
+                    id,
+                    &entry,
+                    path,
+                    None,
+                    manifest,
+                    (chunk_size, channel),
+                )
+                .await?;
+            }
+            TransferResponseCode::Verify => verified = answer(peer, manifest).await?,
+            TransferResponseCode::TransferFail => return Err(error::Send::PeerFailed),
+            TransferResponseCode::Merge => return Err(crate::io::Error::UnexpectedMessage.into()),
+        }
+    }
+}
+
+/// Sends every entry of the archive, or only the one with the id, reading the contents of
+/// the files in a single pass over it.
+async fn send_unpacked<Ep>(
+    peer: &mut Ep,
+    source: &archive::Source,
+    only: Option<usize>,
+    mut stream: Option<&mut Hasher>,
+    manifest: &mut Manifest,
+    progress: (usize, Option<&Channel>),
+) -> Result<(), error::Send>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let (mut reader, reading) = source.contents(only);
+    let ids = only.map_or(0..source.len(), |id| id..id + 1);
+
+    for id in ids {
+        let file_data = source.file_data(id).ok_or(error::Send::HashMismatch)?;
+
+        let mut contents = file_data
+            .is_file
+            .then(|| (&mut reader).take(file_data.file_size));
+
+        let stream = stream.as_deref_mut();
+        file::send_entry(
+            peer,
+            file_data,
+            contents.as_mut(),
+            stream,
+            manifest,
+            progress,
+        )
+        .await?;
+
+        // NOTE: A failed read ends the contents early, so its error is the cause
+        if contents.is_some_and(|c| c.limit() > 0) {
+            reading.await.expect("Task was aborted")?;
+
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+
+    reading.await.expect("Task was aborted")?;
+
+    Ok(())
+}
+
+/// Answers the receiver like [`respond`], sending again the entries of the archive it requests.
+async fn respond_unpacked<Ep>(
+    peer: &mut Ep,
+    source: &archive::Source,
+    stream_hash: bool,
+    manifest: &mut Manifest,
+    progress: (usize, Option<&Channel>),
+) -> Result<bool, error::Send>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let mut verified = false;
+
+    loop {
+        match peer.read_ser_enc::<TransferResponseCode>().await? {
+            TransferResponseCode::Ok => return Ok(verified),
+            TransferResponseCode::HashMismatch if stream_hash => {
+                log::info!("Transfer hash mismatch, sending every entry again");
+
+                let mut hasher = Hasher::default();
+                send_unpacked(peer, source, None, Some(&mut hasher), manifest, progress).await?;
+
+                peer.write_ser_enc(&Hash(hasher.finalize())).await?;
+            }
+            TransferResponseCode::HashMismatch => {
+                let res = peer.read_ser_enc::<FileData>().await?;
+
+                #[allow(clippy::cast_possible_truncation)]
+                let id = res.id as usize;
+
+                send_unpacked(peer, source, Some(id), None, manifest, progress).await?;
+            }
+            TransferResponseCode::Verify => verified = answer(peer, manifest).await?,
+            TransferResponseCode::TransferFail => return Err(error::Send::PeerFailed),
+            TransferResponseCode::Merge => return Err(crate::io::Error::UnexpectedMessage.into()),
+        }
+    }
+}
+
+/// Waits for the receiver to accept a piped transfer, which can not be sent again,
+/// returning whether it was verified.
+async fn confirm<Ep>(peer: &mut Ep, manifest: &Manifest) -> Result<bool, error::Send>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let mut verified = false;
+
+    loop {
+        match peer.read_ser_enc::<TransferResponseCode>().await? {
+            TransferResponseCode::Ok => return Ok(verified),
+            TransferResponseCode::Verify => verified = answer(peer, manifest).await?,
+            TransferResponseCode::HashMismatch => return Err(error::Send::HashMismatch),
+            TransferResponseCode::TransferFail => return Err(error::Send::PeerFailed),
+            TransferResponseCode::Merge => return Err(crate::io::Error::UnexpectedMessage.into()),
+        }
+    }
+}
+
+/// Answers the manifest root sent by the receiver with ours, returning whether they match.
+async fn answer<Ep>(peer: &mut Ep, manifest: &Manifest) -> Result<bool, error::Send>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let Hash(received) = peer.read_ser_enc::<Hash>().await?;
+
+    let root = manifest.root();
+    peer.write_ser_enc(&Hash(root.0)).await?;
+
+    if received == root.0 {
+        log::info!("Transfer verified with manifest root {root}");
+    } else {
+        log::warn!("Receiver manifest root does not match {root}");
+    }
+
+    Ok(received == root.0)
+}
+
+/// Exchanges the manifest roots with the sender, returning whether they match.
+/// If strict a mismatch fails the transfer.
+async fn verify<Ep>(
+    peer: &mut Ep,
+    manifest: &Manifest,
+    strict: bool,
+) -> Result<bool, error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let root = manifest.root();
+
+    peer.write_ser_enc(&TransferResponseCode::Verify).await?;
+    peer.write_ser_enc(&Hash(root.0)).await?;
+
+    let Hash(sent) = peer.read_ser_enc::<Hash>().await?;
+
+    if sent == root.0 {
+        log::info!("Transfer verified with manifest root {root}");
+
+        return Ok(true);
+    }
+
+    log::warn!("Sender manifest root does not match {root}");
+
+    if strict {
+        peer.write_ser_enc(&TransferResponseCode::TransferFail)
+            .await?;
+
+        return Err(error::Receive::ManifestMismatch);
+    }
+
+    Ok(false)
+}
+
+async fn receive_file<Ep>(
+    mut dest: PathBuf,
+    transfer_data: &TransferData,
+    peer: &mut Ep,
+    options: &TransferOptions<Receiver>,
+    route: Route,
+    channel: Option<&Channel>,
+) -> Result<TransferReport, error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let base = if dest.is_dir() {
+        dest.as_path()
+    } else {
+        dest.parent()
+            .expect("Parent must exist as path is sanitized")
+    };
+
+    let file = tempfile::NamedTempFile::new_in(base)?;
+
+    let mut manifest = Manifest::default();
+    let temp = file.path();
+
+    let total_size =
+        receive_single(temp, transfer_data, peer, options, &mut manifest, channel).await?;
+
+    channel::send(channel, Message::Finished).await;
+
+    let root_name = &transfer_data.root_name;
+
+    let (dest, kept_existing) = if let Some(format) = options.archive {
+        let sink = archive::Sink::new(format, base, "")?;
+        sink.commit(temp, root_name).await?;
+
+        place_archive(sink, dest, root_name, options.on_conflict).await?
+    } else {
+        if dest.is_dir() {
+            let path = path::received(&dest, root_name).ok_or(error::Receive::Destination)?;
+
+            dest = PathBuf::from(path.as_str());
+        }
+
+        if let Some(target) = path::target(&dest, options.on_conflict).await {
+            log::info!("Persisting file to path {}", target.display());
+
+            // NOTE: Persisting renames the file, so an existing one is replaced atomically
+            file.persist(&target)
+                .map_err(|_| error::Receive::Destination)?;
+
+            (target, false)
+        } else {
+            log::info!(
+                "Keeping existing {}, discarding received file",
+                dest.display()
+            );
+
+            (dest, true)
+        }
+    };
+
+    peer.write_ser_enc(&TransferResponseCode::Ok).await?;
+
+    Ok(TransferReport {
+        destination: dest,
+        total_files: transfer_data.total_files,
+        total_size,
+        skipped: Vec::new(),
+        root: manifest.root(),
+        verified: true,
+        kept_existing,
+        unchanged: 0,
+        route,
+    })
+}
+
+/// Receives a single file into the path and verifies it with the sender, returning its size.
+async fn receive_single<Ep>(
+    temp: &Path,
+    transfer_data: &TransferData,
+    peer: &mut Ep,
+    options: &TransferOptions<Receiver>,
+    manifest: &mut Manifest,
+    channel: Option<&Channel>,
+) -> Result<u64, error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let timeout = options.timeouts();
+    let mut total_size = transfer_data.total_size;
+
+    let verified = if transfer_data.piped {
+        let writer = tokio::fs::OpenOptions::new().write(true).open(temp).await?;
+
+        let (size, valid) = pipe::receive(peer, writer, manifest, (timeout.stall, channel)).await?;
+        total_size = size;
+
+        valid
+    } else if transfer_data.stream_hash {
+        receive_stream(temp, transfer_data, peer, options, manifest, channel).await?
+    } else {
+        let (data, retry) = file::receive(temp, peer, None, manifest, timeout, channel).await?;
+
+        !retry || retry_file(temp, &data, peer, options, manifest, channel).await?
+    };
+
+    if !verified {
+        peer.write_ser_enc(&TransferResponseCode::TransferFail)
+            .await?;
+
+        return Err(error::Receive::HashMismatch);
+    }
+
+    verify(peer, manifest, true).await?;
+
+    Ok(total_size)
+}
+
+/// Receives a folder packed in a single archive, unpacking it while it arrives, and places
+/// it where [`receive_folder`] would.
+async fn receive_archive<Ep>(
+    dest: PathBuf,
+    transfer_data: &TransferData,
+    peer: &mut Ep,
+    options: &TransferOptions<Receiver>,
+    route: Route,
+    channel: Option<&Channel>,
+) -> Result<TransferReport, error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let base = if dest.is_dir() {
+        dest.clone()
+    } else {
+        dest.parent()
+            .expect("Parent must exist as path is sanitized")
+            .to_owned()
+    };
+
+    let dir = tempfile::tempdir_in(&base)?;
+    let mut manifest = Manifest::default();
+
+    let stall = options.timeouts().stall;
+    let valid = match deflate::receive(peer, dir.path(), &mut manifest, (stall, channel)).await {
+        Ok(valid) => valid,
+        Err(e) => {
+            let _ = peer
+                .write_ser_enc(&TransferResponseCode::TransferFail)
+                .await;
+
+            return Err(e);
+        }
+    };
+
+    if !valid {
+        peer.write_ser_enc(&TransferResponseCode::TransferFail)
+            .await?;
+
+        return Err(error::Receive::HashMismatch);
+    }
+
+    verify(peer, &manifest, true).await?;
+
+    let total_files = path::count_entries(dir.path()).await?;
+
+    channel::send(channel, Message::Finished).await;
+
+    let root_name = &transfer_data.root_name;
+
+    let (dest, kept_existing) = if let Some(format) = options.archive {
+        let sink = archive::Sink::new(format, &base, root_name)?;
+        sink.commit_all(dir.path()).await?;
+
+        place_archive(sink, dest, root_name, options.on_conflict).await?
+    } else {
+        place_folder(dir, dest, root_name, options.on_conflict).await?
+    };
+
+    peer.write_ser_enc(&TransferResponseCode::Ok).await?;
+
+    Ok(TransferReport {
+        destination: dest,
+        total_files,
+        total_size: transfer_data.total_size,
+        skipped: Vec::new(),
+        root: manifest.root(),
+        verified: true,
+        kept_existing,
+        unchanged: 0,
+        route,
+    })
+}
+
+/// Folder received until the connection was lost, so the transfer resumes into it.
+struct Staged {
+    root_name: String,
+    /// Destination the folder is placed at, as given to [`receive_folder`]
+    dest: PathBuf,
+    dir: tempfile::TempDir,
+}
+
+/// Folder the received folder is staged in, next to where it will be placed.
+async fn folder_base<'a, Ep>(
+    dest: &'a Path,
+    peer: &mut Ep,
+    options: &TransferOptions<Receiver>,
+) -> Result<&'a Path, error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let parent = dest
+        .parent()
+        .expect("Parent must exist as path is sanitized");
+
+    // NOTE: An archive replaces the file according to the conflict policy
+    if dest.is_file() && options.archive.is_none() {
+        let _ = peer
+            .write_ser_enc(&TransferResponseCode::TransferFail)
+            .await;
+
+        return Err(error::Receive::FolderIntoFile(dest.to_owned()));
+    }
+
+    if !parent.exists() {
+        return Err(error::Receive::Destination);
+    }
+
+    if dest.is_file() {
+        Ok(parent)
+    } else if tokio::fs::try_exists(dest)
+        .await
+        .map_err(|_| error::Receive::Destination)?
+    {
+        Ok(dest)
+    } else {
+        Ok(parent)
+    }
+}
+
+/// Receives a folder into a staging folder, placing it at the destination once verified.
+/// If the connection is lost the staging folder is kept, so the transfer resumes into it.
+async fn receive_folder<Ep>(
+    dest: PathBuf,
+    transfer_data: TransferData,
+    peer: &mut Ep,
+    lanes: Vec<QuicNetworkPeer>,
+    options: &TransferOptions<Receiver>,
+    (route, channel): (Route, Option<&Channel>),
+    staged: &mut Option<Staged>,
+) -> Result<TransferReport, error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let timeout = options.timeouts();
+
+    let base_path = folder_base(&dest, peer, options).await?;
+
+    let dir = tempfile::tempdir_in(base_path)?;
+    let staging = dir.path();
+
+    let root_name = &transfer_data.root_name;
+    let sink = match options.archive {
+        Some(format) => Some(archive::Sink::new(format, base_path, root_name)?),
+        None => None,
+    };
+
+    let mut manifest = Manifest::default();
+
+    let data = &transfer_data;
+
+    let received = async {
+        let retries = if transfer_data.stream_hash {
+            if !receive_stream(staging, data, peer, options, &mut manifest, channel).await? {
+                peer.write_ser_enc(&TransferResponseCode::TransferFail)
+                    .await?;
+                return Err(error::Receive::HashMismatch);
+            }
+
+            Vec::new()
+        } else if lanes.is_empty() {
+            let commit = (timeout, sink.as_ref());
+            receive_files(staging, data, peer, None, &mut manifest, commit, channel).await?
+        } else {
+            // NOTE: Directories and links come first on this stream, so files find their parents
+            let dirs = TransferData {
+                total_dirs: data.total_dirs,
+                total_links: data.total_links,
+                ..Default::default()
+            };
+
+            let commit = (timeout, sink.as_ref());
+            receive_files(staging, &dirs, peer, None, &mut manifest, commit, channel).await?;
+
+            receive_lanes(staging, lanes, &mut manifest, commit, channel).await?
+        };
+
+        // NOTE: Files committed to the archive are no longer in the staging folder
+        let received =
+            path::count_entries(staging).await? + sink.as_ref().map_or(0, archive::Sink::committed);
+        let expected = transfer_data.total_files + transfer_data.total_links;
+        if received != expected {
+            log::warn!("Received files do not match the transfer information");
+
+            let _ = peer
+                .write_ser_enc(&TransferResponseCode::TransferFail)
+                .await;
+
+            return Err(error::Receive::FileCount { expected, received });
+        }
+
+        let skipped = retry_files(
+            staging,
+            retries,
+            peer,
+            options,
+            sink.as_ref(),
+            &mut manifest,
+            channel,
+        )
+        .await?;
+
+        // NOTE: Skipped files are missing from the manifest, so the roots can only match without them
+        let verified = verify(peer, &manifest, skipped.is_empty()).await?;
+
+        Ok::<_, error::Receive>((skipped, verified))
+    }
+    .await;
+
+    let (skipped, verified) = match received {
+        // NOTE: Entries committed to an archive are gone, so only a plain folder resumes
+        Err(e) if e.is_connection_lost() && sink.is_none() => {
+            log::info!("Keeping what arrived of {root_name} to resume the transfer");
+
+            *staged = Some(Staged {
+                root_name: root_name.clone(),
+                dest,
+                dir,
+            });
+
+            return Err(e);
+        }
+        received => received?,
+    };
+
+    channel::send(channel, Message::Finished).await;
+
+    let (dest, kept_existing) = if let Some(sink) = sink {
+        sink.commit_all(staging).await?;
+
+        place_archive(sink, dest, root_name, options.on_conflict).await?
+    } else {
+        place_folder(dir, dest, root_name, options.on_conflict).await?
+    };
+
+    peer.write_ser_enc(&TransferResponseCode::Ok).await?;
+
+    Ok(TransferReport {
+        destination: dest,
+        total_files: transfer_data.total_files,
+        total_size: transfer_data.total_size,
+        skipped,
+        root: manifest.root(),
+        verified,
+        kept_existing,
+        unchanged: 0,
+        route,
+    })
+}
+
+/// Requests again the files whose hash did not match, returning the names of those skipped.
+/// Files that match are committed to the sink if any.
+async fn retry_files<Ep>(
+    dest: &Path,
+    retries: Vec<FileData>,
+    peer: &mut Ep,
+    options: &TransferOptions<Receiver>,
+    sink: Option<&archive::Sink>,
+    manifest: &mut Manifest,
+    channel: Option<&Channel>,
+) -> Result<Vec<String>, error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let mut skipped = Vec::new();
+
+    for data in retries {
+        if retry_file(dest, &data, peer, options, manifest, channel).await? {
+            if let Some(sink) = sink {
+                commit(sink, dest, &data).await?;
+            }
+
+            continue;
+        }
+
+        if !options.retry_policy.skip_failed {
+            peer.write_ser_enc(&TransferResponseCode::TransferFail)
+                .await?;
+            return Err(error::Receive::HashMismatch);
+        }
+
+        log::warn!("Skipping file {} as its hash did not match", data.file_name);
+
+        let path = path::received(dest, &data.file_name).ok_or(error::Receive::Destination)?;
+        tokio::fs::remove_file(path).await?;
+
+        manifest.remove(&data.file_name);
+        skipped.push(data.file_name);
+    }
+
+    Ok(skipped)
+}
+
+/// Existing copy of the folder to merge the transfer into, if the conflict policy asks for it.
+fn merge_root(
+    dest: &Path,
+    transfer_data: &TransferData,
+    on_conflict: OnConflict,
+) -> Option<PathBuf> {
+    if on_conflict != OnConflict::Merge || !dest.is_dir() {
+        return None;
+    }
+
+    let root = path::received(dest, &transfer_data.root_name)?;
+    let root = PathBuf::from(root.as_str());
+
+    // NOTE: A link to a folder is not merged, as writes would land outside the destination
+    let is_dir = std::fs::symlink_metadata(&root).is_ok_and(|m| m.is_dir());
+
+    is_dir.then_some(root)
+}
+
+/// Merges the folder into the existing copy, only receiving the entries that differ from it.
+/// Received files are written in place, replacing the copy.
+///
+/// A staged folder is the copy when resuming a transfer, placed once it is complete as
+/// [`receive_folder`] would. It stays staged if the connection is lost again.
+async fn receive_merge<Ep>(
+    root: PathBuf,
+    transfer_data: TransferData,
+    peer: &mut Ep,
+    options: &TransferOptions<Receiver>,
+    route: Route,
+    channel: Option<&Channel>,
+    staged: &mut Option<Staged>,
+) -> Result<TransferReport, error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    log::info!("Merging the transfer into {}", root.display());
+
+    let timeout = options.timeouts();
+    let mut manifest = Manifest::default();
+
+    let changed = merge::compare(peer, &root, &transfer_data, &mut manifest).await?;
+    let unchanged = (transfer_data.total_files + transfer_data.total_links)
+        .saturating_sub(changed.len() as u64);
+
+    merge::request(peer, &root, &changed).await?;
+
+    let total_size = changed.iter().map(|f| f.file_size).sum();
+
+    #[allow(clippy::cast_possible_truncation)]
+    channel::send(channel, Message::ProgressSize(total_size as usize)).await;
+
+    let mut retries = Vec::new();
+    for _ in &changed {
+        let (file_data, failed) =
+            file::receive(&root, peer, None, &mut manifest, timeout, channel).await?;
+
+        if failed {
+            retries.push(file_data);
+        }
+    }
+
+    let skipped = retry_files(&root, retries, peer, options, None, &mut manifest, channel).await?;
+
+    let verified = verify(peer, &manifest, skipped.is_empty()).await?;
+
+    channel::send(channel, Message::Finished).await;
+
+    let (destination, kept_existing) = match staged.take() {
+        Some(Staged {
+            root_name,
+            dest,
+            dir,
+        }) => place_folder(dir, dest, &root_name, options.on_conflict).await?,
+        None => (root, false),
+    };
+
+    peer.write_ser_enc(&TransferResponseCode::Ok).await?;
+
+    Ok(TransferReport {
+        destination,
+        total_files: transfer_data.total_files,
+        total_size,
+        skipped,
+        root: manifest.root(),
+        verified,
+        kept_existing,
+        unchanged,
+        route,
+    })
+}
+
+/// Moves the received folder to the destination according to the conflict policy, inside it
+/// named after the root if it is a folder. Returns where it is and whether an existing one
+/// was kept instead.
+async fn place_folder(
+    dir: tempfile::TempDir,
+    dest: PathBuf,
+    root_name: &str,
+    on_conflict: OnConflict,
+) -> Result<(PathBuf, bool), error::Receive> {
+    let dest = if dest.is_dir() {
+        let path = path::received(&dest, root_name).ok_or(error::Receive::Destination)?;
+
+        PathBuf::from(path.as_str())
+    } else {
+        dest
+    };
+
+    let Some(target) = path::target(&dest, on_conflict).await else {
+        log::info!(
+            "Keeping existing {}, discarding received folder",
+            dest.display()
+        );
+
+        return Ok((dest, true));
+    };
+
+    let tmp = dir.into_path();
+    if let Err(e) = path::replace(&tmp, &target).await {
+        let _ = tokio::fs::remove_dir_all(&tmp).await;
+        return Err(e.into());
+    }
+
+    Ok((target, false))
+}
+
+/// Saves the archive where the received entry would be placed, named after it with the extension
+/// of the format inside a folder. Returns where it is and whether an existing one was kept instead.
+async fn place_archive(
+    sink: archive::Sink,
+    dest: PathBuf,
+    root_name: &str,
+    on_conflict: OnConflict,
+) -> Result<(PathBuf, bool), error::Receive> {
+    let dest = if dest.is_dir() {
+        let name = format!("{root_name}.{}", sink.format());
+        let path = path::received(&dest, &name).ok_or(error::Receive::Destination)?;
+
+        PathBuf::from(path.as_str())
+    } else {
+        dest
+    };
+
+    let file = sink.finish().await?;
+
+    let Some(target) = path::target(&dest, on_conflict).await else {
+        log::info!(
+            "Keeping existing {}, discarding received archive",
+            dest.display()
+        );
+
+        return Ok((dest, true));
+    };
+
+    log::info!("Persisting archive to path {}", target.display());
+
+    file.persist(&target)
+        .map_err(|_| error::Receive::Destination)?;
+
+    Ok((target, false))
+}
+
+/// Moves a verified file from the staging folder into the archive.
+async fn commit(
+    sink: &archive::Sink,
+    dest: &Path,
+    file_data: &FileData,
+) -> Result<(), error::Receive> {
+    let path = path::received(dest, &file_data.file_name).ok_or(error::Receive::Destination)?;
+
+    sink.commit(Path::new(path.as_str()), &file_data.file_name)
+        .await
+        .map_err(Into::into)
+}
+
+/// Receives entries until the totals are reached, returning the files whose hash did not match.
+/// Files that match are committed to the sink if any, so it is not given with a stream hash.
+async fn receive_files<Ep>(
+    dest: &Path,
+    transfer_data: &TransferData,
+    peer: &mut Ep,
+    mut stream: Option<&mut Hasher>,
+    manifest: &mut Manifest,
+    (timeout, sink): (file::Timeouts, Option<&archive::Sink>),
+    channel: Option<&Channel>,
+) -> Result<Vec<FileData>, error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    // NOTE: The root folder is not sent
+    let total_dirs = transfer_data.total_dirs.saturating_sub(1);
+
+    let mut files = 0;
+    let mut dirs = 0;
+    let mut links = 0;
+
+    let mut retries = Vec::new();
+
+    while files < transfer_data.total_files
+        || dirs < total_dirs
+        || links < transfer_data.total_links
+    {
+        let stream = stream.as_deref_mut();
+        let received = file::receive(dest, peer, stream, manifest, timeout, channel).await;
+
+        let (file_data, retry) = match received {
+            Err(error::Receive::Timeout) => {
+                log::warn!("Received {files} of {} files", transfer_data.total_files);
+
+                let _ = peer
+                    .write_ser_enc(&TransferResponseCode::TransferFail)
+                    .await;
+
+                return Err(error::Receive::Timeout);
+            }
+            received => received?,
+        };
+
+        if file_data.is_file {
+            files += 1;
+        } else if file_data.link.is_some() {
+            links += 1;
+        } else {
+            dirs += 1;
+        }
+
+        if retry {
+            retries.push(file_data);
+        } else if let Some(sink) = sink.filter(|_| file_data.is_file) {
+            commit(sink, dest, &file_data).await?;
+        }
+    }
+
+    Ok(retries)
+}
+
+/// Receives the files spread over the lanes, returning those whose hash did not match.
+async fn receive_lanes(
+    dest: &Path,
+    lanes: Vec<QuicNetworkPeer>,
+    manifest: &mut Manifest,
+    (timeout, sink): (file::Timeouts, Option<&archive::Sink>),
+    channel: Option<&Channel>,
+) -> Result<Vec<FileData>, error::Receive> {
+    let mut set = JoinSet::new();
+
+    for mut lane in lanes {
+        let (dest, sink, channel) = (dest.to_owned(), sink.cloned(), channel.cloned());
+
+        set.spawn(async move {
+            let lane_data = lane.read_ser_enc::<TransferData>().await?;
+
+            let mut manifest = Manifest::default();
+            let (sink, channel) = (sink.as_ref(), channel.as_ref());
+
+            let retries = receive_files(
+                &dest,
+                &lane_data,
+                &mut lane,
+                None,
+                &mut manifest,
+                (timeout, sink),
+                channel,
+            )
+            .await?;
+
+            Ok::<_, error::Receive>((retries, manifest))
+        });
+    }
+
+    let mut retries = Vec::new();
+
+    while let Some(received) = set.join_next().await {
+        let (failed, lane_manifest) = received.expect("Task was aborted")?;
+
+        retries.extend(failed);
+        manifest.extend(lane_manifest);
+    }
+
+    Ok(retries)
+}
+
+/// Receives the whole transfer verifying it with a single hash.
+/// On mismatch the whole transfer is requested again up to the retries allowed by the policy.
+/// Returns whether an attempt arrived with a matching hash.
+async fn receive_stream<Ep>(
+    dest: &Path,
+    transfer_data: &TransferData,
+    peer: &mut Ep,
+    options: &TransferOptions<Receiver>,
+    manifest: &mut Manifest,
+    channel: Option<&Channel>,
+) -> Result<bool, error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let timeout = options.timeouts();
+
+    for attempt in 0..=options.retry_policy.retries {
+        if attempt > 0 {
+            log::info!("Retrying transfer, attempt {attempt}");
+
+            if dest.is_dir() {
+                path::clear(dest).await?;
+            }
+
+            peer.write_ser_enc(&TransferResponseCode::HashMismatch)
+                .await?;
+        }
+
+        let mut hasher = Hasher::default();
+
+        // NOTE: Nothing is committed until the whole attempt is verified
+        receive_files(
+            dest,
+            transfer_data,
+            peer,
+            Some(&mut hasher),
+            manifest,
+            (timeout, None),
+            channel,
+        )
+        .await?;
+
+        let Hash(received) = peer.read_ser_enc::<Hash>().await?;
+
+        if hasher.finalize() == received {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Requests a mismatched file again up to the retries allowed by the policy.
+/// Returns whether a retry arrived with a matching hash.
+async fn retry_file<Ep>(
+    dest: &Path,
+    data: &FileData,
+    peer: &mut Ep,
+    options: &TransferOptions<Receiver>,
+    manifest: &mut Manifest,
+    channel: Option<&Channel>,
+) -> Result<bool, error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+{
+    let timeout = options.timeouts();
+
+    for attempt in 1..=options.retry_policy.retries {
+        log::info!("Retrying file {}, attempt {attempt}", data.file_name);
+
+        peer.write_ser_enc(&TransferResponseCode::HashMismatch)
+            .await?;
+        peer.write_ser_enc(data).await?;
+
+        let (_, mismatch) = file::receive(dest, peer, None, manifest, timeout, channel).await?;
+
+        if !mismatch {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::crypto::cert::{Certificate, CertificateKey};
+    use crate::crypto::cipher::Cipher;
+    use crate::net::peer::send_cancel;
+    use crate::net::quic::Transport;
+    use crate::net::EncryptedNetworkPeer;
+    use crate::parser::{Parser, SerdeIO};
+
+    async fn peers() -> (EncryptedNetworkPeer, EncryptedNetworkPeer) {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .expect("Bind ephemeral port");
+        let address = listener.local_addr().expect("Bound address");
+
+        let (sender, receiver) =
+            tokio::join!(tokio::net::TcpStream::connect(address), listener.accept());
+
+        let cipher = Arc::new(Cipher::new(&[0; 32]));
+
+        (
+            EncryptedNetworkPeer::new(sender.expect("Connect"), cipher.clone()),
+            EncryptedNetworkPeer::new(receiver.expect("Accept").0, cipher),
+        )
+    }
+
+    async fn connections(transport: Transport) -> (QuicConnection, QuicConnection) {
+        let cipher = Arc::new(Cipher::new(&[0; 32]));
+        let certificate = CertificateKey::new(vec!["127.0.0.1".into()]).expect("Certificate");
+        let root = Certificate::from(certificate.cert_der());
+
+        let socket = || std::net::UdpSocket::bind(("127.0.0.1", 0)).expect("Bind ephemeral port");
+        let (server_socket, client_socket) = (socket(), socket());
+        let server_address = server_socket.local_addr().expect("Address");
+        let client_address = client_socket.local_addr().expect("Address");
+
+        let (server, client) = tokio::join!(
+            QuicConnection::server(
+                client_address,
+                server_socket,
+                cipher.clone(),
+                certificate,
+                tokio::spawn(async {}).into(),
+                transport,
+            ),
+            QuicConnection::client(
+                server_address,
+                client_socket,
+                cipher,
+                root,
+                tokio::spawn(async {}).into(),
+                transport,
+            ),
+        );
+
+        (server.expect("Server"), client.expect("Client"))
+    }
+
+    /// Sender peer that flips the first byte of the chunks of a file after it was hashed,
+    /// as if they were corrupted on the way, until it corrupted as many as the limit.
+    struct Corrupting {
+        peer: EncryptedNetworkPeer,
+        file: u64,
+        limit: usize,
+        corrupted: usize,
+    }
+
+    impl Corrupting {
+        const fn new(peer: EncryptedNetworkPeer, file: u64, limit: usize) -> Self {
+            Self {
+                peer,
+                file,
+                limit,
+                corrupted: 0,
+            }
+        }
+    }
+
+    impl SerdeIO for Corrupting {
+        async fn read_ser<P: Parser + Sync>(&mut self) -> Result<P, crate::io::Error> {
+            self.peer.read_ser().await
+        }
+
+        async fn write_ser<P: Parser + Sync>(&mut self, input: &P) -> Result<(), crate::io::Error> {
+            self.peer.write_ser(input).await
+        }
+    }
+
+    impl EncryptedSerdeIO for Corrupting {
+        async fn read_enc(&mut self, buffer: &mut [u8]) -> Result<usize, crate::io::Error> {
+            self.peer.read_enc(buffer).await
+        }
+
+        async fn read_enc_in(
+            &mut self,
+            buffer: &mut [u8],
+            domain: &[u8],
+        ) -> Result<usize, crate::io::Error> {
+            self.peer.read_enc_in(buffer, domain).await
+        }
+
+        async fn read_ser_enc<P: Parser + Sync>(&mut self) -> Result<P, crate::io::Error> {
+            self.peer.read_ser_enc().await
+        }
+
+        async fn write_enc(&mut self, input: &mut [u8]) -> Result<(), crate::io::Error> {
+            self.peer.write_enc(input).await
+        }
+
+        async fn write_enc_in(
+            &mut self,
+            input: &mut [u8],
+            domain: &[u8],
+        ) -> Result<(), crate::io::Error> {
+            let chunk = domain == self.file.to_be_bytes() && !input.is_empty();
+
+            if chunk && self.corrupted < self.limit {
+                input[0] ^= 1;
+                self.corrupted += 1;
+            }
+
+            self.peer.write_enc_in(input, domain).await
+        }
+
+        async fn write_ser_enc<P: Parser + Sync>(
+            &mut self,
+            input: &P,
+        ) -> Result<(), crate::io::Error> {
+            self.peer.write_ser_enc(input).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receiver_failure_ends_sender() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let (mut sender, mut receiver) = peers().await;
+
+        for _ in 0..2 {
+            receiver
+                .write_ser_enc(&TransferResponseCode::TransferFail)
+                .await
+                .expect("Write response");
+        }
+
+        let mut manifest = Manifest::default();
+
+        let confirmed = confirm(&mut sender, &manifest).await;
+        assert!(matches!(confirmed, Err(error::Send::PeerFailed)));
+
+        let (path, filter) = (source.path(), &Filter::DEFAULT);
+        let responded = respond(
+            &mut sender,
+            path,
+            filter,
+            false,
+            &mut manifest,
+            (DEFAULT_CHUNK_SIZE, None),
+        )
+        .await;
+        assert!(matches!(responded, Err(error::Send::PeerFailed)));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_reaches_the_peer() {
+        let (mut sender, mut receiver) = peers().await;
+
+        send_cancel(&mut receiver).await;
+
+        let confirmed = confirm(&mut sender, &Manifest::default()).await;
+        assert!(matches!(confirmed, Err(error::Send::PeerCancelled)));
+
+        let (server, client) = connections(Transport::DEFAULT).await;
+
+        let mut stream = client.new_stream().await.expect("Open stream");
+        stream
+            .write_ser_enc(&TransferResponseCode::Ok)
+            .await
+            .expect("Write response");
+
+        let mut accepted = server.new_stream().await.expect("Accept stream");
+        accepted
+            .read_ser_enc::<TransferResponseCode>()
+            .await
+            .expect("Read response");
+
+        client.cancel().await;
+
+        let entry = accepted.read_ser_enc::<FileData>().await;
+        let entry = entry.map_err(error::Receive::from);
+        assert!(matches!(entry, Err(error::Receive::PeerCancelled)));
+    }
+
+    #[tokio::test]
+    async fn test_silent_connection_is_reported_lost() {
+        let transport = Transport {
+            idle_timeout: Duration::from_millis(300),
+            keep_alive_interval: None,
+        };
+        let (server, client) = connections(transport).await;
+
+        let mut stream = client.new_stream().await.expect("Open stream");
+        stream
+            .write_ser_enc(&TransferResponseCode::Ok)
+            .await
+            .expect("Write response");
+
+        let mut accepted = server.new_stream().await.expect("Accept stream");
+        accepted
+            .read_ser_enc::<TransferResponseCode>()
+            .await
+            .expect("Read response");
+
+        // NOTE: Nothing else is sent, as if the network of the sender went away
+        let entry = accepted.read_ser_enc::<FileData>().await;
+        let entry = entry.map_err(error::Receive::from);
+        assert!(matches!(entry, Err(error::Receive::ConnectionLost)));
+    }
+
+    #[tokio::test]
+    async fn test_wrong_file_count_times_out() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let folder = source.path().join("folder");
+
+        std::fs::create_dir(&folder).expect("Create dir");
+        std::fs::write(folder.join("a"), b"content").expect("Write file");
+        std::fs::write(folder.join("b"), b"content").expect("Write file");
+
+        let dest = tempfile::tempdir().expect("Temp dir");
+
+        let (mut sender, mut receiver) = peers().await;
+
+        let send = async {
+            let mut transfer_data = get_transfer_data(&folder, &Filter::DEFAULT)?;
+            transfer_data.total_files += 1;
+            sender.write_ser_enc(&transfer_data).await?;
+
+            let mut manifest = Manifest::default();
+            send_files(
+                &mut sender,
+                &folder,
+                &Filter::DEFAULT,
+                false,
+                &mut manifest,
+                (DEFAULT_CHUNK_SIZE, None),
+            )
+            .await?;
+
+            Ok::<_, error::Send>(sender.read_ser_enc::<TransferResponseCode>().await?)
+        };
+
+        let receive = async {
+            let transfer_data = receiver.read_ser_enc::<TransferData>().await?;
+            let dest = dest.path().to_owned();
+            let timeout = Duration::from_millis(200);
+
+            receive_folder(
+                dest,
+                transfer_data,
+                &mut receiver,
+                Vec::new(),
+                &TransferOptions::DEFAULT.entry_timeout(timeout),
+                (Route::Relay, None),
+                &mut None,
+            )
+            .await
+        };
+
+        let transfer = Box::pin(async { tokio::join!(send, receive) });
+
+        let (response, report) = tokio::time::timeout(Duration::from_secs(5), transfer)
+            .await
+            .expect("Transfer must not hang");
+
+        assert_eq!(TransferResponseCode::TransferFail, response.expect("Sent"));
+        assert!(matches!(report, Err(error::Receive::Timeout)));
+        assert_eq!(0, std::fs::read_dir(dest.path()).expect("Dir").count());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unannounced_link_fails_file_count() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let folder = source.path().join("folder");
+
+        std::fs::create_dir(&folder).expect("Create dir");
+        std::fs::write(folder.join("b"), b"content").expect("Write file");
+        std::os::unix::fs::symlink("b", folder.join("a")).expect("Link");
+
+        let dest = tempfile::tempdir().expect("Temp dir");
+
+        let (mut sender, mut receiver) = peers().await;
+
+        let filter = Filter {
+            symlinks: SymlinkMode::Preserve,
+            ..Filter::DEFAULT
+        };
+
+        let send = async {
+            // NOTE: The link is sent first, so the receiver still waits for the announced file
+            let mut transfer_data = get_transfer_data(&folder, &filter)?;
+            transfer_data.total_links = 0;
+            sender.write_ser_enc(&transfer_data).await?;
+
+            let mut manifest = Manifest::default();
+            send_files(
+                &mut sender,
+                &folder,
+                &filter,
+                false,
+                &mut manifest,
+                (DEFAULT_CHUNK_SIZE, None),
+            )
+            .await?;
+
+            Ok::<_, error::Send>(sender.read_ser_enc::<TransferResponseCode>().await?)
+        };
+
+        let receive = async {
+            let transfer_data = receiver.read_ser_enc::<TransferData>().await?;
+
+            receive_folder(
+                dest.path().to_owned(),
+                transfer_data,
+                &mut receiver,
+                Vec::new(),
+                &TransferOptions::DEFAULT,
+                (Route::Relay, None),
+                &mut None,
+            )
+            .await
+        };
+
+        let (response, report) = tokio::join!(send, receive);
+
+        assert_eq!(TransferResponseCode::TransferFail, response.expect("Sent"));
+        assert!(matches!(
+            report,
+            Err(error::Receive::FileCount {
+                expected: 1,
+                received: 2
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_sender_fails_receiver() {
+        let dest = tempfile::tempdir().expect("Temp dir");
+
+        let (mut sender, mut receiver) = peers().await;
+
+        let file_data = FileData {
+            id: 0,
+            file_size: 8,
+            file_name: "file".to_owned(),
+            is_file: true,
+            link: None,
+        };
+        sender.write_ser_enc(&file_data).await.expect("File data");
+
+        // NOTE: A heartbeat and half the file, then the sender goes silent
+        let domain = file_data.id.to_be_bytes();
+        sender
+            .write_enc_in(&mut [], &domain)
+            .await
+            .expect("Heartbeat");
+        let mut chunk = b"half".to_vec();
+        sender
+            .write_enc_in(&mut chunk, &domain)
+            .await
+            .expect("Chunk");
+
+        let timeout = file::Timeouts {
+            entry: Duration::from_secs(1),
+            stall: Duration::from_millis(200),
+        };
+
+        let mut manifest = Manifest::default();
+        let result = file::receive(
+            dest.path(),
+            &mut receiver,
+            None,
+            &mut manifest,
+            timeout,
+            None,
+        )
+        .await;
+
+        assert!(matches!(result, Err(error::Receive::Stalled)));
+        drop(sender);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_pipe_fails_receiver() {
+        let (mut sender, mut receiver) = peers().await;
+
+        // NOTE: One chunk of the stream, then the sender goes silent
+        let mut chunk = b"\0half".to_vec();
+        sender.write_enc(&mut chunk).await.expect("Chunk");
+
+        let mut manifest = Manifest::default();
+        let stall = Duration::from_millis(200);
+        let result = pipe::receive(
+            &mut receiver,
+            tokio::io::sink(),
+            &mut manifest,
+            (stall, None),
+        );
+
+        assert!(matches!(result.await, Err(error::Receive::Stalled)));
+        drop(sender);
+    }
+
+    #[tokio::test]
+    async fn test_pipe_heartbeats_are_not_written() {
+        let (mut sender, mut receiver) = peers().await;
+
+        let send = async {
+            let mut heartbeat = [1];
+            sender.write_enc(&mut heartbeat).await.expect("Heartbeat");
+
+            let mut manifest = Manifest::default();
+            pipe::send(&mut sender, b"piped data".as_slice(), &mut manifest, None).await
+        };
+
+        let mut written = Vec::new();
+        let mut manifest = Manifest::default();
+        let stall = Duration::from_secs(1);
+        let receive = pipe::receive(&mut receiver, &mut written, &mut manifest, (stall, None));
+
+        let (total, report) = tokio::join!(send, receive);
+
+        assert_eq!(10, total.expect("Sent"));
+        assert_eq!((10, true), report.expect("Received"));
+        assert_eq!(b"piped data".as_slice(), written);
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_byte_fails_stream_hash() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let folder = source.path().join("folder");
+
+        std::fs::create_dir(&folder).expect("Create dir");
+        std::fs::write(folder.join("a"), b"first file").expect("Write file");
+        std::fs::write(folder.join("b"), b"second file").expect("Write file");
+
+        let dest = tempfile::tempdir().expect("Temp dir");
+
+        let (sender, mut receiver) = peers().await;
+
+        // NOTE: The first file, as the root folder is entry 0
+        let mut sender = Corrupting::new(sender, 1, 1);
+
+        let send = async {
+            let mut transfer_data = get_transfer_data(&folder, &Filter::DEFAULT)?;
+            transfer_data.stream_hash = true;
+            sender.write_ser_enc(&transfer_data).await?;
+
+            let filter = &Filter::DEFAULT;
+            let mut manifest = Manifest::default();
+            let progress = (DEFAULT_CHUNK_SIZE, None);
+            send_files(&mut sender, &folder, filter, true, &mut manifest, progress).await?;
+
+            respond(&mut sender, &folder, filter, true, &mut manifest, progress).await
+        };
+
+        let receive = async {
+            let transfer_data = receiver.read_ser_enc::<TransferData>().await?;
+            let dest = dest.path().to_owned();
+
+            let options = TransferOptions::DEFAULT.retry_policy(RetryPolicy {
+                retries: 0,
+                skip_failed: false,
+            });
+
+            receive_folder(
+                dest,
+                transfer_data,
+                &mut receiver,
+                Vec::new(),
+                &options,
+                (Route::Relay, None),
+                &mut None,
+            )
+            .await
+        };
+
+        let (response, report) = tokio::join!(send, receive);
+
+        assert!(matches!(report, Err(error::Receive::HashMismatch)));
+        assert!(matches!(response, Err(error::Send::PeerFailed)));
+        assert_eq!(1, sender.corrupted);
+        assert!(!dest.path().join("folder").exists());
+    }
+
+    #[tokio::test]
+    async fn test_failing_file_is_skipped_after_its_retries() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let folder = source.path().join("folder");
+
+        std::fs::create_dir(&folder).expect("Create dir");
+        std::fs::write(folder.join("a"), b"first file").expect("Write file");
+        std::fs::write(folder.join("b"), b"second file").expect("Write file");
+        std::fs::write(folder.join("c"), b"third file").expect("Write file");
+
+        let dest = tempfile::tempdir().expect("Temp dir");
+
+        let (sender, mut receiver) = peers().await;
+
+        // NOTE: Every attempt of the second file arrives corrupted
+        let mut sender = Corrupting::new(sender, 2, usize::MAX);
+
+        let send = async {
+            let transfer_data = get_transfer_data(&folder, &Filter::DEFAULT)?;
+            sender.write_ser_enc(&transfer_data).await?;
+
+            let filter = &Filter::DEFAULT;
+            let mut manifest = Manifest::default();
+            let progress = (DEFAULT_CHUNK_SIZE, None);
+            send_files(&mut sender, &folder, filter, false, &mut manifest, progress).await?;
+
+            respond(&mut sender, &folder, filter, false, &mut manifest, progress).await
+        };
+
+        let receive = async {
+            let transfer_data = receiver.read_ser_enc::<TransferData>().await?;
+            let dest = dest.path().to_owned();
+
+            let options = TransferOptions::DEFAULT.retry_policy(RetryPolicy {
+                retries: 2,
+                skip_failed: true,
+            });
+
+            receive_folder(
+                dest,
+                transfer_data,
+                &mut receiver,
+                Vec::new(),
+                &options,
+                (Route::Relay, None),
+                &mut None,
+            )
+            .await
+        };
+
+        let (response, report) = tokio::join!(send, receive);
+
+        response.expect("Sent");
+        let report = report.expect("Received");
+
+        // NOTE: Sent once and then once for each retry
+        assert_eq!(3, sender.corrupted);
+        assert_eq!(vec!["b".to_owned()], report.skipped);
+        assert!(!report.destination.join("b").exists());
+        assert_eq!(
+            b"first file".as_slice(),
+            std::fs::read(report.destination.join("a")).expect("Read")
+        );
+        assert_eq!(
+            b"third file".as_slice(),
+            std::fs::read(report.destination.join("c")).expect("Read")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_traversing_names_are_rejected() {
+        for name in ["../../etc/passwd", "/abs/path"] {
+            let root = tempfile::tempdir().expect("Temp dir");
+            let dest = root.path().join("dest");
+            std::fs::create_dir(&dest).expect("Create dir");
+
+            let (mut sender, mut receiver) = peers().await;
+
+            let send = async {
+                let transfer_data = TransferData {
+                    total_files: 1,
+                    total_size: 7,
+                    root_name: "folder".to_owned(),
+                    total_dirs: 1,
+                    ..Default::default()
+                };
+                sender.write_ser_enc(&transfer_data).await?;
+
+                let file_data = FileData {
+                    file_size: 7,
+                    id: 1,
+                    file_name: name.to_owned(),
+                    is_file: true,
+                    link: None,
+                };
+                sender.write_ser_enc(&file_data).await?;
+                sender.write_enc(&mut b"content".to_vec()).await?;
+
+                Ok::<_, error::Send>(())
+            };
+
+            let receive = async {
+                let transfer_data = receiver.read_ser_enc::<TransferData>().await?;
+
+                receive_folder(
+                    dest.clone(),
+                    transfer_data,
+                    &mut receiver,
+                    Vec::new(),
+                    &TransferOptions::DEFAULT,
+                    (Route::Relay, None),
+                    &mut None,
+                )
+                .await
+            };
+
+            let (response, report) = tokio::join!(send, receive);
+
+            response.expect("Sent");
+            assert!(matches!(report, Err(error::Receive::Destination)), "{name}");
+            assert_eq!(1, std::fs::read_dir(root.path()).expect("Dir").count());
+            assert_eq!(0, std::fs::read_dir(&dest).expect("Dir").count());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_dir_survives_round_trip() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let folder = source.path().join("folder");
+
+        std::fs::create_dir_all(folder.join("a")).expect("Create dir");
+        std::fs::write(folder.join("a/file"), b"content").expect("Write file");
+        std::fs::create_dir(folder.join("z")).expect("Create dir");
+        std::fs::create_dir_all(folder.join("b/c")).expect("Create dir");
+
+        let dest = tempfile::tempdir().expect("Temp dir");
+
+        let (mut sender, mut receiver) = peers().await;
+
+        let send = async {
+            let transfer_data = get_transfer_data(&folder, &Filter::DEFAULT)?;
+            sender.write_ser_enc(&transfer_data).await?;
+
+            let filter = &Filter::DEFAULT;
+            let mut manifest = Manifest::default();
+            send_files(
+                &mut sender,
+                &folder,
+                filter,
+                false,
+                &mut manifest,
+                (DEFAULT_CHUNK_SIZE, None),
+            )
+            .await?;
+
+            let verified = respond(
+                &mut sender,
+                &folder,
+                filter,
+                false,
+                &mut manifest,
+                (DEFAULT_CHUNK_SIZE, None),
+            )
+            .await?;
+
+            Ok::<_, error::Send>((verified, manifest.root()))
+        };
+
+        let receive = async {
+            let transfer_data = receiver.read_ser_enc::<TransferData>().await?;
+            let dest = dest.path().to_owned();
+
+            receive_folder(
+                dest,
+                transfer_data,
+                &mut receiver,
+                Vec::new(),
+                &TransferOptions::DEFAULT,
+                (Route::Relay, None),
+                &mut None,
+            )
+            .await
+        };
+
+        let (response, report) = tokio::join!(send, receive);
+
+        let (verified, root) = response.expect("Sent");
+        assert!(verified);
+
+        let report = report.expect("Received");
+        assert!(report.destination.join("a/file").is_file());
+        assert!(report.destination.join("z").is_dir());
+        assert!(report.destination.join("b/c").is_dir());
+        assert!(report.verified);
+        assert_eq!(root, report.root);
+    }
+
+    #[tokio::test]
+    async fn test_folder_received_into_archive() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let folder = source.path().join("folder");
+
+        std::fs::create_dir_all(folder.join("a")).expect("Create dir");
+        std::fs::write(folder.join("a/file"), b"content").expect("Write file");
+        std::fs::write(folder.join("b"), b"other").expect("Write file");
+        std::fs::create_dir(folder.join("z")).expect("Create dir");
+
+        let dest = tempfile::tempdir().expect("Temp dir");
+
+        let (mut sender, mut receiver) = peers().await;
+
+        let send = async {
+            let transfer_data = get_transfer_data(&folder, &Filter::DEFAULT)?;
+            sender.write_ser_enc(&transfer_data).await?;
+
+            let filter = &Filter::DEFAULT;
+            let mut manifest = Manifest::default();
+            let progress = (DEFAULT_CHUNK_SIZE, None);
+            send_files(&mut sender, &folder, filter, false, &mut manifest, progress).await?;
+
+            respond(&mut sender, &folder, filter, false, &mut manifest, progress).await
+        };
+
+        let receive = async {
+            let transfer_data = receiver.read_ser_enc::<TransferData>().await?;
+            let options = TransferOptions::DEFAULT.archive(Some(ArchiveFormat::Tar));
+
+            let dest = dest.path().to_owned();
+            let peer = &mut receiver;
+            receive_folder(
+                dest,
+                transfer_data,
+                peer,
+                Vec::new(),
+                &options,
+                (Route::Relay, None),
+                &mut None,
+            )
+            .await
+        };
+
+        let (verified, report) = tokio::join!(send, receive);
+        assert!(verified.expect("Sent"));
+
+        let report = report.expect("Received");
+        assert_eq!(dest.path().join("folder.tar"), report.destination);
+        assert_eq!(1, std::fs::read_dir(dest.path()).expect("Read dir").count());
+
+        let file = std::fs::File::open(&report.destination).expect("Open archive");
+        let mut entries = tar::Archive::new(file)
+            .entries()
+            .expect("Entries")
+            .map(|e| {
+                let mut entry = e.expect("Entry");
+                let mut contents = String::new();
+                std::io::Read::read_to_string(&mut entry, &mut contents).expect("Read entry");
+
+                let path = entry.path().expect("Path").to_string_lossy().into_owned();
+                (path, contents)
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+
+        let expected = [
+            ("folder/a", ""),
+            ("folder/a/file", "content"),
+            ("folder/b", "other"),
+            ("folder/z", ""),
+        ]
+        .map(|(p, c)| (p.to_owned(), c.to_owned()));
+        assert_eq!(expected.as_slice(), entries);
+    }
+
+    #[tokio::test]
+    async fn test_archive_entries_are_sent_as_folder() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let archive = source.path().join("backup.tar");
+
+        let mut tar = tar::Builder::new(std::fs::File::create(&archive).expect("Create archive"));
+        for (name, contents) in [("data/a/file", "content"), ("data/b", "other")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_cksum();
+
+            tar.append_data(&mut header, name, contents.as_bytes())
+                .expect("Append entry");
+        }
+        tar.finish().expect("Finish archive");
+
+        let dest = tempfile::tempdir().expect("Temp dir");
+
+        let (mut sender, mut receiver) = peers().await;
+
+        let send = async {
+            let source = archive::Source::open(&archive).await?;
+            sender.write_ser_enc(&source.transfer_data()).await?;
+
+            let mut manifest = Manifest::default();
+            let progress = (DEFAULT_CHUNK_SIZE, None);
+            send_unpacked(&mut sender, &source, None, None, &mut manifest, progress).await?;
+
+            respond_unpacked(&mut sender, &source, false, &mut manifest, progress).await
+        };
+
+        let receive = async {
+            let transfer_data = receiver.read_ser_enc::<TransferData>().await?;
+
+            let dest = dest.path().to_owned();
+            let options = &TransferOptions::DEFAULT;
+            let peer = &mut receiver;
+            receive_folder(
+                dest,
+                transfer_data,
+                peer,
+                Vec::new(),
+                options,
+                (Route::Relay, None),
+                &mut None,
+            )
+            .await
+        };
+
+        let (verified, report) = tokio::join!(send, receive);
+        assert!(verified.expect("Sent"));
+
+        let report = report.expect("Received");
+        assert_eq!(dest.path().join("data"), report.destination);
+        assert_eq!(2, report.total_files);
+        assert_eq!(
+            b"content".as_slice(),
+            std::fs::read(report.destination.join("a/file")).expect("Read")
+        );
+        assert_eq!(
+            b"other".as_slice(),
+            std::fs::read(report.destination.join("b")).expect("Read")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_names_are_rejected_before_sending() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let source = tempfile::tempdir().expect("Temp dir");
+        let folder = source.path().join("folder");
+        let latin1 = std::ffi::OsStr::from_bytes(b"caf\xe9");
+
+        std::fs::create_dir_all(folder.join(latin1)).expect("Create dir");
+        std::fs::write(folder.join(latin1).join("men\u{fa}"), b"content").expect("Write file");
+
+        let result = get_transfer_data(&folder, &Filter::DEFAULT);
+
+        assert!(matches!(result, Err(error::Send::NonUtf8Name(p)) if p == folder.join(latin1)));
+    }
+
+    #[tokio::test]
+    async fn test_files_are_announced_before_their_progress() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let folder = source.path().join("folder");
+
+        std::fs::create_dir_all(folder.join("a")).expect("Create dir");
+        std::fs::write(folder.join("a/file"), b"content").expect("Write file");
+        std::fs::write(folder.join("b"), b"more content").expect("Write file");
+
+        let dest = tempfile::tempdir().expect("Temp dir");
+
+        let (progress, mut messages) = tokio::sync::mpsc::channel(100);
+        let channel = Channel {
+            progress: Some(progress),
+            paused: None,
+            cancelled: None,
+        };
+
+        let (mut sender, mut receiver) = peers().await;
+
+        let send = async {
+            let transfer_data = get_transfer_data(&folder, &Filter::DEFAULT)?;
+            sender.write_ser_enc(&transfer_data).await?;
+
+            let filter = &Filter::DEFAULT;
+            let mut manifest = Manifest::default();
+            let channel = Some(&channel);
+            send_files(
+                &mut sender,
+                &folder,
+                filter,
+                false,
+                &mut manifest,
+                (DEFAULT_CHUNK_SIZE, channel),
+            )
+            .await?;
+
+            respond(
+                &mut sender,
+                &folder,
+                filter,
+                false,
+                &mut manifest,
+                (DEFAULT_CHUNK_SIZE, None),
+            )
+            .await
+        };
+
+        let receive = async {
+            let transfer_data = receiver.read_ser_enc::<TransferData>().await?;
+            let dest = dest.path().to_owned();
+
+            receive_folder(
+                dest,
+                transfer_data,
+                &mut receiver,
+                Vec::new(),
+                &TransferOptions::DEFAULT,
+                (Route::Relay, None),
+                &mut None,
+            )
+            .await
+        };
+
+        let (response, report) = tokio::join!(send, receive);
+
+        assert!(response.expect("Sent"));
+        assert!(report.expect("Received").verified);
+
+        drop(channel);
+
+        let mut started = Vec::new();
+        while let Some(message) = messages.recv().await {
+            match message {
+                Message::FileStarted { name, size } => started.push((name, size, 0)),
+                Message::Progress(n) => started.last_mut().expect("Announced file").2 += n,
+                _ => {}
+            }
+        }
+
+        started.sort();
+        let expected = vec![("a/file".to_owned(), 7, 7), ("b".to_owned(), 12, 12)];
+        assert_eq!(expected, started);
+    }
+
+    #[tokio::test]
+    async fn test_progress_matches_size_for_any_chunk_size() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let folder = source.path().join("folder");
+        std::fs::create_dir_all(&folder).expect("Create dir");
+
+        let contents = (0..25_000_u32)
+            .flat_map(u32::to_le_bytes)
+            .collect::<Vec<_>>();
+        std::fs::write(folder.join("file"), &contents).expect("Write file");
+
+        for chunk_size in [MIN_CHUNK_SIZE, DEFAULT_CHUNK_SIZE, 50_000, MAX_CHUNK_SIZE] {
+            let dest = tempfile::tempdir().expect("Temp dir");
+
+            let channel = || {
+                let (progress, messages) = tokio::sync::mpsc::channel(1000);
+                let channel = Channel {
+                    progress: Some(progress),
+                    paused: None,
+                    cancelled: None,
+                };
+                (channel, messages)
+            };
+            let (sender_channel, mut sender_messages) = channel();
+            let (receiver_channel, mut receiver_messages) = channel();
+
+            let (mut sender, mut receiver) = peers().await;
+
+            let send = async {
+                let transfer_data = get_transfer_data(&folder, &Filter::DEFAULT)?;
+                sender.write_ser_enc(&transfer_data).await?;
+
+                let (filter, mut manifest) = (&Filter::DEFAULT, Manifest::default());
+                let progress = (chunk_size, Some(&sender_channel));
+                send_files(&mut sender, &folder, filter, false, &mut manifest, progress).await?;
+
+                respond(&mut sender, &folder, filter, false, &mut manifest, progress).await
+            };
+
+            let receive = async {
+                let transfer_data = receiver.read_ser_enc::<TransferData>().await?;
+
+                receive_folder(
+                    dest.path().to_owned(),
+                    transfer_data,
+                    &mut receiver,
+                    Vec::new(),
+                    &TransferOptions::DEFAULT,
+                    (Route::Relay, Some(&receiver_channel)),
+                    &mut None,
+                )
+                .await
+            };
+
+            let (response, report) = tokio::join!(send, receive);
+            assert!(response.expect("Sent"));
+
+            let report = report.expect("Received");
+            assert_eq!(
+                contents,
+                std::fs::read(report.destination.join("file")).expect("Read")
+            );
+
+            drop((sender_channel, receiver_channel));
+
+            for messages in [&mut sender_messages, &mut receiver_messages] {
+                let mut total = 0;
+                while let Some(message) = messages.recv().await {
+                    if let Message::Progress(n) = message {
+                        assert!(n <= chunk_size);
+                        total += n;
+                    }
+                }
+
+                assert_eq!(contents.len(), total, "Chunks of {chunk_size} bytes");
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_preserved_symlinks_are_recreated() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let folder = source.path().join("folder");
+
+        std::fs::create_dir_all(folder.join("a")).expect("Create dir");
+        std::fs::write(folder.join("a/file"), b"content").expect("Write file");
+        std::os::unix::fs::symlink("a/file", folder.join("file_link")).expect("Link");
+        std::os::unix::fs::symlink("a", folder.join("dir_link")).expect("Link");
+
+        let dest = tempfile::tempdir().expect("Temp dir");
+
+        let (mut sender, mut receiver) = peers().await;
+
+        let filter = Filter {
+            symlinks: SymlinkMode::Preserve,
+            ..Filter::DEFAULT
+        };
+
+        let send = async {
+            let transfer_data = get_transfer_data(&folder, &filter)?;
+            sender.write_ser_enc(&transfer_data).await?;
+
+            let mut manifest = Manifest::default();
+            send_files(
+                &mut sender,
+                &folder,
+                &filter,
+                false,
+                &mut manifest,
+                (DEFAULT_CHUNK_SIZE, None),
+            )
+            .await?;
+
+            let verified = respond(
+                &mut sender,
+                &folder,
+                &filter,
+                false,
+                &mut manifest,
+                (DEFAULT_CHUNK_SIZE, None),
+            )
+            .await?;
+
+            Ok::<_, error::Send>((verified, manifest.root()))
+        };
+
+        let receive = async {
+            let transfer_data = receiver.read_ser_enc::<TransferData>().await?;
+            assert_eq!(1, transfer_data.total_files);
+            assert_eq!(2, transfer_data.total_links);
+
+            let dest = dest.path().to_owned();
+
+            receive_folder(
+                dest,
+                transfer_data,
+                &mut receiver,
+                Vec::new(),
+                &TransferOptions::DEFAULT,
+                (Route::Relay, None),
+                &mut None,
+            )
+            .await
+        };
+
+        let (response, report) = tokio::join!(send, receive);
+
+        let (verified, root) = response.expect("Sent");
+        assert!(verified);
+
+        let report = report.expect("Received");
+        let link = |name| std::fs::read_link(report.destination.join(name)).expect("Link");
+        assert_eq!(PathBuf::from("a/file"), link("file_link"));
+        assert_eq!(PathBuf::from("a"), link("dir_link"));
+        assert_eq!(
+            b"content".to_vec(),
+            std::fs::read(report.destination.join("file_link")).expect("Read")
+        );
+        assert_eq!(root, report.root);
+    }
+
+    #[tokio::test]
+    async fn test_piped_data_is_saved_as_file() {
+        let content = b"piped content".repeat(5000);
+
+        let dest = tempfile::tempdir().expect("Temp dir");
+
+        let (mut sender, mut receiver) = peers().await;
+
+        let send = async {
+            let transfer_data = TransferData {
+                total_files: 1,
+                root_name: pipe::NAME.to_owned(),
+                piped: true,
+                ..Default::default()
+            };
+            sender.write_ser_enc(&transfer_data).await?;
+
+            let mut manifest = Manifest::default();
+            pipe::send(&mut sender, content.as_slice(), &mut manifest, None).await?;
+
+            let code = sender.read_ser_enc::<TransferResponseCode>().await?;
+            assert_eq!(TransferResponseCode::Verify, code);
+
+            let verified = answer(&mut sender, &manifest).await?;
+            let code = sender.read_ser_enc::<TransferResponseCode>().await?;
+
+            Ok::<_, error::Send>((verified, code))
+        };
+
+        let receive = async {
+            let transfer_data = receiver.read_ser_enc::<TransferData>().await?;
+            let dest = dest.path().to_owned();
+
+            receive_file(
+                dest,
+                &transfer_data,
+                &mut receiver,
+                &TransferOptions::DEFAULT,
+                Route::Relay,
+                None,
+            )
+            .await
+        };
+
+        let (response, report) = tokio::join!(send, receive);
+
+        let (verified, code) = response.expect("Sent");
+        assert!(verified);
+        assert_eq!(TransferResponseCode::Ok, code);
+
+        let report = report.expect("Received");
+        assert_eq!(dest.path().join(pipe::NAME), report.destination);
+        assert_eq!(content.len() as u64, report.total_size);
+        assert_eq!(content, std::fs::read(report.destination).expect("Read"));
+    }
+
+    #[test]
+    fn test_destination_can_rename_the_result() {
+        let dir = tempfile::tempdir().expect("Temp dir");
+        let base = std::fs::canonicalize(dir.path()).expect("Canonical");
+
+        let renamed = destination(&dir.path().join("renamed.txt")).expect("Valid destination");
+        assert_eq!(base.join("renamed.txt"), renamed);
+
+        let missing = dir.path().join("missing/renamed.txt");
+        assert!(matches!(
+            destination(&missing),
+            Err(error::Receive::MissingParent(p)) if p == missing
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_receive_leaves_no_files() {
+        let dest = tempfile::tempdir().expect("Temp dir");
+
+        // NOTE: The sender stays connected but never sends the file
+        let (_sender, mut receiver) = peers().await;
+
+        let transfer_data = TransferData {
+            total_files: 1,
+            total_size: 10,
+            root_name: "file".to_owned(),
+            ..Default::default()
+        };
+
+        let receiving = async {
+            while std::fs::read_dir(dest.path()).expect("Read dir").count() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        tokio::select! {
+            _ = receive_file(
+                dest.path().to_owned(),
+                &transfer_data,
+                &mut receiver,
+                &TransferOptions::DEFAULT,
+                Route::Relay,
+                None,
+            ) => panic!("Nothing was sent"),
+            () = receiving => {}
+        }
+
+        assert_eq!(0, std::fs::read_dir(dest.path()).expect("Read dir").count());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_folder_is_unpacked() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let folder = source.path().join("folder");
+
+        std::fs::create_dir_all(folder.join("a")).expect("Create dir");
+        std::fs::write(folder.join("a/file"), b"content").expect("Write file");
+        std::fs::write(folder.join("b"), b"other").expect("Write file");
+        std::fs::write(folder.join("out.log"), b"excluded").expect("Write file");
+
+        let filter = Filter {
+            ignore: Some(ignore::Ignore::new(&["*.log".to_owned()]).expect("Valid pattern")),
+            ..Filter::DEFAULT
+        };
+
+        let transfer_data = TransferData {
+            total_files: 2,
+            total_size: 12,
+            root_name: "folder".to_owned(),
+            compressed: true,
+            piped: true,
+            ..Default::default()
+        };
+
+        let dest = tempfile::tempdir().expect("Temp dir");
+
+        let (mut sender, mut receiver) = peers().await;
+
+        let send = async {
+            let mut manifest = Manifest::default();
+            deflate::send(&mut sender, &folder, &filter, &mut manifest, None).await?;
+
+            confirm(&mut sender, &manifest).await
+        };
+
+        let receive = receive_archive(
+            dest.path().to_owned(),
+            &transfer_data,
+            &mut receiver,
+            &TransferOptions::DEFAULT,
+            Route::Relay,
+            None,
+        );
+
+        let (verified, report) = tokio::join!(send, receive);
+        assert!(verified.expect("Sent"));
+
+        let report = report.expect("Received");
+        assert_eq!(dest.path().join("folder"), report.destination);
+        assert_eq!(2, report.total_files);
+        assert_eq!(
+            b"content".as_slice(),
+            std::fs::read(report.destination.join("a/file")).expect("Read")
+        );
+        assert!(report.destination.join("b").is_file());
+        assert!(!report.destination.join("out.log").exists());
+    }
+
+    #[tokio::test]
+    async fn test_folder_spread_over_streams() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let folder = source.path().join("folder");
+
+        std::fs::create_dir_all(folder.join("a/b")).expect("Create dir");
+        std::fs::write(folder.join("a/b/file"), b"nested").expect("Write file");
+        std::fs::write(folder.join("a/file"), vec![1; 100_000]).expect("Write file");
+        std::fs::write(folder.join("c"), b"small").expect("Write file");
+        std::fs::write(folder.join("d"), b"").expect("Write file");
+
+        let transfer_data = get_transfer_data(&folder, &Filter::DEFAULT).expect("Transfer data");
+        let dest = tempfile::tempdir().expect("Temp dir");
+
+        let (mut sender, mut receiver) = peers().await;
+
+        // NOTE: The sender accepts the streams, so each only opens once the receiver greets
+        let (server, client) = connections(Transport::DEFAULT).await;
+
+        let send = async {
+            let lanes = open_lanes(Some(&server), 3).await.expect("Open lanes");
+            let (filter, mut manifest) = (&Filter::DEFAULT, Manifest::default());
+
+            send_lanes(
+                &mut sender,
+                lanes,
+                &folder,
+                filter,
+                &mut manifest,
+                (DEFAULT_CHUNK_SIZE, None),
+            )
+            .await?;
+
+            respond(
+                &mut sender,
+                &folder,
+                filter,
+                false,
+                &mut manifest,
+                (DEFAULT_CHUNK_SIZE, None),
+            )
+            .await
+        };
+
+        let receive = async {
+            let lanes = open_lanes(Some(&client), 3).await.expect("Open lanes");
+
+            receive_folder(
+                dest.path().to_owned(),
+                transfer_data,
+                &mut receiver,
+                lanes,
+                &TransferOptions::DEFAULT,
+                (Route::HolePunching, None),
+                &mut None,
+            )
+            .await
+        };
+
+        let (verified, report) = tokio::join!(send, receive);
+        assert!(verified.expect("Sent"));
+
+        let report = report.expect("Received");
+        assert_eq!(4, report.total_files);
+        assert_eq!(
+            b"nested".as_slice(),
+            std::fs::read(report.destination.join("a/b/file")).expect("Read")
+        );
+        assert_eq!(
+            100_000,
+            std::fs::metadata(report.destination.join("a/file"))
+                .expect("Metadata")
+                .len()
+        );
+        assert!(report.destination.join("d").is_file());
+    }
+
+    #[tokio::test]
+    async fn test_folder_arrives_while_connection_moves() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let folder = source.path().join("folder");
+        std::fs::create_dir_all(&folder).expect("Create dir");
+
+        let contents = (0..50_000_u32)
+            .flat_map(u32::to_le_bytes)
+            .collect::<Vec<_>>();
+        for name in ["a", "b", "c", "d"] {
+            std::fs::write(folder.join(name), &contents).expect("Write file");
+        }
+
+        let dest = tempfile::tempdir().expect("Temp dir");
+        let (server, client) = connections(Transport::DEFAULT).await;
+
+        let (progress, mut messages) = tokio::sync::mpsc::channel(1000);
+        let sender_channel = Channel {
+            progress: Some(progress),
+            paused: None,
+            cancelled: None,
+        };
+
+        // NOTE: The client opens the stream, so it sends first
+        let send = async {
+            let mut sender = client.new_stream().await.expect("Open stream");
+
+            let transfer_data = get_transfer_data(&folder, &Filter::DEFAULT)?;
+            sender.write_ser_enc(&transfer_data).await?;
+
+            let (filter, mut manifest) = (&Filter::DEFAULT, Manifest::default());
+            let progress = (DEFAULT_CHUNK_SIZE, Some(&sender_channel));
+            send_files(&mut sender, &folder, filter, false, &mut manifest, progress).await?;
+
+            respond(&mut sender, &folder, filter, false, &mut manifest, progress).await
+        };
+
+        let receive = async {
+            let mut receiver = server.new_stream().await.expect("Accept stream");
+            let transfer_data = receiver.read_ser_enc::<TransferData>().await?;
+
+            receive_folder(
+                dest.path().to_owned(),
+                transfer_data,
+                &mut receiver,
+                Vec::new(),
+                &TransferOptions::DEFAULT,
+                (Route::HolePunching, None),
+                &mut None,
+            )
+            .await
+        };
+
+        // NOTE: Moves the sender to a new socket once the first chunk is out, like after a
+        // network change
+        let rebind = async {
+            while let Some(message) = messages.recv().await {
+                if matches!(message, Message::Progress(_)) {
+                    let socket =
+                        std::net::UdpSocket::bind(("127.0.0.1", 0)).expect("Bind ephemeral port");
+                    client.endpoint().rebind(socket).expect("Rebind");
+
+                    return client.local_address().expect("Address");
+                }
+            }
+
+            panic!("Nothing was sent");
+        };
+
+        let before = client.local_address().expect("Address");
+
+        let transfer = async { tokio::join!(send, receive, rebind) };
+        let (verified, report, after) =
+            Box::pin(tokio::time::timeout(Duration::from_secs(10), transfer))
+                .await
+                .expect("Transfer must not hang");
+
+        assert_ne!(before, after);
+        assert_eq!(after, server.remote_address());
+
+        assert!(verified.expect("Sent"));
+
+        let report = report.expect("Received");
+        for name in ["a", "b", "c", "d"] {
+            assert_eq!(
+                contents,
+                std::fs::read(report.destination.join(name)).expect("Read")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merge_only_receives_changed_files() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let folder = source.path().join("folder");
+
+        std::fs::create_dir_all(folder.join("a/new")).expect("Create dir");
+        std::fs::write(folder.join("a/same"), b"unchanged").expect("Write file");
+        std::fs::write(folder.join("a/changed"), b"original").expect("Write file");
+        std::fs::write(folder.join("missing"), b"sent").expect("Write file");
+        std::fs::write(folder.join("swap"), b"was a folder").expect("Write file");
+
+        let dest = tempfile::tempdir().expect("Temp dir");
+        let copy = dest.path().join("folder");
+
+        std::fs::create_dir_all(copy.join("a")).expect("Create dir");
+        std::fs::create_dir_all(copy.join("swap")).expect("Create dir");
+        std::fs::write(copy.join("a/same"), b"unchanged").expect("Write file");
+        std::fs::write(copy.join("a/changed"), b"bit rot!").expect("Write file");
+        std::fs::write(copy.join("local"), b"kept").expect("Write file");
+
+        let transfer_data = get_transfer_data(&folder, &Filter::DEFAULT).expect("Data");
+        let root = merge_root(dest.path(), &transfer_data, OnConflict::Merge).expect("Root");
+
+        let (mut sender, mut receiver) = peers().await;
+
+        let send = async {
+            let (filter, mut manifest) = (&Filter::DEFAULT, Manifest::default());
+
+            merge::announce(&mut sender, &folder, filter, &mut manifest).await?;
+            let totals = merge::send(
+                &mut sender,
+                &folder,
+                filter,
+                &mut manifest,
+                (DEFAULT_CHUNK_SIZE, None),
+            )
+            .await?;
+            let verified = respond(
+                &mut sender,
+                &folder,
+                filter,
+                false,
+                &mut manifest,
+                (DEFAULT_CHUNK_SIZE, None),
+            )
+            .await?;
+
+            Ok::<_, error::Send>((totals, verified))
+        };
+
+        let mut staged = None;
+        let receive = receive_merge(
+            root,
+            transfer_data,
+            &mut receiver,
+            &TransferOptions::DEFAULT,
+            Route::Relay,
+            None,
+            &mut staged,
+        );
+
+        let (merged, report) = tokio::join!(send, receive);
+        let ((files, size), verified) = merged.expect("Sent");
+        assert!(verified);
+        assert_eq!((3, 24), (files, size));
+
+        let report = report.expect("Received");
+        assert_eq!(copy, report.destination);
+        assert_eq!(1, report.unchanged);
+        assert!(report.verified);
+
+        let read = |name: &str| std::fs::read(copy.join(name)).expect("Read");
+        assert_eq!(b"original".as_slice(), read("a/changed"));
+        assert_eq!(b"sent".as_slice(), read("missing"));
+        assert_eq!(b"was a folder".as_slice(), read("swap"));
+        assert_eq!(b"kept".as_slice(), read("local"));
+        assert!(copy.join("a/new").is_dir());
+    }
+
+    #[tokio::test]
+    async fn test_verify_reports_changed_and_missing_files() {
+        let source = tempfile::tempdir().expect("Temp dir");
+        let folder = source.path().join("folder");
+
+        std::fs::create_dir_all(folder.join("a")).expect("Create dir");
+        std::fs::write(folder.join("a/same"), b"unchanged").expect("Write file");
+        std::fs::write(folder.join("a/changed"), b"original").expect("Write file");
+        std::fs::write(folder.join("gone"), b"deleted").expect("Write file");
+
+        let dest = tempfile::tempdir().expect("Temp dir");
+        let copy = dest.path().join("folder");
+
+        std::fs::create_dir_all(copy.join("a")).expect("Create dir");
+        std::fs::write(copy.join("a/same"), b"unchanged").expect("Write file");
+        std::fs::write(copy.join("a/changed"), b"bit rot!").expect("Write file");
+
+        let mut transfer_data = get_transfer_data(&folder, &Filter::DEFAULT).expect("Data");
+        transfer_data.verify_only = true;
+
+        let (mut sender, mut receiver) = peers().await;
+
+        let (sent, result) = tokio::join!(
+            check::send(&mut sender, &folder, &Filter::DEFAULT, None),
+            check::receive(&mut receiver, &copy, &transfer_data, None),
+        );
+        sent.expect("Sent");
+
+        let result = result.expect("Checked");
+        assert_eq!(vec!["a/changed".to_owned()], result.mismatched);
+        assert_eq!(vec!["gone".to_owned()], result.missing);
+    }
+
+    #[test]
+    fn test_auto_compression_threshold() {
+        let threshold = CompressionThreshold {
+            files: 10,
+            size: 100,
+        };
+        let data = |files, size| TransferData {
+            total_files: files,
+            total_size: size,
+            ..Default::default()
+        };
+
+        assert!(Compression::Auto.applies(threshold, &data(10, 100)));
+        assert!(!Compression::Auto.applies(threshold, &data(9, 100)));
+        assert!(!Compression::Auto.applies(threshold, &data(10, 101)));
+        assert!(Compression::Always.applies(threshold, &data(1, 1000)));
+        assert!(!Compression::Never.applies(threshold, &data(10, 100)));
+    }
 }
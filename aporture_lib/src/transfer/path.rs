@@ -1,7 +1,15 @@
 use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 
-use typed_path::Utf8PlatformPathBuf;
+use typed_path::{Utf8Component, Utf8PlatformPathBuf, Utf8UnixPathBuf};
+
+use super::OnConflict;
+
+/// Names Windows keeps for devices, whatever their extension
+const WINDOWS_RESERVED: [&str; 4] = ["CON", "PRN", "AUX", "NUL"];
+
+/// Characters Windows does not allow in names
+const WINDOWS_INVALID: &str = r#"<>:"\|?*"#;
 
 pub async fn non_existant(mut path: PathBuf) -> PathBuf {
     let mut suffix = 0;
@@ -29,6 +37,46 @@ pub async fn non_existant(mut path: PathBuf) -> PathBuf {
     path
 }
 
+/// Where a received entry is saved, `None` if the existing one is kept.
+pub async fn target(path: &Path, on_conflict: OnConflict) -> Option<PathBuf> {
+    let exists = tokio::fs::try_exists(path).await.is_ok_and(|b| b);
+
+    match on_conflict {
+        OnConflict::Rename => Some(non_existant(path.to_owned()).await),
+        // NOTE: Merged folders never get here, so an existing one is kept and the copy renamed
+        OnConflict::Merge if path.is_dir() => Some(non_existant(path.to_owned()).await),
+        OnConflict::Skip if exists => None,
+        OnConflict::Overwrite | OnConflict::Skip | OnConflict::Merge => Some(path.to_owned()),
+    }
+}
+
+/// Moves a received entry to the destination, replacing whatever is there.
+///
+/// The existing entry is moved aside and only removed once the new one is in place,
+/// so it is restored if the move fails.
+pub async fn replace(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    let Ok(existing) = tokio::fs::symlink_metadata(to).await else {
+        return tokio::fs::rename(from, to).await;
+    };
+
+    let mut name = to.file_name().unwrap_or_default().to_owned();
+    name.push(".old");
+    let old = non_existant(to.with_file_name(name)).await;
+
+    tokio::fs::rename(to, &old).await?;
+
+    if let Err(e) = tokio::fs::rename(from, to).await {
+        let _ = tokio::fs::rename(&old, to).await;
+        return Err(e);
+    }
+
+    if existing.is_dir() {
+        tokio::fs::remove_dir_all(old).await
+    } else {
+        tokio::fs::remove_file(old).await
+    }
+}
+
 pub fn sanitize(path: &Path) -> Result<PathBuf, std::io::Error> {
     let sanitized = if let Ok(sanitized) = std::fs::canonicalize(path) {
         if !sanitized.is_dir() && !sanitized.is_file() {
@@ -66,3 +114,262 @@ pub fn platform(path: &Path) -> Utf8PlatformPathBuf {
             .expect("Should be valid utf8 as path was sanitized"),
     )
 }
+
+/// Name of the path relative to the base as sent to the peer, with `/` separators.
+/// Returns `None` if it is not valid UTF-8, as the peer could only get a replaced one.
+pub fn relative_name(base: &Path, path: &Path) -> Option<String> {
+    let relative = path
+        .strip_prefix(base)
+        .expect("Path must be a subpath from base");
+
+    let components = relative
+        .components()
+        .map(|c| c.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(components.join("/"))
+}
+
+/// Joins a name sent by the peer to the base.
+/// Returns `None` if the name is absolute or has `..` segments, as it could escape the base,
+/// or if this is Windows and it can not create it.
+pub fn received(base: &Path, file_name: &str) -> Option<Utf8PlatformPathBuf> {
+    let unix_path = Utf8UnixPathBuf::from(file_name);
+
+    // NOTE: Checked before converting, as Windows would split names with a `\` instead
+    if cfg!(windows)
+        && unix_path
+            .components()
+            .any(|c| invalid_on_windows(c.as_str()))
+    {
+        log::warn!("Rejecting received name {file_name:?} as Windows can not create it");
+
+        return None;
+    }
+
+    let received_path = unix_path.with_platform_encoding();
+
+    // NOTE: Checked once converted as other platforms may parse prefixes out of the name
+    let mut components = received_path.components();
+    let inside = components.all(|c| c.is_normal() || c.is_current());
+
+    if !inside || !received_path.components().any(|c| c.is_normal()) {
+        log::warn!("Rejecting received name {file_name:?} as it escapes the destination");
+
+        return None;
+    }
+
+    let mut path = platform(base);
+    path.push(received_path.normalize());
+
+    Some(path)
+}
+
+/// Whether Windows can not create a file or folder with the name, `.` and `..` are valid.
+fn invalid_on_windows(name: &str) -> bool {
+    if name == "." || name == ".." {
+        return false;
+    }
+
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    let numbered = |prefix: &str| {
+        stem.len() == 4
+            && stem
+                .get(..3)
+                .is_some_and(|p| p.eq_ignore_ascii_case(prefix))
+            && matches!(stem.as_bytes()[3], b'1'..=b'9')
+    };
+
+    let reserved = WINDOWS_RESERVED
+        .iter()
+        .any(|r| stem.eq_ignore_ascii_case(r))
+        || numbered("COM")
+        || numbered("LPT");
+
+    reserved
+        || name.ends_with(['.', ' '])
+        || name
+            .chars()
+            .any(|c| c.is_ascii_control() || WINDOWS_INVALID.contains(c))
+}
+
+/// Whether the path or any of its parents inside the base is a symbolic link.
+pub fn through_link(base: &Path, path: &Path) -> bool {
+    path.ancestors()
+        .take_while(|p| *p != base)
+        .any(Path::is_symlink)
+}
+
+/// Creates a symbolic link pointing to the target.
+#[cfg(unix)]
+pub async fn symlink(target: &str, link: &Path) -> Result<(), std::io::Error> {
+    tokio::fs::symlink(target, link).await
+}
+
+/// Creates a symbolic link pointing to the target.
+/// Windows needs to know whether the target is a directory, links to missing targets are files.
+#[cfg(windows)]
+pub async fn symlink(target: &str, link: &Path) -> Result<(), std::io::Error> {
+    let resolved = link.parent().map(|p| p.join(target));
+
+    if resolved.is_some_and(|p| p.is_dir()) {
+        tokio::fs::symlink_dir(target, link).await
+    } else {
+        tokio::fs::symlink_file(target, link).await
+    }
+}
+
+/// Removes every entry inside the directory, keeping the directory itself.
+pub async fn clear(dir: &Path) -> Result<(), std::io::Error> {
+    let mut entries = tokio::fs::read_dir(dir).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            tokio::fs::remove_dir_all(entry.path()).await?;
+        } else {
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Counts the files and links inside the directory and its subdirectories, the same entries
+/// the sender reports as files and links.
+pub async fn count_entries(dir: &Path) -> Result<u64, std::io::Error> {
+    let dir = dir.to_owned();
+
+    tokio::task::spawn_blocking(move || {
+        walkdir::WalkDir::new(dir).into_iter().try_fold(
+            0,
+            |count, entry| -> Result<u64, std::io::Error> {
+                let kind = entry?.file_type();
+
+                Ok(count + u64::from(kind.is_file() || kind.is_symlink()))
+            },
+        )
+    })
+    .await
+    .expect("Task was aborted")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_conflict_policy_targets() {
+        let dir = tempfile::tempdir().expect("Temp dir");
+        let existing = dir.path().join("report.pdf");
+        std::fs::write(&existing, b"old").expect("Write");
+
+        let renamed = target(&existing, OnConflict::Rename).await;
+        assert_eq!(Some(dir.path().join("report (1).pdf")), renamed);
+        assert_eq!(
+            Some(existing.clone()),
+            target(&existing, OnConflict::Overwrite).await
+        );
+        assert_eq!(None, target(&existing, OnConflict::Skip).await);
+        assert_eq!(
+            Some(existing.clone()),
+            target(&existing, OnConflict::Merge).await
+        );
+
+        let received = dir.path().join("received");
+        std::fs::create_dir(&received).expect("Create dir");
+
+        let unmerged = target(&received, OnConflict::Merge).await;
+        assert_eq!(Some(dir.path().join("received (1)")), unmerged);
+        std::fs::write(received.join("new"), b"new").expect("Write");
+
+        replace(&received, &existing).await.expect("Replace");
+
+        assert!(existing.join("new").is_file());
+        assert_eq!(1, std::fs::read_dir(dir.path()).expect("Read dir").count());
+    }
+
+    #[test]
+    fn test_received_names_stay_inside_base() {
+        let base = Path::new("/home/user/Downloads");
+
+        for name in [
+            "../../etc/passwd",
+            "/abs/path",
+            "a/../../b",
+            "a/..",
+            "..",
+            "",
+            ".",
+        ] {
+            assert_eq!(None, received(base, name), "{name:?} must be rejected");
+        }
+
+        let path = received(base, "./folder/file.txt").expect("Valid name");
+        assert_eq!(
+            Path::new("/home/user/Downloads/folder/file.txt"),
+            Path::new(&path)
+        );
+    }
+
+    #[test]
+    fn test_names_invalid_on_windows() {
+        for name in [
+            "CON",
+            "nul",
+            "Aux.txt",
+            "com1",
+            "LPT9.log",
+            "a:b",
+            "what?",
+            "back\\slash",
+            "dot.",
+            "space ",
+            "tab\t",
+        ] {
+            assert!(invalid_on_windows(name), "{name:?} must be invalid");
+        }
+
+        for name in [
+            "CONFIG",
+            "console.log",
+            "COM0",
+            "com10",
+            "file.txt",
+            ".hidden",
+            ".",
+            "..",
+            "caf\u{e9}",
+        ] {
+            assert!(!invalid_on_windows(name), "{name:?} must be valid");
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_received_names_windows_can_not_create_are_rejected() {
+        let base = Path::new("C:\\Users\\user\\Downloads");
+
+        for name in ["folder/CON", "a:b", "folder/name.", "back\\slash"] {
+            assert_eq!(None, received(base, name), "{name:?} must be rejected");
+        }
+
+        assert!(received(base, "folder/file.txt").is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_names_are_not_sent() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let base = Path::new("/home/user/folder");
+        let latin1 = base
+            .join(std::ffi::OsStr::from_bytes(b"caf\xe9"))
+            .join("menu");
+
+        assert_eq!(None, relative_name(base, &latin1));
+        assert_eq!(
+            Some("sub/men\u{fa}".to_owned()),
+            relative_name(base, &base.join("sub/men\u{fa}"))
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+
+use crate::crypto::hasher::Hasher;
+use crate::parser::EncryptedSerdeIO;
+use crate::protocol::Hash;
+use crate::transfer::channel;
+use crate::transfer::channel::{Channel, Message};
+use crate::transfer::file::HEARTBEAT_INTERVAL;
+use crate::transfer::manifest::Manifest;
+
+/// Name of a piped transfer, used when the receiver saves it as a file
+pub const NAME: &str = "stdin";
+
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// First byte of a chunk with data of the stream
+const DATA: u8 = 0;
+/// First and only byte of a chunk that tells the receiver the sender is still there
+const HEARTBEAT: u8 = 1;
+
+/// Sends everything read until the end of the reader, followed by an empty chunk and its hash.
+/// Returns the amount of bytes sent.
+///
+/// As the size is unknown an empty chunk marks the end, so every other chunk starts with its
+/// kind. Heartbeats are sent while paused or while the reader has nothing new.
+pub async fn send<Ep, R>(
+    peer: &mut Ep,
+    mut reader: R,
+    manifest: &mut Manifest,
+    channel: Option<&Channel>,
+) -> Result<u64, super::error::Send>
+where
+    Ep: EncryptedSerdeIO + Send,
+    R: AsyncRead + Unpin + Send,
+{
+    let mut hasher = Hasher::default();
+    let mut buffer = vec![0; BUFFER_SIZE + 1];
+    let mut total = 0;
+
+    loop {
+        let count = next_chunk(peer, &mut reader, &mut buffer[1..], channel).await?;
+
+        if count == 0 {
+            peer.write_enc(&mut []).await?;
+            break;
+        }
+
+        hasher.add(&buffer[1..=count]);
+
+        // NOTE: Encrypted in place, so the kind is set again for every chunk
+        buffer[0] = DATA;
+        peer.write_enc(&mut buffer[..=count]).await?;
+
+        total += count as u64;
+        channel::send(channel, Message::Progress(count)).await;
+    }
+
+    let hash = hasher.finalize();
+
+    peer.write_ser_enc(&Hash(hash)).await?;
+
+    manifest.add(NAME.to_owned(), hash);
+
+    Ok(total)
+}
+
+/// Reads the next chunk of the reader once resumed, sending heartbeats while waiting for it.
+async fn next_chunk<Ep, R>(
+    peer: &mut Ep,
+    reader: &mut R,
+    buffer: &mut [u8],
+    channel: Option<&Channel>,
+) -> Result<usize, super::error::Send>
+where
+    Ep: EncryptedSerdeIO + Send,
+    R: AsyncRead + Unpin + Send,
+{
+    loop {
+        let next = async {
+            channel::resumed(channel).await;
+            reader.read(buffer).await
+        };
+
+        // NOTE: Reading is cancel safe, nothing read is lost when it times out
+        match tokio::time::timeout(HEARTBEAT_INTERVAL, next).await {
+            Ok(count) => return Ok(count?),
+            Err(_) => peer.write_enc(&mut [HEARTBEAT]).await?,
+        }
+    }
+}
+
+/// Writes every chunk received until the empty one,
+/// returning the amount of bytes written and whether the hash matched.
+/// Fails if a chunk does not arrive before the stall timeout, not counting local pauses.
+pub async fn receive<Ep, W>(
+    peer: &mut Ep,
+    writer: W,
+    manifest: &mut Manifest,
+    (stall, channel): (Duration, Option<&Channel>),
+) -> Result<(u64, bool), super::error::Receive>
+where
+    Ep: EncryptedSerdeIO + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut writer = BufWriter::with_capacity(10 * BUFFER_SIZE, writer);
+    let mut hasher = Hasher::default();
+    let mut buffer = vec![0; BUFFER_SIZE + 1];
+    let mut total = 0;
+
+    loop {
+        channel::resumed(channel).await;
+
+        let count = tokio::time::timeout(stall, peer.read_enc(&mut buffer))
+            .await
+            .map_err(|_| super::error::Receive::Stalled)??;
+
+        let data = match buffer[..count] {
+            [] => break,
+            [HEARTBEAT] => continue,
+            [DATA, ref data @ ..] => data,
+            _ => return Err(crate::io::Error::UnexpectedMessage.into()),
+        };
+
+        total += data.len() as u64;
+        channel::send(channel, Message::Progress(data.len())).await;
+
+        hasher.add(data);
+        writer.write_all(data).await?;
+    }
+
+    writer.flush().await?;
+
+    let hash = hasher.finalize();
+    let Hash(received) = peer.read_ser_enc::<Hash>().await?;
+
+    if hash != received {
+        log::warn!("Calculated hash and received hash do not match for piped transfer");
+    }
+
+    manifest.add(NAME.to_owned(), hash);
+
+    Ok((total, hash == received))
+}
@@ -0,0 +1,72 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use clap::Parser;
+
+const DEFAULT_PORT: u16 = 8765;
+const DEFAULT_GRACE_PERIOD: u64 = 30;
+const DEFAULT_PAIRING_TIMEOUT: u64 = 5 * 60;
+const DEFAULT_UDP_RATE: u32 = 20;
+
+#[derive(Debug, Parser)]
+#[command(name = "Aporture Server", author, version, about)]
+pub struct Args {
+    /// Address of the interface to listen on
+    #[arg(short, long, env = "APORTURE_BIND", default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
+    pub bind: IpAddr,
+
+    /// Port to listen on for both tcp and udp
+    #[arg(short, long, env = "APORTURE_PORT", default_value_t = DEFAULT_PORT)]
+    pub port: u16,
+
+    /// Seconds to wait for in progress pairings to finish when shutting down
+    #[arg(short, long, env = "APORTURE_GRACE_PERIOD", default_value_t = DEFAULT_GRACE_PERIOD, value_names(["SECONDS"]))]
+    pub grace_period: u64,
+
+    /// Seconds a peer can wait for its pair before being disconnected
+    #[arg(long, env = "APORTURE_PAIRING_TIMEOUT", default_value_t = DEFAULT_PAIRING_TIMEOUT, value_names(["SECONDS"]))]
+    pub pairing_timeout: u64,
+
+    /// Udp requests per second allowed from a single ip
+    #[arg(long, env = "APORTURE_UDP_RATE", default_value_t = DEFAULT_UDP_RATE, value_parser = clap::value_parser!(u32).range(1..))]
+    pub udp_rate: u32,
+
+    /// Senders allowed to wait for their pair at once, unlimited if not set
+    #[arg(long, env = "APORTURE_MAX_PAIRINGS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_pairings: Option<u64>,
+
+    /// Bytes relayed for a single pairing, adding both directions, before closing both peers.
+    /// Unlimited if not set
+    #[arg(long, env = "APORTURE_RELAY_MAX_BYTES", value_names(["BYTES"]), value_parser = clap::value_parser!(u64).range(1..))]
+    pub relay_max_bytes: Option<u64>,
+
+    /// Port to serve Prometheus metrics on, disabled if not set
+    #[cfg(feature = "metrics")]
+    #[arg(long, env = "APORTURE_METRICS_PORT")]
+    pub metrics_port: Option<u16>,
+}
+
+impl Args {
+    pub const fn address(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_port
+            .map(|port| SocketAddr::new(self.bind, port))
+    }
+
+    pub const fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period)
+    }
+
+    pub const fn pairing_timeout(&self) -> Duration {
+        Duration::from_secs(self.pairing_timeout)
+    }
+
+    pub fn max_pairings(&self) -> Option<usize> {
+        self.max_pairings
+            .map(|max| usize::try_from(max).unwrap_or(usize::MAX))
+    }
+}
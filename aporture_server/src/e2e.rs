@@ -0,0 +1,354 @@
+//! Pairs a real sender and receiver through the server and transfers a file between them,
+//! all in process, catching regressions that unit tests of each side on its own can not.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use aporture::pairing::{AporturePairingProtocol, Kind, Start};
+use aporture::transfer::{AportureTransferProtocol, Route, TransferOptions};
+use aporture::{Receiver, Sender, State};
+use tokio_util::sync::CancellationToken;
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+const PASSPHRASE: &[u8] = b"end-to-end-test";
+
+/// Config folder of every test in the process, so the config of the user is never touched
+static CONFIG: LazyLock<tempfile::TempDir> =
+    LazyLock::new(|| tempfile::tempdir().expect("Temp dir"));
+
+fn isolate() {
+    // NOTE: Only fails if another test already set the same folder
+    let _ = aporture::fs::set_path(CONFIG.path().to_owned());
+}
+
+/// Starts the server on an ephemeral port, returning its address.
+async fn server(shutdown: &CancellationToken) -> SocketAddr {
+    let address = std::net::TcpListener::bind((LOCALHOST, 0))
+        .and_then(|l| l.local_addr())
+        .expect("Free ephemeral port");
+
+    tokio::spawn(super::app_handler(
+        address,
+        Duration::from_secs(1),
+        Duration::from_secs(90),
+        super::Limits::default(),
+        shutdown.clone(),
+    ));
+    tokio::spawn(super::address_handler(address, 1000, shutdown.clone()));
+
+    for _ in 0..50 {
+        if tokio::net::TcpStream::connect(address).await.is_ok() {
+            return address;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    panic!("Server is not listening on {address}");
+}
+
+/// Keeps every candidate on loopback, so the test does not depend on the network it runs on.
+fn loopback<K: Kind>(app: &mut AporturePairingProtocol<Start<K>>, server: SocketAddr) {
+    app.set_servers(vec![server]);
+    app.set_bind_ip(LOCALHOST);
+    app.set_upnp(false);
+    app.set_hole_punching(false);
+    app.set_timeout(Duration::from_secs(20));
+}
+
+async fn send(server: SocketAddr, path: &Path) -> aporture::transfer::SendReport {
+    let mut app = AporturePairingProtocol::<Sender>::new(PASSPHRASE.to_vec(), false);
+    loopback(&mut app, server);
+
+    let mut pair_info = app.pair().await.expect("Sender paired");
+
+    let report = AportureTransferProtocol::<Sender>::new(&mut pair_info, path)
+        .transfer()
+        .await
+        .expect("Sent");
+
+    pair_info.finalize().await;
+
+    report
+}
+
+async fn receive(server: SocketAddr, dest: &Path) -> aporture::transfer::TransferReport {
+    let mut app = AporturePairingProtocol::<Receiver>::new(PASSPHRASE.to_vec(), false);
+    loopback(&mut app, server);
+    app.set_wait(Duration::from_secs(10));
+
+    let mut pair_info = app.pair().await.expect("Receiver paired");
+
+    let report = AportureTransferProtocol::<Receiver>::new(&mut pair_info, dest)
+        .transfer()
+        .await
+        .expect("Received");
+
+    pair_info.finalize().await;
+
+    report
+}
+
+#[tokio::test]
+async fn test_file_is_paired_and_transferred_through_server() {
+    isolate();
+
+    let shutdown = CancellationToken::new();
+    let address = server(&shutdown).await;
+
+    let source = tempfile::tempdir().expect("Temp dir");
+    let file = source.path().join("data.bin");
+    let contents = (0..200_000_u32)
+        .flat_map(u32::to_le_bytes)
+        .collect::<Vec<_>>();
+    std::fs::write(&file, &contents).expect("Write file");
+
+    let dest = tempfile::tempdir().expect("Temp dir");
+
+    let transfer = async {
+        tokio::join!(
+            Box::pin(send(address, &file)),
+            receive(address, dest.path())
+        )
+    };
+    let (sent, received) = tokio::time::timeout(Duration::from_secs(30), transfer)
+        .await
+        .expect("Transfer must not hang");
+
+    shutdown.cancel();
+
+    assert_eq!(dest.path().join("data.bin"), received.destination);
+    assert_eq!(
+        contents,
+        std::fs::read(&received.destination).expect("Read")
+    );
+
+    assert!(sent.verified && received.verified);
+    assert_eq!(sent.root, received.root);
+    assert_eq!(contents.len() as u64, received.total_size);
+
+    // NOTE: Only the local candidate is offered, so falling back to the relay means it broke
+    assert_ne!(Route::Relay, received.route);
+}
+
+/// Gives no time to connect directly, so the transfer needs the relay kept by the session.
+fn relayed<S: State>() -> TransferOptions<S> {
+    TransferOptions::default().connection_timeout(Duration::ZERO)
+}
+
+#[tokio::test]
+async fn test_session_transfers_directly_then_through_kept_relay() {
+    isolate();
+
+    let shutdown = CancellationToken::new();
+    let address = server(&shutdown).await;
+
+    let source = tempfile::tempdir().expect("Temp dir");
+    let (first, second) = (source.path().join("first"), source.path().join("second"));
+    std::fs::write(&first, b"first transfer").expect("Write file");
+    std::fs::write(&second, b"second transfer").expect("Write file");
+
+    let dest = tempfile::tempdir().expect("Temp dir");
+
+    let send = async {
+        let mut app = AporturePairingProtocol::<Sender>::new(PASSPHRASE.to_vec(), false);
+        loopback(&mut app, address);
+
+        let mut pair_info = app.pair().await.expect("Sender paired");
+        pair_info.set_session(true);
+
+        let direct = AportureTransferProtocol::<Sender>::new(&mut pair_info, &first)
+            .transfer()
+            .await
+            .expect("Sent directly");
+
+        let relay =
+            AportureTransferProtocol::<Sender>::with_options(&mut pair_info, &second, relayed())
+                .transfer()
+                .await
+                .expect("Sent through the relay");
+
+        pair_info.close().await;
+
+        (direct, relay)
+    };
+
+    let receive = async {
+        let mut app = AporturePairingProtocol::<Receiver>::new(PASSPHRASE.to_vec(), false);
+        loopback(&mut app, address);
+        app.set_wait(Duration::from_secs(10));
+
+        let mut pair_info = app.pair().await.expect("Receiver paired");
+        pair_info.set_session(true);
+
+        let direct = AportureTransferProtocol::<Receiver>::new(&mut pair_info, dest.path())
+            .transfer()
+            .await
+            .expect("Received directly");
+
+        let relay = AportureTransferProtocol::<Receiver>::with_options(
+            &mut pair_info,
+            dest.path(),
+            relayed(),
+        )
+        .transfer()
+        .await
+        .expect("Received through the relay");
+
+        pair_info.close().await;
+
+        (direct, relay)
+    };
+
+    let transfer = async { tokio::join!(Box::pin(send), Box::pin(receive)) };
+    let ((sent_direct, sent_relay), (direct, relay)) =
+        tokio::time::timeout(Duration::from_secs(30), transfer)
+            .await
+            .expect("Transfers must not hang");
+
+    shutdown.cancel();
+
+    assert_ne!(Route::Relay, direct.route);
+    assert_eq!(Route::Relay, relay.route);
+    assert!(sent_direct.verified && sent_relay.verified);
+
+    assert_eq!(
+        b"first transfer".to_vec(),
+        std::fs::read(&direct.destination).expect("Read")
+    );
+    assert_eq!(
+        b"second transfer".to_vec(),
+        std::fs::read(&relay.destination).expect("Read")
+    );
+}
+
+/// Drops the connection when the peer is silent for a moment, so a paused sender loses it.
+fn lossy<S: State>() -> TransferOptions<S> {
+    TransferOptions::default()
+        .reconnect_window(Duration::from_millis(500))
+        .keep_alive_interval(None)
+}
+
+#[tokio::test]
+async fn test_lost_connection_resumes_folder() {
+    use aporture::transfer::ChannelMessage;
+
+    isolate();
+
+    let shutdown = CancellationToken::new();
+    let address = server(&shutdown).await;
+
+    let source = tempfile::tempdir().expect("Temp dir");
+    let folder = source.path().join("photos");
+    std::fs::create_dir(&folder).expect("Create dir");
+
+    let names = (0..8).map(|i| format!("{i}.jpg")).collect::<Vec<_>>();
+    for (i, name) in (0_u32..).zip(&names) {
+        let contents = (0..64_000_u32)
+            .flat_map(|n| (n ^ i).to_le_bytes())
+            .collect::<Vec<_>>();
+        std::fs::write(folder.join(name), contents).expect("Write file");
+    }
+
+    let dest = tempfile::tempdir().expect("Temp dir");
+
+    let (pause, paused) = tokio::sync::watch::channel(false);
+    let (sender_progress, mut sender_messages) = tokio::sync::mpsc::channel(1000);
+    let (receiver_progress, mut receiver_messages) = tokio::sync::mpsc::channel(1000);
+
+    let sending = async {
+        let mut app = AporturePairingProtocol::<Sender>::new(PASSPHRASE.to_vec(), false);
+        loopback(&mut app, address);
+
+        let mut pair_info = app.pair().await.expect("Sender paired");
+
+        // NOTE: A single stream sends the files in order, so the first ones are complete
+        let options = lossy().streams(0);
+        let mut transfer =
+            AportureTransferProtocol::<Sender>::with_options(&mut pair_info, &folder, options);
+        transfer.add_pause_control(paused);
+        transfer.add_progress_notifier(sender_progress);
+
+        let report = transfer.transfer().await.expect("Sent after reconnecting");
+
+        pair_info.finalize().await;
+
+        report
+    };
+
+    let receiving = async {
+        let mut app = AporturePairingProtocol::<Receiver>::new(PASSPHRASE.to_vec(), false);
+        loopback(&mut app, address);
+        app.set_wait(Duration::from_secs(10));
+
+        let mut pair_info = app.pair().await.expect("Receiver paired");
+
+        let mut transfer = AportureTransferProtocol::<Receiver>::with_options(
+            &mut pair_info,
+            dest.path(),
+            lossy(),
+        );
+        transfer.add_progress_notifier(receiver_progress);
+
+        let report = transfer
+            .transfer()
+            .await
+            .expect("Received after reconnecting");
+
+        pair_info.finalize().await;
+
+        report
+    };
+
+    // NOTE: Pauses the sender halfway until the receiver noticed the connection was lost
+    let lose = async {
+        let mut started = 0;
+        while let Some(message) = sender_messages.recv().await {
+            if matches!(message, ChannelMessage::FileStarted { .. }) {
+                started += 1;
+            }
+
+            if started == names.len() / 2 + 1 {
+                pause.send_replace(true);
+                break;
+            }
+        }
+
+        while let Some(message) = receiver_messages.recv().await {
+            if matches!(message, ChannelMessage::Reconnecting) {
+                pause.send_replace(false);
+                return true;
+            }
+        }
+
+        false
+    };
+
+    let transfer = async { tokio::join!(Box::pin(sending), Box::pin(receiving), lose) };
+    let (sent, received, reconnected) =
+        Box::pin(tokio::time::timeout(Duration::from_secs(30), transfer))
+            .await
+            .expect("Transfer must not hang");
+
+    shutdown.cancel();
+
+    assert!(reconnected);
+    assert!(sent.verified && received.verified);
+    assert_eq!(sent.root, received.root);
+
+    // NOTE: Files that arrived before the connection was lost are not sent again
+    assert_eq!(names.len() as u64 / 2, received.unchanged);
+
+    assert_eq!(dest.path().join("photos"), received.destination);
+    for name in &names {
+        assert_eq!(
+            std::fs::read(folder.join(name)).expect("Read"),
+            std::fs::read(received.destination.join(name)).expect("Read")
+        );
+    }
+
+    // NOTE: The staging folder was placed, nothing is left behind
+    assert_eq!(1, std::fs::read_dir(dest.path()).expect("Read dir").count());
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(30);
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Token bucket per source ip, refilled at `rate` tokens per second up to a burst of `rate`.
+pub struct RateLimiter {
+    rate: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate: f64::from(rate),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Takes a token from the ip bucket, returning false if it is empty.
+    pub async fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now()).await
+    }
+
+    async fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().await;
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.rate,
+            last: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = elapsed.mul_add(self.rate, bucket.tokens).min(self.rate);
+        bucket.last = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        drop(buckets);
+
+        allowed
+    }
+
+    /// Periodically removes the buckets of ips that stopped sending.
+    pub async fn prune_idle(&self, shutdown: CancellationToken) {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => (),
+                () = shutdown.cancelled() => return,
+            }
+
+            self.prune_at(Instant::now()).await;
+        }
+    }
+
+    async fn prune_at(&self, now: Instant) {
+        let mut buckets = self.buckets.lock().await;
+
+        buckets.retain(|_, b| now.saturating_duration_since(b.last) < PRUNE_INTERVAL);
+        let remaining = buckets.len();
+
+        drop(buckets);
+
+        log::debug!("{remaining} udp sources being rate limited");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    #[tokio::test]
+    async fn test_bucket_drops_requests_over_rate() {
+        let limiter = RateLimiter::new(20);
+        let now = Instant::now();
+
+        for _ in 0..20 {
+            assert!(limiter.allow_at(IP, now).await);
+        }
+        assert!(!limiter.allow_at(IP, now).await);
+
+        let other = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+        assert!(limiter.allow_at(other, now).await);
+
+        let later = now + Duration::from_millis(100);
+        assert!(limiter.allow_at(IP, later).await);
+        assert!(limiter.allow_at(IP, later).await);
+        assert!(!limiter.allow_at(IP, later).await);
+    }
+
+    #[tokio::test]
+    async fn test_idle_buckets_are_pruned() {
+        let limiter = RateLimiter::new(20);
+        let now = Instant::now();
+
+        assert!(limiter.allow_at(IP, now).await);
+
+        limiter.prune_at(now + PRUNE_INTERVAL / 2).await;
+        assert_eq!(1, limiter.buckets.lock().await.len());
+
+        limiter.prune_at(now + PRUNE_INTERVAL).await;
+        assert!(limiter.buckets.lock().await.is_empty());
+    }
+}
@@ -1,13 +1,24 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use aporture::parser::Parser;
 use aporture::protocol::HolePunchingRequest;
-use net::Connection;
+use args::Args;
+use clap::Parser as _;
+use limit::RateLimiter;
+use net::{Connection, Limits};
 use tokio::net::{TcpListener, UdpSocket};
 use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 
+mod args;
+#[cfg(test)]
+mod e2e;
+mod limit;
+mod metrics;
 mod net;
 
 fn init_logger() {
@@ -30,43 +41,148 @@ fn init_logger() {
         .init();
 }
 
-const DEFAULT_PORT: u16 = 8765;
-
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
     init_logger();
 
-    let address = ([0, 0, 0, 0], DEFAULT_PORT).into();
+    let args = Args::parse();
+
+    let address = args.address();
+
+    let shutdown = CancellationToken::new();
+
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+
+        async move {
+            if let Err(e) = shutdown_signal().await {
+                log::error!("Could not listen for shutdown signals: {e}");
+                return;
+            }
+
+            log::info!("Shutdown signal received, no longer accepting connections");
+            shutdown.cancel();
+        }
+    });
+
+    #[cfg(feature = "metrics")]
+    if let Some(metrics_address) = args.metrics_address() {
+        tokio::spawn(metrics::serve(metrics_address, shutdown.clone()));
+    }
 
     tokio::try_join! {
-        app_handler(address),
-        address_handler(address),
+        app_handler(
+            address,
+            args.grace_period(),
+            args.pairing_timeout(),
+            Limits {
+                max_pairings: args.max_pairings(),
+                relay_max_bytes: args.relay_max_bytes,
+            },
+            shutdown.clone(),
+        ),
+        address_handler(address, args.udp_rate, shutdown),
     }?;
 
     Ok(())
 }
 
-async fn app_handler(address: SocketAddr) -> Result<(), std::io::Error> {
+async fn shutdown_signal() -> Result<(), std::io::Error> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+async fn app_handler(
+    address: SocketAddr,
+    grace_period: Duration,
+    pairing_timeout: Duration,
+    limits: Limits,
+    shutdown: CancellationToken,
+) -> Result<(), std::io::Error> {
     log::info!("Binding to tcp {address}");
 
-    let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT))).await?;
+    let listener = TcpListener::bind(address).await?;
 
     let map: Arc<Mutex<HashMap<[u8; 32], Connection>>> = Arc::default();
+    let connections = TaskTracker::new();
+
+    tokio::spawn(net::evict_idle(
+        map.clone(),
+        pairing_timeout,
+        shutdown.clone(),
+    ));
 
     log::info!("Server ready to accept connections");
 
     loop {
-        let connection = Connection::from(listener.accept().await?);
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            () = shutdown.cancelled() => break,
+        };
+
+        let connection = Connection::from(accepted);
+
+        connections.spawn(net::handle_connection(connection, map.clone(), limits));
+    }
+
+    drop(listener);
+    connections.close();
 
-        tokio::spawn(net::handle_connection(connection, map.clone()));
+    log::info!(
+        "Waiting up to {}s for {} connections to finish",
+        grace_period.as_secs(),
+        connections.len()
+    );
+
+    if tokio::time::timeout(grace_period, connections.wait())
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "Grace period expired with {} connections still in progress",
+            connections.len()
+        );
+    }
+
+    let pending = map.lock().await.len();
+    if pending > 0 {
+        log::warn!("Shutting down with {pending} pairings still waiting for a peer");
     }
+
+    log::info!("Server stopped");
+
+    Ok(())
 }
 
-async fn address_handler(address: SocketAddr) -> Result<(), std::io::Error> {
+async fn address_handler(
+    address: SocketAddr,
+    rate: u32,
+    shutdown: CancellationToken,
+) -> Result<(), std::io::Error> {
     log::info!("Binding to udp {address}");
 
     let socket = Mutex::new(Arc::new(UdpSocket::bind(address).await?));
 
+    let limiter = Arc::new(RateLimiter::new(rate));
+
+    tokio::spawn({
+        let limiter = Arc::clone(&limiter);
+        let shutdown = shutdown.clone();
+
+        async move { limiter.prune_idle(shutdown).await }
+    });
+
     log::info!("Server ready to accept udp connections");
 
     loop {
@@ -75,10 +191,18 @@ async fn address_handler(address: SocketAddr) -> Result<(), std::io::Error> {
         drop(socket);
         let mut buffer = [0; 1500];
 
-        let (len, address) = s.recv_from(&mut buffer).await?;
+        let (len, address) = tokio::select! {
+            received = s.recv_from(&mut buffer) => received?,
+            () = shutdown.cancelled() => return Ok(()),
+        };
 
         log::debug!("UDP message");
 
+        if !limiter.allow(address.ip()).await {
+            log::warn!("Dropping udp message from {address}, rate limit exceeded");
+            continue;
+        }
+
         tokio::spawn(async move {
             let Ok(message) = HolePunchingRequest::deserialize_from(&buffer[..len]) else {
                 log::warn!("Invalid UDP message");
@@ -93,6 +217,8 @@ async fn address_handler(address: SocketAddr) -> Result<(), std::io::Error> {
 
                     if result.is_err() {
                         log::warn!("Unable to respond to udp connection");
+                    } else {
+                        metrics::hole_punch_served();
                     }
                 }
                 HolePunchingRequest::Relay => todo!(),
@@ -101,3 +227,41 @@ async fn address_handler(address: SocketAddr) -> Result<(), std::io::Error> {
         });
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_app_handler_binds_given_address() {
+        let address = std::net::TcpListener::bind(("127.0.0.1", 0))
+            .and_then(|l| l.local_addr())
+            .expect("Free ephemeral port");
+
+        let shutdown = CancellationToken::new();
+        let handler = tokio::spawn(app_handler(
+            address,
+            Duration::from_secs(1),
+            Duration::from_secs(90),
+            Limits::default(),
+            shutdown.clone(),
+        ));
+
+        let mut connected = false;
+        for _ in 0..50 {
+            if tokio::net::TcpStream::connect(address).await.is_ok() {
+                connected = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+
+        assert!(!handler.is_finished(), "Handler stopped: bind failed");
+        assert!(connected, "Handler is not listening on {address}");
+
+        shutdown.cancel();
+
+        let stopped = tokio::time::timeout(Duration::from_secs(3), handler).await;
+        assert!(matches!(stopped, Ok(Ok(Ok(())))), "Handler did not stop");
+    }
+}
@@ -0,0 +1,151 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static PAIRINGS: AtomicU64 = AtomicU64::new(0);
+static ACTIVE_PAIRINGS: AtomicU64 = AtomicU64::new(0);
+static RELAY_BYTES: AtomicU64 = AtomicU64::new(0);
+static HOLE_PUNCH_REQUESTS: AtomicU64 = AtomicU64::new(0);
+static RELAY_LIMITED: AtomicU64 = AtomicU64::new(0);
+
+pub fn pairing_started() {
+    PAIRINGS.fetch_add(1, Ordering::Relaxed);
+    ACTIVE_PAIRINGS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn pairing_finished(relayed: u64) {
+    ACTIVE_PAIRINGS.fetch_sub(1, Ordering::Relaxed);
+    RELAY_BYTES.fetch_add(relayed, Ordering::Relaxed);
+}
+
+pub fn hole_punch_served() {
+    HOLE_PUNCH_REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn relay_limited() {
+    RELAY_LIMITED.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(feature = "metrics")]
+pub use endpoint::serve;
+
+#[cfg(feature = "metrics")]
+mod endpoint {
+    use std::fmt::Write as _;
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::sync::CancellationToken;
+
+    use super::{Ordering, ACTIVE_PAIRINGS, HOLE_PUNCH_REQUESTS, PAIRINGS};
+    use super::{RELAY_BYTES, RELAY_LIMITED};
+
+    /// Serves the counters in Prometheus text format until shutdown.
+    pub async fn serve(
+        address: SocketAddr,
+        shutdown: CancellationToken,
+    ) -> Result<(), std::io::Error> {
+        log::info!("Binding metrics to tcp {address}");
+
+        let listener = TcpListener::bind(address).await?;
+
+        loop {
+            let (stream, _) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                () = shutdown.cancelled() => return Ok(()),
+            };
+
+            tokio::spawn(async move {
+                if let Err(e) = respond(stream).await {
+                    log::warn!("Error answering metrics request: {e}");
+                }
+            });
+        }
+    }
+
+    async fn respond(mut stream: TcpStream) -> Result<(), std::io::Error> {
+        let mut buffer = [0; 1024];
+        let len = stream.read(&mut buffer).await?;
+
+        let response = if buffer[..len].starts_with(b"GET /metrics") {
+            let body = render();
+
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        } else {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+        };
+
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+
+    pub fn render() -> String {
+        let metrics = [
+            (
+                "aporture_pairings_total",
+                "counter",
+                "Pairings started between two peers",
+                PAIRINGS.load(Ordering::Relaxed),
+            ),
+            (
+                "aporture_active_pairings",
+                "gauge",
+                "Pairings currently relaying data",
+                ACTIVE_PAIRINGS.load(Ordering::Relaxed),
+            ),
+            (
+                "aporture_relay_bytes_total",
+                "counter",
+                "Bytes forwarded between paired peers",
+                RELAY_BYTES.load(Ordering::Relaxed),
+            ),
+            (
+                "aporture_hole_punch_requests_total",
+                "counter",
+                "Address requests answered for hole punching",
+                HOLE_PUNCH_REQUESTS.load(Ordering::Relaxed),
+            ),
+            (
+                "aporture_relay_limited_total",
+                "counter",
+                "Pairings closed for going over the relay limit",
+                RELAY_LIMITED.load(Ordering::Relaxed),
+            ),
+        ];
+
+        metrics
+            .into_iter()
+            .fold(String::new(), |mut out, (name, kind, help, value)| {
+                let _ = writeln!(out, "# HELP {name} {help}");
+                let _ = writeln!(out, "# TYPE {name} {kind}");
+                let _ = writeln!(out, "{name} {value}");
+                out
+            })
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_render_exposes_every_counter() {
+            super::super::hole_punch_served();
+
+            let rendered = render();
+
+            for name in [
+                "aporture_pairings_total",
+                "aporture_active_pairings",
+                "aporture_relay_bytes_total",
+                "aporture_hole_punch_requests_total",
+                "aporture_relay_limited_total",
+            ] {
+                assert!(rendered.contains(&format!("# TYPE {name} ")));
+            }
+
+            assert!(!rendered.contains("aporture_hole_punch_requests_total 0\n"));
+        }
+    }
+}
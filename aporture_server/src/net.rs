@@ -1,17 +1,63 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use aporture::net::message::{self, HEADER_SIZE, MAX_MESSAGE_SIZE};
+use aporture::net::peer::send_relay_limit;
 use aporture::net::NetworkPeer;
 use aporture::parser::SerdeIO;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::{Mutex, MutexGuard};
+use tokio_util::sync::CancellationToken;
 
-use aporture::protocol::{Hello, PairKind, PairingResponseCode};
+use aporture::protocol::{
+    Hello, PairKind, PairingResponseCode, SupportedVersion, PROTOCOL_VERSION,
+};
+
+use crate::metrics;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The relay holds up to a whole message in each direction, so it only forwards whole ones
+const RELAY_BUFFER_SIZE: usize = MAX_MESSAGE_SIZE;
+
+/// Limits that keep the server from being abused, none of them set by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// Senders allowed to wait for their pair at once
+    pub max_pairings: Option<usize>,
+    /// Bytes relayed for a single pairing, adding both directions
+    pub relay_max_bytes: Option<u64>,
+}
+
+/// Short tag that relates the logs of both peers of a pairing.
+///
+/// It is taken from the pair id, which is already a hash, so the passphrase never reaches the logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Correlation([u8; 4]);
+
+impl Correlation {
+    pub fn new(id: &[u8]) -> Self {
+        let mut tag = [0; 4];
+        tag.iter_mut().zip(id).for_each(|(t, b)| *t = *b);
+
+        Self(tag)
+    }
+}
+
+impl std::fmt::Display for Correlation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
 
 pub struct Connection {
     pub stream: NetworkPeer,
     pub address: SocketAddr,
+    pub since: Instant,
 }
 
 impl From<(TcpStream, SocketAddr)> for Connection {
@@ -20,6 +66,7 @@ impl From<(TcpStream, SocketAddr)> for Connection {
         Self {
             stream: peer,
             address,
+            since: Instant::now(),
         }
     }
 }
@@ -27,6 +74,7 @@ impl From<(TcpStream, SocketAddr)> for Connection {
 pub async fn handle_connection(
     mut connection: Connection,
     map: Arc<Mutex<HashMap<[u8; 32], Connection>>>,
+    limits: Limits,
 ) {
     let hello = match connection.stream.read_ser::<Hello>().await {
         Ok(hello) => hello,
@@ -45,22 +93,47 @@ pub async fn handle_connection(
         }
     };
 
-    if hello.version != aporture::protocol::PROTOCOL_VERSION {
-        log::warn!("Not supported protocol version");
+    if hello.version != PROTOCOL_VERSION {
+        log::warn!("Not supported protocol version {}", hello.version);
 
         let _ = connection
             .stream
             .write_ser(&PairingResponseCode::UnsupportedVersion)
             .await;
 
+        // NOTE: Lets the client tell which side must be updated
+        let _ = connection
+            .stream
+            .write_ser(&SupportedVersion(PROTOCOL_VERSION))
+            .await;
+
         return;
     }
 
     let map = map.lock().await;
 
+    // NOTE: Receivers are always let in, as they free the place of their sender
+    let full = limits.max_pairings.is_some_and(|max| map.len() >= max);
+    if full && hello.kind == PairKind::Sender && !map.contains_key(&hello.pair_id) {
+        drop(map);
+
+        log::info!(
+            "[{}] failed: server busy, refusing sender from {}",
+            Correlation::new(&hello.pair_id),
+            connection.address
+        );
+
+        let _ = connection
+            .stream
+            .write_ser(&PairingResponseCode::ServerBusy)
+            .await;
+
+        return;
+    }
+
     match hello.kind {
         PairKind::Sender => handle_sender(connection, hello.pair_id, map),
-        PairKind::Receiver => handle_receiver(connection, &hello.pair_id, map).await,
+        PairKind::Receiver => handle_receiver(connection, &hello.pair_id, map, limits).await,
     }
 }
 
@@ -69,8 +142,16 @@ fn handle_sender(
     id: [u8; 32],
     mut map: MutexGuard<'_, HashMap<[u8; 32], Connection>>,
 ) {
-    log::info!("received hello from sender from {}", connection.address);
-    map.insert(id, connection);
+    let pairing = Correlation::new(&id);
+
+    log::info!("[{pairing}] first peer arrived from {}", connection.address);
+
+    if let Some(previous) = map.insert(id, connection) {
+        log::info!(
+            "[{pairing}] failed: sender from {} replaced by a new one",
+            previous.address
+        );
+    }
 
     drop(map);
 }
@@ -79,13 +160,18 @@ async fn handle_receiver(
     connection: Connection,
     id: &[u8],
     mut map: MutexGuard<'_, HashMap<[u8; 32], Connection>>,
+    limits: Limits,
 ) {
-    log::info!("received hello from receiver from {}", connection.address);
+    let pairing = Correlation::new(id);
+
     let mut receiver = connection;
     let Some(mut sender) = map.remove(id) else {
         drop(map);
 
-        log::warn!("Sender must arrive first and has not");
+        log::info!(
+            "[{pairing}] failed: receiver from {} arrived before the sender",
+            receiver.address
+        );
 
         let _ = receiver
             .stream
@@ -98,6 +184,23 @@ async fn handle_receiver(
     // NOTE: Drop map to allow other connections
     drop(map);
 
+    log::info!(
+        "[{pairing}] second peer matched from {} after {}s",
+        receiver.address,
+        sender.since.elapsed().as_secs()
+    );
+
+    if is_closed(&mut sender).await {
+        log::info!("[{pairing}] failed: sender left before the receiver arrived");
+
+        let _ = receiver
+            .stream
+            .write_ser(&PairingResponseCode::PeerDisconnected)
+            .await;
+
+        return;
+    }
+
     let response = if receiver.address.ip() == sender.address.ip() {
         PairingResponseCode::OkSamePublicIP
     } else {
@@ -105,34 +208,413 @@ async fn handle_receiver(
     };
 
     if sender.stream.write_ser(&response).await.is_err() {
-        log::warn!("Connection closed from sender");
+        log::info!("[{pairing}] failed: sender closed before being answered");
 
         let _ = receiver
             .stream
-            .write_ser(&PairingResponseCode::NoPeer)
+            .write_ser(&PairingResponseCode::PeerDisconnected)
             .await;
 
         return;
     }
 
     if receiver.stream.write_ser(&response).await.is_err() {
-        log::warn!("Connection closed from receiver");
+        log::info!("[{pairing}] failed: receiver closed before being answered");
 
-        let _ = sender.stream.write_ser(&PairingResponseCode::NoPeer).await;
+        let _ = sender
+            .stream
+            .write_ser(&PairingResponseCode::PeerDisconnected)
+            .await;
 
         return;
     }
 
-    log::info!("Starting bidirectional APP");
+    log::info!("[{pairing}] relaying key exchange");
+
+    metrics::pairing_started();
+    let start = Instant::now();
 
     // NOTE: Delegate talking between pairs
-    let result =
-        tokio::io::copy_bidirectional(sender.stream.inner(), receiver.stream.inner()).await;
+    let total = AtomicU64::new(0);
+    let result = relay(
+        sender.stream.inner(),
+        receiver.stream.inner(),
+        &total,
+        limits.relay_max_bytes,
+    )
+    .await;
+
+    let relayed = total.load(Ordering::Relaxed);
+    metrics::pairing_finished(relayed);
+
+    match result {
+        Ok((from_sender, from_receiver)) => log::info!(
+            "[{pairing}] negotiation complete, relayed {from_sender}B from sender and {from_receiver}B from receiver in {}ms",
+            start.elapsed().as_millis()
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::QuotaExceeded => {
+            metrics::relay_limited();
+
+            // NOTE: Both peers are told why, the transfer can not be resumed
+            close_at_limit(&mut sender.stream, &mut receiver.stream).await;
+
+            log::info!(
+                "[{pairing}] failed: relay limit reached, closed both peers after relaying {relayed}B in {}ms",
+                start.elapsed().as_millis()
+            );
+        }
+        Err(e) => log::info!(
+            "[{pairing}] failed: relay interrupted after relaying {relayed}B in {}ms: {e}",
+            start.elapsed().as_millis()
+        ),
+    }
+}
+
+/// Forwards data between both peers until both finish, returning how much each sent.
+/// Fails once the total of the pairing would go over the limit, without forwarding the excess.
+///
+/// Only whole messages are forwarded, and a direction is never stopped while writing one,
+/// so the peers can still read the message closing them after a failure.
+async fn relay(
+    sender: &mut TcpStream,
+    receiver: &mut TcpStream,
+    total: &AtomicU64,
+    max: Option<u64>,
+) -> Result<(u64, u64), std::io::Error> {
+    let (sender_read, sender_write) = sender.split();
+    let (receiver_read, receiver_write) = receiver.split();
+
+    let stop = CancellationToken::new();
+
+    let (from_sender, from_receiver) = tokio::join!(
+        forward(sender_read, receiver_write, total, max, &stop),
+        forward(receiver_read, sender_write, total, max, &stop),
+    );
+
+    Ok((from_sender?, from_receiver?))
+}
+
+/// Forwards messages in one direction, closing the writing side once the reading one is
+/// finished. A failure stops the other direction before it reads again.
+async fn forward<R, W>(
+    from: R,
+    to: W,
+    total: &AtomicU64,
+    max: Option<u64>,
+    stop: &CancellationToken,
+) -> Result<u64, std::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let result = forward_messages(from, to, total, max, stop).await;
 
     if result.is_err() {
-        log::warn!("Error during pairing");
-        return;
+        stop.cancel();
+    }
+
+    result
+}
+
+async fn forward_messages<R, W>(
+    mut from: R,
+    mut to: W,
+    total: &AtomicU64,
+    max: Option<u64>,
+    stop: &CancellationToken,
+) -> Result<u64, std::io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0; RELAY_BUFFER_SIZE];
+    let mut filled = 0;
+    let mut forwarded = 0;
+
+    loop {
+        // NOTE: The buffer always fits the next message, so it is never full here
+        let count = tokio::select! {
+            count = from.read(&mut buffer[filled..]) => count?,
+            () = stop.cancelled() => return Ok(forwarded),
+        };
+        if count == 0 {
+            break;
+        }
+        filled += count;
+
+        let complete = whole_messages(&buffer[..filled])?;
+        if complete == 0 {
+            continue;
+        }
+
+        let relayed = total.fetch_add(complete as u64, Ordering::Relaxed) + complete as u64;
+        if max.is_some_and(|max| relayed > max) {
+            return Err(std::io::Error::from(std::io::ErrorKind::QuotaExceeded));
+        }
+
+        to.write_all(&buffer[..complete]).await?;
+        forwarded += complete as u64;
+
+        buffer.copy_within(complete..filled, 0);
+        filled -= complete;
+    }
+
+    // NOTE: A peer that closed in the middle of a message does not get it any further
+    if filled > 0 {
+        log::debug!("Dropping {filled}B of an incomplete message");
+    }
+
+    to.shutdown().await?;
+
+    Ok(forwarded)
+}
+
+/// Length of the whole messages at the start of the data.
+fn whole_messages(data: &[u8]) -> Result<usize, std::io::Error> {
+    let mut length = 0;
+
+    while let Some(header) = data.get(length..length + HEADER_SIZE) {
+        let header = [header[0], header[1], header[2]];
+
+        let size = message::message_size(header)
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+
+        if length + size > data.len() {
+            break;
+        }
+
+        length += size;
+    }
+
+    Ok(length)
+}
+
+/// Tells both peers the pairing reached the relay limit, before closing them.
+async fn close_at_limit(sender: &mut NetworkPeer, receiver: &mut NetworkPeer) {
+    tokio::join!(send_relay_limit(sender), send_relay_limit(receiver));
+}
+
+/// Whether the waiting peer closed its connection, as it sends nothing until it is paired.
+async fn is_closed(connection: &mut Connection) -> bool {
+    let mut buffer = [0; 1];
+
+    // NOTE: A zero timeout only checks what already arrived without waiting for more
+    let peek = connection.stream.inner().peek(&mut buffer);
+
+    matches!(
+        tokio::time::timeout(Duration::ZERO, peek).await,
+        Ok(Ok(0) | Err(_))
+    )
+}
+
+/// Periodically disconnects the peers that waited longer than the timeout for their pair.
+pub async fn evict_idle(
+    map: Arc<Mutex<HashMap<[u8; 32], Connection>>>,
+    timeout: Duration,
+    shutdown: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(timeout.min(SWEEP_INTERVAL));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            () = shutdown.cancelled() => return,
+        }
+
+        evict_stale(&map, timeout).await;
+    }
+}
+
+async fn evict_stale(map: &Mutex<HashMap<[u8; 32], Connection>>, timeout: Duration) {
+    let mut map = map.lock().await;
+
+    let stale = map
+        .iter()
+        .filter(|(_, connection)| connection.since.elapsed() >= timeout)
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+
+    let stale = stale
+        .iter()
+        .filter_map(|id| map.remove(id).map(|c| (Correlation::new(id), c)))
+        .collect::<Vec<_>>();
+
+    // NOTE: Drop map to allow other connections
+    drop(map);
+
+    for (pairing, mut connection) in stale {
+        log::info!(
+            "[{pairing}] failed: evicting peer {} after waiting {}s for its pair",
+            connection.address,
+            connection.since.elapsed().as_secs()
+        );
+
+        let _ = connection
+            .stream
+            .write_ser(&PairingResponseCode::Expired)
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Both ends of a connection over the loopback interface, the accepted one with the
+    /// address of its peer.
+    async fn connected_pair() -> (TcpStream, (TcpStream, SocketAddr)) {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .expect("Bind ephemeral port");
+        let address = listener.local_addr().expect("Bound address");
+
+        let (connected, accepted) = tokio::join!(TcpStream::connect(address), listener.accept());
+
+        (connected.expect("Connect"), accepted.expect("Accept"))
     }
 
-    log::info!("Finished pairing");
+    #[test]
+    fn test_correlation_is_a_short_prefix_of_the_id() {
+        let mut id = [0xff; 32];
+        id[..4].copy_from_slice(&[0x0a, 0x1b, 0x2c, 0x3d]);
+
+        assert_eq!("0a1b2c3d", Correlation::new(&id).to_string());
+        assert_eq!(Correlation::new(&id), Correlation::new(&id[..4]));
+    }
+
+    #[tokio::test]
+    async fn test_stale_pairing_is_evicted() {
+        let (client, accepted) = connected_pair().await;
+        let mut connection = Connection::from(accepted);
+
+        let timeout = Duration::from_secs(90);
+        connection.since = Instant::now()
+            .checked_sub(2 * timeout)
+            .expect("Instant in the past");
+
+        let map = Mutex::new(HashMap::from([([1; 32], connection)]));
+        let (fresh, accepted) = connected_pair().await;
+        map.lock().await.insert([2; 32], Connection::from(accepted));
+
+        evict_stale(&map, timeout).await;
+
+        let map = map.into_inner();
+        assert!(!map.contains_key(&[1; 32]));
+        assert!(map.contains_key(&[2; 32]));
+
+        let response = NetworkPeer::new(client)
+            .read_ser::<PairingResponseCode>()
+            .await
+            .expect("Response to evicted peer");
+        assert_eq!(PairingResponseCode::Expired, response);
+
+        drop(fresh);
+    }
+
+    #[tokio::test]
+    async fn test_sender_is_refused_when_server_is_full() {
+        let (waiting, accepted) = connected_pair().await;
+        let map = Arc::new(Mutex::new(HashMap::from([(
+            [1; 32],
+            Connection::from(accepted),
+        )])));
+
+        let (sender, accepted) = connected_pair().await;
+        let mut sender = NetworkPeer::new(sender);
+        let connection = Connection::from(accepted);
+
+        sender
+            .write_ser(&Hello::new(PairKind::Sender, [2; 32]))
+            .await
+            .expect("Write hello");
+
+        let limits = Limits {
+            max_pairings: Some(1),
+            ..Limits::default()
+        };
+        handle_connection(connection, map.clone(), limits).await;
+
+        let response = sender
+            .read_ser::<PairingResponseCode>()
+            .await
+            .expect("Response to sender");
+        assert_eq!(PairingResponseCode::ServerBusy, response);
+        assert!(!map.lock().await.contains_key(&[2; 32]));
+
+        drop(waiting);
+    }
+
+    #[tokio::test]
+    async fn test_relay_stops_at_the_limit() {
+        let (sender, (mut sender_side, _)) = connected_pair().await;
+        let (receiver, (mut receiver_side, _)) = connected_pair().await;
+
+        let (mut sender, mut receiver) = (NetworkPeer::new(sender), NetworkPeer::new(receiver));
+
+        // NOTE: Every message is 8 bytes, so only the first one fits
+        let total = AtomicU64::new(0);
+        let relayed = relay(&mut sender_side, &mut receiver_side, &total, Some(10));
+
+        let send = async {
+            sender.write_ser(&SupportedVersion(1)).await.expect("Write");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            sender.write_ser(&SupportedVersion(2)).await.expect("Write");
+        };
+
+        let (result, ()) = tokio::join!(relayed, send);
+
+        let error = result.expect_err("Limit reached");
+        assert_eq!(std::io::ErrorKind::QuotaExceeded, error.kind());
+
+        let (mut sender_side, mut receiver_side) = (
+            NetworkPeer::new(sender_side),
+            NetworkPeer::new(receiver_side),
+        );
+        close_at_limit(&mut sender_side, &mut receiver_side).await;
+
+        let forwarded = receiver.read_ser::<SupportedVersion>().await;
+        assert_eq!(SupportedVersion(1), forwarded.expect("Read"));
+
+        for peer in [&mut receiver, &mut sender] {
+            let closed = peer.read_ser::<SupportedVersion>().await;
+            assert!(matches!(closed, Err(aporture::io::Error::RelayLimit)));
+        }
+    }
+
+    #[test]
+    fn test_only_whole_messages_are_relayed() {
+        let mut data = Vec::new();
+        for content in [b"first".as_slice(), b"second"] {
+            data.extend_from_slice(&u16::try_from(content.len()).expect("Short").to_be_bytes());
+            data.push(0);
+            data.extend_from_slice(content);
+        }
+
+        assert_eq!(data.len(), whole_messages(&data).expect("Valid"));
+        assert_eq!(8, whole_messages(&data[..data.len() - 1]).expect("Valid"));
+        assert_eq!(0, whole_messages(&data[..2]).expect("Valid"));
+
+        data[2] = 0x7f;
+        assert!(whole_messages(&data).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_receiver_is_told_when_sender_left() {
+        let (sender, accepted) = connected_pair().await;
+        let map = Mutex::new(HashMap::from([([1; 32], Connection::from(accepted))]));
+
+        drop(sender);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let (receiver, accepted) = connected_pair().await;
+        let connection = Connection::from(accepted);
+
+        handle_receiver(connection, &[1; 32], map.lock().await, Limits::default()).await;
+
+        let response = NetworkPeer::new(receiver)
+            .read_ser::<PairingResponseCode>()
+            .await
+            .expect("Response to receiver");
+        assert_eq!(PairingResponseCode::PeerDisconnected, response);
+    }
 }